@@ -0,0 +1,456 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Request, Version},
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use http_body::Frame;
+use tower::{Layer, Service};
+
+use crate::{listener::IpConnectInfo, realip::real_ip, AnyError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    // one json object per line
+    Json,
+    // apache combined log format
+    Combined,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Stdout,
+    File {
+        path: PathBuf,
+        max_size: u64,
+        keep: usize,
+    },
+}
+
+// one line per request, written from its own thread and independent from the
+// tracing subscriber. the line is written once the response body is finished,
+// so bytes and latency cover the whole body
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    target: Target,
+    format: AccessLogFormat,
+    buffer: usize,
+}
+
+impl AccessLog {
+    pub fn stdout() -> AccessLog {
+        AccessLog {
+            target: Target::Stdout,
+            format: AccessLogFormat::Json,
+            buffer: 10_000,
+        }
+    }
+    // rotated to `<path>.1`, `<path>.2`, ... once it grows past 100MiB, 5 old files are kept
+    pub fn file<P: Into<PathBuf>>(path: P) -> AccessLog {
+        AccessLog {
+            target: Target::File {
+                path: path.into(),
+                max_size: 100 * 1024 * 1024,
+                keep: 5,
+            },
+            format: AccessLogFormat::Json,
+            buffer: 10_000,
+        }
+    }
+    // TOKI_ACCESS_LOG is `stdout` or a file path, TOKI_ACCESS_LOG_FORMAT `json` or `combined`
+    pub fn from_env() -> Option<AccessLog> {
+        let log = match env::var("TOKI_ACCESS_LOG").ok()?.as_str() {
+            "" | "off" => return None,
+            "stdout" | "-" => AccessLog::stdout(),
+            path => AccessLog::file(path),
+        };
+        match env::var("TOKI_ACCESS_LOG_FORMAT").as_deref() {
+            Ok("combined") => Some(log.format(AccessLogFormat::Combined)),
+            _ => Some(log),
+        }
+    }
+    pub fn format(mut self, format: AccessLogFormat) -> AccessLog {
+        self.format = format;
+        self
+    }
+    // only applies to file logs, 0 never rotates
+    pub fn max_size(mut self, bytes: u64) -> AccessLog {
+        if let Target::File { max_size, .. } = &mut self.target {
+            *max_size = bytes;
+        }
+        self
+    }
+    pub fn keep(mut self, files: usize) -> AccessLog {
+        if let Target::File { keep, .. } = &mut self.target {
+            *keep = files;
+        }
+        self
+    }
+    // lines waiting for the writer, further ones are dropped and counted
+    pub fn buffer(mut self, lines: usize) -> AccessLog {
+        self.buffer = lines;
+        self
+    }
+    // opens the log and starts the writer thread
+    pub fn layer(self) -> Result<AccessLogLayer, AnyError> {
+        let mut writer = Writer::open(self.target)?;
+        let (tx, rx) = mpsc::sync_channel::<String>(self.buffer);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let counter = dropped.clone();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                let mut reported = 0;
+                let mut last: Option<Instant> = None;
+                for line in rx {
+                    if let Err(e) = writer.write(&line) {
+                        tracing::warn!("access log write failed: {}", e);
+                    }
+                    // at most once a second while the writer falls behind
+                    let dropped = counter.load(Ordering::Relaxed);
+                    if dropped > reported && last.is_none_or(|l| l.elapsed().as_secs() >= 1) {
+                        last = Some(Instant::now());
+                        tracing::warn!("access log dropped {} lines", dropped - reported);
+                        reported = dropped;
+                    }
+                }
+            })?;
+        Ok(AccessLogLayer {
+            format: self.format,
+            tx,
+            dropped,
+        })
+    }
+}
+
+enum Writer {
+    Stdout(io::Stdout),
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_size: u64,
+        keep: usize,
+    },
+}
+
+impl Writer {
+    fn open(target: Target) -> io::Result<Writer> {
+        match target {
+            Target::Stdout => Ok(Writer::Stdout(io::stdout())),
+            Target::File {
+                path,
+                max_size,
+                keep,
+            } => {
+                let file = append(&path)?;
+                let size = file.metadata()?.len();
+                Ok(Writer::File {
+                    path,
+                    file,
+                    size,
+                    max_size,
+                    keep,
+                })
+            }
+        }
+    }
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Writer::Stdout(stdout) => stdout.lock().write_all(line.as_bytes()),
+            Writer::File {
+                path,
+                file,
+                size,
+                max_size,
+                keep,
+            } => {
+                if *max_size > 0 && *size > 0 && *size + line.len() as u64 > *max_size {
+                    rotate(path, *keep)?;
+                    *file = append(path)?;
+                    *size = 0;
+                }
+                file.write_all(line.as_bytes())?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    let _ = fs::remove_file(numbered(keep));
+    for n in (1..keep).rev() {
+        match fs::rename(numbered(n), numbered(n + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    fs::rename(path, numbered(1))
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogLayer {
+    format: AccessLogFormat,
+    tx: mpsc::SyncSender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLogLayer {
+    // lines dropped so far because the writer could not keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLogService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLogService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogService<S> {
+    inner: S,
+    layer: AccessLogLayer,
+}
+
+struct Entry {
+    format: AccessLogFormat,
+    tx: mpsc::SyncSender<String>,
+    dropped: Arc<AtomicU64>,
+    start: Instant,
+    time: SystemTime,
+    method: String,
+    target: String,
+    path: String,
+    version: Version,
+    ip: Option<String>,
+    request_id: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    status: u16,
+    bytes: u64,
+}
+
+impl<S, B> Service<Request<B>> for AccessLogService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let headers = req.headers();
+        let mut entry = Entry {
+            format: self.layer.format,
+            tx: self.layer.tx.clone(),
+            dropped: self.layer.dropped.clone(),
+            start: Instant::now(),
+            time: SystemTime::now(),
+            method: req.method().to_string(),
+            target: req
+                .uri()
+                .path_and_query()
+                .map(|p| p.as_str().to_string())
+                .unwrap_or_else(|| "/".to_string()),
+            path: req.uri().path().to_string(),
+            version: req.version(),
+            ip: req
+                .extensions()
+                .get::<ConnectInfo<IpConnectInfo>>()
+                .map(|info| real_ip(headers, &info.0)),
+            request_id: header_value(headers, "x-request-id"),
+            referer: header_value(headers, header::REFERER.as_str()),
+            user_agent: header_value(headers, header::USER_AGENT.as_str()),
+            status: 0,
+            bytes: 0,
+        };
+        let fut = inner.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            entry.status = res.status().as_u16();
+            if entry.request_id.is_none() {
+                entry.request_id = header_value(res.headers(), "x-request-id");
+            }
+            let (parts, body) = res.into_parts();
+            Ok(Response::from_parts(
+                parts,
+                Body::new(Counted {
+                    inner: body,
+                    entry: Some(entry),
+                }),
+            ))
+        })
+    }
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+// counts the body as it is sent and writes the line when it ends or is dropped
+struct Counted {
+    inner: Body,
+    entry: Option<Entry>,
+}
+
+impl http_body::Body for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(entry)) = (frame.data_ref(), self.entry.as_mut()) {
+                    entry.bytes += data.len() as u64;
+                }
+            }
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Counted {
+    fn finish(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            // requests never wait on the log
+            if let Err(mpsc::TrySendError::Full(_)) = entry.tx.try_send(entry.line()) {
+                entry.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl Entry {
+    fn line(&self) -> String {
+        let latency = self.start.elapsed().as_secs_f64() * 1000.0;
+        let secs = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (year, month, day, hour, minute, second) = civil(secs.as_secs());
+        match self.format {
+            AccessLogFormat::Json => {
+                let line = serde_json::json!({
+                    "ts": format!(
+                        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                        year, month, day, hour, minute, second, secs.subsec_millis()
+                    ),
+                    "method": self.method,
+                    "path": self.path,
+                    "status": self.status,
+                    "bytes": self.bytes,
+                    "latency": (latency * 1000.0).round() / 1000.0,
+                    "real_ip": self.ip,
+                    "request_id": self.request_id,
+                    "user_agent": self.user_agent,
+                });
+                format!("{}\n", line)
+            }
+            AccessLogFormat::Combined => {
+                const MONTHS: [&str; 12] = [
+                    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+                    "Dec",
+                ];
+                format!(
+                    "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{} {} {:?}\" {} {} \"{}\" \"{}\"\n",
+                    self.ip.as_deref().unwrap_or("-"),
+                    day,
+                    MONTHS[month as usize - 1],
+                    year,
+                    hour,
+                    minute,
+                    second,
+                    self.method,
+                    escape(&self.target),
+                    self.version,
+                    self.status,
+                    match self.bytes {
+                        0 => "-".to_string(),
+                        bytes => bytes.to_string(),
+                    },
+                    escape(self.referer.as_deref().unwrap_or("-")),
+                    escape(self.user_agent.as_deref().unwrap_or("-")),
+                )
+            }
+        }
+    }
+}
+
+// quotes and control characters would break the line apart
+fn escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+// utc date and time from a unix timestamp, see howardhinnant.github.io/date_algorithms.html
+pub(crate) fn civil(secs: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
@@ -0,0 +1,232 @@
+use std::sync::Arc;
+
+use axum::Router;
+use futures_util::StreamExt;
+use rustls_acme::{
+    caches::{DirCache, NoCache},
+    is_tls_alpn_challenge, AcmeConfig,
+};
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+use tokio_rustls::LazyConfigAcceptor;
+
+use crate::listener::{
+    accept_tcp, bind_tcp, describe_tcp, option_flag, serve_with, shutdown_requested, split_options,
+    ServeOptions, Shutdown,
+};
+
+pub(crate) async fn listen_acme<F>(
+    addr: &str,
+    app: F,
+    serve_options: &ServeOptions,
+    shutdown: Shutdown,
+) where
+    F: FnOnce(&str) -> Router,
+{
+    let (bind, options) = split_options(addr.strip_prefix("acme:").unwrap());
+    let domains = list_option(options.get("domains"));
+    if domains.is_empty() {
+        tracing::error!("acme: no domains given for {}", addr);
+        std::process::exit(2401);
+    }
+    let staging = match option_flag(&options, "staging") {
+        Ok(staging) => staging.unwrap_or(false),
+        Err(e) => {
+            tracing::error!("acme: {}", e);
+            std::process::exit(2401);
+        }
+    };
+    let contact = list_option(options.get("contact"))
+        .into_iter()
+        .map(|c| match c.contains(':') {
+            true => c,
+            false => format!("mailto:{}", c),
+        });
+    let mut config = AcmeConfig::new(domains)
+        .contact(contact)
+        .directory_lets_encrypt(!staging);
+    if let Some(directory) = options.get("directory") {
+        config = config.directory(directory);
+    }
+    let config = match options.get("cache").map(|c| c.as_str()) {
+        #[cfg(feature = "kv")]
+        Some(cache) if cache.starts_with("kv:") => {
+            let kv = crate::KVManager::new(cache.strip_prefix("kv:").unwrap().to_string());
+            match kv {
+                Ok(kv) => config.cache_with_boxed_err(KVCache(kv)),
+                Err(e) => {
+                    tracing::error!("acme: unable to open kv cache: {}", e);
+                    std::process::exit(2401);
+                }
+            }
+        }
+        Some(dir) => config.cache_with_boxed_err(DirCache::new(dir.to_string())),
+        None => {
+            tracing::warn!("acme: no cache configured, certificates are not persisted");
+            config.cache_with_boxed_err(NoCache::<std::io::Error>::default())
+        }
+    };
+
+    let mut state = config.state();
+    let challenge_config = state.challenge_rustls_config();
+    #[cfg(feature = "mtls")]
+    let mut default_config = match crate::mtls::client_verifier(&options) {
+        Ok(Some(verifier)) => tokio_rustls::rustls::ServerConfig::builder_with_provider(Arc::new(
+            tokio_rustls::rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(state.resolver()),
+        Ok(None) => (*state.default_rustls_config()).clone(),
+        Err(e) => {
+            tracing::error!("acme: {}", e);
+            std::process::exit(2401);
+        }
+    };
+    #[cfg(not(feature = "mtls"))]
+    if options.contains_key("client_ca") {
+        tracing::error!("mtls support is not enabled");
+        std::process::exit(9);
+    }
+    #[cfg(not(feature = "mtls"))]
+    let mut default_config = (*state.default_rustls_config()).clone();
+    default_config.alpn_protocols = serve_options.alpn();
+    let default_config = Arc::new(default_config);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                event = state.next() => match event {
+                    Some(Ok(ok)) => tracing::info!("acme: {:?}", ok),
+                    Some(Err(err)) => tracing::error!("acme: {:?}", err),
+                    None => return,
+                },
+                _ = shutdown_requested() => return,
+            }
+        }
+    });
+
+    let listener = bind_tcp(bind).and_then(TcpListener::from_std);
+    if let Err(e) = listener {
+        tracing::error!("unable to bind to {}: {}", addr, e);
+        std::process::exit(2301);
+    }
+    let listener = listener.unwrap();
+    crate::startup::listening(describe_tcp(addr, &listener));
+    let app = app(addr);
+    serve_with(
+        || accept_tcp(&listener, &serve_options.tcp),
+        |tcp, info| {
+            let challenge_config = challenge_config.clone();
+            let default_config = default_config.clone();
+            async move {
+                let start = LazyConfigAcceptor::new(Default::default(), tcp).await?;
+                if is_tls_alpn_challenge(&start.client_hello()) {
+                    tracing::info!("acme: answering tls-alpn-01 challenge");
+                    let mut tls = start.into_stream(challenge_config).await?;
+                    tls.shutdown().await?;
+                    return Ok(None);
+                }
+                let tls = start.into_stream(default_config).await?;
+                #[cfg(feature = "mtls")]
+                let info = crate::listener::IpConnectInfo {
+                    client_cert: tls
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|certs| certs.first())
+                        .and_then(|cert| crate::ClientCert::parse(cert))
+                        .map(Arc::new),
+                    ..info
+                };
+                Ok(Some((tls, info)))
+            }
+        },
+        app,
+        serve_options,
+        shutdown,
+    )
+    .await;
+}
+
+fn list_option(value: Option<&String>) -> Vec<String> {
+    value
+        .map(|v| {
+            v.split(',')
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(feature = "kv")]
+struct KVCache(crate::KVManager);
+
+#[cfg(feature = "kv")]
+impl KVCache {
+    async fn load(&self, key: &str) -> Result<Option<Vec<u8>>, crate::AnyError> {
+        use base64::prelude::*;
+
+        match self.0.get_some::<String>(key).await? {
+            Some(data) => Ok(Some(BASE64_STANDARD.decode(data)?)),
+            None => Ok(None),
+        }
+    }
+    async fn store(&self, key: &str, data: &[u8]) -> Result<(), crate::AnyError> {
+        use base64::prelude::*;
+
+        self.0.set(key, &BASE64_STANDARD.encode(data), 3600).await?;
+        self.0.persist(key).await
+    }
+}
+
+#[cfg(feature = "kv")]
+#[axum::async_trait]
+impl rustls_acme::CertCache for KVCache {
+    type EC = crate::AnyError;
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        self.load(&cache_key("cert", domains, directory_url)).await
+    }
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        self.store(&cache_key("cert", domains, directory_url), cert)
+            .await
+    }
+}
+
+#[cfg(feature = "kv")]
+#[axum::async_trait]
+impl rustls_acme::AccountCache for KVCache {
+    type EA = crate::AnyError;
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        self.load(&cache_key("account", contact, directory_url))
+            .await
+    }
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        self.store(&cache_key("account", contact, directory_url), account)
+            .await
+    }
+}
+
+#[cfg(feature = "kv")]
+fn cache_key(kind: &str, names: &[String], directory_url: &str) -> String {
+    format!("acme:{}:{}:{}", kind, directory_url, names.join(","))
+}
@@ -0,0 +1,219 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+#[cfg(feature = "kv")]
+use serde::Deserialize;
+
+use crate::{
+    build_info::{self, BuildInfo},
+    listener::trigger_shutdown,
+    startup, Maintenance, MaintenanceState, SimpleError,
+};
+
+// routes for the internal listener, every one of them needs `authorization: Bearer <token>`
+#[derive(Clone)]
+pub struct Admin {
+    token: String,
+    config: Option<serde_json::Value>,
+    maintenance: Option<Maintenance>,
+    build_info: Option<BuildInfo>,
+    #[cfg(feature = "kv")]
+    kv: Option<crate::KVManager>,
+    #[cfg(feature = "log-reload")]
+    log_level: Option<crate::LogLevel>,
+}
+
+// with only what the process registered globally, see Admin for the rest
+pub fn router(token: &str) -> Router {
+    Admin::new(token).router()
+}
+
+#[cfg(feature = "kv")]
+#[derive(Deserialize)]
+struct Flush {
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+impl Admin {
+    // an empty token locks every route
+    pub fn new(token: &str) -> Admin {
+        Admin {
+            token: token.to_string(),
+            config: None,
+            maintenance: None,
+            build_info: None,
+            #[cfg(feature = "kv")]
+            kv: None,
+            #[cfg(feature = "log-reload")]
+            log_level: None,
+        }
+    }
+    // shown as is under GET /config, leave secrets out of it
+    pub fn config<T: serde::Serialize>(mut self, config: &T) -> Admin {
+        match serde_json::to_value(config) {
+            Ok(config) => self.config = Some(config),
+            Err(e) => tracing::warn!("admin config is not serializable: {}", e),
+        }
+        self
+    }
+    pub fn maintenance(mut self, maintenance: &Maintenance) -> Admin {
+        self.maintenance = Some(maintenance.clone());
+        self
+    }
+    // defaults to the one passed to BuildInfo::register
+    pub fn build_info(mut self, info: BuildInfo) -> Admin {
+        self.build_info = Some(info);
+        self
+    }
+    // defaults to the kv passed to startup::register_kv
+    #[cfg(feature = "kv")]
+    pub fn kv(mut self, kv: &crate::KVManager) -> Admin {
+        self.kv = Some(kv.clone());
+        self
+    }
+    #[cfg(feature = "log-reload")]
+    pub fn log_level(mut self, level: &crate::LogLevel) -> Admin {
+        self.log_level = Some(level.clone());
+        self
+    }
+    pub fn router(&self) -> Router {
+        let mut router = Router::new()
+            .route("/config", get(show_config).with_state(self.config.clone()))
+            .route(
+                "/shutdown",
+                post(|| async {
+                    tracing::warn!("shutdown requested through the admin routes");
+                    trigger_shutdown();
+                    StatusCode::ACCEPTED
+                }),
+            );
+        let info = self.build_info;
+        router = router.route(
+            "/__version",
+            get(move || async move {
+                info.or_else(build_info::registered)
+                    .map(Json)
+                    .ok_or_else(|| SimpleError::not_found("no build info is registered"))
+            }),
+        );
+        #[cfg(feature = "kv")]
+        {
+            use axum::extract::Query;
+
+            let kv = self.kv.clone();
+            router = router.route(
+                "/kv",
+                axum::routing::delete(move |Query(flush): Query<Flush>| async move {
+                    let kv = kv
+                        .or_else(startup::registered_kv)
+                        .ok_or_else(|| SimpleError::not_found("no kv is registered"))?;
+                    // flushing everything has to be spelled out
+                    if flush.prefix.is_empty() {
+                        return Err(SimpleError::bad_request("a prefix is required"));
+                    }
+                    let deleted =
+                        kv.del_prefix(&flush.prefix, flush.dry_run)
+                            .await
+                            .map_err(|e| {
+                                SimpleError::wrap(e, StatusCode::SERVICE_UNAVAILABLE)
+                                    .context("unable to flush kv")
+                            })?;
+                    tracing::warn!(
+                        "kv prefix {:?} flushed through the admin routes: {} keys{}",
+                        flush.prefix,
+                        deleted,
+                        if flush.dry_run { " (dry run)" } else { "" }
+                    );
+                    Ok(Json(serde_json::json!({
+                        "deleted": deleted,
+                        "dry_run": flush.dry_run,
+                    })))
+                }),
+            );
+        }
+        if let Some(maintenance) = &self.maintenance {
+            let show = maintenance.clone();
+            let enable = maintenance.clone();
+            let disable = maintenance.clone();
+            router = router.route(
+                "/maintenance",
+                get(move || async move {
+                    let state = show.status().await.map_err(unavailable)?;
+                    Ok::<_, SimpleError>(Json(serde_json::json!({
+                        "enabled": state.is_some(),
+                        "state": state,
+                    })))
+                })
+                .put(move |state: Option<Json<MaintenanceState>>| async move {
+                    let state = state.map(|Json(state)| state).unwrap_or_default();
+                    enable.enable(&state).await.map_err(unavailable)?;
+                    Ok::<_, SimpleError>(StatusCode::NO_CONTENT)
+                })
+                .delete(move || async move {
+                    disable.disable().await.map_err(unavailable)?;
+                    Ok::<_, SimpleError>(StatusCode::NO_CONTENT)
+                }),
+            );
+        }
+        #[cfg(feature = "log-reload")]
+        if let Some(level) = &self.log_level {
+            let show = level.clone();
+            let set = level.clone();
+            router = router.route(
+                "/log_level",
+                get(move || async move { show.current() }).put(move |body: String| async move {
+                    set.set(&body).map_err(|e| {
+                        SimpleError::wrap(e, StatusCode::BAD_REQUEST).context("invalid log level")
+                    })?;
+                    Ok::<_, SimpleError>(set.current())
+                }),
+            );
+        }
+        #[cfg(feature = "jobs")]
+        {
+            router = router.route("/jobs", get(|| async { Json(crate::Jobs::status()) }));
+        }
+        let token = self.token.clone();
+        router.route_layer(middleware::from_fn(move |req: Request, next: Next| {
+            let token = token.clone();
+            async move {
+                if !authorized(&req, &token) {
+                    return SimpleError::unauthorized("invalid admin token")
+                        .with_header(header::WWW_AUTHENTICATE, "Bearer")
+                        .into_response();
+                }
+                next.run(req).await
+            }
+        }))
+    }
+}
+
+async fn show_config(State(config): State<Option<serde_json::Value>>) -> Response {
+    Json(serde_json::json!({
+        "startup": startup::collect().await,
+        "config": config,
+    }))
+    .into_response()
+}
+
+fn authorized(req: &Request, token: &str) -> bool {
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    !token.is_empty() && startup::constant_time_eq(given.as_bytes(), token.as_bytes())
+}
+
+fn unavailable(err: crate::AnyError) -> SimpleError {
+    SimpleError::wrap(err, StatusCode::SERVICE_UNAVAILABLE).context("unable to access maintenance")
+}
@@ -0,0 +1,163 @@
+use std::{ops::Deref, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{AnyError, KVManager, SimpleError};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApiKeyInfo {
+    pub owner: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default, flatten)]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ApiKeyInfo {
+    pub fn new(owner: &str) -> ApiKeyInfo {
+        ApiKeyInfo {
+            owner: owner.to_string(),
+            ..Default::default()
+        }
+    }
+    pub fn scope(mut self, scope: &str) -> ApiKeyInfo {
+        self.scopes.push(scope.to_string());
+        self
+    }
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope || s == "*")
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiKeys {
+    kv: KVManager,
+    prefix: String,
+    scopes: Vec<String>,
+}
+
+impl ApiKeys {
+    pub fn new(kv: KVManager) -> ApiKeys {
+        ApiKeys {
+            kv,
+            prefix: "apikey".to_string(),
+            scopes: Vec::new(),
+        }
+    }
+    pub fn prefix(mut self, prefix: &str) -> ApiKeys {
+        self.prefix = prefix.to_string();
+        self
+    }
+    pub fn scope(mut self, scope: &str) -> ApiKeys {
+        self.scopes.push(scope.to_string());
+        self
+    }
+    pub fn extension(self) -> Extension<Arc<ApiKeys>> {
+        Extension(Arc::new(self))
+    }
+    // only a hash of the key is stored, so a leaked kv does not leak usable keys
+    fn kv_key(&self, key: &str) -> String {
+        let hash = Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("{}:{}", self.prefix, hash)
+    }
+    pub async fn insert(&self, key: &str, info: &ApiKeyInfo, expire: u64) -> Result<(), AnyError> {
+        self.kv.set(&self.kv_key(key), info, expire).await
+    }
+    pub async fn revoke(&self, key: &str) -> Result<(), AnyError> {
+        self.kv.del(&self.kv_key(key)).await
+    }
+    pub async fn verify(&self, key: &str) -> Result<ApiKeyInfo, SimpleError> {
+        let info = self
+            .kv
+            .get_some::<ApiKeyInfo>(&self.kv_key(key))
+            .await
+            .map_err(|e| {
+                SimpleError::wrap(e, StatusCode::SERVICE_UNAVAILABLE)
+                    .context("unable to load api key")
+            })?
+            .ok_or_else(|| unauthorized("unknown api key"))?;
+        for scope in &self.scopes {
+            require(&info, scope)?;
+        }
+        Ok(info)
+    }
+}
+
+fn unauthorized(reason: &str) -> SimpleError {
+    tracing::debug!("api key rejected: {}", reason);
+    SimpleError::new("Unauthorized", StatusCode::UNAUTHORIZED)
+        .with_header(header::WWW_AUTHENTICATE, "ApiKey")
+}
+
+fn require(info: &ApiKeyInfo, scope: &str) -> Result<(), SimpleError> {
+    if info.has_scope(scope) {
+        return Ok(());
+    }
+    tracing::debug!("api key of {} is missing scope {}", info.owner, scope);
+    Err(SimpleError::new("Forbidden", StatusCode::FORBIDDEN))
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiKey(pub ApiKeyInfo);
+
+impl ApiKey {
+    pub fn require(&self, scope: &str) -> Result<(), SimpleError> {
+        require(&self.0, scope)
+    }
+}
+
+impl Deref for ApiKey {
+    type Target = ApiKeyInfo;
+    fn deref(&self) -> &ApiKeyInfo {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ApiKey
+where
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let keys = parts
+            .extensions
+            .get::<Arc<ApiKeys>>()
+            .cloned()
+            .ok_or_else(|| {
+                SimpleError::new(
+                    "api key extension is not installed",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            })?;
+        let key = parts
+            .headers
+            .get("x-api-key")
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| {
+                parts
+                    .headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| {
+                        v.strip_prefix("ApiKey ")
+                            .or_else(|| v.strip_prefix("apikey "))
+                    })
+            })
+            .map(|key| key.trim())
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| unauthorized("missing api key"))?;
+        Ok(ApiKey(keys.verify(key).await?))
+    }
+}
@@ -0,0 +1,433 @@
+use std::{ops::Deref, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+
+use crate::{startup::constant_time_eq, AnyError, SimpleError};
+
+#[derive(Clone)]
+enum JwtKey {
+    Static(Algorithm, DecodingKey),
+    #[cfg(feature = "auth-jwks")]
+    Jwks {
+        url: String,
+        kv: Box<crate::KVManager>,
+        expire: u64,
+    },
+}
+
+#[derive(Clone)]
+pub struct JwtAuth {
+    key: JwtKey,
+    issuer: Vec<String>,
+    audience: Vec<String>,
+    leeway: u64,
+}
+
+impl JwtAuth {
+    pub fn hs256(secret: &[u8]) -> JwtAuth {
+        JwtAuth::new(JwtKey::Static(
+            Algorithm::HS256,
+            DecodingKey::from_secret(secret),
+        ))
+    }
+    pub fn rs256_pem(pem: &[u8]) -> Result<JwtAuth, AnyError> {
+        Ok(JwtAuth::new(JwtKey::Static(
+            Algorithm::RS256,
+            DecodingKey::from_rsa_pem(pem)?,
+        )))
+    }
+    #[cfg(feature = "auth-jwks")]
+    pub fn jwks(url: &str, kv: crate::KVManager) -> JwtAuth {
+        JwtAuth::new(JwtKey::Jwks {
+            url: url.to_string(),
+            kv: Box::new(kv),
+            expire: 3600,
+        })
+    }
+    fn new(key: JwtKey) -> JwtAuth {
+        JwtAuth {
+            key,
+            issuer: Vec::new(),
+            audience: Vec::new(),
+            leeway: 60,
+        }
+    }
+    pub fn issuer(mut self, issuer: &str) -> JwtAuth {
+        self.issuer.push(issuer.to_string());
+        self
+    }
+    pub fn audience(mut self, audience: &str) -> JwtAuth {
+        self.audience.push(audience.to_string());
+        self
+    }
+    pub fn leeway(mut self, leeway: u64) -> JwtAuth {
+        self.leeway = leeway;
+        self
+    }
+    #[cfg(feature = "auth-jwks")]
+    pub fn jwks_expire(mut self, expire: u64) -> JwtAuth {
+        if let JwtKey::Jwks {
+            expire: current, ..
+        } = &mut self.key
+        {
+            *current = expire;
+        }
+        self
+    }
+    pub fn extension(self) -> Extension<Arc<JwtAuth>> {
+        Extension(Arc::new(self))
+    }
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway;
+        if !self.issuer.is_empty() {
+            validation.set_issuer(&self.issuer);
+        }
+        if self.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audience);
+        }
+        validation
+    }
+    pub async fn verify<T>(&self, token: &str) -> Result<T, SimpleError>
+    where
+        T: DeserializeOwned,
+    {
+        let (algorithm, key) = match &self.key {
+            JwtKey::Static(algorithm, key) => (*algorithm, key.clone()),
+            #[cfg(feature = "auth-jwks")]
+            JwtKey::Jwks { url, kv, expire } => {
+                let header = jsonwebtoken::decode_header(token).map_err(unauthorized)?;
+                let kid = header.kid.ok_or_else(|| unauthorized("token has no kid"))?;
+                let jwk = jwks::find(kv, url, &kid, *expire).await?;
+                // the token header is attacker controlled, the key says what it signs with
+                (
+                    jwks::algorithm(&jwk)?,
+                    DecodingKey::from_jwk(&jwk).map_err(unauthorized)?,
+                )
+            }
+        };
+        decode::<T>(token, &key, &self.validation(algorithm))
+            .map(|data| data.claims)
+            .map_err(unauthorized)
+    }
+}
+
+fn unauthorized<E>(err: E) -> SimpleError
+where
+    E: std::fmt::Display,
+{
+    tracing::debug!("jwt rejected: {}", err);
+    SimpleError::new("Unauthorized", StatusCode::UNAUTHORIZED)
+        .with_header(header::WWW_AUTHENTICATE, "Bearer")
+}
+
+#[cfg(feature = "auth-jwks")]
+mod jwks {
+    use std::str::FromStr;
+
+    use axum::http::StatusCode;
+    use jsonwebtoken::{
+        jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+        Algorithm,
+    };
+
+    use crate::{KVManager, SimpleError};
+
+    // unknown kids refetch the set at most this often, in seconds
+    const REFETCH_INTERVAL: u64 = 60;
+
+    async fn fetch(url: &str) -> Result<JwkSet, crate::AnyError> {
+        Ok(reqwest::get(url)
+            .await?
+            .error_for_status()?
+            .json::<JwkSet>()
+            .await?)
+    }
+
+    pub(super) async fn find(
+        kv: &KVManager,
+        url: &str,
+        kid: &str,
+        expire: u64,
+    ) -> Result<Jwk, SimpleError> {
+        let key = format!("jwks:{}", url);
+        let set = kv
+            .get_or_init(&key, || fetch(url), expire)
+            .await
+            .map_err(unavailable)?;
+        if let Some(jwk) = set.value.find(kid) {
+            return Ok(jwk.clone());
+        }
+        if !set.hit {
+            return Err(super::unauthorized("unknown kid"));
+        }
+        // the key may have been rotated since the set was cached. made up kids
+        // would otherwise fetch on every request, the lock is never released
+        // and only expires, so every instance together refetches once per interval
+        let refetch = format!("jwks-refetch:{}", url);
+        if !kv
+            .try_lock(&refetch, REFETCH_INTERVAL)
+            .await
+            .map_err(unavailable)?
+        {
+            return Err(super::unauthorized("unknown kid"));
+        }
+        let set = fetch(url).await.map_err(unavailable)?;
+        kv.set(&key, &set, expire).await.map_err(unavailable)?;
+        set.find(kid)
+            .cloned()
+            .ok_or_else(|| super::unauthorized("unknown kid"))
+    }
+
+    // symmetric keys are refused, anyone can read a jwks
+    pub(super) fn algorithm(jwk: &Jwk) -> Result<Algorithm, SimpleError> {
+        let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+            (Some(algorithm), _) => {
+                Algorithm::from_str(&algorithm.to_string()).map_err(super::unauthorized)?
+            }
+            (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+            (None, AlgorithmParameters::EllipticCurve(params)) => match params.curve {
+                EllipticCurve::P256 => Algorithm::ES256,
+                EllipticCurve::P384 => Algorithm::ES384,
+                _ => return Err(super::unauthorized("unsupported jwk curve")),
+            },
+            (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+            (None, AlgorithmParameters::OctetKey(_)) => {
+                return Err(super::unauthorized("symmetric jwk"))
+            }
+        };
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                Err(super::unauthorized("symmetric jwk"))
+            }
+            algorithm => Ok(algorithm),
+        }
+    }
+
+    fn unavailable(err: crate::AnyError) -> SimpleError {
+        SimpleError::wrap(err, StatusCode::SERVICE_UNAVAILABLE).context("unable to load jwks")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JwtClaims<T>(pub T);
+
+impl<T> Deref for JwtClaims<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for JwtClaims<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + Send,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let auth = parts
+            .extensions
+            .get::<Arc<JwtAuth>>()
+            .cloned()
+            .ok_or_else(|| {
+                SimpleError::new(
+                    "jwt auth extension is not installed",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            })?;
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                v.strip_prefix("Bearer ")
+                    .or_else(|| v.strip_prefix("bearer "))
+            })
+            .ok_or_else(|| unauthorized("missing bearer token"))?;
+        let claims = auth.verify::<T>(token.trim()).await?;
+        Ok(JwtClaims(claims))
+    }
+}
+
+fn authorization<'a>(parts: &'a Parts, scheme: &str) -> Option<&'a str> {
+    let value = parts.headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (given, rest) = value.split_once(' ')?;
+    given
+        .eq_ignore_ascii_case(scheme)
+        .then(|| rest.trim())
+        .filter(|rest| !rest.is_empty())
+}
+
+fn challenge(scheme: &str, realm: &str, reason: &str) -> SimpleError {
+    tracing::debug!("{} auth rejected: {}", scheme.to_lowercase(), reason);
+    SimpleError::new("Unauthorized", StatusCode::UNAUTHORIZED).with_header(
+        header::WWW_AUTHENTICATE,
+        format!("{} realm=\"{}\"", scheme, realm.replace('"', "")),
+    )
+}
+
+fn not_installed(name: &str) -> SimpleError {
+    SimpleError::new(
+        &format!("{} extension is not installed", name),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
+
+#[derive(Clone)]
+pub struct BasicCredentials {
+    users: Vec<(String, String)>,
+    realm: String,
+}
+
+impl BasicCredentials {
+    pub fn new() -> BasicCredentials {
+        BasicCredentials {
+            users: Vec::new(),
+            realm: "restricted".to_string(),
+        }
+    }
+    pub fn user(mut self, user: &str, password: &str) -> BasicCredentials {
+        self.users.push((user.to_string(), password.to_string()));
+        self
+    }
+    pub fn realm(mut self, realm: &str) -> BasicCredentials {
+        self.realm = realm.to_string();
+        self
+    }
+    pub fn extension(self) -> Extension<Arc<BasicCredentials>> {
+        Extension(Arc::new(self))
+    }
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        // no early return, every configured user is compared
+        self.users.iter().fold(false, |found, (u, p)| {
+            let matched = constant_time_eq(u.as_bytes(), user.as_bytes())
+                & constant_time_eq(p.as_bytes(), password.as_bytes());
+            found | matched
+        })
+    }
+}
+
+impl Default for BasicCredentials {
+    fn default() -> BasicCredentials {
+        BasicCredentials::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    pub user: String,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for BasicAuth
+where
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let credentials = parts
+            .extensions
+            .get::<Arc<BasicCredentials>>()
+            .cloned()
+            .ok_or_else(|| not_installed("basic auth"))?;
+        let reject = |reason| challenge("Basic", &credentials.realm, reason);
+        let encoded = authorization(parts, "Basic").ok_or_else(|| reject("missing credentials"))?;
+        let decoded = STANDARD
+            .decode(encoded)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or_else(|| reject("malformed credentials"))?;
+        let (user, password) = decoded
+            .split_once(':')
+            .ok_or_else(|| reject("malformed credentials"))?;
+        if !credentials.verify(user, password) {
+            return Err(reject("invalid credentials"));
+        }
+        Ok(BasicAuth {
+            user: user.to_string(),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct BearerTokens {
+    tokens: Vec<String>,
+    realm: String,
+}
+
+impl BearerTokens {
+    pub fn new() -> BearerTokens {
+        BearerTokens {
+            tokens: Vec::new(),
+            realm: "restricted".to_string(),
+        }
+    }
+    pub fn token(mut self, token: &str) -> BearerTokens {
+        self.tokens.push(token.to_string());
+        self
+    }
+    pub fn realm(mut self, realm: &str) -> BearerTokens {
+        self.realm = realm.to_string();
+        self
+    }
+    pub fn extension(self) -> Extension<Arc<BearerTokens>> {
+        Extension(Arc::new(self))
+    }
+    pub fn verify(&self, token: &str) -> bool {
+        self.tokens.iter().fold(false, |found, t| {
+            found | constant_time_eq(t.as_bytes(), token.as_bytes())
+        })
+    }
+}
+
+impl Default for BearerTokens {
+    fn default() -> BearerTokens {
+        BearerTokens::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BearerToken(pub String);
+
+impl Deref for BearerToken {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for BearerToken
+where
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let tokens = parts
+            .extensions
+            .get::<Arc<BearerTokens>>()
+            .cloned()
+            .ok_or_else(|| not_installed("bearer token"))?;
+        let reject = |reason| challenge("Bearer", &tokens.realm, reason);
+        let token = authorization(parts, "Bearer").ok_or_else(|| reject("missing token"))?;
+        if !tokens.verify(token) {
+            return Err(reject("invalid token"));
+        }
+        Ok(BearerToken(token.to_string()))
+    }
+}
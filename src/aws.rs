@@ -0,0 +1,35 @@
+use std::time::SystemTime;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// aws signature version 4 helpers shared by the s3 and dynamodb stores
+
+pub(crate) fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let (year, month, day, hour, minute, second) = crate::access_log::civil(secs);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!("{}T{:02}{:02}{:02}Z", date, hour, minute, second);
+    (date, timestamp)
+}
+
+pub(crate) fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+    for part in [region, service, "aws4_request"] {
+        key = hmac(&key, part);
+    }
+    key
+}
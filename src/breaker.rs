@@ -0,0 +1,485 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    future::Future,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::AnyError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BreakerOutcome {
+    Success,
+    Failure,
+    Timeout,
+    // not attempted, the circuit was open
+    Rejected,
+}
+
+impl BreakerOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerOutcome::Success => "success",
+            BreakerOutcome::Failure => "failure",
+            BreakerOutcome::Timeout => "timeout",
+            BreakerOutcome::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerEvent<'a> {
+    pub name: &'a str,
+    pub outcome: BreakerOutcome,
+    // the state after the call, differs from `previous` when it tripped or recovered
+    pub state: BreakerState,
+    pub previous: BreakerState,
+    pub latency: Duration,
+}
+
+pub trait BreakerObserver: Send + Sync {
+    fn observe(&self, event: &BreakerEvent);
+}
+
+type Observers = RwLock<Vec<Arc<dyn BreakerObserver>>>;
+
+fn observers() -> &'static Observers {
+    static OBSERVERS: OnceLock<Observers> = OnceLock::new();
+    OBSERVERS.get_or_init(Default::default)
+}
+
+#[derive(Debug)]
+pub struct CircuitOpenError {
+    name: String,
+    retry_after: Duration,
+}
+impl CircuitOpenError {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn retry_after(&self) -> Duration {
+        self.retry_after
+    }
+}
+impl Display for CircuitOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "circuit {} is open", self.name)
+    }
+}
+impl Error for CircuitOpenError {}
+
+#[derive(Debug)]
+pub struct CallTimeoutError {
+    name: String,
+}
+impl Display for CallTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "call to {} timed out", self.name)
+    }
+}
+impl Error for CallTimeoutError {}
+
+#[derive(Debug)]
+struct Circuit {
+    state: BreakerState,
+    window_start: Instant,
+    calls: u32,
+    failures: u32,
+    open_until: Instant,
+    // trial calls running and succeeded while half open
+    probes: u32,
+    successes: u32,
+    #[cfg(feature = "kv")]
+    checked: Option<Instant>,
+}
+
+impl Circuit {
+    fn new() -> Circuit {
+        let now = Instant::now();
+        Circuit {
+            state: BreakerState::Closed,
+            window_start: now,
+            calls: 0,
+            failures: 0,
+            open_until: now,
+            probes: 0,
+            successes: 0,
+            #[cfg(feature = "kv")]
+            checked: None,
+        }
+    }
+    fn open(&mut self, until: Instant) {
+        self.state = BreakerState::Open;
+        self.open_until = until;
+        self.probes = 0;
+        self.successes = 0;
+    }
+    fn close(&mut self) {
+        self.state = BreakerState::Closed;
+        self.window_start = Instant::now();
+        self.calls = 0;
+        self.failures = 0;
+    }
+}
+
+#[cfg(feature = "kv")]
+#[derive(Clone)]
+struct Shared {
+    kv: crate::KVManager,
+    prefix: String,
+}
+
+// stops calling a downstream that keeps failing so requests fail fast instead
+// of piling up behind it. a circuit opens once the failure rate within the
+// window crosses the threshold, after open_for a few trial calls decide
+// whether it closes again. every name is its own circuit
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failure_rate: f64,
+    min_calls: u32,
+    window: Duration,
+    open_for: Duration,
+    half_open_calls: u32,
+    timeout: Option<Duration>,
+    #[cfg(feature = "kv")]
+    shared: Option<Shared>,
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new()
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            failure_rate: 0.5,
+            min_calls: 10,
+            window: Duration::from_secs(60),
+            open_for: Duration::from_secs(30),
+            half_open_calls: 1,
+            timeout: None,
+            #[cfg(feature = "kv")]
+            shared: None,
+            circuits: Default::default(),
+        }
+    }
+    // share of failed calls that opens the circuit, 0.0 to 1.0
+    pub fn failure_rate(mut self, rate: f64) -> CircuitBreaker {
+        self.failure_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+    // calls needed within the window before the rate is looked at
+    pub fn min_calls(mut self, calls: u32) -> CircuitBreaker {
+        self.min_calls = calls.max(1);
+        self
+    }
+    pub fn window(mut self, window: Duration) -> CircuitBreaker {
+        self.window = window;
+        self
+    }
+    pub fn open_for(mut self, duration: Duration) -> CircuitBreaker {
+        self.open_for = duration;
+        self
+    }
+    // trial calls that have to succeed while half open before the circuit closes
+    pub fn half_open_calls(mut self, calls: u32) -> CircuitBreaker {
+        self.half_open_calls = calls.max(1);
+        self
+    }
+    // calls running longer are cancelled and count as failures
+    pub fn timeout(mut self, timeout: Duration) -> CircuitBreaker {
+        self.timeout = Some(timeout);
+        self
+    }
+    // an open circuit is written to kv so other replicas stop calling too,
+    // they look it up at most once a second
+    #[cfg(feature = "kv")]
+    pub fn shared(mut self, kv: &crate::KVManager, prefix: &str) -> CircuitBreaker {
+        self.shared = Some(Shared {
+            kv: kv.clone(),
+            prefix: prefix.to_string(),
+        });
+        self
+    }
+    pub fn add_observer<O>(observer: O)
+    where
+        O: BreakerObserver + 'static,
+    {
+        observers().write().unwrap().push(Arc::new(observer));
+    }
+    pub fn state(&self, name: &str) -> BreakerState {
+        let mut circuits = self.circuits.lock().unwrap();
+        match circuits.get_mut(name) {
+            Some(circuit) => self.current(circuit),
+            None => BreakerState::Closed,
+        }
+    }
+    pub fn states(&self) -> HashMap<String, BreakerState> {
+        let mut circuits = self.circuits.lock().unwrap();
+        circuits
+            .iter_mut()
+            .map(|(name, circuit)| (name.clone(), self.current(circuit)))
+            .collect()
+    }
+    // opens or closes a circuit by hand, e.g. from an admin endpoint
+    pub fn force(&self, name: &str, state: BreakerState) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(name.to_string())
+            .or_insert_with(Circuit::new);
+        match state {
+            BreakerState::Open => circuit.open(Instant::now() + self.open_for),
+            BreakerState::HalfOpen => circuit.open(Instant::now()),
+            BreakerState::Closed => circuit.close(),
+        }
+    }
+
+    pub async fn call<T, E, F>(&self, name: &str, fut: F) -> Result<T, AnyError>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: Into<AnyError>,
+    {
+        #[cfg(feature = "kv")]
+        self.sync(name).await;
+        let start = Instant::now();
+        let previous = match self.acquire(name) {
+            Ok(previous) => previous,
+            Err(e) => {
+                let state = self.state(name);
+                emit(name, BreakerOutcome::Rejected, state, state, start);
+                return Err(e.into());
+            }
+        };
+        // a probe dropped before it completes gives its slot back, otherwise
+        // the circuit would stay half open and reject everything for good
+        let mut probe = Probe {
+            breaker: self,
+            name,
+            armed: previous == BreakerState::HalfOpen,
+        };
+        let res = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, fut).await {
+                Ok(res) => res.map_err(Into::into),
+                Err(_) => Err(CallTimeoutError {
+                    name: name.to_string(),
+                }
+                .into()),
+            },
+            None => fut.await.map_err(Into::into),
+        };
+        probe.armed = false;
+        let outcome = match &res {
+            Ok(_) => BreakerOutcome::Success,
+            Err(e) if e.is::<CallTimeoutError>() => BreakerOutcome::Timeout,
+            Err(_) => BreakerOutcome::Failure,
+        };
+        let state = self.record(name, outcome == BreakerOutcome::Success);
+        if state != previous {
+            match state {
+                BreakerState::Open => tracing::warn!("circuit {} opened", name),
+                BreakerState::Closed => tracing::info!("circuit {} closed", name),
+                BreakerState::HalfOpen => {}
+            }
+            #[cfg(feature = "kv")]
+            self.publish(name, state).await;
+        }
+        emit(name, outcome, state, previous, start);
+        res
+    }
+
+    fn current(&self, circuit: &mut Circuit) -> BreakerState {
+        if circuit.state == BreakerState::Open && Instant::now() >= circuit.open_until {
+            circuit.state = BreakerState::HalfOpen;
+        }
+        circuit.state
+    }
+
+    // lets the call through or rejects it, returns the state it went through in
+    fn acquire(&self, name: &str) -> Result<BreakerState, CircuitOpenError> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(name.to_string())
+            .or_insert_with(Circuit::new);
+        let rejected = |circuit: &Circuit| CircuitOpenError {
+            name: name.to_string(),
+            retry_after: circuit.open_until.saturating_duration_since(Instant::now()),
+        };
+        match self.current(circuit) {
+            BreakerState::Closed => {
+                if circuit.window_start.elapsed() >= self.window {
+                    circuit.close();
+                }
+                Ok(BreakerState::Closed)
+            }
+            BreakerState::Open => Err(rejected(circuit)),
+            BreakerState::HalfOpen if circuit.probes >= self.half_open_calls => {
+                Err(rejected(circuit))
+            }
+            BreakerState::HalfOpen => {
+                circuit.probes += 1;
+                Ok(BreakerState::HalfOpen)
+            }
+        }
+    }
+
+    fn release(&self, name: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(name) {
+            if circuit.state == BreakerState::HalfOpen {
+                circuit.probes = circuit.probes.saturating_sub(1);
+            }
+        }
+    }
+
+    fn record(&self, name: &str, success: bool) -> BreakerState {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(name.to_string())
+            .or_insert_with(Circuit::new);
+        match circuit.state {
+            BreakerState::Closed => {
+                circuit.calls += 1;
+                if !success {
+                    circuit.failures += 1;
+                }
+                if circuit.calls >= self.min_calls
+                    && circuit.failures as f64 >= circuit.calls as f64 * self.failure_rate
+                    && circuit.failures > 0
+                {
+                    circuit.open(Instant::now() + self.open_for);
+                }
+            }
+            BreakerState::HalfOpen if success => {
+                circuit.successes += 1;
+                if circuit.successes >= self.half_open_calls {
+                    circuit.close();
+                }
+            }
+            BreakerState::HalfOpen => circuit.open(Instant::now() + self.open_for),
+            // another call tripped it while this one was running
+            BreakerState::Open => {}
+        }
+        circuit.state
+    }
+
+    #[cfg(feature = "kv")]
+    async fn sync(&self, name: &str) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        {
+            let mut circuits = self.circuits.lock().unwrap();
+            let circuit = circuits
+                .entry(name.to_string())
+                .or_insert_with(Circuit::new);
+            if circuit.state != BreakerState::Closed
+                || circuit
+                    .checked
+                    .is_some_and(|checked| checked.elapsed() < Duration::from_secs(1))
+            {
+                return;
+            }
+            circuit.checked = Some(Instant::now());
+        }
+        let key = format!("{}{}", shared.prefix, name);
+        let remaining = match shared.kv.ttl(&key).await {
+            Ok(Some(0)) => return,
+            Ok(Some(ttl)) => Duration::from_secs(ttl),
+            Ok(None) => self.open_for,
+            Err(e) => {
+                if !e.is::<crate::NotFoundError>() {
+                    tracing::debug!("circuit {} lookup failed: {}", name, e);
+                }
+                return;
+            }
+        };
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(name) {
+            if circuit.state == BreakerState::Closed {
+                tracing::warn!("circuit {} opened by another replica", name);
+                circuit.open(Instant::now() + remaining);
+            }
+        }
+    }
+
+    #[cfg(feature = "kv")]
+    async fn publish(&self, name: &str, state: BreakerState) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        let key = format!("{}{}", shared.prefix, name);
+        let res = match state {
+            BreakerState::Open => {
+                let secs = self.open_for.as_secs().max(1);
+                shared.kv.set(&key, &true, secs).await
+            }
+            BreakerState::Closed => match shared.kv.del(&key).await {
+                Err(e) if e.is::<crate::NotFoundError>() => Ok(()),
+                res => res,
+            },
+            BreakerState::HalfOpen => Ok(()),
+        };
+        if let Err(e) = res {
+            tracing::warn!("circuit {} not shared: {}", name, e);
+        }
+    }
+}
+
+struct Probe<'a> {
+    breaker: &'a CircuitBreaker,
+    name: &'a str,
+    armed: bool,
+}
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.breaker.release(self.name);
+        }
+    }
+}
+
+fn emit(
+    name: &str,
+    outcome: BreakerOutcome,
+    state: BreakerState,
+    previous: BreakerState,
+    start: Instant,
+) {
+    let observers = observers().read().unwrap();
+    if observers.is_empty() {
+        return;
+    }
+    let event = BreakerEvent {
+        name,
+        outcome,
+        state,
+        previous,
+        latency: start.elapsed(),
+    };
+    for observer in observers.iter() {
+        observer.observe(&event);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    env,
+    process::Command,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::startup;
+
+// what the binary was built from, captured with build_info!() in the app crate.
+// everything but name and version needs emit() in the app's build.rs
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_sha: Option<&'static str>,
+    pub built_at: Option<&'static str>,
+    pub rustc: Option<&'static str>,
+    pub profile: Option<&'static str>,
+}
+
+// expands in the calling crate, so the version is the app's and not rstartup's
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::build_info::BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: option_env!("TOKI_BUILD_SHA"),
+            built_at: option_env!("TOKI_BUILD_TIME"),
+            rustc: option_env!("TOKI_BUILD_RUSTC"),
+            profile: option_env!("TOKI_BUILD_PROFILE"),
+        }
+    };
+}
+
+static REGISTERED: OnceLock<BuildInfo> = OnceLock::new();
+
+impl BuildInfo {
+    // reported by the startup log and the admin /__version route, the first one wins
+    pub fn register(self) -> BuildInfo {
+        let info = *REGISTERED.get_or_init(|| self);
+        startup::set_version(info.version, info.git_sha);
+        info
+    }
+}
+
+pub fn registered() -> Option<BuildInfo> {
+    REGISTERED.get().copied()
+}
+
+// call from the app's build.rs, with rstartup as a build dependency:
+//
+//     fn main() {
+//         rstartup::build_info::emit();
+//     }
+//
+// TOKI_BUILD_SHA given to the build wins over git, for builds without the
+// .git directory, SOURCE_DATE_EPOCH pins the build time for reproducible builds
+pub fn emit() {
+    let sha = env::var("TOKI_BUILD_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| command("git", &["rev-parse", "--short=12", "HEAD"]));
+    if let Some(sha) = sha {
+        println!("cargo:rustc-env=TOKI_BUILD_SHA={}", sha);
+    }
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    let (year, month, day, hour, minute, second) = crate::access_log::civil(secs);
+    println!(
+        "cargo:rustc-env=TOKI_BUILD_TIME={:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    );
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = command(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=TOKI_BUILD_RUSTC={}", version);
+    }
+    if let Ok(profile) = env::var("PROFILE") {
+        println!("cargo:rustc-env=TOKI_BUILD_PROFILE={}", profile);
+    }
+    // rebuilt on new commits rather than on every build
+    if let Some(head) = command("git", &["rev-parse", "--git-path", "HEAD"]) {
+        println!("cargo:rerun-if-changed={}", head);
+    }
+    if let Some(head) = command("git", &["symbolic-ref", "-q", "HEAD"]) {
+        if let Some(path) = command("git", &["rev-parse", "--git-path", &head]) {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    println!("cargo:rerun-if-env-changed=TOKI_BUILD_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+fn command(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
@@ -0,0 +1,340 @@
+use std::{
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures_util::future::BoxFuture;
+use http_body::Body as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tower::{Layer, Service};
+
+use crate::{AnyError, CacheLookup, KVManager, SimpleError};
+
+// caches whole GET responses in kv, HEAD is answered from them too. the key
+// is the host, path and query plus the values of the vary() request headers.
+// handlers control it with Cache-Control: no-store, no-cache and private
+// skip the cache, s-maxage or max-age set the ttl. responses with
+// Set-Cookie, with a Vary on headers not given to vary(), streamed ones
+// and those to requests with Authorization or Cookie (unless public) are not stored
+#[derive(Clone)]
+pub struct CacheLayer {
+    kv: KVManager,
+    prefix: String,
+    ttl: u64,
+    vary: Vec<HeaderName>,
+    max_body: usize,
+}
+
+impl CacheLayer {
+    pub fn new(kv: KVManager) -> CacheLayer {
+        CacheLayer {
+            kv,
+            prefix: "cache:".to_string(),
+            ttl: 60,
+            vary: Vec::new(),
+            max_body: 1024 * 1024,
+        }
+    }
+    pub fn prefix(mut self, prefix: &str) -> CacheLayer {
+        self.prefix = prefix.to_string();
+        self
+    }
+    // for responses without max-age, in seconds
+    pub fn ttl(mut self, ttl: u64) -> CacheLayer {
+        self.ttl = ttl;
+        self
+    }
+    // request headers that pick a different response, e.g. accept-language
+    pub fn vary(mut self, header: &str) -> CacheLayer {
+        let header = HeaderName::try_from(header).expect("invalid vary header name");
+        if !self.vary.contains(&header) {
+            self.vary.push(header);
+        }
+        self
+    }
+    // larger responses are passed through without being stored
+    pub fn max_body(mut self, max_body: usize) -> CacheLayer {
+        self.max_body = max_body;
+        self
+    }
+    // drops every cached variant of a path, whatever the host, query and vary headers
+    pub async fn purge(&self, path: &str) -> Result<u64, AnyError> {
+        self.kv.del_prefix(&self.path_prefix(path), false).await
+    }
+    // kv keys are normalized lossily, hashing keeps distinct urls apart
+    fn path_prefix(&self, path: &str) -> String {
+        format!("{}{}:", self.prefix, hash(path.as_bytes()))
+    }
+    fn key<B>(&self, req: &Request<B>) -> String {
+        // the same path on another virtual host is another resource
+        let authority = req
+            .uri()
+            .authority()
+            .map(|a| a.as_str().as_bytes())
+            .or_else(|| req.headers().get(header::HOST).map(|h| h.as_bytes()))
+            .unwrap_or_default();
+        let mut variant = req
+            .uri()
+            .scheme_str()
+            .unwrap_or_default()
+            .as_bytes()
+            .to_vec();
+        variant.push(b'\n');
+        variant.extend_from_slice(&authority.to_ascii_lowercase());
+        variant.push(b'\n');
+        variant.extend_from_slice(req.uri().query().unwrap_or_default().as_bytes());
+        for name in &self.vary {
+            variant.push(b'\n');
+            for value in req.headers().get_all(name) {
+                variant.extend_from_slice(value.as_bytes());
+                variant.push(b',');
+            }
+        }
+        format!("{}{}", self.path_prefix(req.uri().path()), hash(&variant))
+    }
+    // None when the response must not be stored
+    fn ttl_for(&self, res: &Response, authorized: bool) -> Option<u64> {
+        if !cacheable(res.status()) || res.headers().contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        for vary in res.headers().get_all(header::VARY) {
+            let vary = vary.to_str().ok()?;
+            for name in vary.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                if name == "*"
+                    || !self
+                        .vary
+                        .iter()
+                        .any(|v| v.as_str().eq_ignore_ascii_case(name))
+                {
+                    return None;
+                }
+            }
+        }
+        let cc = CacheControl::parse(res.headers());
+        if cc.no_store || cc.no_cache || cc.private {
+            return None;
+        }
+        if authorized && !cc.public && cc.s_maxage.is_none() {
+            return None;
+        }
+        match cc.s_maxage.or(cc.max_age).unwrap_or(self.ttl) {
+            0 => None,
+            ttl => Some(ttl),
+        }
+    }
+}
+
+fn hash(data: &[u8]) -> String {
+    Sha256::digest(data)[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// the ones rfc 9110 lets caches store without explicit freshness
+fn cacheable(status: StatusCode) -> bool {
+    matches!(
+        status.as_u16(),
+        200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+#[derive(Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> CacheControl {
+        let mut cc = CacheControl::default();
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for directive in directives {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let value = value.trim().trim_matches('"').parse::<u64>().ok();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "max-age" => cc.max_age = value,
+                "s-maxage" => cc.s_maxage = value,
+                _ => {}
+            }
+        }
+        cc
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Cached {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    stored_at: u64,
+}
+
+impl Cached {
+    fn response(&self, head: bool) -> Response {
+        let body = BASE64_STANDARD.decode(&self.body).unwrap_or_default();
+        let len = body.len();
+        let mut res = match head {
+            true => Response::new(Body::empty()),
+            false => Response::new(Body::from(body)),
+        };
+        *res.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::try_from(value.as_str()),
+            ) {
+                res.headers_mut().append(name, value);
+            }
+        }
+        if head {
+            res.headers_mut()
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+        }
+        let age = now().saturating_sub(self.stored_at);
+        res.headers_mut()
+            .insert(header::AGE, HeaderValue::from(age));
+        res.headers_mut()
+            .insert(CacheLookup::HEADER, CacheLookup::Hit.header_value());
+        res
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// hop by hop and per response headers are not replayed
+fn stored_header(name: &HeaderName) -> bool {
+    !matches!(
+        name.as_str(),
+        "connection" | "keep-alive" | "transfer-encoding" | "date" | "age" | "x-cache-lookup"
+    )
+}
+
+fn miss(mut res: Response) -> Response {
+    res.headers_mut()
+        .insert(CacheLookup::HEADER, CacheLookup::Miss.header_value());
+    res
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = CacheService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CacheService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheService<S> {
+    inner: S,
+    layer: CacheLayer,
+}
+
+impl<S> Service<Request<Body>> for CacheService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let head = match *req.method() {
+                Method::GET => false,
+                Method::HEAD => true,
+                _ => return inner.call(req).await,
+            };
+            let cc = CacheControl::parse(req.headers());
+            if cc.no_store {
+                return inner.call(req).await;
+            }
+            let key = layer.key(&req);
+            // a session cookie identifies the user as much as Authorization does
+            let authorized = req.headers().contains_key(header::AUTHORIZATION)
+                || req.headers().contains_key(header::COOKIE);
+            // no-cache from the client skips the lookup but refreshes the entry,
+            // authorized requests never get what was stored for someone else
+            if !cc.no_cache && !authorized {
+                match layer.kv.get_some::<Cached>(&key).await {
+                    Ok(Some(cached)) => return Ok(cached.response(head)),
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!("cache lookup failed for {}: {}", key, e),
+                }
+            }
+            let res = inner.call(req).await?;
+            if head {
+                return Ok(miss(res));
+            }
+            let Some(ttl) = layer.ttl_for(&res, authorized) else {
+                return Ok(miss(res));
+            };
+            // streamed bodies have no exact size and are never buffered
+            match res.body().size_hint().exact() {
+                Some(len) if len as usize <= layer.max_body => {}
+                _ => return Ok(miss(res)),
+            }
+            let (parts, body) = res.into_parts();
+            let body = match to_bytes(body, layer.max_body).await {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::warn!("cached response for {} failed: {}", key, e);
+                    return Ok(SimpleError::internal("internal server error").into_response());
+                }
+            };
+            let cached = Cached {
+                status: parts.status.as_u16(),
+                headers: parts
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| stored_header(name))
+                    .filter_map(|(name, value)| {
+                        Some((name.to_string(), value.to_str().ok()?.to_string()))
+                    })
+                    .collect(),
+                body: BASE64_STANDARD.encode(&body),
+                stored_at: now(),
+            };
+            if let Err(e) = layer.kv.set(&key, &cached, ttl).await {
+                tracing::warn!("response for {} not cached: {}", key, e);
+            }
+            Ok(miss(Response::from_parts(parts, Body::from(body))))
+        })
+    }
+}
@@ -0,0 +1,122 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::SimpleError;
+
+fn has_content_type(headers: &HeaderMap, expected: &[&str]) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| expected.contains(&value.trim()))
+        .unwrap_or(false)
+}
+
+async fn read_body<S>(req: Request, state: &S, expected: &[&str]) -> Result<Bytes, SimpleError>
+where
+    S: Send + Sync,
+{
+    if !has_content_type(req.headers(), expected) {
+        return Err(SimpleError::new(
+            &format!("expected content type {}", expected[0]),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ));
+    }
+    Bytes::from_request(req, state)
+        .await
+        .map_err(|e| SimpleError::new(&e.body_text(), e.status()))
+}
+
+fn binary_response(content_type: &'static str, body: Result<Vec<u8>, String>) -> Response {
+    match body {
+        Ok(body) => (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            body,
+        )
+            .into_response(),
+        Err(err) => SimpleError::new(&err, StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPack<T>(pub T);
+
+#[cfg(feature = "msgpack")]
+const MSGPACK_TYPES: &[&str] = &["application/msgpack", "application/x-msgpack"];
+
+#[cfg(feature = "msgpack")]
+#[async_trait]
+impl<T, S> FromRequest<S> for MsgPack<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let body = read_body(req, state, MSGPACK_TYPES).await?;
+        rmp_serde::from_slice(&body)
+            .map(MsgPack)
+            .map_err(|e| SimpleError::new(&e.to_string(), StatusCode::UNPROCESSABLE_ENTITY))
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl<T> IntoResponse for MsgPack<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        binary_response(
+            MSGPACK_TYPES[0],
+            rmp_serde::to_vec_named(&self.0).map_err(|e| e.to_string()),
+        )
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor<T>(pub T);
+
+#[cfg(feature = "cbor")]
+const CBOR_TYPES: &[&str] = &["application/cbor"];
+
+#[cfg(feature = "cbor")]
+#[async_trait]
+impl<T, S> FromRequest<S> for Cbor<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let body = read_body(req, state, CBOR_TYPES).await?;
+        ciborium::from_reader(body.as_ref())
+            .map(Cbor)
+            .map_err(|e| SimpleError::new(&e.to_string(), StatusCode::UNPROCESSABLE_ENTITY))
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<T> IntoResponse for Cbor<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let mut body = Vec::new();
+        binary_response(
+            CBOR_TYPES[0],
+            ciborium::into_writer(&self.0, &mut body)
+                .map(|_| body)
+                .map_err(|e| e.to_string()),
+        )
+    }
+}
@@ -0,0 +1,125 @@
+use std::env;
+
+use axum::http::{header, Response};
+use tower_http::compression::{
+    predicate::{NotForContentType, SizeAbove},
+    CompressionLayer, Predicate,
+};
+
+use crate::limits::parse_size;
+
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: u16,
+    gzip: bool,
+    br: bool,
+    zstd: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            min_size: 1024,
+            gzip: true,
+            br: true,
+            zstd: true,
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression::default()
+    }
+    pub fn min_size(mut self, min_size: u16) -> Compression {
+        self.min_size = min_size;
+        self
+    }
+    pub fn gzip(mut self, enabled: bool) -> Compression {
+        self.gzip = enabled;
+        self
+    }
+    pub fn br(mut self, enabled: bool) -> Compression {
+        self.br = enabled;
+        self
+    }
+    pub fn zstd(mut self, enabled: bool) -> Compression {
+        self.zstd = enabled;
+        self
+    }
+    pub fn from_env() -> Compression {
+        let mut compression = Compression::default();
+        if let Some(min_size) = env::var("TOKI_COMPRESS_MIN_SIZE")
+            .ok()
+            .and_then(|v| parse_size(&v).ok())
+        {
+            compression = compression.min_size(min_size.min(u16::MAX as usize) as u16);
+        }
+        if let Ok(algorithms) = env::var("TOKI_COMPRESS_ALGORITHMS") {
+            let algorithms = algorithms
+                .split(',')
+                .map(|a| a.trim().to_ascii_lowercase())
+                .collect::<Vec<_>>();
+            let enabled = |name: &str| algorithms.iter().any(|a| a == name);
+            compression = compression
+                .gzip(enabled("gzip"))
+                .br(enabled("br"))
+                .zstd(enabled("zstd"));
+        }
+        compression
+    }
+    pub fn layer(&self) -> CompressionLayer<CompressPredicate> {
+        CompressionLayer::new()
+            .gzip(self.gzip)
+            .br(self.br)
+            .zstd(self.zstd)
+            .no_deflate()
+            .compress_when(CompressPredicate {
+                min_size: self.min_size,
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompressPredicate {
+    min_size: u16,
+}
+
+// formats that are compressed already, compressing again only costs cpu
+const COMPRESSED_TYPES: &[&str] = &[
+    "application/zip",
+    "application/gzip",
+    "application/zstd",
+    "application/x-7z-compressed",
+    "application/msgpack",
+    "font/woff2",
+    "video/",
+    "audio/",
+];
+
+impl Predicate for CompressPredicate {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: http_body::Body,
+    {
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        // encoders hold data back until their buffer fills, which stalls sse and ndjson
+        // streams, SseStream marks itself with x-accel-buffering: no
+        let streaming = content_type.starts_with("text/event-stream")
+            || content_type.starts_with("application/x-ndjson")
+            || response
+                .headers()
+                .get("x-accel-buffering")
+                .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"no"));
+        !streaming
+            && !COMPRESSED_TYPES.iter().any(|t| content_type.starts_with(t))
+            && SizeAbove::new(self.min_size)
+                .and(NotForContentType::GRPC)
+                .and(NotForContentType::IMAGES)
+                .should_compress(response)
+    }
+}
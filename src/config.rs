@@ -0,0 +1,443 @@
+use std::{collections::BTreeMap, env, ops::Deref, path::PathBuf, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+    Extension,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::watch;
+
+use crate::{AnyError, SimpleError};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub listen: Vec<String>,
+    pub kv: Option<String>,
+    pub trusted_proxies: Vec<String>,
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+impl Settings {
+    pub fn load(path: Option<&PathBuf>) -> Result<Settings, AnyError> {
+        let mut settings = match path {
+            Some(path) => {
+                crate::startup::config_source(&format!("file:{}", path.display()));
+                toml::from_str(&std::fs::read_to_string(path)?)?
+            }
+            None => Settings::default(),
+        };
+        if let Ok(listen) = env::var("TOKI_LISTEN") {
+            crate::startup::config_source("env:TOKI_LISTEN");
+            settings.listen = split_list(&listen);
+        }
+        if let Ok(kv) = env::var("TOKI_KV") {
+            crate::startup::config_source("env:TOKI_KV");
+            settings.kv = Some(kv);
+        }
+        if let Ok(proxies) = env::var("TOKI_TRUSTED_PROXIES") {
+            crate::startup::config_source("env:TOKI_TRUSTED_PROXIES");
+            settings.trusted_proxies = split_list(&proxies);
+        }
+        Ok(settings)
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct SettingsHandle {
+    path: Option<PathBuf>,
+    tx: Arc<watch::Sender<Arc<Settings>>>,
+}
+
+impl SettingsHandle {
+    pub fn load() -> Result<SettingsHandle, AnyError> {
+        SettingsHandle::load_from(env::var("TOKI_CONFIG").ok().map(PathBuf::from))
+    }
+    pub fn load_from(path: Option<PathBuf>) -> Result<SettingsHandle, AnyError> {
+        let settings = Settings::load(path.as_ref())?;
+        let (tx, _) = watch::channel(Arc::new(settings));
+        Ok(SettingsHandle {
+            path,
+            tx: Arc::new(tx),
+        })
+    }
+    pub fn get(&self) -> Arc<Settings> {
+        self.tx.borrow().clone()
+    }
+    pub fn watch(&self) -> watch::Receiver<Arc<Settings>> {
+        self.tx.subscribe()
+    }
+    pub fn reload(&self) -> Result<(), AnyError> {
+        let settings = Settings::load(self.path.as_ref())?;
+        self.tx.send_replace(Arc::new(settings));
+        Ok(())
+    }
+    pub fn reload_on_sighup(&self) {
+        #[cfg(unix)]
+        {
+            let handle = self.clone();
+            tokio::spawn(async move {
+                let mut hangup =
+                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                        .expect("failed to install SIGHUP handler");
+                while hangup.recv().await.is_some() {
+                    match handle.reload() {
+                        Ok(()) => tracing::info!("SIGHUP received, config reloaded"),
+                        Err(e) => tracing::error!("config reload failed: {}", e),
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EnvConfig<T>(Arc<T>);
+
+impl<T> Clone for EnvConfig<T> {
+    fn clone(&self) -> Self {
+        EnvConfig(self.0.clone())
+    }
+}
+
+impl<T> EnvConfig<T>
+where
+    T: DeserializeOwned,
+{
+    pub fn from_env(prefix: &str) -> Result<EnvConfig<T>, AnyError> {
+        let config = envy::prefixed(prefix).from_env::<T>()?;
+        crate::startup::config_source(&format!("env:{}*", prefix));
+        Ok(EnvConfig(Arc::new(config)))
+    }
+    pub fn validate<F>(self, check: F) -> Result<EnvConfig<T>, AnyError>
+    where
+        F: FnOnce(&T) -> Result<(), String>,
+    {
+        check(&self.0)?;
+        Ok(self)
+    }
+    pub fn extension(&self) -> Extension<EnvConfig<T>> {
+        Extension(self.clone())
+    }
+}
+
+impl<T> Deref for EnvConfig<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for EnvConfig<T>
+where
+    S: Send + Sync,
+    T: Send + Sync + 'static,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<EnvConfig<T>>()
+            .cloned()
+            .ok_or_else(|| {
+                SimpleError::new(
+                    "config extension is not installed",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            })
+    }
+}
+
+// layers, lowest precedence first: T::default(), the config file (toml or json,
+// from --config or TOKI_CONFIG), PREFIX_* env vars and --set key=value flags
+#[derive(Debug, Clone)]
+pub struct Config {
+    env_prefix: String,
+    path: Option<PathBuf>,
+    args: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            env_prefix: "TOKI_APP_".to_string(),
+            path: None,
+            args: env::args().skip(1).collect(),
+        }
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config::default()
+    }
+    pub fn load<T>() -> Result<Configured<T>, AnyError>
+    where
+        T: DeserializeOwned + Serialize + Default,
+    {
+        Config::new().read()
+    }
+    // nested fields are separated by a double underscore, PREFIX_DB__HOST sets db.host
+    pub fn env_prefix(mut self, prefix: &str) -> Config {
+        self.env_prefix = prefix.to_string();
+        self
+    }
+    pub fn file<P: Into<PathBuf>>(mut self, path: P) -> Config {
+        self.path = Some(path.into());
+        self
+    }
+    pub fn args<I>(mut self, args: I) -> Config
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.args = args.into_iter().collect();
+        self
+    }
+    pub fn read<T>(&self) -> Result<Configured<T>, AnyError>
+    where
+        T: DeserializeOwned + Serialize + Default,
+    {
+        let mut tree = serde_json::to_value(T::default())?;
+        let mut sources = BTreeMap::new();
+        record_leaves(&tree, "", "default", &mut sources);
+
+        if let Some(path) = self.config_path() {
+            let source = format!("file:{}", path.display());
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+            let file: Value = match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => serde_json::from_str(&content)?,
+                _ => toml::from_str(&content)?,
+            };
+            record_leaves(&file, "", &source, &mut sources);
+            merge(&mut tree, file);
+            crate::startup::config_source(&source);
+        }
+
+        let mut from_env = false;
+        let mut vars = env::vars()
+            .filter(|(k, _)| k.starts_with(&self.env_prefix) && k.len() > self.env_prefix.len())
+            .collect::<Vec<_>>();
+        vars.sort();
+        for (name, value) in vars {
+            let path = name[self.env_prefix.len()..]
+                .to_ascii_lowercase()
+                .replace("__", ".");
+            set_path(&mut tree, &path, &value);
+            sources.insert(path, format!("env:{}", name));
+            from_env = true;
+        }
+        if from_env {
+            crate::startup::config_source(&format!("env:{}*", self.env_prefix));
+        }
+
+        let overrides = self.overrides()?;
+        for (path, value) in &overrides {
+            set_path(&mut tree, path, value);
+            sources.insert(path.clone(), "cli:--set".to_string());
+        }
+        if !overrides.is_empty() {
+            crate::startup::config_source("cli:--set");
+        }
+
+        let value = serde_path_to_error::deserialize(tree).map_err(|e| {
+            let path = e.path().to_string();
+            match source_of(&sources, &path) {
+                Some(source) => format!("invalid config {} from {}: {}", path, source, e.inner()),
+                None => format!("invalid config {}: {}", path, e.inner()),
+            }
+        })?;
+        for (path, source) in &sources {
+            tracing::debug!("config {} from {}", path, source);
+        }
+        Ok(Configured { value, sources })
+    }
+    fn config_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.path {
+            return Some(path.clone());
+        }
+        let mut args = self.args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                return args.next().map(PathBuf::from);
+            }
+            if let Some(path) = arg.strip_prefix("--config=") {
+                return Some(PathBuf::from(path));
+            }
+        }
+        env::var("TOKI_CONFIG").ok().map(PathBuf::from)
+    }
+    fn overrides(&self) -> Result<Vec<(String, String)>, AnyError> {
+        let mut overrides = Vec::new();
+        let mut args = self.args.iter();
+        while let Some(arg) = args.next() {
+            let pair = match arg.strip_prefix("--set=") {
+                Some(pair) => pair,
+                None if arg == "--set" => args.next().ok_or("--set needs a key=value")?,
+                None => continue,
+            };
+            let (path, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("--set needs a key=value: {}", pair))?;
+            overrides.push((path.trim().to_string(), value.to_string()));
+        }
+        Ok(overrides)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Configured<T> {
+    value: T,
+    sources: BTreeMap<String, String>,
+}
+
+impl<T> Configured<T> {
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+    // where the value at a dotted path came from, e.g. "default" or "env:TOKI_APP_PORT"
+    pub fn source(&self, path: &str) -> Option<&str> {
+        source_of(&self.sources, path)
+    }
+    pub fn sources(&self) -> &BTreeMap<String, String> {
+        &self.sources
+    }
+}
+
+impl<T> Deref for Configured<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+// a table set as a whole has no entry of its own, the closest parent answers
+fn source_of<'a>(sources: &'a BTreeMap<String, String>, path: &str) -> Option<&'a str> {
+    let mut path = path;
+    loop {
+        if let Some(source) = sources.get(path) {
+            return Some(source);
+        }
+        path = path.rsplit_once('.')?.0;
+    }
+}
+
+fn record_leaves(value: &Value, path: &str, source: &str, sources: &mut BTreeMap<String, String>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+                record_leaves(value, &path, source, sources);
+            }
+        }
+        _ if !path.is_empty() => {
+            sources.insert(path.to_string(), source.to_string());
+        }
+        _ => {}
+    }
+}
+
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Object(base), Value::Object(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+fn set_path(tree: &mut Value, path: &str, raw: &str) {
+    let mut node = tree;
+    for key in path.split('.') {
+        if !node.is_object() {
+            *node = Value::Object(Map::new());
+        }
+        node = node
+            .as_object_mut()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert(Value::Null);
+    }
+    *node = coerce(node, raw);
+}
+
+// env vars and flags are plain strings, the current value decides what they become
+fn coerce(existing: &Value, raw: &str) -> Value {
+    match existing {
+        Value::String(_) => Value::String(raw.to_string()),
+        Value::Array(items) => {
+            let item = items.first().cloned().unwrap_or(Value::Null);
+            Value::Array(split_list(raw).iter().map(|v| coerce(&item, v)).collect())
+        }
+        _ => match serde_json::from_str::<Value>(raw) {
+            Ok(value @ (Value::Number(_) | Value::Bool(_))) => value,
+            _ => Value::String(raw.to_string()),
+        },
+    }
+}
+
+pub mod duration {
+    use std::time::Duration;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn parse(value: &str) -> Result<Duration, String> {
+        let value = value.trim();
+        let split = value
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("invalid duration: {}", value))?;
+        let secs = match unit.trim() {
+            "ms" => number / 1000.0,
+            "" | "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 3600.0,
+            "d" => number * 86400.0,
+            _ => return Err(format!("invalid duration unit: {}", value)),
+        };
+        Ok(Duration::from_secs_f64(secs))
+    }
+
+    pub fn serialize<S>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{}ms", value.as_millis()))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        parse(&value).map_err(de::Error::custom)
+    }
+}
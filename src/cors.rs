@@ -0,0 +1,115 @@
+use std::{env, time::Duration};
+
+use axum::http::{header::HeaderName, request::Parts, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer, ExposeHeaders};
+
+#[derive(Debug, Clone)]
+pub struct Cors {
+    origins: Vec<String>,
+    credentials: bool,
+    max_age: Option<u64>,
+    expose: Vec<HeaderName>,
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors {
+            origins: vec!["*".to_string()],
+            credentials: false,
+            max_age: Some(600),
+            expose: Vec::new(),
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Cors {
+        Cors::default()
+    }
+    pub fn origins<I, O>(mut self, origins: I) -> Cors
+    where
+        I: IntoIterator<Item = O>,
+        O: Into<String>,
+    {
+        self.origins = origins.into_iter().map(|o| o.into()).collect();
+        self
+    }
+    pub fn credentials(mut self, credentials: bool) -> Cors {
+        self.credentials = credentials;
+        self
+    }
+    pub fn max_age(mut self, max_age: u64) -> Cors {
+        self.max_age = Some(max_age).filter(|max_age| *max_age > 0);
+        self
+    }
+    pub fn expose(mut self, header: HeaderName) -> Cors {
+        self.expose.push(header);
+        self
+    }
+    pub fn from_env() -> Cors {
+        let mut cors = Cors::default();
+        if let Ok(origins) = env::var("TOKI_CORS_ORIGINS") {
+            cors = cors.origins(
+                origins
+                    .split(',')
+                    .map(|o| o.trim())
+                    .filter(|o| !o.is_empty()),
+            );
+        }
+        if let Ok(credentials) = env::var("TOKI_CORS_CREDENTIALS") {
+            cors = cors.credentials(matches!(credentials.as_str(), "1" | "true" | "yes" | "on"));
+        }
+        if let Some(max_age) = env::var("TOKI_CORS_MAX_AGE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            cors = cors.max_age(max_age);
+        }
+        cors
+    }
+    pub fn layer(&self) -> CorsLayer {
+        let origins = self.origins.clone();
+        // origins are matched and echoed back instead of answering `*`, which
+        // browsers reject for credentialed requests
+        let mut layer = CorsLayer::new()
+            .allow_origin(AllowOrigin::predicate(
+                move |origin: &HeaderValue, _: &Parts| {
+                    origin
+                        .to_str()
+                        .map(|origin| origins.iter().any(|p| origin_matches(p, origin)))
+                        .unwrap_or(false)
+                },
+            ))
+            .allow_methods([
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers(AllowHeaders::mirror_request())
+            .allow_credentials(self.credentials);
+        if !self.expose.is_empty() {
+            layer = layer.expose_headers(ExposeHeaders::list(self.expose.clone()));
+        }
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(Duration::from_secs(max_age));
+        }
+        layer
+    }
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            origin.len() >= prefix.len() + suffix.len()
+                && origin.starts_with(prefix)
+                && origin.ends_with(suffix)
+        }
+        None => pattern.eq_ignore_ascii_case(origin),
+    }
+}
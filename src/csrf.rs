@@ -0,0 +1,298 @@
+use std::task::{Context, Poll};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderName, HeaderValue, Method, Request},
+    response::{IntoResponse, Response},
+};
+use base64::prelude::*;
+use futures_util::future::BoxFuture;
+use tower::{Layer, Service};
+
+use crate::{startup::constant_time_eq, AnyError, SimpleError};
+
+// larger form bodies have to send the token in the header
+const FORM_LIMIT: usize = 1024 * 1024;
+
+#[derive(Clone)]
+enum Store {
+    // the cookie holds the token itself, the client echoes it back
+    Cookie,
+    // the cookie only holds an id, the token lives in kv
+    #[cfg(feature = "kv")]
+    Kv(Box<crate::KVManager>, u64),
+}
+
+#[derive(Clone)]
+pub struct Csrf {
+    store: Store,
+    cookie: String,
+    header: HeaderName,
+    field: String,
+    secure: bool,
+    exempt: Vec<String>,
+}
+
+// the token for the current request, render it into forms or hand it to scripts
+#[derive(Debug, Clone)]
+pub struct CsrfToken(pub String);
+
+impl Csrf {
+    // double submit cookie
+    pub fn new() -> Csrf {
+        Csrf {
+            store: Store::Cookie,
+            cookie: "csrf_token".to_string(),
+            header: HeaderName::from_static("x-csrf-token"),
+            field: "csrf_token".to_string(),
+            secure: true,
+            exempt: Vec::new(),
+        }
+    }
+    // synchronizer tokens, the cookie is http only and the token is never sent
+    // in a cookie at all
+    #[cfg(feature = "kv")]
+    pub fn kv(kv: crate::KVManager, ttl: u64) -> Csrf {
+        Csrf {
+            store: Store::Kv(Box::new(kv), ttl),
+            cookie: "csrf_id".to_string(),
+            ..Csrf::new()
+        }
+    }
+    pub fn cookie_name(mut self, name: &str) -> Csrf {
+        self.cookie = name.to_string();
+        self
+    }
+    pub fn header_name(mut self, name: &'static str) -> Csrf {
+        self.header = HeaderName::from_static(name);
+        self
+    }
+    pub fn field_name(mut self, name: &str) -> Csrf {
+        self.field = name.to_string();
+        self
+    }
+    // drops the Secure cookie attribute, for plain http during development
+    pub fn insecure(mut self) -> Csrf {
+        self.secure = false;
+        self
+    }
+    // path prefixes that are not checked, e.g. webhooks signed some other way
+    pub fn exempt(mut self, prefix: &str) -> Csrf {
+        self.exempt.push(prefix.to_string());
+        self
+    }
+    pub fn layer(&self) -> CsrfLayer {
+        CsrfLayer { csrf: self.clone() }
+    }
+    fn set_cookie(&self, value: &str) -> Option<HeaderValue> {
+        let mut cookie = format!("{}={}; Path=/; SameSite=Lax", self.cookie, value);
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if !matches!(self.store, Store::Cookie) {
+            cookie.push_str("; HttpOnly");
+        }
+        HeaderValue::from_str(&cookie).ok()
+    }
+    // the token for this request and the cookie to set if the client has none yet
+    async fn token(&self, cookie: Option<String>) -> Result<(String, Option<String>), AnyError> {
+        match &self.store {
+            Store::Cookie => match cookie {
+                Some(token) => Ok((token, None)),
+                None => {
+                    let token = random_token()?;
+                    Ok((token.clone(), Some(token)))
+                }
+            },
+            #[cfg(feature = "kv")]
+            Store::Kv(kv, ttl) => {
+                if let Some(id) = &cookie {
+                    if let Some(token) = kv.get_some::<String>(&format!("csrf:{}", id)).await? {
+                        return Ok((token, None));
+                    }
+                }
+                let id = random_token()?;
+                let token = random_token()?;
+                kv.set(&format!("csrf:{}", id), &token, *ttl).await?;
+                Ok((token, Some(id)))
+            }
+        }
+    }
+    async fn expected(&self, cookie: Option<String>) -> Result<Option<String>, AnyError> {
+        match &self.store {
+            Store::Cookie => Ok(cookie),
+            #[cfg(feature = "kv")]
+            Store::Kv(kv, _) => match cookie {
+                Some(id) => kv.get_some::<String>(&format!("csrf:{}", id)).await,
+                None => Ok(None),
+            },
+        }
+    }
+    fn cookie_value(&self, req: &Request<Body>) -> Option<String> {
+        req.headers()
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie)
+            .map(|(_, value)| value.to_string())
+            .filter(|value| !value.is_empty())
+    }
+    // header first, urlencoded forms are buffered and put back for the handler
+    async fn submitted(&self, req: Request<Body>) -> (Option<String>, Request<Body>) {
+        if let Some(token) = req
+            .headers()
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+        {
+            return (Some(token.to_string()), req);
+        }
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+        if !is_form {
+            return (None, req);
+        }
+        let (parts, body) = req.into_parts();
+        let Ok(body) = axum::body::to_bytes(body, FORM_LIMIT).await else {
+            return (None, Request::from_parts(parts, Body::empty()));
+        };
+        let token = form_urlencoded::parse(&body)
+            .find(|(name, _)| *name == self.field)
+            .map(|(_, value)| value.to_string());
+        (token, Request::from_parts(parts, Body::from(body)))
+    }
+}
+
+impl Default for Csrf {
+    fn default() -> Csrf {
+        Csrf::new()
+    }
+}
+
+fn random_token() -> Result<String, AnyError> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| format!("unable to generate csrf token: {}", e))?;
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(bytes))
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+#[derive(Clone)]
+pub struct CsrfLayer {
+    csrf: Csrf,
+}
+
+impl<S> Layer<S> for CsrfLayer {
+    type Service = CsrfService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CsrfService {
+            inner,
+            csrf: self.csrf.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CsrfService<S> {
+    inner: S,
+    csrf: Csrf,
+}
+
+impl<S> Service<Request<Body>> for CsrfService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let csrf = self.csrf.clone();
+        Box::pin(async move {
+            let path = req.uri().path();
+            if csrf.exempt.iter().any(|prefix| path.starts_with(prefix)) {
+                return inner.call(req).await;
+            }
+            let cookie = csrf.cookie_value(&req);
+            let mut req = req;
+            if !is_safe(req.method()) {
+                let expected = match csrf.expected(cookie.clone()).await {
+                    Ok(expected) => expected,
+                    Err(e) => {
+                        tracing::error!("unable to verify csrf token: {}", e);
+                        return Ok(
+                            SimpleError::internal("unable to verify csrf token").into_response()
+                        );
+                    }
+                };
+                let (submitted, rebuilt) = csrf.submitted(req).await;
+                req = rebuilt;
+                let valid = match (expected, submitted) {
+                    (Some(expected), Some(submitted)) => {
+                        constant_time_eq(expected.as_bytes(), submitted.as_bytes())
+                    }
+                    _ => false,
+                };
+                if !valid {
+                    tracing::debug!("csrf check failed for {} {}", req.method(), req.uri());
+                    return Ok(SimpleError::forbidden("invalid csrf token").into_response());
+                }
+            }
+            let (token, issued) = match csrf.token(cookie).await {
+                Ok(token) => token,
+                Err(e) => {
+                    tracing::error!("unable to issue csrf token: {}", e);
+                    return Ok(SimpleError::internal("unable to issue csrf token").into_response());
+                }
+            };
+            req.extensions_mut().insert(CsrfToken(token));
+            let mut res = inner.call(req).await?;
+            if let Some(cookie) = issued.and_then(|value| csrf.set_cookie(&value)) {
+                res.headers_mut().append(header::SET_COOKIE, cookie);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<CsrfToken>()
+            .cloned()
+            .ok_or_else(|| SimpleError::internal("csrf layer is not installed"))
+    }
+}
+
+impl std::fmt::Display for CsrfToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    io::SeekFrom,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::{StreamExt, TryStreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::static_files::{content_type, parse_range, range_header, read_chunks};
+
+// attr-char from rfc 5987, everything else is percent encoded in filename*
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+enum Source {
+    File {
+        file: tokio::fs::File,
+        len: u64,
+        modified: Option<SystemTime>,
+    },
+    Bytes(Bytes),
+}
+
+pub struct FileDownload {
+    source: Source,
+    name: Option<String>,
+    content_type: Option<HeaderValue>,
+    inline: bool,
+    range: Option<String>,
+    if_range: Option<String>,
+}
+
+impl FileDownload {
+    // the file is opened right away so a missing file surfaces as an error in the handler
+    pub async fn open<P: AsRef<Path>>(path: P) -> std::io::Result<FileDownload> {
+        let path = path.as_ref();
+        let mut file = tokio::fs::File::open(path).await?;
+        let meta = file.metadata().await?;
+        if !meta.is_file() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not a file", path.display()),
+            ));
+        }
+        let mut content_type = content_type(path);
+        if content_type.as_bytes() == b"application/octet-stream" {
+            // unknown extension, look at the first bytes instead
+            let mut head = vec![0; 1024];
+            let n = file.read(&mut head).await?;
+            file.seek(SeekFrom::Start(0)).await?;
+            content_type = HeaderValue::from_static(sniff(&head[..n]));
+        }
+        Ok(FileDownload {
+            source: Source::File {
+                file,
+                len: meta.len(),
+                modified: meta.modified().ok(),
+            },
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string()),
+            content_type: Some(content_type),
+            inline: false,
+            range: None,
+            if_range: None,
+        })
+    }
+    pub fn bytes<B: Into<Bytes>>(data: B, name: &str) -> FileDownload {
+        FileDownload {
+            source: Source::Bytes(data.into()),
+            name: Some(name.to_string()),
+            content_type: Some(content_type(Path::new(name)))
+                .filter(|t| t.as_bytes() != b"application/octet-stream"),
+            inline: false,
+            range: None,
+            if_range: None,
+        }
+    }
+    pub fn name(mut self, name: &str) -> FileDownload {
+        self.name = Some(name.to_string());
+        if self.content_type.is_none() {
+            self.content_type = Some(content_type(Path::new(name)))
+                .filter(|t| t.as_bytes() != b"application/octet-stream");
+        }
+        self
+    }
+    pub fn content_type(mut self, content_type: &str) -> FileDownload {
+        self.content_type = HeaderValue::from_str(content_type).ok();
+        self
+    }
+    // shows the file in the browser instead of saving it
+    pub fn inline(mut self) -> FileDownload {
+        self.inline = true;
+        self
+    }
+    // responses only see the request through this, pass the request headers to
+    // answer range requests
+    pub fn request(mut self, headers: &HeaderMap) -> FileDownload {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        self.range = value(header::RANGE);
+        self.if_range = value(header::IF_RANGE);
+        self
+    }
+    fn disposition(&self) -> HeaderValue {
+        let kind = if self.inline { "inline" } else { "attachment" };
+        let Some(name) = &self.name else {
+            return HeaderValue::from_static(kind);
+        };
+        // old clients only read the quoted ascii fallback
+        let fallback = name
+            .chars()
+            .map(|c| match c {
+                ' '..='~' if c != '"' && c != '\\' => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        let value = match fallback == *name {
+            true => format!("{}; filename=\"{}\"", kind, name),
+            false => format!(
+                "{}; filename=\"{}\"; filename*=UTF-8''{}",
+                kind,
+                fallback,
+                utf8_percent_encode(name, ATTR_CHAR)
+            ),
+        };
+        HeaderValue::from_str(&value).unwrap_or(HeaderValue::from_static("attachment"))
+    }
+}
+
+// magic numbers for the formats reports usually come in, text is anything valid utf-8
+fn sniff(data: &[u8]) -> &'static str {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"%PDF-", "application/pdf"),
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+    for (magic, content_type) in MAGIC {
+        if data.starts_with(magic) {
+            return content_type;
+        }
+    }
+    let head = &data[..data.len().min(1024)];
+    let text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // the sample may end inside a multibyte character
+        Err(e) => e.error_len().is_none(),
+    };
+    match text && !head.contains(&0) {
+        true => "text/plain; charset=utf-8",
+        false => "application/octet-stream",
+    }
+}
+
+impl IntoResponse for FileDownload {
+    fn into_response(self) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_DISPOSITION, self.disposition());
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        let (len, etag, modified) = match &self.source {
+            Source::File { len, modified, .. } => {
+                let etag = format!(
+                    "\"{:x}-{:x}\"",
+                    len,
+                    modified
+                        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_nanos())
+                        .unwrap_or(0)
+                );
+                (*len, etag, *modified)
+            }
+            Source::Bytes(data) => (data.len() as u64, String::new(), None),
+        };
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            if !etag.is_empty() {
+                headers.insert(header::ETAG, value);
+            }
+        }
+        if let Some(modified) = modified {
+            if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+                headers.insert(header::LAST_MODIFIED, value);
+            }
+        }
+
+        let mut status = StatusCode::OK;
+        let (mut start, mut end) = (0, len);
+        let mut request = HeaderMap::new();
+        if let Some(range) = self.range.as_deref().and_then(|v| v.parse().ok()) {
+            request.insert(header::RANGE, range);
+        }
+        if let Some(if_range) = self.if_range.as_deref().and_then(|v| v.parse().ok()) {
+            request.insert(header::IF_RANGE, if_range);
+        }
+        // byte sources have no validator, If-Range never matches them
+        let if_range_ok = !etag.is_empty() || !request.contains_key(header::IF_RANGE);
+        if let Some(range) = range_header(&request, &etag, modified).filter(|_| if_range_ok) {
+            match parse_range(range, len) {
+                Some((from, to)) => {
+                    status = StatusCode::PARTIAL_CONTENT;
+                    (start, end) = (from, to + 1);
+                    if let Ok(value) =
+                        HeaderValue::from_str(&format!("bytes {}-{}/{}", from, to, len))
+                    {
+                        headers.insert(header::CONTENT_RANGE, value);
+                    }
+                }
+                None if range.starts_with("bytes=") && !range.contains(',') => {
+                    if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+                        headers.insert(header::CONTENT_RANGE, value);
+                    }
+                    return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response();
+                }
+                None => {}
+            }
+        }
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+
+        match self.source {
+            Source::Bytes(data) => {
+                let content_type = self
+                    .content_type
+                    .unwrap_or_else(|| HeaderValue::from_static(sniff(&data)));
+                headers.insert(header::CONTENT_TYPE, content_type);
+                let body = data.slice(start as usize..end as usize);
+                (status, headers, body).into_response()
+            }
+            Source::File { mut file, .. } => {
+                let content_type = self
+                    .content_type
+                    .unwrap_or_else(|| HeaderValue::from_static("application/octet-stream"));
+                headers.insert(header::CONTENT_TYPE, content_type);
+                let body = futures_util::stream::once(async move {
+                    if start > 0 {
+                        file.seek(SeekFrom::Start(start)).await?;
+                    }
+                    Ok::<_, std::io::Error>(read_chunks(file, end - start))
+                })
+                .try_flatten()
+                .boxed();
+                (status, headers, Body::from_stream(body)).into_response()
+            }
+        }
+    }
+}
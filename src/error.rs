@@ -1,21 +1,122 @@
-use std::fmt::Display;
+use std::{
+    env,
+    fmt::Display,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
-use axum::{http::StatusCode, response::IntoResponse, response::Response};
+use axum::{
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::IntoResponse,
+    response::Response,
+};
+
+use crate::{current_request_id, response::insert_header};
 
 pub type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Debug)]
 pub struct SimpleError {
-    msg: String,
+    msg: Option<String>,
     status: StatusCode,
+    source: Option<AnyError>,
+    report: bool,
+    headers: Option<Box<HeaderMap>>,
 }
 impl SimpleError {
     pub fn new(msg: &str, status: StatusCode) -> SimpleError {
         SimpleError {
-            msg: msg.to_string(),
+            msg: Some(msg.to_string()),
+            status,
+            source: None,
+            report: false,
+            headers: None,
+        }
+    }
+    pub fn wrap<E>(err: E, status: StatusCode) -> SimpleError
+    where
+        E: Into<AnyError>,
+    {
+        SimpleError {
+            msg: None,
             status,
+            source: Some(err.into()),
+            report: false,
+            headers: None,
+        }
+    }
+    // accepts a StatusCode or a plain u16, unknown codes become 500
+    pub fn http<S>(status: S, msg: &str) -> SimpleError
+    where
+        S: TryInto<StatusCode>,
+    {
+        let status = status
+            .try_into()
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        SimpleError {
+            report: status.is_server_error(),
+            ..SimpleError::new(msg, status)
+        }
+    }
+    pub fn bad_request(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::BAD_REQUEST)
+    }
+    pub fn unauthorized(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::UNAUTHORIZED)
+    }
+    pub fn forbidden(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::FORBIDDEN)
+    }
+    pub fn not_found(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::NOT_FOUND)
+    }
+    pub fn conflict(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::CONFLICT)
+    }
+    pub fn gone(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::GONE)
+    }
+    pub fn payload_too_large(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::PAYLOAD_TOO_LARGE)
+    }
+    pub fn unprocessable(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::UNPROCESSABLE_ENTITY)
+    }
+    pub fn too_many_requests(msg: &str) -> SimpleError {
+        SimpleError::new(msg, StatusCode::TOO_MANY_REQUESTS)
+    }
+    pub fn internal(msg: &str) -> SimpleError {
+        SimpleError::http(StatusCode::INTERNAL_SERVER_ERROR, msg)
+    }
+    pub fn bad_gateway(msg: &str) -> SimpleError {
+        SimpleError::http(StatusCode::BAD_GATEWAY, msg)
+    }
+    pub fn service_unavailable(msg: &str) -> SimpleError {
+        SimpleError::http(StatusCode::SERVICE_UNAVAILABLE, msg)
+    }
+    pub fn context(mut self, ctx: &str) -> SimpleError {
+        SimpleError {
+            msg: Some(ctx.to_string()),
+            status: self.status,
+            report: self.report,
+            headers: self.headers.take(),
+            source: Some(Box::new(self)),
         }
     }
+    pub fn with_header<K, V>(mut self, name: K, value: V) -> SimpleError
+    where
+        K: TryInto<HeaderName>,
+        V: TryInto<HeaderValue>,
+    {
+        insert_header(
+            self.headers.get_or_insert_with(Default::default),
+            name,
+            value,
+        );
+        self
+    }
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
     pub fn from<T, E>(result: Result<T, E>, status: StatusCode) -> Result<T, SimpleError>
     where
         E: Display,
@@ -52,11 +153,35 @@ impl SimpleError {
         E: Display,
         E: std::error::Error + Send + Sync + 'static,
     {
-        #[cfg(feature = "sentry")]
-        sentry::capture_error(&err);
-        SimpleError::new(&err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        SimpleError {
+            report: true,
+            ..SimpleError::wrap(err, StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub trait SimpleContext<T> {
+    fn context(self, ctx: &str) -> Result<T, SimpleError>;
+}
+impl<T, E> SimpleContext<T> for Result<T, E>
+where
+    E: Into<SimpleError>,
+{
+    fn context(self, ctx: &str) -> Result<T, SimpleError> {
+        self.map_err(|err| err.into().context(ctx))
     }
 }
+
+// bail_http!(404, "no such user {}", id) returns early with a SimpleError
+#[macro_export]
+macro_rules! bail_http {
+    ($status:expr, $($arg:tt)+) => {
+        return ::std::result::Result::Err(
+            $crate::SimpleError::http($status, &::std::format!($($arg)+)).into(),
+        )
+    };
+}
+
 #[macro_export(local_inner_macros)]
 macro_rules! impl_simple_error {
     ($t:ty) => {
@@ -87,29 +212,260 @@ macro_rules! impl_simple_error_outside {
 
 impl From<AnyError> for SimpleError {
     fn from(err: AnyError) -> Self {
-        let err = err.as_ref();
-        if err.is::<SimpleError>() {
-            let err = err.downcast_ref::<SimpleError>().unwrap();
-            return SimpleError {
-                msg: err.msg.clone(),
-                status: err.status,
-            };
+        let err = match err.downcast::<SimpleError>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<crate::CircuitOpenError>() {
+            Ok(err) => return (*err).into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<crate::CallTimeoutError>() {
+            Ok(err) => return (*err).into(),
+            Err(err) => err,
+        };
+        #[cfg(feature = "kv")]
+        let err = match kv_error(err) {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        SimpleError {
+            report: true,
+            ..SimpleError::wrap(err, StatusCode::INTERNAL_SERVER_ERROR)
         }
-        #[cfg(feature = "sentry")]
-        sentry::capture_error(err);
-        SimpleError::new(&err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
     }
 }
 impl std::fmt::Display for SimpleError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
+        match (&self.msg, &self.source) {
+            (Some(msg), _) => write!(f, "{}", msg)?,
+            (None, Some(source)) => write!(f, "{}", source)?,
+            (None, None) => {}
+        }
+        if f.alternate() {
+            let mut source = std::error::Error::source(self);
+            while let Some(err) = source {
+                write!(f, ": {}", err)?;
+                source = err.source();
+            }
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for SimpleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match (&self.msg, &self.source) {
+            (Some(_), Some(source)) => Some(source.as_ref()),
+            (None, Some(source)) => source.source(),
+            _ => None,
+        }
     }
 }
-impl std::error::Error for SimpleError {}
 impl_simple_error!(std::io::Error);
 
+impl SimpleError {
+    fn classified<E>(err: E, status: StatusCode) -> SimpleError
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        SimpleError {
+            report: status.is_server_error(),
+            ..SimpleError::wrap(err, status)
+        }
+    }
+}
+
+impl SimpleError {
+    // for json the client sent: malformed is a 400, the wrong shape a 422.
+    // `?` on any other json error is the server's fault
+    pub fn json_body(err: serde_json::Error) -> SimpleError {
+        let status = match err.classify() {
+            serde_json::error::Category::Io => StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::error::Category::Data => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        SimpleError::classified(err, status)
+    }
+}
+
+// kv entries, upstream bodies and the handler's own values
+impl From<serde_json::Error> for SimpleError {
+    fn from(err: serde_json::Error) -> Self {
+        SimpleError::classified(err, StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl From<crate::CircuitOpenError> for SimpleError {
+    fn from(err: crate::CircuitOpenError) -> Self {
+        SimpleError::classified(err, StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+impl From<crate::CallTimeoutError> for SimpleError {
+    fn from(err: crate::CallTimeoutError) -> Self {
+        SimpleError::classified(err, StatusCode::GATEWAY_TIMEOUT)
+    }
+}
+
+// KVManager hands out boxed errors, so `?` in handlers lands in From<AnyError>
+#[cfg(feature = "kv")]
+fn kv_error(err: AnyError) -> Result<SimpleError, AnyError> {
+    let err = match err.downcast::<crate::kv::NotFoundError>() {
+        Ok(err) => return Ok((*err).into()),
+        Err(err) => err,
+    };
+    let err = match err.downcast::<crate::ConflictError>() {
+        Ok(err) => return Ok((*err).into()),
+        Err(err) => err,
+    };
+    let err = match err.downcast::<crate::KvUnavailable>() {
+        Ok(err) => return Ok((*err).into()),
+        Err(err) => err,
+    };
+    match err.downcast::<redis::RedisError>() {
+        Ok(err) => Ok((*err).into()),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(feature = "kv")]
+impl From<crate::KvUnavailable> for SimpleError {
+    fn from(err: crate::KvUnavailable) -> Self {
+        SimpleError::classified(err, StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+#[cfg(feature = "kv")]
+impl From<crate::kv::NotFoundError> for SimpleError {
+    fn from(err: crate::kv::NotFoundError) -> Self {
+        SimpleError::classified(err, StatusCode::NOT_FOUND)
+    }
+}
+
+#[cfg(feature = "kv")]
+impl From<crate::ConflictError> for SimpleError {
+    fn from(err: crate::ConflictError) -> Self {
+        SimpleError::classified(err, StatusCode::CONFLICT)
+    }
+}
+
+#[cfg(feature = "kv")]
+impl From<redis::RedisError> for SimpleError {
+    fn from(err: redis::RedisError) -> Self {
+        let status = if err.is_timeout() {
+            StatusCode::GATEWAY_TIMEOUT
+        } else if err.is_connection_refusal() || err.is_connection_dropped() {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        SimpleError::classified(err, status)
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl From<reqwest::Error> for SimpleError {
+    fn from(err: reqwest::Error) -> Self {
+        let status = if err.is_timeout() {
+            StatusCode::GATEWAY_TIMEOUT
+        } else if err.is_connect() || err.is_status() || err.is_decode() {
+            StatusCode::BAD_GATEWAY
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        SimpleError::classified(err, status)
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl From<sqlx::Error> for SimpleError {
+    fn from(err: sqlx::Error) -> Self {
+        let status = match &err {
+            sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+            sqlx::Error::PoolTimedOut => StatusCode::SERVICE_UNAVAILABLE,
+            sqlx::Error::Database(db) if db.is_unique_violation() => StatusCode::CONFLICT,
+            sqlx::Error::Database(db)
+                if db.is_foreign_key_violation() || db.is_check_violation() =>
+            {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        SimpleError::classified(err, status)
+    }
+}
+
+// 0 until TOKI_REDACT_ERRORS has been read, then 1 for off and 2 for on
+static REDACT: AtomicU8 = AtomicU8::new(0);
+
+fn redact() -> bool {
+    match REDACT.load(Ordering::Relaxed) {
+        0 => {
+            let on = matches!(
+                env::var("TOKI_REDACT_ERRORS").as_deref(),
+                Ok("1" | "true" | "yes" | "on")
+            );
+            REDACT.store(if on { 2 } else { 1 }, Ordering::Relaxed);
+            on
+        }
+        state => state == 2,
+    }
+}
+
+impl SimpleError {
+    // 5xx responses only say what kind of failure it was and which request id to
+    // look for, the message itself is only logged. overrides TOKI_REDACT_ERRORS
+    pub fn redact_server_errors(enabled: bool) {
+        REDACT.store(if enabled { 2 } else { 1 }, Ordering::Relaxed);
+    }
+    pub(crate) fn redacts() -> bool {
+        redact()
+    }
+    fn redacted(&self) -> bool {
+        self.status.is_server_error() && redact()
+    }
+    pub(crate) fn report(&self) {
+        // a redacted message has to end up somewhere
+        if self.report || self.redacted() {
+            match current_request_id() {
+                Some(request_id) => tracing::error!(request_id, "{:#}", self),
+                None => tracing::error!("{:#}", self),
+            }
+        }
+        #[cfg(feature = "sentry")]
+        if self.report {
+            sentry::capture_error(self);
+        }
+    }
+    // what the client gets to see
+    pub(crate) fn client_message(&self) -> String {
+        if !self.redacted() {
+            return self.to_string();
+        }
+        let reason = self
+            .status
+            .canonical_reason()
+            .unwrap_or("Server Error")
+            .to_lowercase();
+        match current_request_id() {
+            Some(request_id) => format!("{} (request id {})", reason, request_id),
+            None => reason,
+        }
+    }
+    pub(crate) fn take_headers(&mut self) -> HeaderMap {
+        self.headers.take().map(|h| *h).unwrap_or_default()
+    }
+}
+
 impl IntoResponse for SimpleError {
     fn into_response(self) -> Response {
-        (self.status, self.msg).into_response()
+        self.report();
+        let body = self.client_message();
+        (
+            self.status,
+            self.headers.map(|h| *h).unwrap_or_default(),
+            body,
+        )
+            .into_response()
     }
 }
@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    routing::{get, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{kv::NotFoundError, AnyError, ConflictError, KVManager, RealIP, SimpleError, Version};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Flag {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout: Option<u8>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub environments: HashMap<String, bool>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FlagContext {
+    user: Option<String>,
+    ip: Option<String>,
+}
+
+impl FlagContext {
+    pub fn new() -> FlagContext {
+        FlagContext::default()
+    }
+    pub fn user(mut self, user: &str) -> FlagContext {
+        self.user = Some(user.to_string());
+        self
+    }
+    pub fn ip(mut self, ip: &str) -> FlagContext {
+        self.ip = Some(ip.to_string());
+        self
+    }
+}
+
+impl From<&RealIP> for FlagContext {
+    fn from(ip: &RealIP) -> FlagContext {
+        FlagContext::new().ip(&ip.0)
+    }
+}
+
+type FlagSet = HashMap<String, Flag>;
+type Cached = Option<(Instant, Arc<FlagSet>)>;
+
+#[derive(Clone)]
+pub struct Flags {
+    kv: KVManager,
+    key: String,
+    environment: Option<String>,
+    ttl: Duration,
+    cache: Arc<Mutex<Cached>>,
+}
+
+impl Flags {
+    pub fn new(kv: KVManager) -> Flags {
+        Flags {
+            kv,
+            key: "flags".to_string(),
+            environment: env::var("TOKI_ENV").ok().filter(|e| !e.is_empty()),
+            ttl: Duration::from_secs(5),
+            cache: Default::default(),
+        }
+    }
+    pub fn key(mut self, key: &str) -> Flags {
+        self.key = key.to_string();
+        self
+    }
+    pub fn environment(mut self, environment: &str) -> Flags {
+        self.environment = Some(environment.to_string());
+        self
+    }
+    pub fn cache_ttl(mut self, ttl: Duration) -> Flags {
+        self.ttl = ttl;
+        self
+    }
+    async fn load(&self) -> Result<Arc<FlagSet>, AnyError> {
+        if let Some((loaded, flags)) = self.cache.lock().unwrap().as_ref() {
+            if loaded.elapsed() < self.ttl {
+                return Ok(flags.clone());
+            }
+        }
+        let flags = Arc::new(
+            self.kv
+                .get_some::<FlagSet>(&self.key)
+                .await?
+                .unwrap_or_default(),
+        );
+        *self.cache.lock().unwrap() = Some((Instant::now(), flags.clone()));
+        Ok(flags)
+    }
+    pub async fn flags(&self) -> Result<FlagSet, AnyError> {
+        Ok(self.load().await?.as_ref().clone())
+    }
+    pub async fn is_enabled(&self, name: &str, context: &FlagContext) -> bool {
+        let flags = match self.load().await {
+            Ok(flags) => flags,
+            Err(e) => {
+                // unknown state, keep the feature off rather than guessing
+                tracing::warn!("unable to load feature flags: {}", e);
+                return false;
+            }
+        };
+        let Some(flag) = flags.get(name) else {
+            return false;
+        };
+        let overridden = self
+            .environment
+            .as_ref()
+            .and_then(|environment| flag.environments.get(environment));
+        if let Some(enabled) = overridden {
+            return *enabled;
+        }
+        if !flag.enabled {
+            return false;
+        }
+        match flag.rollout {
+            None | Some(100..) => true,
+            Some(percentage) => {
+                let Some(id) = context.user.as_ref().or(context.ip.as_ref()) else {
+                    return false;
+                };
+                bucket(name, id) < percentage as u64
+            }
+        }
+    }
+    async fn update<F>(&self, change: F) -> Result<(), AnyError>
+    where
+        F: Fn(&mut FlagSet),
+    {
+        loop {
+            let (mut flags, version) = match self.kv.get_versioned::<FlagSet>(&self.key).await {
+                Ok(res) => res,
+                Err(e) if e.is::<NotFoundError>() => (FlagSet::new(), Version::absent()),
+                Err(e) => return Err(e),
+            };
+            change(&mut flags);
+            match self.kv.set_if(&self.key, &flags, &version, 0).await {
+                Ok(()) => {}
+                Err(e) if e.is::<ConflictError>() => continue,
+                Err(e) => return Err(e),
+            }
+            *self.cache.lock().unwrap() = Some((Instant::now(), Arc::new(flags)));
+            return Ok(());
+        }
+    }
+    pub async fn set(&self, name: &str, flag: Flag) -> Result<(), AnyError> {
+        self.update(|flags| {
+            flags.insert(name.to_string(), flag.clone());
+        })
+        .await
+    }
+    pub async fn remove(&self, name: &str) -> Result<(), AnyError> {
+        self.update(|flags| {
+            flags.remove(name);
+        })
+        .await
+    }
+    // mount behind the app's own authentication, the routes do not check access
+    pub fn router(&self) -> Router {
+        let list = self.clone();
+        let show = self.clone();
+        let set = self.clone();
+        let remove = self.clone();
+        Router::new()
+            .route(
+                "/",
+                get(move || async move { list.flags().await.map(Json).map_err(unavailable) }),
+            )
+            .route(
+                "/:name",
+                put(
+                    move |Path(name): Path<String>, Json(flag): Json<Flag>| async move {
+                        set.set(&name, flag.clone()).await.map_err(unavailable)?;
+                        Ok::<_, SimpleError>(Json(flag))
+                    },
+                )
+                .get(move |Path(name): Path<String>| async move {
+                    let flags = show.load().await.map_err(unavailable)?;
+                    match flags.get(&name) {
+                        Some(flag) => Ok(Json(flag.clone())),
+                        None => Err(SimpleError::new("Not Found", StatusCode::NOT_FOUND)),
+                    }
+                })
+                .delete(move |Path(name): Path<String>| async move {
+                    remove.remove(&name).await.map_err(unavailable)?;
+                    Ok::<_, SimpleError>(StatusCode::NO_CONTENT)
+                }),
+            )
+    }
+}
+
+fn unavailable(err: AnyError) -> SimpleError {
+    SimpleError::wrap(err, StatusCode::SERVICE_UNAVAILABLE)
+        .context("unable to access feature flags")
+}
+
+// FNV-1a, so every process puts the same user in the same rollout bucket
+fn bucket(name: &str, id: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in name.bytes().chain([0]).chain(id.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash % 100
+}
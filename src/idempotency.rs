@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{response::Parts, HeaderName, HeaderValue, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures_util::future::BoxFuture;
+use http_body::Body as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tower::{Layer, Service};
+
+use crate::{KVManager, SimpleError};
+
+// keys longer than this are rejected, they end up in kv keys and file names
+const MAX_KEY_LEN: usize = 255;
+
+type InFlight = Arc<Mutex<HashMap<String, watch::Receiver<Option<Arc<Stored>>>>>>;
+
+// replays the first response for retries carrying the same Idempotency-Key,
+// method and path. duplicates arriving while the first is still running wait
+// for it, on other instances they poll kv until the lock timeout and then get
+// a 409. 5xx responses are not stored so the client can retry them. a key
+// reused with another request body gets a 422
+#[derive(Clone)]
+pub struct IdempotencyLayer {
+    kv: KVManager,
+    header: HeaderName,
+    prefix: String,
+    ttl: u64,
+    lock_timeout: u64,
+    max_body: usize,
+    max_request: usize,
+    in_flight: InFlight,
+}
+
+impl IdempotencyLayer {
+    pub fn new(kv: KVManager) -> IdempotencyLayer {
+        IdempotencyLayer {
+            kv,
+            header: HeaderName::from_static("idempotency-key"),
+            prefix: "idempotency:".to_string(),
+            ttl: 86400,
+            lock_timeout: 30,
+            max_body: 1024 * 1024,
+            max_request: 1024 * 1024,
+            in_flight: Default::default(),
+        }
+    }
+    pub fn header(mut self, header: &str) -> IdempotencyLayer {
+        self.header = HeaderName::try_from(header).expect("invalid idempotency header name");
+        self
+    }
+    pub fn prefix(mut self, prefix: &str) -> IdempotencyLayer {
+        self.prefix = prefix.to_string();
+        self
+    }
+    // how long responses are replayed for, in seconds
+    pub fn ttl(mut self, ttl: u64) -> IdempotencyLayer {
+        self.ttl = ttl;
+        self
+    }
+    // how long other instances wait for a request still in flight, in seconds
+    pub fn lock_timeout(mut self, lock_timeout: u64) -> IdempotencyLayer {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+    // larger responses are passed through without being stored
+    pub fn max_body(mut self, max_body: usize) -> IdempotencyLayer {
+        self.max_body = max_body;
+        self
+    }
+    // request bodies are buffered to be fingerprinted, larger ones get a 413
+    pub fn max_request(mut self, max_request: usize) -> IdempotencyLayer {
+        self.max_request = max_request;
+        self
+    }
+}
+
+impl<S> Layer<S> for IdempotencyLayer {
+    type Service = IdempotencyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IdempotencyService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IdempotencyService<S> {
+    inner: S,
+    layer: IdempotencyLayer,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Stored {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    // hash of the request body the response belongs to
+    #[serde(default)]
+    fingerprint: String,
+}
+
+// cookies belong to whoever made the first request, hop by hop headers to its connection
+fn stored_header(name: &HeaderName) -> bool {
+    !matches!(
+        name.as_str(),
+        "set-cookie" | "connection" | "keep-alive" | "transfer-encoding" | "date"
+    )
+}
+
+fn fingerprint(body: &[u8]) -> String {
+    Sha256::digest(body)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Stored {
+    fn new(parts: &Parts, body: &[u8], fingerprint: &str) -> Stored {
+        Stored {
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .filter(|(name, _)| stored_header(name))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body: BASE64_STANDARD.encode(body),
+            fingerprint: fingerprint.to_string(),
+        }
+    }
+    fn response(&self, fingerprint: &str) -> Response {
+        if !self.fingerprint.is_empty() && self.fingerprint != fingerprint {
+            return SimpleError::unprocessable(
+                "the idempotency key was already used with a different request",
+            )
+            .into_response();
+        }
+        let mut res = Response::new(Body::from(
+            BASE64_STANDARD.decode(&self.body).unwrap_or_default(),
+        ));
+        *res.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::try_from(value.as_str()),
+            ) {
+                res.headers_mut().append(name, value);
+            }
+        }
+        res.headers_mut().insert(
+            HeaderName::from_static("idempotent-replayed"),
+            HeaderValue::from_static("true"),
+        );
+        res
+    }
+}
+
+impl<S> Service<Request<Body>> for IdempotencyService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            // safe methods are idempotent already
+            if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+                return inner.call(req).await;
+            }
+            let Some(key) = req.headers().get(&layer.header) else {
+                return inner.call(req).await;
+            };
+            let key = match key.to_str() {
+                Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key,
+                _ => return Ok(SimpleError::bad_request("invalid idempotency key").into_response()),
+            };
+            let key = format!(
+                "{}{}:{}:{}",
+                layer.prefix,
+                req.method(),
+                req.uri().path(),
+                key
+            );
+            let (parts, body) = req.into_parts();
+            let body = match to_bytes(body, layer.max_request).await {
+                Ok(body) => body,
+                Err(_) => {
+                    return Ok(SimpleError::payload_too_large(
+                        "request body too large for an idempotent request",
+                    )
+                    .into_response())
+                }
+            };
+            let fingerprint = fingerprint(&body);
+            let req = Request::from_parts(parts, Body::from(body));
+            loop {
+                match layer.kv.get_some::<Stored>(&key).await {
+                    Ok(Some(stored)) => return Ok(stored.response(&fingerprint)),
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::warn!("idempotency lookup failed for {}: {}", key, e);
+                        return inner.call(req).await;
+                    }
+                }
+                let waiting = {
+                    let mut in_flight = layer.in_flight.lock().unwrap();
+                    match in_flight.get(&key) {
+                        Some(rx) => Err(rx.clone()),
+                        None => {
+                            let (tx, rx) = watch::channel(None);
+                            in_flight.insert(key.clone(), rx);
+                            Ok(tx)
+                        }
+                    }
+                };
+                let mut rx = match waiting {
+                    Ok(tx) => return lead(inner, req, layer, key, fingerprint, tx).await,
+                    Err(rx) => rx,
+                };
+                // a dropped sender means the first request was cancelled, start over
+                let stored = match rx.wait_for(|stored| stored.is_some()).await {
+                    Ok(stored) => stored.clone(),
+                    Err(_) => None,
+                };
+                if let Some(stored) = stored {
+                    return Ok(stored.response(&fingerprint));
+                }
+            }
+        })
+    }
+}
+
+// removes the in-flight entry however the first request ends
+struct Leading {
+    in_flight: InFlight,
+    key: String,
+}
+
+impl Drop for Leading {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+async fn lead<S>(
+    mut inner: S,
+    req: Request<Body>,
+    layer: IdempotencyLayer,
+    key: String,
+    fingerprint: String,
+    tx: watch::Sender<Option<Arc<Stored>>>,
+) -> Result<Response, S::Error>
+where
+    S: Service<Request<Body>, Response = Response>,
+{
+    let _leading = Leading {
+        in_flight: layer.in_flight.clone(),
+        key: key.clone(),
+    };
+    let kv = &layer.kv;
+    match kv.try_lock(&key, layer.lock_timeout).await {
+        Ok(true) => {}
+        Ok(false) => {
+            // another instance has it, wait for its response to show up
+            for _ in 0..layer.lock_timeout * 10 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                if let Ok(Some(stored)) = kv.get_some::<Stored>(&key).await {
+                    let res = stored.response(&fingerprint);
+                    let _ = tx.send(Some(Arc::new(stored)));
+                    return Ok(res);
+                }
+            }
+            return Ok(SimpleError::new(
+                "a request with this idempotency key is still in progress",
+                StatusCode::CONFLICT,
+            )
+            .into_response());
+        }
+        Err(e) => tracing::warn!("idempotency lock failed for {}: {}", key, e),
+    }
+    let res = inner.call(req).await;
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = kv.unlock(&key).await;
+            return Err(e);
+        }
+    };
+    // streamed or large bodies are passed through unbuffered and not stored,
+    // duplicates waiting here get a conflict instead of running it again
+    match res.body().size_hint().exact() {
+        Some(len) if len as usize <= layer.max_body => {}
+        _ => {
+            let _ = kv.unlock(&key).await;
+            let conflict = SimpleError::new(
+                "the response for this idempotency key cannot be replayed",
+                StatusCode::CONFLICT,
+            )
+            .into_response();
+            let (parts, body) = conflict.into_parts();
+            if let Ok(body) = to_bytes(body, usize::MAX).await {
+                let _ = tx.send(Some(Arc::new(Stored::new(&parts, &body, &fingerprint))));
+            }
+            return Ok(res);
+        }
+    }
+    let (parts, body) = res.into_parts();
+    let body = match to_bytes(body, layer.max_body).await {
+        Ok(body) => body,
+        Err(e) => {
+            let _ = kv.unlock(&key).await;
+            tracing::warn!("idempotent response for {} failed: {}", key, e);
+            return Ok(SimpleError::internal("internal server error").into_response());
+        }
+    };
+    let stored = Stored::new(&parts, &body, &fingerprint);
+    if !parts.status.is_server_error() {
+        if let Err(e) = kv.set(&key, &stored, layer.ttl).await {
+            tracing::warn!("idempotent response for {} not stored: {}", key, e);
+        }
+    }
+    let _ = kv.unlock(&key).await;
+    let _ = tx.send(Some(Arc::new(stored)));
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use chrono::Utc;
+use croner::Cron;
+use serde::Serialize;
+use tokio::task::JoinSet;
+
+use crate::{listener::shutdown_requested, AnyError};
+
+#[cfg(feature = "kv")]
+use crate::KVManager;
+
+type JobFuture = Pin<Box<dyn Future<Output = Result<(), AnyError>> + Send>>;
+type JobHandler = Arc<dyn Fn() -> JobFuture + Send + Sync>;
+
+struct Job {
+    name: String,
+    pattern: String,
+    schedule: Cron,
+    handler: JobHandler,
+}
+
+// times are unix seconds
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: String,
+    pub running: bool,
+    pub next_run: Option<i64>,
+    pub last_run: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+fn registry() -> &'static Mutex<BTreeMap<String, JobStatus>> {
+    static JOBS: OnceLock<Mutex<BTreeMap<String, JobStatus>>> = OnceLock::new();
+    JOBS.get_or_init(Default::default)
+}
+
+fn update_status<F>(name: &str, update: F)
+where
+    F: FnOnce(&mut JobStatus),
+{
+    if let Some(status) = registry().lock().unwrap().get_mut(name) {
+        update(status);
+    }
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Vec<Job>,
+    #[cfg(feature = "kv")]
+    kv: Option<KVManager>,
+}
+
+impl Jobs {
+    pub fn new() -> Jobs {
+        Jobs::default()
+    }
+    pub fn every<F, Fut>(self, schedule: &str, handler: F) -> Jobs
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), AnyError>> + Send + 'static,
+    {
+        self.job(schedule, schedule, handler)
+    }
+    pub fn job<F, Fut>(mut self, name: &str, schedule: &str, handler: F) -> Jobs
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), AnyError>> + Send + 'static,
+    {
+        let pattern = schedule.to_string();
+        let schedule = Cron::new(schedule)
+            .with_seconds_optional()
+            .parse()
+            .unwrap_or_else(|e| panic!("invalid cron expression {:?}: {}", schedule, e));
+        self.jobs.push(Job {
+            name: name.to_string(),
+            pattern,
+            schedule,
+            handler: Arc::new(move || Box::pin(handler())),
+        });
+        self
+    }
+    #[cfg(feature = "kv")]
+    pub fn with_lock(mut self, kv: KVManager) -> Jobs {
+        self.kv = Some(kv);
+        self
+    }
+    pub fn names(&self) -> Vec<String> {
+        self.jobs.iter().map(|job| job.name.clone()).collect()
+    }
+    // every job started through run() in this process
+    pub fn status() -> Vec<JobStatus> {
+        registry().lock().unwrap().values().cloned().collect()
+    }
+    pub async fn run(self) {
+        let mut set = JoinSet::new();
+        for job in self.jobs {
+            registry().lock().unwrap().insert(
+                job.name.clone(),
+                JobStatus {
+                    name: job.name.clone(),
+                    schedule: job.pattern.clone(),
+                    ..Default::default()
+                },
+            );
+            #[cfg(feature = "kv")]
+            let kv = self.kv.clone();
+            set.spawn(async move {
+                loop {
+                    let now = Utc::now();
+                    let next = match job.schedule.find_next_occurrence(&now, false) {
+                        Ok(next) => next,
+                        Err(e) => {
+                            tracing::error!("job {} has no next run: {}", job.name, e);
+                            return;
+                        }
+                    };
+                    update_status(&job.name, |status| status.next_run = Some(next.timestamp()));
+                    let wait = (next - now).to_std().unwrap_or_default();
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {},
+                        _ = shutdown_requested() => return,
+                    }
+                    #[cfg(feature = "kv")]
+                    if let Some(kv) = &kv {
+                        match kv.try_lock(&format!("jobs:{}", job.name), 30).await {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(e) => {
+                                tracing::error!("job {} lock failed: {}", job.name, e);
+                                continue;
+                            }
+                        }
+                    }
+                    tracing::debug!("job {} started", job.name);
+                    update_status(&job.name, |status| {
+                        status.running = true;
+                        status.last_run = Some(Utc::now().timestamp());
+                    });
+                    let res = (job.handler)().await;
+                    match &res {
+                        Ok(()) => tracing::debug!("job {} finished", job.name),
+                        Err(e) => tracing::error!("job {} failed: {}", job.name, e),
+                    }
+                    update_status(&job.name, |status| {
+                        status.running = false;
+                        status.last_error = res.err().map(|e| e.to_string());
+                    });
+                }
+            });
+        }
+        while set.join_next().await.is_some() {}
+    }
+}
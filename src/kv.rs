@@ -1,13 +1,31 @@
 use std::{
+    collections::HashMap,
     env,
     error::Error,
     fmt::{self, Display},
     future::Future,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
 use axum::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
 use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::kv_codec::{deserialize, Codec};
+#[cfg(feature = "kv-consul")]
+use crate::kv_consul::KVConsul;
+#[cfg(feature = "kv-dynamodb")]
+use crate::kv_dynamo::KVDynamo;
+use crate::kv_observer::{add_observer, observe, KvObserver, KvOperation};
+#[cfg(feature = "kv-s3")]
+use crate::kv_s3::KVS3;
+use crate::kv_tombstone::is_tombstone;
 
 pub type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -18,19 +36,210 @@ pub fn now() -> u64 {
         .as_secs()
 }
 
+// set_if takes 0 for an entry that never expires, stored as an expiry of 0
+pub(crate) fn expire_at(expire: u64) -> u64 {
+    match expire {
+        0 => 0,
+        expire => expire + now(),
+    }
+}
+
+#[async_trait]
+pub trait KVStore: fmt::Debug + Send + Sync {
+    async fn get_raw(&self, key: &str) -> Result<Vec<u8>, AnyError>;
+    async fn set_raw(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError>;
+    async fn del(&self, key: &str) -> Result<(), AnyError>;
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError>;
+    async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError>;
+    async fn persist(&self, key: &str) -> Result<(), AnyError>;
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError>;
+    async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError>;
+    async fn lock(&self, key: &str, ttl: u64) -> Result<bool, AnyError>;
+    async fn unlock(&self, key: &str) -> Result<(), AnyError>;
+    async fn get_versioned_raw(&self, _key: &str) -> Result<(Vec<u8>, Version), AnyError> {
+        Err("compare-and-swap is not supported by this kv store".into())
+    }
+    async fn set_if_raw(
+        &self,
+        _key: &str,
+        _value: &[u8],
+        _version: &Version,
+        _expire: u64,
+    ) -> Result<(), AnyError> {
+        Err("compare-and-swap is not supported by this kv store".into())
+    }
+    // lists and sets are stored as a json array of the json elements, changed
+    // under lock(). stores with native lists and sets override all of these
+    async fn update_elements(
+        &self,
+        key: &str,
+        update: &mut (dyn for<'a> FnMut(&'a mut Vec<Box<RawValue>>) -> bool + Send),
+    ) -> Result<(), AnyError> {
+        let lock = format!("{}-elements", key);
+        let mut attempts = 0;
+        while !self.lock(&lock, 10).await? {
+            attempts += 1;
+            if attempts >= 500 {
+                return Err(format!("timed out waiting for kv entry {}", key).into());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let res = async {
+            let (mut elements, ttl) = match self.get_raw(key).await {
+                Ok(raw) => (
+                    serde_json::from_slice::<Vec<Box<RawValue>>>(&raw)?,
+                    self.ttl(key).await?,
+                ),
+                Err(e) if e.is::<NotFoundError>() => (Vec::new(), None),
+                Err(e) => return Err(e),
+            };
+            if !update(&mut elements) {
+                return Ok(());
+            }
+            // like redis, an empty list or set is no key at all
+            if elements.is_empty() {
+                return match self.del(key).await {
+                    Err(e) if !e.is::<NotFoundError>() => Err(e),
+                    _ => Ok(()),
+                };
+            }
+            let raw = serde_json::to_vec(&elements)?;
+            match ttl {
+                Some(ttl) => self.set_raw(key, &raw, ttl.max(1)).await,
+                None => {
+                    self.set_raw(key, &raw, 86400).await?;
+                    self.persist(key).await
+                }
+            }
+        }
+        .await;
+        let _ = self.unlock(&lock).await;
+        res
+    }
+    async fn list_push_raw(&self, key: &str, value: &[u8]) -> Result<u64, AnyError> {
+        let value = RawValue::from_string(String::from_utf8(value.to_vec())?)?;
+        let mut value = Some(value);
+        let mut len = 0;
+        self.update_elements(key, &mut |elements| {
+            elements.extend(value.take());
+            len = elements.len() as u64;
+            true
+        })
+        .await?;
+        Ok(len)
+    }
+    async fn list_pop_raw(&self, key: &str) -> Result<Option<Vec<u8>>, AnyError> {
+        let mut popped = None;
+        self.update_elements(key, &mut |elements| {
+            if elements.is_empty() {
+                return false;
+            }
+            popped = Some(elements.remove(0).get().as_bytes().to_vec());
+            true
+        })
+        .await?;
+        Ok(popped)
+    }
+    async fn list_range_raw(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Vec<u8>>, AnyError> {
+        let elements = stored_elements(self, key).await?;
+        Ok(match element_range(elements.len(), start, stop) {
+            Some(range) => elements[range]
+                .iter()
+                .map(|e| e.get().as_bytes().to_vec())
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+    async fn set_add_raw(&self, key: &str, member: &[u8]) -> Result<bool, AnyError> {
+        let member = RawValue::from_string(String::from_utf8(member.to_vec())?)?;
+        let mut member = Some(member);
+        let mut added = false;
+        self.update_elements(key, &mut |elements| {
+            let Some(member) = member.take() else {
+                return false;
+            };
+            added = !elements.iter().any(|e| e.get() == member.get());
+            if added {
+                elements.push(member);
+            }
+            added
+        })
+        .await?;
+        Ok(added)
+    }
+    async fn set_remove_raw(&self, key: &str, member: &[u8]) -> Result<bool, AnyError> {
+        let member = std::str::from_utf8(member)?;
+        let mut removed = false;
+        self.update_elements(key, &mut |elements| {
+            let len = elements.len();
+            elements.retain(|e| e.get() != member);
+            removed = elements.len() != len;
+            removed
+        })
+        .await?;
+        Ok(removed)
+    }
+    async fn set_members_raw(&self, key: &str) -> Result<Vec<Vec<u8>>, AnyError> {
+        Ok(stored_elements(self, key)
+            .await?
+            .iter()
+            .map(|e| e.get().as_bytes().to_vec())
+            .collect())
+    }
+}
+
+async fn stored_elements<S>(store: &S, key: &str) -> Result<Vec<Box<RawValue>>, AnyError>
+where
+    S: KVStore + ?Sized,
+{
+    match store.get_raw(key).await {
+        Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+        Err(e) if e.is::<NotFoundError>() => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// redis style inclusive indexes, negative ones count from the end
+fn element_range(len: usize, start: i64, stop: i64) -> Option<std::ops::Range<usize>> {
+    let len = len as i64;
+    let index = |i: i64| if i < 0 { len + i } else { i };
+    let start = index(start).max(0);
+    let stop = index(stop).min(len - 1);
+    (start <= stop).then(|| start as usize..stop as usize + 1)
+}
+
 #[async_trait]
-pub trait KVTrait {
+pub trait KVTrait: KVStore {
     async fn get<B>(&self, key: &str) -> Result<B, AnyError>
     where
         B: serde::Serialize,
-        B: serde::de::DeserializeOwned;
+        B: serde::de::DeserializeOwned,
+    {
+        let data = self.get_raw(key).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
     async fn set<B>(&self, key: &str, value: &B, expire: u64) -> Result<(), AnyError>
     where
         B: Sync,
         B: serde::Serialize,
-        B: serde::de::DeserializeOwned;
-    async fn del(&self, key: &str) -> Result<(), AnyError>;
+        B: serde::de::DeserializeOwned,
+    {
+        let data = serde_json::to_vec(value)?;
+        self.set_raw(key, &data, expire).await
+    }
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, AnyError> {
+        self.get_raw(key).await
+    }
+    async fn set_bytes(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        self.set_raw(key, value, expire).await
+    }
 }
+impl<T> KVTrait for T where T: KVStore + ?Sized {}
 
 #[derive(Debug)]
 pub struct NotFoundError {}
@@ -44,29 +253,253 @@ pub fn not_found_error() -> Result<(), NotFoundError> {
     Err(NotFoundError {})
 }
 
+#[derive(Debug)]
+pub struct KvUnavailable {
+    attempts: u32,
+    source: redis::RedisError,
+}
+impl KvUnavailable {
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+impl Display for KvUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "kv store unavailable after {} attempts", self.attempts)
+    }
+}
+impl Error for KvUnavailable {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConflictError {}
+impl Display for ConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Version conflict")
+    }
+}
+impl Error for ConflictError {}
+
+// opaque per-backend token, an empty version stands for a missing key
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Version(String);
+impl Version {
+    pub fn absent() -> Version {
+        Version(String::new())
+    }
+    pub fn is_absent(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub(crate) fn new(version: String) -> Version {
+        Version(version)
+    }
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+impl Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn current_version(res: Result<Version, AnyError>) -> Result<Version, AnyError> {
+    match res {
+        Err(e) if e.is::<NotFoundError>() => Ok(Version::absent()),
+        res => res,
+    }
+}
+
 pub fn normailze_key(key: &str) -> String {
+    let key = key.replace(
+        ['/', '\\', ':', '*', '?', '\"', '<', '>', '|', '.', '@', '_'],
+        "-",
+    );
+    format!("{}{}", key_prefix(), key)
+}
+
+// like normailze_key but `/` separates directories, empty segments are dropped
+pub(crate) fn normalize_nested_key(key: &str) -> String {
     let key = key
-        .to_string()
-        .replace('/', "-")
-        .replace('\\', "-")
-        .replace(':', "-")
-        .replace('*', "-")
-        .replace('?', "-")
-        .replace('\"', "-")
-        .replace('<', "-")
-        .replace('>', "-")
-        .replace('|', "-")
-        .replace('.', "-")
-        .replace('@', "-")
-        .replace('_', "-");
-    let prrefix = env::var("TOKI_KV_PREFIX").unwrap_or_else(|_| "".into());
-    return format!("{}{}", prrefix, key);
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            segment.replace(
+                ['\\', ':', '*', '?', '\"', '<', '>', '|', '.', '@', '_'],
+                "-",
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("{}{}", key_prefix(), key)
+}
+
+pub(crate) fn key_prefix() -> String {
+    env::var("TOKI_KV_PREFIX").unwrap_or_else(|_| "".into())
+}
+
+// percent, u32::MAX until TOKI_KV_TTL_JITTER has been read
+static TTL_JITTER: AtomicU32 = AtomicU32::new(u32::MAX);
+
+pub(crate) fn ttl_jitter() -> u32 {
+    let jitter = TTL_JITTER.load(Ordering::Relaxed);
+    if jitter != u32::MAX {
+        return jitter;
+    }
+    let jitter = env::var("TOKI_KV_TTL_JITTER")
+        .ok()
+        .and_then(|v| v.trim_end_matches('%').parse::<u32>().ok())
+        .unwrap_or(0)
+        .min(100);
+    TTL_JITTER.store(jitter, Ordering::Relaxed);
+    jitter
+}
+
+// spreads expiry over expire ± percent so entries written together do not expire together
+pub(crate) fn jittered(expire: u64, percent: u32) -> u64 {
+    let spread = expire * percent.min(100) as u64 / 100;
+    if spread == 0 {
+        return expire;
+    }
+    let random =
+        std::hash::BuildHasher::hash_one(&std::collections::hash_map::RandomState::new(), expire);
+    (expire - spread + random % (spread * 2 + 1)).max(1)
+}
+
+// tokens of the locks taken through one store, unlock only removes a lock
+// still carrying its token, not one taken over after the ttl ran out
+#[derive(Debug, Clone, Default)]
+struct LockOwners(Arc<Mutex<HashMap<String, String>>>);
+impl LockOwners {
+    fn token() -> String {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let random = std::hash::BuildHasher::hash_one(
+            &std::collections::hash_map::RandomState::new(),
+            (std::process::id(), count, Instant::now()),
+        );
+        format!("{}-{}-{:016x}", std::process::id(), count, random)
+    }
+    fn hold(&self, key: &str, token: String) {
+        self.0.lock().unwrap().insert(key.to_string(), token);
+    }
+    fn release(&self, key: &str) -> Option<String> {
+        self.0.lock().unwrap().remove(key)
+    }
+}
+
+// temp files are written next to their destination, so renames stay on one filesystem
+fn tmp_path(path: &str) -> String {
+    static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    format!(
+        "{}.{}-{}.tmp",
+        path,
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// binary values from set_bytes, json never starts with a zero byte.
+// 0x04 and up mark the serializers from kv_codec
+const RAW_BYTES: u8 = 0x00;
+const COMPRESS_GZIP: u8 = 0x01;
+const COMPRESS_ZSTD: u8 = 0x02;
+#[cfg(not(feature = "kv-encrypt"))]
+const ENCRYPT_AES_GCM: u8 = 0x03;
+
+// key is the normalized storage key, encrypted values are bound to it
+pub(crate) fn encode_value(key: &str, json: Vec<u8>) -> Result<Vec<u8>, AnyError> {
+    let json = compress_value(json)?;
+    #[cfg(feature = "kv-encrypt")]
+    let json = crate::kv_encrypt::encrypt_value(key, json)?;
+    #[cfg(not(feature = "kv-encrypt"))]
+    let _ = key;
+    Ok(json)
+}
+
+// binary payloads are usually compressed already, they are only encrypted
+fn encode_bytes(key: &str, data: &[u8]) -> Result<Vec<u8>, AnyError> {
+    let mut raw = Vec::with_capacity(data.len() + 1);
+    raw.push(RAW_BYTES);
+    raw.extend_from_slice(data);
+    #[cfg(feature = "kv-encrypt")]
+    let raw = crate::kv_encrypt::encrypt_value(key, raw)?;
+    #[cfg(not(feature = "kv-encrypt"))]
+    let _ = key;
+    Ok(raw)
+}
+
+fn compress_value(json: Vec<u8>) -> Result<Vec<u8>, AnyError> {
+    #[cfg(feature = "kv-compress")]
+    {
+        use std::io::Write;
+
+        let threshold = env::var("TOKI_KV_COMPRESS_THRESHOLD")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(1024);
+        if json.len() >= threshold {
+            match env::var("TOKI_KV_COMPRESS").as_deref() {
+                Ok("gzip") => {
+                    let mut encoder = flate2::write::GzEncoder::new(
+                        vec![COMPRESS_GZIP],
+                        flate2::Compression::default(),
+                    );
+                    encoder.write_all(&json)?;
+                    return Ok(encoder.finish()?);
+                }
+                Ok("zstd") => {
+                    let mut encoded = vec![COMPRESS_ZSTD];
+                    zstd::stream::copy_encode(json.as_slice(), &mut encoded, 0)?;
+                    return Ok(encoded);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(json)
+}
+
+pub(crate) fn decode_value(key: &str, mut raw: Vec<u8>) -> Result<Vec<u8>, AnyError> {
+    match raw.first() {
+        Some(&RAW_BYTES) => Ok(raw.split_off(1)),
+        #[cfg(feature = "kv-encrypt")]
+        Some(&crate::kv_encrypt::ENCRYPT_AES_GCM) => {
+            decode_value(key, crate::kv_encrypt::decrypt_value(key, &raw)?)
+        }
+        #[cfg(not(feature = "kv-encrypt"))]
+        Some(&ENCRYPT_AES_GCM) => {
+            Err(format!("encrypted kv value {} requires the kv-encrypt feature", key).into())
+        }
+        #[cfg(feature = "kv-compress")]
+        Some(&COMPRESS_GZIP) => {
+            use std::io::Read;
+
+            let mut decoded = Vec::new();
+            flate2::read::GzDecoder::new(&raw[1..]).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+        #[cfg(feature = "kv-compress")]
+        Some(&COMPRESS_ZSTD) => Ok(zstd::stream::decode_all(&raw[1..])?),
+        #[cfg(not(feature = "kv-compress"))]
+        Some(&COMPRESS_GZIP) | Some(&COMPRESS_ZSTD) => {
+            Err("compressed kv value requires the kv-compress feature".into())
+        }
+        _ => Ok(raw),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct KVFilesystem {
     path: String,
+    nested: bool,
+    locks: LockOwners,
 }
+
 #[derive(Serialize, Deserialize)]
 pub struct KVFilesystemJsonData<T>
 where
@@ -74,100 +507,1275 @@ where
 {
     data: T,
     expire: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blob: Option<String>,
 }
 
 impl KVFilesystem {
     pub fn new(path: &str) -> KVFilesystem {
         KVFilesystem {
             path: path.to_string(),
+            nested: false,
+            locks: LockOwners::default(),
         }
     }
-}
-
-#[async_trait]
-impl KVTrait for KVFilesystem {
-    async fn get<B>(&self, key: &str) -> Result<B, AnyError>
-    where
-        B: serde::Serialize,
-        B: serde::de::DeserializeOwned,
-    {
-        let path = format!("{}/{}.json", self.path, key);
-        let contents = tokio::fs::read_to_string(path).await;
+    // `user/42/profile` is stored as `user/42/profile.json` instead of
+    // `user-42-profile.json`, listing by prefix walks the directories
+    pub fn nested(mut self, nested: bool) -> KVFilesystem {
+        self.nested = nested;
+        self
+    }
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+    pub(crate) fn is_nested(&self) -> bool {
+        self.nested
+    }
+    fn file(&self, key: &str, extension: &str) -> String {
+        format!("{}/{}.{}", self.path, key, extension)
+    }
+    // parent directories of nested keys are created on first write
+    async fn create_parent(&self, path: &str) -> Result<(), AnyError> {
+        if !self.nested {
+            return Ok(());
+        }
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+    // every file below the root with the extension, as keys relative to the
+    // root. nested stores start at the directory the prefix points into
+    async fn walk(&self, prefix: &str, extension: &str) -> Result<Vec<String>, AnyError> {
+        let suffix = format!(".{}", extension);
+        let start = match self.nested {
+            true => prefix.rsplit_once('/').map(|(dir, _)| dir).unwrap_or(""),
+            false => "",
+        };
+        let mut files = Vec::new();
+        let mut dirs = vec![start.to_string()];
+        while let Some(dir) = dirs.pop() {
+            let path = match dir.is_empty() {
+                true => self.path.clone(),
+                false => format!("{}/{}", self.path, dir),
+            };
+            let mut entries = match tokio::fs::read_dir(&path).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && !dir.is_empty() => continue,
+                Err(e) => return Err(Box::new(e)),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                let relative = match dir.is_empty() {
+                    true => name.to_string(),
+                    false => format!("{}/{}", dir, name),
+                };
+                if self.nested && entry.file_type().await?.is_dir() {
+                    dirs.push(relative);
+                } else if let Some(key) = relative.strip_suffix(&suffix) {
+                    if key.starts_with(prefix) {
+                        files.push(key.to_string());
+                    }
+                }
+            }
+        }
+        Ok(files)
+    }
+    async fn read_entry(&self, key: &str) -> Result<KVFilesystemJsonData<Box<RawValue>>, AnyError> {
+        Ok(self.read_versioned(key).await?.0)
+    }
+    async fn read_versioned(
+        &self,
+        key: &str,
+    ) -> Result<(KVFilesystemJsonData<Box<RawValue>>, Version), AnyError> {
+        let contents = tokio::fs::read_to_string(self.file(key, "json")).await;
         match contents {
             Ok(contents) => {
-                let json: KVFilesystemJsonData<B> = serde_json::from_str(&contents)?;
+                let json: KVFilesystemJsonData<Box<RawValue>> =
+                    match serde_json::from_str(&contents) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            tracing::warn!("kv entry {} is corrupt: {}", key, e);
+                            self.quarantine(key).await;
+                            return Err(Box::new(NotFoundError {}));
+                        }
+                    };
                 if json.expire > 0 && json.expire < now() {
                     not_found_error()?;
                 }
-                Ok(json.data)
+                let mut hasher = std::hash::DefaultHasher::new();
+                std::hash::Hasher::write(&mut hasher, contents.as_bytes());
+                let version = format!("{:016x}", std::hash::Hasher::finish(&hasher));
+                Ok((json, Version::new(version)))
             }
             Err(_) => Err(Box::new(NotFoundError {})),
         }
     }
-    async fn set<B>(&self, key: &str, value: &B, expire: u64) -> Result<(), AnyError>
+    async fn quarantine(&self, key: &str) {
+        if !matches!(
+            env::var("TOKI_KV_QUARANTINE").as_deref(),
+            Ok("1" | "true" | "yes" | "on")
+        ) {
+            return;
+        }
+        let path = self.file(key, "json");
+        let target = format!("{}.corrupt-{}", path, now());
+        match tokio::fs::rename(&path, &target).await {
+            Ok(()) => tracing::warn!("kv entry {} moved to {}", key, target),
+            Err(e) => tracing::warn!("unable to quarantine kv entry {}: {}", key, e),
+        }
+    }
+    async fn write_entry<T>(
+        &self,
+        key: &str,
+        data: &KVFilesystemJsonData<T>,
+    ) -> Result<(), AnyError>
     where
-        B: Sync,
-        B: serde::Serialize,
-        B: serde::de::DeserializeOwned,
+        T: Serialize,
     {
-        let path = format!("{}/{}.json", self.path, key);
-        let data = KVFilesystemJsonData {
-            data: value,
-            expire: expire + now(),
-        };
-        let contents = serde_json::to_string(&data)?;
-        tokio::fs::write(path, contents).await?;
+        let path = self.file(key, "json");
+        self.create_parent(&path).await?;
+        // write next to the destination and rename, so readers never see a partial file
+        let tmp = tmp_path(&path);
+        tokio::fs::write(&tmp, serde_json::to_string(data)?).await?;
+        if let Err(e) = tokio::fs::rename(&tmp, &path).await {
+            tokio::fs::remove_file(&tmp).await.unwrap_or(());
+            return Err(Box::new(e));
+        }
         Ok(())
     }
+    // json values stay readable on disk, anything else is kept as a blob
+    async fn write_value(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        let data = match serde_json::from_slice::<&RawValue>(value) {
+            Ok(raw) => KVFilesystemJsonData {
+                data: raw,
+                expire,
+                blob: None,
+            },
+            Err(_) => KVFilesystemJsonData {
+                data: RawValue::NULL,
+                expire,
+                blob: Some(BASE64_STANDARD.encode(value)),
+            },
+        };
+        self.write_entry(key, &data).await
+    }
+    // writers of the same key take turns through a `.cas` marker file, so the
+    // version check and the write happen as one step across processes
+    async fn cas_guard(&self, key: &str) -> Result<CasGuard, AnyError> {
+        let path = self.file(key, "cas");
+        self.create_parent(&path).await?;
+        for _ in 0..500 {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(CasGuard(path)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let stale = std::fs::metadata(&path)
+                        .and_then(|meta| meta.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok())
+                        .is_some_and(|age| age > Duration::from_secs(10));
+                    if stale {
+                        std::fs::remove_file(&path).unwrap_or(());
+                        continue;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Err(format!("timed out waiting for kv entry {}", key).into())
+    }
+    // entries rewritten while a sweep runs are left alone, the file must still
+    // have the mtime it had when its expiry was read
+    pub async fn sweep(&self) -> Result<u64, AnyError> {
+        #[derive(Deserialize)]
+        struct Expiry {
+            expire: u64,
+        }
+
+        let mut expired = Vec::new();
+        let mut files = Vec::new();
+        for extension in ["json", "lock"] {
+            for key in self.walk("", extension).await? {
+                files.push((self.file(&key, extension), extension == "lock"));
+            }
+        }
+        for (path, is_lock) in files {
+            let Ok(modified) = tokio::fs::metadata(&path)
+                .await
+                .and_then(|meta| meta.modified())
+            else {
+                continue;
+            };
+            let Ok(contents) = tokio::fs::read_to_string(&path).await else {
+                continue;
+            };
+            let expire = match is_lock {
+                true => contents.trim().parse::<u64>().ok(),
+                false => serde_json::from_str::<Expiry>(&contents)
+                    .ok()
+                    .map(|e| e.expire),
+            };
+            if expire.is_some_and(|expire| expire > 0 && expire < now()) {
+                expired.push((path, modified));
+            }
+        }
+        let mut count = 0;
+        for batch in expired.chunks(500) {
+            for (path, modified) in batch {
+                let unchanged = tokio::fs::metadata(path)
+                    .await
+                    .and_then(|meta| meta.modified())
+                    .is_ok_and(|current| current == *modified);
+                if unchanged && tokio::fs::remove_file(path).await.is_ok() {
+                    count += 1;
+                }
+            }
+            tokio::task::yield_now().await;
+        }
+        Ok(count)
+    }
+    pub fn spawn_sweeper(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let kv = self.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(interval);
+            timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                timer.tick().await;
+                match kv.sweep().await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::debug!("kv sweep removed {} expired entries", count),
+                    Err(e) => tracing::warn!("kv sweep of {} failed: {}", kv.path, e),
+                }
+            }
+        })
+    }
+    async fn set_expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        let mut json = self.read_entry(key).await?;
+        json.expire = expire;
+        self.write_entry(key, &json).await
+    }
+}
+
+struct CasGuard(String);
+impl Drop for CasGuard {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).unwrap_or(());
+    }
+}
+
+// expiry and owner token of a lock file
+async fn lock_file(path: &str) -> Option<(u64, String)> {
+    let contents = tokio::fs::read_to_string(path).await.ok()?;
+    let contents = contents.trim();
+    // files from before owner tokens only hold the expiry
+    let (expire, token) = contents.split_once(' ').unwrap_or((contents, ""));
+    Some((expire.parse().ok()?, token.to_string()))
+}
+
+#[async_trait]
+impl KVStore for KVFilesystem {
+    async fn get_raw(&self, key: &str) -> Result<Vec<u8>, AnyError> {
+        let json = self.read_entry(key).await?;
+        match json.blob {
+            Some(blob) => Ok(BASE64_STANDARD.decode(blob)?),
+            None => Ok(json.data.get().as_bytes().to_vec()),
+        }
+    }
+    async fn set_raw(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        self.write_value(key, value, expire + now()).await
+    }
     async fn del(&self, key: &str) -> Result<(), AnyError> {
-        let path = format!("{}/{}.json", self.path, key);
-        tokio::fs::remove_file(path).await?;
+        tokio::fs::remove_file(self.file(key, "json")).await?;
+        Ok(())
+    }
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        let json = self.read_entry(key).await?;
+        if json.expire == 0 {
+            return Ok(None);
+        }
+        Ok(Some(json.expire.saturating_sub(now())))
+    }
+    async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        self.set_expire(key, now() + expire).await
+    }
+    async fn persist(&self, key: &str) -> Result<(), AnyError> {
+        self.set_expire(key, 0).await
+    }
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError> {
+        self.walk(prefix, "json").await
+    }
+    async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError> {
+        let keys = self.keys(prefix).await?;
+        if dry_run {
+            return Ok(keys.len() as u64);
+        }
+        let mut count = 0;
+        for key in keys {
+            match tokio::fs::remove_file(self.file(&key, "json")).await {
+                Ok(()) => count += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Ok(count)
+    }
+    // the lock file is written in full and then linked into place, so nobody
+    // reads it half written
+    async fn lock(&self, key: &str, ttl: u64) -> Result<bool, AnyError> {
+        let path = self.file(key, "lock");
+        self.create_parent(&path).await?;
+        let token = LockOwners::token();
+        let tmp = tmp_path(&path);
+        tokio::fs::write(&tmp, format!("{} {}", now() + ttl, token)).await?;
+        let locked = async {
+            match tokio::fs::hard_link(&tmp, &path).await {
+                Ok(()) => return Ok(true),
+                Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+                    return Err(Box::new(e) as AnyError)
+                }
+                Err(_) => {}
+            }
+            // an expired lock is taken over under the guard, so only one caller gets it
+            let _guard = self.cas_guard(key).await?;
+            if lock_file(&path)
+                .await
+                .is_some_and(|(expire, _)| expire >= now())
+            {
+                return Ok(false);
+            }
+            tokio::fs::rename(&tmp, &path).await?;
+            Ok(true)
+        }
+        .await;
+        tokio::fs::remove_file(&tmp).await.unwrap_or(());
+        if let Ok(true) = locked {
+            self.locks.hold(key, token);
+        }
+        locked
+    }
+    async fn unlock(&self, key: &str) -> Result<(), AnyError> {
+        let Some(token) = self.locks.release(key) else {
+            return Ok(());
+        };
+        let path = self.file(key, "lock");
+        let _guard = self.cas_guard(key).await?;
+        if lock_file(&path)
+            .await
+            .is_some_and(|(_, owner)| owner == token)
+        {
+            tokio::fs::remove_file(&path).await?;
+        }
         Ok(())
     }
+    async fn get_versioned_raw(&self, key: &str) -> Result<(Vec<u8>, Version), AnyError> {
+        let (json, version) = self.read_versioned(key).await?;
+        let value = match json.blob {
+            Some(blob) => BASE64_STANDARD.decode(blob)?,
+            None => json.data.get().as_bytes().to_vec(),
+        };
+        Ok((value, version))
+    }
+    async fn set_if_raw(
+        &self,
+        key: &str,
+        value: &[u8],
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError> {
+        let _guard = self.cas_guard(key).await?;
+        let current = current_version(self.read_versioned(key).await.map(|(_, v)| v))?;
+        if current != *version {
+            return Err(Box::new(ConflictError {}));
+        }
+        self.write_value(key, value, expire_at(expire)).await
+    }
+    // the array is the entry's data, so it stays readable on disk
+    async fn update_elements(
+        &self,
+        key: &str,
+        update: &mut (dyn for<'a> FnMut(&'a mut Vec<Box<RawValue>>) -> bool + Send),
+    ) -> Result<(), AnyError> {
+        let _guard = self.cas_guard(key).await?;
+        let (mut elements, expire) = match self.read_entry(key).await {
+            Ok(json) if json.blob.is_some() => {
+                return Err(format!("kv entry {} is not a list or set", key).into())
+            }
+            Ok(json) => (serde_json::from_str(json.data.get())?, json.expire),
+            Err(e) if e.is::<NotFoundError>() => (Vec::new(), 0),
+            Err(e) => return Err(e),
+        };
+        if !update(&mut elements) {
+            return Ok(());
+        }
+        if elements.is_empty() {
+            return match tokio::fs::remove_file(self.file(key, "json")).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Box::new(e)),
+                _ => Ok(()),
+            };
+        }
+        let data = KVFilesystemJsonData {
+            data: elements,
+            expire,
+            blob: None,
+        };
+        self.write_entry(key, &data).await
+    }
+}
+
+#[derive(Clone)]
+enum RedisSource {
+    Client(redis::Client),
+    Cluster(redis::cluster::ClusterClient),
+    Sentinel(Arc<tokio::sync::Mutex<redis::sentinel::SentinelClient>>),
+}
+impl fmt::Debug for RedisSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RedisSource::Client(client) => write!(f, "Client({:?})", client),
+            RedisSource::Cluster(_) => write!(f, "Cluster"),
+            RedisSource::Sentinel(_) => write!(f, "Sentinel"),
+        }
+    }
+}
+
+pub(crate) enum RedisConnection {
+    Single(redis::aio::MultiplexedConnection),
+    Cluster(redis::cluster_async::ClusterConnection),
+}
+impl redis::aio::ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        match self {
+            RedisConnection::Single(con) => con.req_packed_command(cmd),
+            RedisConnection::Cluster(con) => con.req_packed_command(cmd),
+        }
+    }
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        match self {
+            RedisConnection::Single(con) => con.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(con) => con.req_packed_commands(cmd, offset, count),
+        }
+    }
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(con) => con.get_db(),
+            RedisConnection::Cluster(con) => con.get_db(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisRetry {
+    attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+}
+impl RedisRetry {
+    pub fn new(attempts: u32) -> RedisRetry {
+        RedisRetry {
+            attempts: attempts.max(1),
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(2),
+            jitter: true,
+        }
+    }
+    pub fn none() -> RedisRetry {
+        RedisRetry::new(1)
+    }
+    pub fn base_delay(mut self, delay: Duration) -> RedisRetry {
+        self.base_delay = delay;
+        self
+    }
+    pub fn max_delay(mut self, delay: Duration) -> RedisRetry {
+        self.max_delay = delay;
+        self
+    }
+    pub fn jitter(mut self, jitter: bool) -> RedisRetry {
+        self.jitter = jitter;
+        self
+    }
+    fn from_env() -> RedisRetry {
+        let mut retry = RedisRetry::default();
+        if let Some(attempts) = env::var("TOKI_KV_RETRY_ATTEMPTS")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+        {
+            retry = RedisRetry {
+                attempts: attempts.max(1),
+                ..retry
+            };
+        }
+        if let Some(delay) = env::var("TOKI_KV_RETRY_DELAY")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+        {
+            retry = retry.base_delay(Duration::from_millis(delay));
+        }
+        retry
+    }
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        if !self.jitter {
+            return delay;
+        }
+        // anywhere between half and the full delay, so clients do not retry in lockstep
+        let random = std::hash::BuildHasher::hash_one(
+            &std::collections::hash_map::RandomState::new(),
+            attempt,
+        );
+        delay / 2 + delay.mul_f64((random as f64 / u64::MAX as f64) / 2.0)
+    }
+}
+impl Default for RedisRetry {
+    fn default() -> RedisRetry {
+        RedisRetry::new(3)
+    }
+}
+
+// only failures where the same command may succeed against a recovered or failed over server
+fn retryable(err: &redis::RedisError) -> bool {
+    err.is_timeout()
+        || err.is_io_error()
+        || err.is_connection_refusal()
+        || err.is_connection_dropped()
+        || matches!(
+            err.kind(),
+            redis::ErrorKind::TryAgain
+                | redis::ErrorKind::ClusterDown
+                | redis::ErrorKind::MasterDown
+                | redis::ErrorKind::BusyLoadingError
+                | redis::ErrorKind::ReadOnly
+        )
 }
 
 #[derive(Debug, Clone)]
 pub struct KVRedis {
-    redis: redis::Client,
+    redis: RedisSource,
+    retry: RedisRetry,
+    locks: LockOwners,
 }
 impl KVRedis {
     pub fn new(redis: redis::Client) -> KVRedis {
-        KVRedis { redis }
+        KVRedis {
+            redis: RedisSource::Client(redis),
+            retry: RedisRetry::from_env(),
+            locks: LockOwners::default(),
+        }
+    }
+    pub fn cluster(cluster: redis::cluster::ClusterClient) -> KVRedis {
+        KVRedis {
+            redis: RedisSource::Cluster(cluster),
+            retry: RedisRetry::from_env(),
+            locks: LockOwners::default(),
+        }
+    }
+    pub fn sentinel(sentinel: redis::sentinel::SentinelClient) -> KVRedis {
+        KVRedis {
+            redis: RedisSource::Sentinel(Arc::new(tokio::sync::Mutex::new(sentinel))),
+            retry: RedisRetry::from_env(),
+            locks: LockOwners::default(),
+        }
+    }
+    // pub/sub needs a dedicated connection, only a plain client hands those out
+    pub(crate) fn client(&self) -> Option<&redis::Client> {
+        match &self.redis {
+            RedisSource::Client(client) => Some(client),
+            _ => None,
+        }
+    }
+    pub fn retry(mut self, retry: RedisRetry) -> KVRedis {
+        self.retry = retry;
+        self
+    }
+    async fn retrying<T, F, Fut>(&self, mut op: F) -> Result<T, AnyError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, AnyError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            let err = match err.downcast::<redis::RedisError>() {
+                Ok(err) if retryable(&err) => err,
+                Ok(err) => return Err(err),
+                Err(err) => return Err(err),
+            };
+            if attempt >= self.retry.attempts {
+                return Err(Box::new(KvUnavailable {
+                    attempts: attempt,
+                    source: *err,
+                }));
+            }
+            let delay = self.retry.delay(attempt);
+            tracing::debug!(
+                "redis attempt {} failed, retrying in {:?}: {}",
+                attempt,
+                delay,
+                err
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+    pub fn open_cluster(conn: &str) -> Result<KVRedis, AnyError> {
+        let nodes = conn
+            .strip_prefix("redis+cluster://")
+            .ok_or("invalid redis cluster connection")?;
+        let nodes = nodes
+            .trim_end_matches('/')
+            .split(',')
+            .map(|node| format!("redis://{}", node))
+            .collect::<Vec<_>>();
+        Ok(KVRedis::cluster(redis::cluster::ClusterClient::new(nodes)?))
+    }
+    pub fn open_sentinel(conn: &str) -> Result<KVRedis, AnyError> {
+        let rest = conn
+            .strip_prefix("redis+sentinel://")
+            .ok_or("invalid redis sentinel connection")?;
+        let (nodes, service) = rest
+            .split_once('/')
+            .ok_or("redis sentinel connection requires a service name")?;
+        let nodes = nodes
+            .split(',')
+            .map(|node| format!("redis://{}", node))
+            .collect::<Vec<_>>();
+        let sentinel = redis::sentinel::SentinelClient::build(
+            nodes,
+            service.trim_end_matches('/').to_string(),
+            None,
+            redis::sentinel::SentinelServerType::Master,
+        )?;
+        Ok(KVRedis::sentinel(sentinel))
+    }
+    pub(crate) async fn connection(&self) -> Result<RedisConnection, AnyError> {
+        Ok(match &self.redis {
+            RedisSource::Client(client) => {
+                RedisConnection::Single(client.get_multiplexed_async_connection().await?)
+            }
+            RedisSource::Cluster(cluster) => {
+                RedisConnection::Cluster(cluster.get_async_connection().await?)
+            }
+            RedisSource::Sentinel(sentinel) => {
+                RedisConnection::Single(sentinel.lock().await.get_async_connection().await?)
+            }
+        })
+    }
+}
+#[async_trait]
+impl KVStore for KVRedis {
+    async fn get_raw(&self, key: &str) -> Result<Vec<u8>, AnyError> {
+        let value: redis::Value = self
+            .retrying(|| async move { Ok(self.connection().await?.get(key).await?) })
+            .await?;
+        match value {
+            redis::Value::BulkString(data) => Ok(data),
+            _ => Err(Box::new(NotFoundError {})),
+        }
+    }
+    async fn set_raw(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        self.retrying(|| async move {
+            let mut con = self.connection().await?;
+            con.set_ex::<_, _, ()>(key, value, expire).await?;
+            Ok(())
+        })
+        .await
+    }
+    async fn del(&self, key: &str) -> Result<(), AnyError> {
+        self.retrying(|| async move {
+            let mut con = self.connection().await?;
+            con.del::<_, ()>(key).await?;
+            Ok(())
+        })
+        .await
+    }
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        let ttl: i64 = self
+            .retrying(|| async move { Ok(self.connection().await?.ttl(key).await?) })
+            .await?;
+        match ttl {
+            -2 => Err(Box::new(NotFoundError {})),
+            -1 => Ok(None),
+            ttl => Ok(Some(ttl as u64)),
+        }
+    }
+    async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        let updated: bool = self
+            .retrying(
+                || async move { Ok(self.connection().await?.expire(key, expire as i64).await?) },
+            )
+            .await?;
+        if !updated {
+            not_found_error()?;
+        }
+        Ok(())
+    }
+    async fn persist(&self, key: &str) -> Result<(), AnyError> {
+        self.retrying(|| async move {
+            let mut con = self.connection().await?;
+            let exists: bool = con.exists(key).await?;
+            if !exists {
+                not_found_error()?;
+            }
+            con.persist::<_, ()>(key).await?;
+            Ok(())
+        })
+        .await
+    }
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError> {
+        let pattern = format!("{}*", prefix.replace('[', "\\[").replace(']', "\\]"));
+        let pattern = pattern.as_str();
+        self.retrying(|| async move {
+            let mut con = self.connection().await?;
+            if let RedisConnection::Cluster(_) = con {
+                // SCAN only walks a single node, KEYS is fanned out to every master
+                return Ok(con.keys(pattern).await?);
+            }
+            let mut iter = con.scan_match::<_, String>(pattern).await?;
+            let mut keys = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            Ok(keys)
+        })
+        .await
+    }
+    async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError> {
+        let keys = self.keys(prefix).await?;
+        if dry_run {
+            return Ok(keys.len() as u64);
+        }
+        let keys = keys.as_slice();
+        self.retrying(|| async move {
+            let mut con = self.connection().await?;
+            let mut count = 0;
+            if let RedisConnection::Cluster(_) = con {
+                // keys can live in different slots, so they are removed one by one
+                for key in keys {
+                    count += con.del::<_, u64>(key).await?;
+                }
+                return Ok(count);
+            }
+            for batch in keys.chunks(500) {
+                count += con.del::<_, u64>(batch).await?;
+            }
+            Ok(count)
+        })
+        .await
+    }
+    // not idempotent, a retried SET NX could find its own lock, so only connecting is retried
+    async fn lock(&self, key: &str, ttl: u64) -> Result<bool, AnyError> {
+        let mut con = self.retrying(|| self.connection()).await?;
+        let token = LockOwners::token();
+        let res: Option<String> = redis::cmd("SET")
+            .arg(format!("{}.lock", key))
+            .arg(&token)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut con)
+            .await?;
+        if res.is_some() {
+            self.locks.hold(key, token);
+        }
+        Ok(res.is_some())
+    }
+    async fn unlock(&self, key: &str) -> Result<(), AnyError> {
+        let Some(token) = self.locks.release(key) else {
+            return Ok(());
+        };
+        let token = token.as_str();
+        self.retrying(|| async move {
+            let mut con = self.connection().await?;
+            redis::Script::new(
+                r"if redis.call('GET', KEYS[1]) == ARGV[1] then
+                return redis.call('DEL', KEYS[1])
+            end
+            return 0",
+            )
+            .key(format!("{}.lock", key))
+            .arg(token)
+            .invoke_async::<()>(&mut con)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+    // scripts instead of WATCH/MULTI, which needs a dedicated connection and
+    // does not work across cluster nodes
+    async fn get_versioned_raw(&self, key: &str) -> Result<(Vec<u8>, Version), AnyError> {
+        let res: Option<(Vec<u8>, String)> = self
+            .retrying(|| async move {
+                let mut con = self.connection().await?;
+                Ok(redis::Script::new(
+                    r"local v = redis.call('GET', KEYS[1])
+            if not v then return false end
+            return {v, redis.sha1hex(v)}",
+                )
+                .key(key)
+                .invoke_async(&mut con)
+                .await?)
+            })
+            .await?;
+        match res {
+            Some((value, version)) => Ok((value, Version::new(version))),
+            None => Err(Box::new(NotFoundError {})),
+        }
+    }
+    // a retried write could report a conflict with itself, so only connecting is retried
+    async fn set_if_raw(
+        &self,
+        key: &str,
+        value: &[u8],
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError> {
+        let mut con = self.retrying(|| self.connection()).await?;
+        let updated: bool = redis::Script::new(
+            r"local v = redis.call('GET', KEYS[1])
+            local current = ''
+            if v then current = redis.sha1hex(v) end
+            if current ~= ARGV[1] then return 0 end
+            if ARGV[3] == '0' then
+                redis.call('SET', KEYS[1], ARGV[2])
+            else
+                redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+            end
+            return 1",
+        )
+        .key(key)
+        .arg(version.as_str())
+        .arg(value)
+        .arg(expire)
+        .invoke_async(&mut con)
+        .await?;
+        if !updated {
+            return Err(Box::new(ConflictError {}));
+        }
+        Ok(())
+    }
+    // pushes, pops, adds and removes are not idempotent, so only connecting is retried
+    async fn list_push_raw(&self, key: &str, value: &[u8]) -> Result<u64, AnyError> {
+        let mut con = self.retrying(|| self.connection()).await?;
+        Ok(con.rpush(key, value).await?)
+    }
+    async fn list_pop_raw(&self, key: &str) -> Result<Option<Vec<u8>>, AnyError> {
+        let mut con = self.retrying(|| self.connection()).await?;
+        Ok(con.lpop(key, None).await?)
+    }
+    async fn list_range_raw(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Vec<u8>>, AnyError> {
+        self.retrying(|| async move {
+            let mut con = self.connection().await?;
+            Ok(con.lrange(key, start as isize, stop as isize).await?)
+        })
+        .await
+    }
+    async fn set_add_raw(&self, key: &str, member: &[u8]) -> Result<bool, AnyError> {
+        let mut con = self.retrying(|| self.connection()).await?;
+        Ok(con.sadd(key, member).await?)
+    }
+    async fn set_remove_raw(&self, key: &str, member: &[u8]) -> Result<bool, AnyError> {
+        let mut con = self.retrying(|| self.connection()).await?;
+        Ok(con.srem(key, member).await?)
+    }
+    async fn set_members_raw(&self, key: &str) -> Result<Vec<Vec<u8>>, AnyError> {
+        self.retrying(|| async move { Ok(self.connection().await?.smembers(key).await?) })
+            .await
+    }
+}
+
+struct MemoryEntry {
+    value: Vec<u8>,
+    expires: Instant,
+    used: u64,
+}
+
+#[derive(Default)]
+struct MemoryTier {
+    entries: HashMap<String, MemoryEntry>,
+    tick: u64,
+}
+impl MemoryTier {
+    fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.expires > Instant::now() => {
+                entry.used = tick;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+    fn put(&mut self, key: &str, value: &[u8], ttl: Duration, capacity: usize) {
+        if capacity == 0 || ttl.is_zero() {
+            return;
+        }
+        self.tick += 1;
+        if !self.entries.contains_key(key) && self.entries.len() >= capacity {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| entry.expires > now);
+            if self.entries.len() >= capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.used)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        self.entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                value: value.to_vec(),
+                expires: Instant::now() + ttl,
+                used: self.tick,
+            },
+        );
+    }
+    fn remove(&mut self, key: &str) {
+        match key.strip_suffix('*') {
+            Some(prefix) => self.remove_prefix(prefix),
+            None => {
+                self.entries.remove(key);
+            }
+        }
+    }
+    fn remove_prefix(&mut self, prefix: &str) {
+        self.entries.retain(|key, _| !key.starts_with(prefix));
+    }
+}
+
+#[derive(Debug, Clone)]
+enum TieredRemote {
+    KVFilesystem(KVFilesystem),
+    KVRedis(KVRedis),
+    #[cfg(feature = "kv-s3")]
+    KVS3(KVS3),
+    #[cfg(feature = "kv-consul")]
+    KVConsul(KVConsul),
+    #[cfg(feature = "kv-dynamodb")]
+    KVDynamo(KVDynamo),
+    Custom(Arc<dyn KVStore>),
+}
+impl TieredRemote {
+    pub(crate) fn store(&self) -> &dyn KVStore {
+        match self {
+            TieredRemote::KVFilesystem(kv) => kv,
+            TieredRemote::KVRedis(kv) => kv,
+            #[cfg(feature = "kv-s3")]
+            TieredRemote::KVS3(kv) => kv,
+            #[cfg(feature = "kv-consul")]
+            TieredRemote::KVConsul(kv) => kv,
+            #[cfg(feature = "kv-dynamodb")]
+            TieredRemote::KVDynamo(kv) => kv,
+            TieredRemote::Custom(kv) => kv.as_ref(),
+        }
+    }
+}
+
+struct Invalidation {
+    channel: String,
+    id: String,
+    started: std::sync::Once,
+}
+
+#[derive(Clone)]
+pub struct KVTiered {
+    remote: TieredRemote,
+    memory: Arc<Mutex<MemoryTier>>,
+    ttl: Duration,
+    capacity: usize,
+    invalidation: Option<Arc<Invalidation>>,
+}
+impl fmt::Debug for KVTiered {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KVTiered")
+            .field("remote", &self.remote)
+            .field("ttl", &self.ttl)
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+impl KVTiered {
+    pub fn new(remote: KVManager) -> Result<KVTiered, AnyError> {
+        let remote = match remote {
+            KVManager::KVFilesystem(kv) => TieredRemote::KVFilesystem(kv),
+            KVManager::KVRedis(kv) => TieredRemote::KVRedis(kv),
+            #[cfg(feature = "kv-s3")]
+            KVManager::KVS3(kv) => TieredRemote::KVS3(kv),
+            #[cfg(feature = "kv-consul")]
+            KVManager::KVConsul(kv) => TieredRemote::KVConsul(kv),
+            #[cfg(feature = "kv-dynamodb")]
+            KVManager::KVDynamo(kv) => TieredRemote::KVDynamo(kv),
+            KVManager::KVTiered(_) => return Err("kv tiers can not be nested".into()),
+            KVManager::Custom(kv) => TieredRemote::Custom(kv),
+        };
+        Ok(KVTiered {
+            remote,
+            memory: Default::default(),
+            ttl: Duration::from_secs(5),
+            capacity: 10000,
+            invalidation: None,
+        })
+    }
+    pub fn ttl(mut self, ttl: u64) -> KVTiered {
+        self.ttl = Duration::from_secs(ttl);
+        self
+    }
+    pub fn capacity(mut self, capacity: usize) -> KVTiered {
+        self.capacity = capacity;
+        self
+    }
+    pub fn invalidation(mut self, channel: &str) -> KVTiered {
+        let id = std::hash::BuildHasher::hash_one(
+            &std::collections::hash_map::RandomState::new(),
+            std::process::id(),
+        );
+        self.invalidation = Some(Arc::new(Invalidation {
+            channel: channel.to_string(),
+            id: format!("{:016x}", id),
+            started: std::sync::Once::new(),
+        }));
+        self
+    }
+    fn from_env(remote: KVManager) -> Result<KVTiered, AnyError> {
+        let mut tiered = KVTiered::new(remote)?;
+        if let Some(ttl) = env::var("TOKI_KV_MEMORY_TTL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            tiered = tiered.ttl(ttl);
+        }
+        if let Some(capacity) = env::var("TOKI_KV_MEMORY_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            tiered = tiered.capacity(capacity);
+        }
+        if let Ok(channel) = env::var("TOKI_KV_INVALIDATE") {
+            tiered = tiered.invalidation(&channel);
+        }
+        Ok(tiered)
+    }
+    fn memory(&self) -> std::sync::MutexGuard<'_, MemoryTier> {
+        self.memory.lock().unwrap()
+    }
+    pub(crate) fn forget(&self, key: &str) {
+        self.memory().remove(key);
+    }
+    fn start_invalidation(&self) {
+        let (Some(invalidation), TieredRemote::KVRedis(kv)) = (&self.invalidation, &self.remote)
+        else {
+            return;
+        };
+        invalidation.started.call_once(|| {
+            let RedisSource::Client(client) = &kv.redis else {
+                tracing::warn!("kv invalidation channel is only supported on single redis nodes");
+                return;
+            };
+            tokio::spawn(listen_invalidation(
+                client.clone(),
+                invalidation.clone(),
+                Arc::downgrade(&self.memory),
+            ));
+        });
+    }
+    pub(crate) async fn publish(&self, key: &str) -> Result<(), AnyError> {
+        let (Some(invalidation), TieredRemote::KVRedis(kv)) = (&self.invalidation, &self.remote)
+        else {
+            return Ok(());
+        };
+        let mut con = kv.connection().await?;
+        con.publish::<_, _, ()>(
+            &invalidation.channel,
+            format!("{} {}", invalidation.id, key),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+async fn listen_invalidation(
+    client: redis::Client,
+    invalidation: Arc<Invalidation>,
+    memory: std::sync::Weak<Mutex<MemoryTier>>,
+) {
+    use futures_util::StreamExt;
+
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => match pubsub.subscribe(&invalidation.channel).await {
+                Ok(()) => {
+                    let mut messages = pubsub.on_message();
+                    while let Some(msg) = messages.next().await {
+                        let Some(memory) = memory.upgrade() else {
+                            return;
+                        };
+                        let payload: String = match msg.get_payload() {
+                            Ok(payload) => payload,
+                            Err(_) => continue,
+                        };
+                        if let Some((from, key)) = payload.split_once(' ') {
+                            if from != invalidation.id {
+                                memory.lock().unwrap().remove(key);
+                            }
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("kv invalidation subscribe failed: {}", e),
+            },
+            Err(e) => tracing::warn!("kv invalidation connection failed: {}", e),
+        }
+        // messages may have been missed while disconnected
+        match memory.upgrade() {
+            Some(memory) => memory.lock().unwrap().entries.clear(),
+            None => return,
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
+
 #[async_trait]
-impl KVTrait for KVRedis {
-    async fn get<B>(&self, key: &str) -> Result<B, AnyError>
-    where
-        B: serde::Serialize,
-        B: serde::de::DeserializeOwned,
-    {
-        let mut con = self.redis.get_async_connection().await?;
-        let value: redis::Value = con.get(key).await?;
-        let res: B;
-        match value {
-            redis::Value::Data(data) => {
-                res = serde_json::from_slice(&data)?;
-                Ok(res)
-            }
-            _ => Err(Box::new(NotFoundError {})),
+impl KVStore for KVTiered {
+    async fn get_raw(&self, key: &str) -> Result<Vec<u8>, AnyError> {
+        self.start_invalidation();
+        if let Some(value) = self.memory().get(key) {
+            return Ok(value);
         }
+        let value = self.remote.store().get_raw(key).await?;
+        self.memory().put(key, &value, self.ttl, self.capacity);
+        Ok(value)
     }
-    async fn set<B>(&self, key: &str, value: &B, expire: u64) -> Result<(), AnyError>
-    where
-        B: Sync,
-        B: serde::Serialize,
-        B: serde::de::DeserializeOwned,
-    {
-        let mut con = self.redis.get_async_connection().await?;
-        let data = serde_json::to_string(value)?;
-        con.set_ex(key, data, expire as usize).await?;
-        Ok(())
+    async fn set_raw(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        self.start_invalidation();
+        self.remote.store().set_raw(key, value, expire).await?;
+        let ttl = self.ttl.min(Duration::from_secs(expire));
+        self.memory().put(key, value, ttl, self.capacity);
+        self.publish(key).await
     }
     async fn del(&self, key: &str) -> Result<(), AnyError> {
-        let mut con = self.redis.get_async_connection().await?;
-        con.del(key).await?;
-        Ok(())
+        self.forget(key);
+        self.remote.store().del(key).await?;
+        self.publish(key).await
+    }
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        self.remote.store().ttl(key).await
+    }
+    async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        self.forget(key);
+        self.remote.store().expire(key, expire).await?;
+        self.publish(key).await
+    }
+    async fn persist(&self, key: &str) -> Result<(), AnyError> {
+        self.remote.store().persist(key).await
+    }
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError> {
+        self.remote.store().keys(prefix).await
+    }
+    async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError> {
+        let count = self.remote.store().del_prefix(prefix, dry_run).await?;
+        if !dry_run {
+            self.memory().remove_prefix(prefix);
+            self.publish(&format!("{}*", prefix)).await?;
+        }
+        Ok(count)
+    }
+    async fn lock(&self, key: &str, ttl: u64) -> Result<bool, AnyError> {
+        self.remote.store().lock(key, ttl).await
+    }
+    async fn unlock(&self, key: &str) -> Result<(), AnyError> {
+        self.remote.store().unlock(key).await
+    }
+    async fn get_versioned_raw(&self, key: &str) -> Result<(Vec<u8>, Version), AnyError> {
+        self.remote.store().get_versioned_raw(key).await
+    }
+    async fn set_if_raw(
+        &self,
+        key: &str,
+        value: &[u8],
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError> {
+        self.start_invalidation();
+        self.remote
+            .store()
+            .set_if_raw(key, value, version, expire)
+            .await?;
+        let ttl = match expire {
+            0 => self.ttl,
+            expire => self.ttl.min(Duration::from_secs(expire)),
+        };
+        self.memory().put(key, value, ttl, self.capacity);
+        self.publish(key).await
+    }
+    // lists and sets are never kept in memory, they change too often
+    async fn list_push_raw(&self, key: &str, value: &[u8]) -> Result<u64, AnyError> {
+        self.forget(key);
+        let len = self.remote.store().list_push_raw(key, value).await?;
+        self.publish(key).await?;
+        Ok(len)
+    }
+    async fn list_pop_raw(&self, key: &str) -> Result<Option<Vec<u8>>, AnyError> {
+        self.forget(key);
+        let value = self.remote.store().list_pop_raw(key).await?;
+        self.publish(key).await?;
+        Ok(value)
+    }
+    async fn list_range_raw(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Vec<u8>>, AnyError> {
+        self.remote.store().list_range_raw(key, start, stop).await
+    }
+    async fn set_add_raw(&self, key: &str, member: &[u8]) -> Result<bool, AnyError> {
+        self.forget(key);
+        let added = self.remote.store().set_add_raw(key, member).await?;
+        self.publish(key).await?;
+        Ok(added)
+    }
+    async fn set_remove_raw(&self, key: &str, member: &[u8]) -> Result<bool, AnyError> {
+        self.forget(key);
+        let removed = self.remote.store().set_remove_raw(key, member).await?;
+        self.publish(key).await?;
+        Ok(removed)
+    }
+    async fn set_members_raw(&self, key: &str) -> Result<Vec<Vec<u8>>, AnyError> {
+        self.remote.store().set_members_raw(key).await
     }
 }
 
@@ -175,30 +1783,176 @@ impl KVTrait for KVRedis {
 pub enum KVManager {
     KVFilesystem(KVFilesystem),
     KVRedis(KVRedis),
+    #[cfg(feature = "kv-s3")]
+    KVS3(KVS3),
+    #[cfg(feature = "kv-consul")]
+    KVConsul(KVConsul),
+    #[cfg(feature = "kv-dynamodb")]
+    KVDynamo(KVDynamo),
+    KVTiered(KVTiered),
+    Custom(Arc<dyn KVStore>),
 }
 impl KVManager {
+    pub fn custom<S>(store: S) -> KVManager
+    where
+        S: KVStore + 'static,
+    {
+        KVManager::Custom(Arc::new(store))
+    }
+    pub(crate) fn store(&self) -> &dyn KVStore {
+        match self {
+            KVManager::KVFilesystem(kv) => kv,
+            KVManager::KVRedis(kv) => kv,
+            #[cfg(feature = "kv-s3")]
+            KVManager::KVS3(kv) => kv,
+            #[cfg(feature = "kv-consul")]
+            KVManager::KVConsul(kv) => kv,
+            #[cfg(feature = "kv-dynamodb")]
+            KVManager::KVDynamo(kv) => kv,
+            KVManager::KVTiered(kv) => kv,
+            KVManager::Custom(kv) => kv.as_ref(),
+        }
+    }
+    // the stores other subsystems can build on directly, tiered managers expose their remote
+    pub(crate) fn redis(&self) -> Option<&KVRedis> {
+        match self {
+            KVManager::KVRedis(kv)
+            | KVManager::KVTiered(KVTiered {
+                remote: TieredRemote::KVRedis(kv),
+                ..
+            }) => Some(kv),
+            _ => None,
+        }
+    }
+    // keys of nested filesystem stores keep their `/`
+    pub(crate) fn normalize(&self, key: &str) -> String {
+        match self.filesystem() {
+            Some(fs) if fs.is_nested() => normalize_nested_key(key),
+            _ => normailze_key(key),
+        }
+    }
+    pub(crate) fn filesystem(&self) -> Option<&KVFilesystem> {
+        match self {
+            KVManager::KVFilesystem(kv)
+            | KVManager::KVTiered(KVTiered {
+                remote: TieredRemote::KVFilesystem(kv),
+                ..
+            }) => Some(kv),
+            _ => None,
+        }
+    }
+    #[cfg(feature = "kv-consul")]
+    pub(crate) fn consul(&self) -> Option<&KVConsul> {
+        match self {
+            KVManager::KVConsul(kv)
+            | KVManager::KVTiered(KVTiered {
+                remote: TieredRemote::KVConsul(kv),
+                ..
+            }) => Some(kv),
+            _ => None,
+        }
+    }
+    pub(crate) fn backend(&self) -> &'static str {
+        match self {
+            KVManager::KVFilesystem(_) => "file",
+            KVManager::KVRedis(_) => "redis",
+            #[cfg(feature = "kv-s3")]
+            KVManager::KVS3(_) => "s3",
+            #[cfg(feature = "kv-consul")]
+            KVManager::KVConsul(_) => "consul",
+            #[cfg(feature = "kv-dynamodb")]
+            KVManager::KVDynamo(_) => "dynamodb",
+            KVManager::KVTiered(_) => "tiered",
+            KVManager::Custom(_) => "custom",
+        }
+    }
+    async fn observe<T, F>(&self, operation: KvOperation, key: &str, fut: F) -> Result<T, AnyError>
+    where
+        F: Future<Output = Result<T, AnyError>>,
+    {
+        observe(operation, self.backend(), key, |_| None, fut).await
+    }
+    // warns about operations slower than this, overrides TOKI_KV_SLOW_MS, None turns it off
+    pub fn slow_threshold(threshold: Option<Duration>) {
+        crate::kv_observer::set_slow_threshold(threshold);
+    }
+    pub fn add_observer<O>(observer: O)
+    where
+        O: KvObserver + 'static,
+    {
+        add_observer(Arc::new(observer));
+    }
     pub fn new(conn: String) -> Result<KVManager, AnyError> {
+        if let Some(remote) = conn.strip_prefix("memory+") {
+            let remote = KVManager::new(remote.to_string())?;
+            return Ok(KVManager::KVTiered(KVTiered::from_env(remote)?));
+        }
         if conn.starts_with("file:") {
-            return Ok(KVManager::KVFilesystem(KVFilesystem::new(
-                conn.strip_prefix("file:").unwrap(),
-            )));
+            // file:/path?nested=true
+            let rest = conn.strip_prefix("file:").unwrap();
+            let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+            let nested = form_urlencoded::parse(query.as_bytes())
+                .any(|(k, v)| k == "nested" && matches!(&*v, "1" | "true" | "yes" | "on"));
+            let kv = KVFilesystem::new(path).nested(nested);
+            let interval = env::var("TOKI_KV_SWEEP_INTERVAL")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|interval| *interval > 0);
+            if let Some(interval) = interval {
+                match tokio::runtime::Handle::try_current() {
+                    Ok(_) => {
+                        kv.spawn_sweeper(Duration::from_secs(interval));
+                    }
+                    Err(_) => tracing::warn!("kv sweeper needs a tokio runtime, not started"),
+                }
+            }
+            return Ok(KVManager::KVFilesystem(kv));
+        }
+        if conn.starts_with("redis+cluster:") {
+            return Ok(KVManager::KVRedis(KVRedis::open_cluster(&conn)?));
+        }
+        if conn.starts_with("redis+sentinel:") {
+            return Ok(KVManager::KVRedis(KVRedis::open_sentinel(&conn)?));
         }
         if conn.starts_with("redis:") || conn.starts_with("redis+unix:") {
             let redis = redis::Client::open(conn)?;
             return Ok(KVManager::KVRedis(KVRedis::new(redis)));
         }
+        if conn.starts_with("s3://") {
+            #[cfg(feature = "kv-s3")]
+            return Ok(KVManager::KVS3(KVS3::open(&conn)?));
+            #[cfg(not(feature = "kv-s3"))]
+            return Err("s3 kv connections require the kv-s3 feature".into());
+        }
+        if conn.starts_with("consul://") || conn.starts_with("consul+https://") {
+            #[cfg(feature = "kv-consul")]
+            return Ok(KVManager::KVConsul(KVConsul::open(&conn)?));
+            #[cfg(not(feature = "kv-consul"))]
+            return Err("consul kv connections require the kv-consul feature".into());
+        }
+        if conn.starts_with("dynamodb://") {
+            #[cfg(feature = "kv-dynamodb")]
+            return Ok(KVManager::KVDynamo(KVDynamo::open(&conn)?));
+            #[cfg(not(feature = "kv-dynamodb"))]
+            return Err("dynamodb kv connections require the kv-dynamodb feature".into());
+        }
         panic!("unsupported kv connection");
     }
-    #[tracing::instrument(skip(self))]
     pub async fn get<B>(&self, key: &str) -> Result<B, AnyError>
     where
         B: serde::Serialize,
         B: serde::de::DeserializeOwned,
     {
-        match self {
-            KVManager::KVFilesystem(kv) => kv.get(&normailze_key(key)).await,
-            KVManager::KVRedis(kv) => kv.get(&normailze_key(key)).await,
-        }
+        let key_normalized = self.normalize(key);
+        let raw = observe(
+            KvOperation::Get,
+            self.backend(),
+            key,
+            |raw: &Vec<u8>| Some(raw.len()),
+            self.store().get_raw(&key_normalized),
+        )
+        .await?;
+        deserialize(&decode_value(&key_normalized, raw)?)
     }
     pub async fn get_some<B>(&self, key: &str) -> Result<Option<B>, AnyError>
     where
@@ -234,25 +1988,326 @@ impl KVManager {
             }
         }
     }
-    #[tracing::instrument(skip(self, value, expire))]
+    // applies to every set, set_if and get_or_init expiry, overrides TOKI_KV_TTL_JITTER
+    pub fn set_ttl_jitter(percent: u32) {
+        TTL_JITTER.store(percent.min(100), Ordering::Relaxed);
+    }
     pub async fn set<B>(&self, key: &str, value: &B, expire: u64) -> Result<(), AnyError>
     where
         B: Sync,
         B: serde::Serialize,
         B: serde::de::DeserializeOwned,
     {
+        self.set_jittered(key, value, expire, ttl_jitter()).await
+    }
+    // expire ± percent for this write only, 0 keeps the exact expiry
+    pub async fn set_jittered<B>(
+        &self,
+        key: &str,
+        value: &B,
+        expire: u64,
+        percent: u32,
+    ) -> Result<(), AnyError>
+    where
+        B: Sync,
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+    {
+        self.set_serialized(key, serde_json::to_vec(value)?, jittered(expire, percent))
+            .await
+    }
+    async fn set_with<B>(
+        &self,
+        codec: Codec,
+        key: &str,
+        value: &B,
+        expire: u64,
+    ) -> Result<(), AnyError>
+    where
+        B: Sync,
+        B: serde::Serialize,
+    {
+        let data = codec.serialize(value)?;
+        self.set_serialized(key, data, jittered(expire, ttl_jitter()))
+            .await
+    }
+    pub(crate) async fn set_serialized(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        expire: u64,
+    ) -> Result<(), AnyError> {
+        let key_normalized = self.normalize(key);
+        let raw = encode_value(&key_normalized, data)?;
+        let size = raw.len();
+        observe(
+            KvOperation::Set,
+            self.backend(),
+            key,
+            |_| Some(size),
+            self.store().set_raw(&key_normalized, &raw, expire),
+        )
+        .await
+    }
+    // the value as it was written with set_bytes, no json involved
+    pub async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, AnyError> {
+        let key_normalized = self.normalize(key);
+        let raw = observe(
+            KvOperation::Get,
+            self.backend(),
+            key,
+            |raw: &Vec<u8>| Some(raw.len()),
+            self.store().get_raw(&key_normalized),
+        )
+        .await?;
+        decode_value(&key_normalized, raw)
+    }
+    pub async fn get_bytes_some(&self, key: &str) -> Result<Option<Vec<u8>>, AnyError> {
+        match self.get_bytes(key).await {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.is::<NotFoundError>() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    // stored behind a one byte marker so get() and get_bytes() can tell the
+    // value apart from json, encrypted like any other value but never compressed
+    pub async fn set_bytes(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        let expire = jittered(expire, ttl_jitter());
+        let key_normalized = self.normalize(key);
+        let raw = encode_bytes(&key_normalized, value)?;
+        let size = raw.len();
+        observe(
+            KvOperation::Set,
+            self.backend(),
+            key,
+            |_| Some(size),
+            self.store().set_raw(&key_normalized, &raw, expire),
+        )
+        .await
+    }
+    pub async fn get_versioned<B>(&self, key: &str) -> Result<(B, Version), AnyError>
+    where
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+    {
+        let key_normalized = self.normalize(key);
+        let (raw, version) = observe(
+            KvOperation::Get,
+            self.backend(),
+            key,
+            |(raw, _): &(Vec<u8>, Version)| Some(raw.len()),
+            self.store().get_versioned_raw(&key_normalized),
+        )
+        .await?;
+        Ok((deserialize(&decode_value(&key_normalized, raw)?)?, version))
+    }
+    // 0 keeps the entry until it is deleted
+    pub async fn set_if<B>(
+        &self,
+        key: &str,
+        value: &B,
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError>
+    where
+        B: Sync,
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+    {
+        self.set_if_serialized(key, serde_json::to_vec(value)?, version, expire)
+            .await
+    }
+    pub(crate) async fn set_if_serialized(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError> {
+        let expire = jittered(expire, ttl_jitter());
+        let key_normalized = self.normalize(key);
+        let raw = encode_value(&key_normalized, data)?;
+        let size = raw.len();
+        observe(
+            KvOperation::Set,
+            self.backend(),
+            key,
+            |_| Some(size),
+            self.store()
+                .set_if_raw(&key_normalized, &raw, version, expire),
+        )
+        .await
+    }
+    // only the filesystem backend keeps expired entries around, others report 0
+    pub async fn sweep(&self) -> Result<u64, AnyError> {
         match self {
-            KVManager::KVFilesystem(kv) => kv.set(&normailze_key(key), value, expire).await,
-            KVManager::KVRedis(kv) => kv.set(&normailze_key(key), value, expire).await,
+            KVManager::KVFilesystem(kv) => kv.sweep().await,
+            KVManager::KVTiered(KVTiered {
+                remote: TieredRemote::KVFilesystem(kv),
+                ..
+            }) => kv.sweep().await,
+            _ => Ok(0),
         }
     }
-    #[tracing::instrument(skip(self))]
     pub async fn del(&self, key: &str) -> Result<(), AnyError> {
-        match self {
-            KVManager::KVFilesystem(kv) => kv.del(&normailze_key(key)).await,
-            KVManager::KVRedis(kv) => kv.del(&normailze_key(key)).await,
+        let key_normalized = self.normalize(key);
+        self.observe(KvOperation::Del, key, async {
+            self.bury(&key_normalized).await?;
+            self.store().del(&key_normalized).await
+        })
+        .await
+    }
+    pub async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        self.observe(
+            KvOperation::Ttl,
+            key,
+            self.store().ttl(&self.normalize(key)),
+        )
+        .await
+    }
+    pub async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        self.observe(
+            KvOperation::Expire,
+            key,
+            self.store().expire(&self.normalize(key), expire),
+        )
+        .await
+    }
+    pub async fn persist(&self, key: &str) -> Result<(), AnyError> {
+        self.observe(
+            KvOperation::Persist,
+            key,
+            self.store().persist(&self.normalize(key)),
+        )
+        .await
+    }
+    pub async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError> {
+        let keys = self
+            .observe(
+                KvOperation::Keys,
+                prefix,
+                self.store().keys(&self.normalize(prefix)),
+            )
+            .await?;
+        let env_prefix = key_prefix();
+        Ok(keys
+            .into_iter()
+            .filter(|key| !is_tombstone(key))
+            .map(|key| key.strip_prefix(&env_prefix).unwrap_or(&key).to_string())
+            .collect())
+    }
+    pub async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError> {
+        self.observe(
+            KvOperation::DelPrefix,
+            prefix,
+            self.store().del_prefix(&self.normalize(prefix), dry_run),
+        )
+        .await
+    }
+    pub async fn try_lock(&self, key: &str, ttl: u64) -> Result<bool, AnyError> {
+        self.observe(
+            KvOperation::Lock,
+            key,
+            self.store().lock(&self.normalize(key), ttl),
+        )
+        .await
+    }
+    pub async fn unlock(&self, key: &str) -> Result<(), AnyError> {
+        self.observe(
+            KvOperation::Unlock,
+            key,
+            self.store().unlock(&self.normalize(key)),
+        )
+        .await
+    }
+
+    // list and set elements are plain json, the codec, compression and
+    // encryption only apply to whole values
+    pub async fn list_push<B>(&self, key: &str, value: &B) -> Result<u64, AnyError>
+    where
+        B: serde::Serialize,
+    {
+        let data = serde_json::to_vec(value)?;
+        self.observe(
+            KvOperation::ListPush,
+            key,
+            self.store().list_push_raw(&self.normalize(key), &data),
+        )
+        .await
+    }
+    // pops from the front, so pushes and pops make a queue
+    pub async fn list_pop<B>(&self, key: &str) -> Result<Option<B>, AnyError>
+    where
+        B: serde::de::DeserializeOwned,
+    {
+        let data = self
+            .observe(
+                KvOperation::ListPop,
+                key,
+                self.store().list_pop_raw(&self.normalize(key)),
+            )
+            .await?;
+        match data {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
         }
     }
+    // inclusive indexes, negative ones count from the end, so 0, -1 is everything
+    pub async fn list_range<B>(&self, key: &str, start: i64, stop: i64) -> Result<Vec<B>, AnyError>
+    where
+        B: serde::de::DeserializeOwned,
+    {
+        let data = self
+            .observe(
+                KvOperation::ListRange,
+                key,
+                self.store()
+                    .list_range_raw(&self.normalize(key), start, stop),
+            )
+            .await?;
+        data.iter()
+            .map(|data| Ok(serde_json::from_slice(data)?))
+            .collect()
+    }
+    pub async fn set_add<B>(&self, key: &str, member: &B) -> Result<bool, AnyError>
+    where
+        B: serde::Serialize,
+    {
+        let data = serde_json::to_vec(member)?;
+        self.observe(
+            KvOperation::SetAdd,
+            key,
+            self.store().set_add_raw(&self.normalize(key), &data),
+        )
+        .await
+    }
+    pub async fn set_remove<B>(&self, key: &str, member: &B) -> Result<bool, AnyError>
+    where
+        B: serde::Serialize,
+    {
+        let data = serde_json::to_vec(member)?;
+        self.observe(
+            KvOperation::SetRemove,
+            key,
+            self.store().set_remove_raw(&self.normalize(key), &data),
+        )
+        .await
+    }
+    pub async fn set_members<B>(&self, key: &str) -> Result<Vec<B>, AnyError>
+    where
+        B: serde::de::DeserializeOwned,
+    {
+        let data = self
+            .observe(
+                KvOperation::SetMembers,
+                key,
+                self.store().set_members_raw(&self.normalize(key)),
+            )
+            .await?;
+        data.iter()
+            .map(|data| Ok(serde_json::from_slice(data)?))
+            .collect()
+    }
 
     pub async fn get_or_init<B, F>(
         &self,
@@ -267,18 +2322,263 @@ impl KVManager {
         B: Clone,
         B: Sync,
     {
-        let value = self.get_some(key).await?;
+        self.get_or_init_with(Codec::Json, key, init, expire).await
+    }
+    pub(crate) async fn get_or_init_with<B, F>(
+        &self,
+        codec: Codec,
+        key: &str,
+        init: impl FnOnce() -> F,
+        expire: u64,
+    ) -> Result<KvGetOrInitResult<B>, AnyError>
+    where
+        F: Future<Output = Result<B, AnyError>>,
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+        B: Clone,
+        B: Sync,
+    {
+        if let Some(value) = self.get_some(key).await? {
+            return Ok(KvGetOrInitResult {
+                value,
+                hit: true,
+                stale: false,
+            });
+        }
+        let flight = Flight::join(key);
+        let _guard = flight.lock.lock().await;
+        if let Some(value) = self.get_some(key).await? {
+            return Ok(KvGetOrInitResult {
+                value,
+                hit: true,
+                stale: false,
+            });
+        }
+        let value = init().await?;
+        self.set_with(codec, key, &value, expire).await?;
+        Ok(KvGetOrInitResult {
+            value,
+            hit: false,
+            stale: false,
+        })
+    }
 
-        match value {
-            Some(v) => Ok(KvGetOrInitResult {
-                value: v,
+    pub async fn get_or_init_locked<B, F>(
+        &self,
+        key: &str,
+        init: impl FnOnce() -> F,
+        expire: u64,
+        lock_ttl: u64,
+    ) -> Result<KvGetOrInitResult<B>, AnyError>
+    where
+        F: Future<Output = Result<B, AnyError>>,
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+        B: Clone,
+        B: Sync,
+    {
+        self.get_or_init_locked_with(Codec::Json, key, init, expire, lock_ttl)
+            .await
+    }
+    pub(crate) async fn get_or_init_locked_with<B, F>(
+        &self,
+        codec: Codec,
+        key: &str,
+        init: impl FnOnce() -> F,
+        expire: u64,
+        lock_ttl: u64,
+    ) -> Result<KvGetOrInitResult<B>, AnyError>
+    where
+        F: Future<Output = Result<B, AnyError>>,
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+        B: Clone,
+        B: Sync,
+    {
+        if let Some(value) = self.get_some(key).await? {
+            return Ok(KvGetOrInitResult {
+                value,
+                hit: true,
+                stale: false,
+            });
+        }
+        let flight = Flight::join(key);
+        let _guard = flight.lock.lock().await;
+        loop {
+            if let Some(value) = self.get_some(key).await? {
+                return Ok(KvGetOrInitResult {
+                    value,
+                    hit: true,
+                    stale: false,
+                });
+            }
+            if self.try_lock(key, lock_ttl).await? {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let value = match self.get_some(key).await? {
+            Some(value) => Ok(KvGetOrInitResult {
+                value,
                 hit: true,
+                stale: false,
             }),
-            None => {
-                let value = init().await?;
-                self.set(key, &value, expire).await?;
-                Ok(KvGetOrInitResult { value, hit: false })
+            None => match init().await {
+                Ok(value) => {
+                    self.set_with(codec, key, &value, expire)
+                        .await
+                        .map(|_| KvGetOrInitResult {
+                            value,
+                            hit: false,
+                            stale: false,
+                        })
+                }
+                Err(e) => Err(e),
+            },
+        };
+        self.unlock(key).await?;
+        value
+    }
+
+    // entries are wrapped with their refresh time, read them through this method only
+    pub async fn get_or_refresh<B, F>(
+        &self,
+        key: &str,
+        init: impl FnOnce() -> F + Send + 'static,
+        soft_ttl: u64,
+        hard_ttl: u64,
+    ) -> Result<KvGetOrInitResult<B>, AnyError>
+    where
+        F: Future<Output = Result<B, AnyError>> + Send + 'static,
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+        B: Clone,
+        B: Send,
+        B: Sync,
+        B: 'static,
+    {
+        self.get_or_refresh_with(Codec::Json, key, init, soft_ttl, hard_ttl)
+            .await
+    }
+    pub(crate) async fn get_or_refresh_with<B, F>(
+        &self,
+        codec: Codec,
+        key: &str,
+        init: impl FnOnce() -> F + Send + 'static,
+        soft_ttl: u64,
+        hard_ttl: u64,
+    ) -> Result<KvGetOrInitResult<B>, AnyError>
+    where
+        F: Future<Output = Result<B, AnyError>> + Send + 'static,
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+        B: Clone,
+        B: Send,
+        B: Sync,
+        B: 'static,
+    {
+        if let Some(entry) = self.get_some::<Refreshable<B>>(key).await? {
+            let stale = now().saturating_sub(entry.refreshed) >= soft_ttl;
+            if stale {
+                self.refresh_in_background(codec, key, init, hard_ttl);
+            }
+            return Ok(KvGetOrInitResult {
+                value: entry.value,
+                hit: true,
+                stale,
+            });
+        }
+        let flight = Flight::join(key);
+        let _guard = flight.lock.lock().await;
+        if let Some(entry) = self.get_some::<Refreshable<B>>(key).await? {
+            return Ok(KvGetOrInitResult {
+                value: entry.value,
+                hit: true,
+                stale: false,
+            });
+        }
+        let value = init().await?;
+        let entry = Refreshable {
+            value: value.clone(),
+            refreshed: now(),
+        };
+        self.set_with(codec, key, &entry, hard_ttl).await?;
+        Ok(KvGetOrInitResult {
+            value,
+            hit: false,
+            stale: false,
+        })
+    }
+
+    fn refresh_in_background<B, F>(
+        &self,
+        codec: Codec,
+        key: &str,
+        init: impl FnOnce() -> F + Send + 'static,
+        hard_ttl: u64,
+    ) where
+        F: Future<Output = Result<B, AnyError>> + Send + 'static,
+        B: serde::Serialize,
+        B: serde::de::DeserializeOwned,
+        B: Send,
+        B: Sync,
+        B: 'static,
+    {
+        let flight = Flight::join(key);
+        // a refresh or initial load is already running in this process
+        let Ok(guard) = flight.lock.clone().try_lock_owned() else {
+            return;
+        };
+        let kv = self.clone();
+        let key = key.to_string();
+        tokio::spawn(async move {
+            let res = match init().await {
+                Ok(value) => {
+                    let entry = Refreshable {
+                        value,
+                        refreshed: now(),
+                    };
+                    kv.set_with(codec, &key, &entry, hard_ttl).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                tracing::warn!("background refresh of {} failed: {}", key, e);
             }
+            drop(guard);
+            drop(flight);
+        });
+    }
+}
+
+type FlightMap = Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>;
+
+fn flights() -> &'static FlightMap {
+    static FLIGHTS: OnceLock<FlightMap> = OnceLock::new();
+    FLIGHTS.get_or_init(Default::default)
+}
+
+struct Flight {
+    key: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+impl Flight {
+    fn join(key: &str) -> Flight {
+        let key = normailze_key(key);
+        let lock = flights()
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        Flight { key, lock }
+    }
+}
+impl Drop for Flight {
+    fn drop(&mut self) {
+        let mut flights = flights().lock().unwrap();
+        if Arc::strong_count(&self.lock) == 2 {
+            flights.remove(&self.key);
         }
     }
 }
@@ -286,4 +2586,12 @@ impl KVManager {
 pub struct KvGetOrInitResult<B> {
     pub value: B,
     pub hit: bool,
+    // only set by get_or_refresh, the value is past its soft ttl and being refreshed
+    pub stale: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Refreshable<B> {
+    value: B,
+    refreshed: u64,
 }
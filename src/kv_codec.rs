@@ -0,0 +1,244 @@
+use std::future::Future;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{kv::jittered, kv::ttl_jitter, AnyError, KVManager, KvGetOrInitResult, Version};
+
+// first byte of values written by the binary codecs, json starts with neither
+#[cfg(feature = "msgpack")]
+const CODEC_MSGPACK: u8 = 0x04;
+#[cfg(feature = "cbor")]
+const CODEC_CBOR: u8 = 0x05;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Codec {
+    pub(crate) fn serialize<B>(&self, value: &B) -> Result<Vec<u8>, AnyError>
+    where
+        B: Serialize + ?Sized,
+    {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "msgpack")]
+            Codec::MsgPack => {
+                let mut data = vec![CODEC_MSGPACK];
+                rmp_serde::encode::write_named(&mut data, value)?;
+                Ok(data)
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                let mut data = vec![CODEC_CBOR];
+                ciborium::into_writer(value, &mut data)?;
+                Ok(data)
+            }
+        }
+    }
+}
+
+// reads whatever codec the value was written with, unmarked values are json
+pub(crate) fn deserialize<B>(data: &[u8]) -> Result<B, AnyError>
+where
+    B: DeserializeOwned,
+{
+    match data.first() {
+        #[cfg(feature = "msgpack")]
+        Some(&CODEC_MSGPACK) => Ok(rmp_serde::from_slice(&data[1..])?),
+        #[cfg(not(feature = "msgpack"))]
+        Some(0x04) => Err("msgpack kv value requires the msgpack feature".into()),
+        #[cfg(feature = "cbor")]
+        Some(&CODEC_CBOR) => Ok(ciborium::from_reader(&data[1..])?),
+        #[cfg(not(feature = "cbor"))]
+        Some(0x05) => Err("cbor kv value requires the cbor feature".into()),
+        _ => Ok(serde_json::from_slice(data)?),
+    }
+}
+
+// a manager that writes values with another codec, reads work with any codec
+// either way. list and set elements stay json, manager() has the rest
+#[derive(Debug, Clone)]
+pub struct KvCodec {
+    kv: KVManager,
+    codec: Codec,
+}
+
+impl KVManager {
+    pub fn with_codec(&self, codec: Codec) -> KvCodec {
+        KvCodec {
+            kv: self.clone(),
+            codec,
+        }
+    }
+}
+
+impl KvCodec {
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+    pub fn manager(&self) -> &KVManager {
+        &self.kv
+    }
+    pub async fn get<B>(&self, key: &str) -> Result<B, AnyError>
+    where
+        B: Serialize + DeserializeOwned,
+    {
+        self.kv.get(key).await
+    }
+    pub async fn get_some<B>(&self, key: &str) -> Result<Option<B>, AnyError>
+    where
+        B: Serialize + DeserializeOwned,
+    {
+        self.kv.get_some(key).await
+    }
+    pub async fn set<B>(&self, key: &str, value: &B, expire: u64) -> Result<(), AnyError>
+    where
+        B: Sync + Serialize + DeserializeOwned,
+    {
+        self.set_jittered(key, value, expire, ttl_jitter()).await
+    }
+    pub async fn set_jittered<B>(
+        &self,
+        key: &str,
+        value: &B,
+        expire: u64,
+        percent: u32,
+    ) -> Result<(), AnyError>
+    where
+        B: Sync + Serialize + DeserializeOwned,
+    {
+        self.kv
+            .set_serialized(key, self.codec.serialize(value)?, jittered(expire, percent))
+            .await
+    }
+    pub async fn get_versioned<B>(&self, key: &str) -> Result<(B, Version), AnyError>
+    where
+        B: Serialize + DeserializeOwned,
+    {
+        self.kv.get_versioned(key).await
+    }
+    pub async fn set_if<B>(
+        &self,
+        key: &str,
+        value: &B,
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError>
+    where
+        B: Sync + Serialize + DeserializeOwned,
+    {
+        self.kv
+            .set_if_serialized(key, self.codec.serialize(value)?, version, expire)
+            .await
+    }
+    pub async fn get_or<B>(&self, key: &str, default: B) -> Result<B, AnyError>
+    where
+        B: Serialize + DeserializeOwned,
+    {
+        self.kv.get_or(key, default).await
+    }
+    pub async fn del(&self, key: &str) -> Result<(), AnyError> {
+        self.kv.del(key).await
+    }
+    pub async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        self.kv.ttl(key).await
+    }
+    pub async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        self.kv.expire(key, expire).await
+    }
+    pub async fn persist(&self, key: &str) -> Result<(), AnyError> {
+        self.kv.persist(key).await
+    }
+    pub async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError> {
+        self.kv.keys(prefix).await
+    }
+    pub async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError> {
+        self.kv.del_prefix(prefix, dry_run).await
+    }
+    pub async fn list_push<B>(&self, key: &str, value: &B) -> Result<u64, AnyError>
+    where
+        B: Serialize,
+    {
+        self.kv.list_push(key, value).await
+    }
+    pub async fn list_pop<B>(&self, key: &str) -> Result<Option<B>, AnyError>
+    where
+        B: DeserializeOwned,
+    {
+        self.kv.list_pop(key).await
+    }
+    pub async fn list_range<B>(&self, key: &str, start: i64, stop: i64) -> Result<Vec<B>, AnyError>
+    where
+        B: DeserializeOwned,
+    {
+        self.kv.list_range(key, start, stop).await
+    }
+    pub async fn set_add<B>(&self, key: &str, member: &B) -> Result<bool, AnyError>
+    where
+        B: Serialize,
+    {
+        self.kv.set_add(key, member).await
+    }
+    pub async fn set_remove<B>(&self, key: &str, member: &B) -> Result<bool, AnyError>
+    where
+        B: Serialize,
+    {
+        self.kv.set_remove(key, member).await
+    }
+    pub async fn set_members<B>(&self, key: &str) -> Result<Vec<B>, AnyError>
+    where
+        B: DeserializeOwned,
+    {
+        self.kv.set_members(key).await
+    }
+    pub async fn get_or_init<B, F>(
+        &self,
+        key: &str,
+        init: impl FnOnce() -> F,
+        expire: u64,
+    ) -> Result<KvGetOrInitResult<B>, AnyError>
+    where
+        F: Future<Output = Result<B, AnyError>>,
+        B: Serialize + DeserializeOwned + Clone + Sync,
+    {
+        self.kv
+            .get_or_init_with(self.codec, key, init, expire)
+            .await
+    }
+    pub async fn get_or_init_locked<B, F>(
+        &self,
+        key: &str,
+        init: impl FnOnce() -> F,
+        expire: u64,
+        lock_ttl: u64,
+    ) -> Result<KvGetOrInitResult<B>, AnyError>
+    where
+        F: Future<Output = Result<B, AnyError>>,
+        B: Serialize + DeserializeOwned + Clone + Sync,
+    {
+        self.kv
+            .get_or_init_locked_with(self.codec, key, init, expire, lock_ttl)
+            .await
+    }
+    pub async fn get_or_refresh<B, F>(
+        &self,
+        key: &str,
+        init: impl FnOnce() -> F + Send + 'static,
+        soft_ttl: u64,
+        hard_ttl: u64,
+    ) -> Result<KvGetOrInitResult<B>, AnyError>
+    where
+        F: Future<Output = Result<B, AnyError>> + Send + 'static,
+        B: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        self.kv
+            .get_or_refresh_with(self.codec, key, init, soft_ttl, hard_ttl)
+            .await
+    }
+}
@@ -0,0 +1,523 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::async_trait;
+use base64::prelude::*;
+use futures_util::Stream;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    kv::{
+        decode_value, expire_at, key_prefix, normailze_key, not_found_error, now, AnyError,
+        ConflictError, KVStore, NotFoundError, Version,
+    },
+    KVManager,
+};
+
+const UNRESERVED_PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+// how long a blocking query waits for a change before consul answers anyway
+const WATCH_WAIT: &str = "5m";
+// buffered changes per watcher
+const CAPACITY: usize = 256;
+
+#[derive(Clone)]
+pub struct KVConsul {
+    client: reqwest::Client,
+    endpoint: String,
+    prefix: String,
+    token: Option<String>,
+    datacenter: Option<String>,
+}
+impl fmt::Debug for KVConsul {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KVConsul")
+            .field("endpoint", &self.endpoint)
+            .field("prefix", &self.prefix)
+            .field("datacenter", &self.datacenter)
+            .finish()
+    }
+}
+
+// the expiry lives in the entry flags as a unix timestamp, 0 is persistent
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulEntry {
+    key: String,
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    flags: u64,
+    modify_index: u64,
+}
+
+impl ConsulEntry {
+    fn is_live(&self) -> bool {
+        self.flags == 0 || self.flags >= now()
+    }
+    fn value(&self) -> Result<Vec<u8>, AnyError> {
+        match &self.value {
+            Some(value) => Ok(BASE64_STANDARD.decode(value)?),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+struct ConsulResponse {
+    status: StatusCode,
+    index: u64,
+    body: Vec<u8>,
+}
+
+impl KVConsul {
+    pub fn new(endpoint: &str, prefix: &str) -> KVConsul {
+        KVConsul {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            token: env::var("CONSUL_HTTP_TOKEN").ok().filter(|t| !t.is_empty()),
+            datacenter: None,
+        }
+    }
+    pub fn token(mut self, token: &str) -> KVConsul {
+        self.token = Some(token.to_string());
+        self
+    }
+    pub fn datacenter(mut self, datacenter: &str) -> KVConsul {
+        self.datacenter = Some(datacenter.to_string());
+        self
+    }
+    // consul://host:port/prefix?token=..&dc=.., consul+https:// for tls
+    pub fn open(conn: &str) -> Result<KVConsul, AnyError> {
+        let (scheme, rest) = if let Some(rest) = conn.strip_prefix("consul+https://") {
+            ("https", rest)
+        } else if let Some(rest) = conn.strip_prefix("consul://") {
+            ("http", rest)
+        } else {
+            return Err("invalid consul connection".into());
+        };
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (host, prefix) = location.split_once('/').unwrap_or((location, ""));
+        let host = match host {
+            "" => "127.0.0.1:8500",
+            host => host,
+        };
+        let mut kv = KVConsul::new(&format!("{}://{}", scheme, host), prefix);
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_encoding::percent_decode_str(value).decode_utf8()?;
+            match name {
+                "token" => kv = kv.token(&value),
+                "dc" => kv = kv.datacenter(&value),
+                _ => return Err(format!("unknown consul option: {}", name).into()),
+            }
+        }
+        Ok(kv)
+    }
+    fn object(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            return key.to_string();
+        }
+        format!("{}/{}", self.prefix, key)
+    }
+    async fn request(
+        &self,
+        method: Method,
+        object: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<ConsulResponse, AnyError> {
+        let mut url = reqwest::Url::parse(&format!(
+            "{}/v1/kv/{}",
+            self.endpoint,
+            utf8_percent_encode(object, UNRESERVED_PATH)
+        ))?;
+        {
+            let mut pairs = url.query_pairs_mut();
+            for (name, value) in query {
+                match value {
+                    &"" => pairs.append_key_only(name),
+                    value => pairs.append_pair(name, value),
+                };
+            }
+            if let Some(dc) = &self.datacenter {
+                pairs.append_pair("dc", dc);
+            }
+        }
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+        let mut request = self.client.request(method, url);
+        if let Some(token) = &self.token {
+            request = request.header("x-consul-token", token);
+        }
+        let res = request.body(body).send().await?;
+        let index = res
+            .headers()
+            .get("x-consul-index")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        Ok(ConsulResponse {
+            status: res.status(),
+            index,
+            body: res.bytes().await?.to_vec(),
+        })
+    }
+    fn check(&self, object: &str, res: &ConsulResponse) -> Result<(), AnyError> {
+        if res.status.is_success() {
+            return Ok(());
+        }
+        if res.status == StatusCode::NOT_FOUND {
+            not_found_error()?;
+        }
+        Err(format!(
+            "consul request for {} failed with {}: {}",
+            object,
+            res.status,
+            String::from_utf8_lossy(&res.body)
+        )
+        .into())
+    }
+    // expired entries are still returned, callers decide what they mean
+    async fn entry(&self, object: &str) -> Result<ConsulEntry, AnyError> {
+        let res = self.request(Method::GET, object, &[], Vec::new()).await?;
+        self.check(object, &res)?;
+        let mut entries = serde_json::from_slice::<Vec<ConsulEntry>>(&res.body)?;
+        match entries.pop() {
+            Some(entry) => Ok(entry),
+            None => Err(Box::new(NotFoundError {})),
+        }
+    }
+    async fn live_entry(&self, object: &str) -> Result<ConsulEntry, AnyError> {
+        let entry = self.entry(object).await?;
+        // expired entries linger until something overwrites or deletes them
+        if !entry.is_live() {
+            not_found_error()?;
+        }
+        Ok(entry)
+    }
+    // false when the check-and-set index no longer matches
+    async fn put(
+        &self,
+        object: &str,
+        value: Vec<u8>,
+        expire: u64,
+        cas: Option<u64>,
+    ) -> Result<bool, AnyError> {
+        let flags = expire.to_string();
+        let cas = cas.map(|index| index.to_string());
+        let mut query = vec![("flags", flags.as_str())];
+        if let Some(cas) = &cas {
+            query.push(("cas", cas));
+        }
+        let res = self.request(Method::PUT, object, &query, value).await?;
+        self.check(object, &res)?;
+        Ok(String::from_utf8_lossy(&res.body).trim() == "true")
+    }
+    async fn set_expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        let object = self.object(key);
+        loop {
+            let entry = self.live_entry(&object).await?;
+            if self
+                .put(&object, entry.value()?, expire, Some(entry.modify_index))
+                .await?
+            {
+                return Ok(());
+            }
+        }
+    }
+    // waits until anything under the prefix changes past index, returns the
+    // live entries and the index to wait on next
+    async fn poll(&self, object: &str, index: u64) -> Result<(Vec<ConsulEntry>, u64), AnyError> {
+        let index_value = index.to_string();
+        let mut query = vec![("recurse", "")];
+        if index > 0 {
+            query.push(("index", &index_value));
+            query.push(("wait", WATCH_WAIT));
+        }
+        let res = self
+            .request(Method::GET, object, &query, Vec::new())
+            .await?;
+        if res.status == StatusCode::NOT_FOUND {
+            return Ok((Vec::new(), res.index));
+        }
+        self.check(object, &res)?;
+        let entries = serde_json::from_slice::<Vec<ConsulEntry>>(&res.body)?
+            .into_iter()
+            .filter(|entry| entry.is_live() && !entry.key.ends_with(".lock"))
+            .collect();
+        Ok((entries, res.index))
+    }
+}
+
+#[async_trait]
+impl KVStore for KVConsul {
+    async fn get_raw(&self, key: &str) -> Result<Vec<u8>, AnyError> {
+        self.live_entry(&self.object(key)).await?.value()
+    }
+    async fn set_raw(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        self.put(&self.object(key), value.to_vec(), expire + now(), None)
+            .await?;
+        Ok(())
+    }
+    async fn del(&self, key: &str) -> Result<(), AnyError> {
+        let object = self.object(key);
+        let res = self
+            .request(Method::DELETE, &object, &[], Vec::new())
+            .await?;
+        self.check(&object, &res)
+    }
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        match self.live_entry(&self.object(key)).await?.flags {
+            0 => Ok(None),
+            expire => Ok(Some(expire.saturating_sub(now()))),
+        }
+    }
+    async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        self.set_expire(key, now() + expire).await
+    }
+    async fn persist(&self, key: &str) -> Result<(), AnyError> {
+        self.set_expire(key, 0).await
+    }
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError> {
+        let base = self.object("");
+        let search = self.object(prefix);
+        let res = self
+            .request(Method::GET, &search, &[("keys", "")], Vec::new())
+            .await?;
+        if res.status == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        self.check(&search, &res)?;
+        Ok(serde_json::from_slice::<Vec<String>>(&res.body)?
+            .into_iter()
+            .filter(|key| !key.ends_with(".lock"))
+            .filter_map(|key| key.strip_prefix(&base).map(|key| key.to_string()))
+            .collect())
+    }
+    async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError> {
+        // one delete per key, a recursive delete would take the locks with it
+        let keys = self.keys(prefix).await?;
+        if dry_run {
+            return Ok(keys.len() as u64);
+        }
+        let mut count = 0;
+        for key in keys {
+            match self.del(&key).await {
+                Ok(()) => count += 1,
+                Err(e) if e.is::<NotFoundError>() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(count)
+    }
+    async fn lock(&self, key: &str, ttl: u64) -> Result<bool, AnyError> {
+        let object = self.object(&format!("{}.lock", key));
+        if self.put(&object, Vec::new(), now() + ttl, Some(0)).await? {
+            return Ok(true);
+        }
+        let entry = match self.entry(&object).await {
+            Ok(entry) => entry,
+            // released in the meantime
+            Err(e) if e.is::<NotFoundError>() => {
+                return self.put(&object, Vec::new(), now() + ttl, Some(0)).await
+            }
+            Err(e) => return Err(e),
+        };
+        if entry.is_live() {
+            return Ok(false);
+        }
+        // the previous holder timed out, only one contender wins the index
+        self.put(&object, Vec::new(), now() + ttl, Some(entry.modify_index))
+            .await
+    }
+    async fn unlock(&self, key: &str) -> Result<(), AnyError> {
+        self.del(&format!("{}.lock", key)).await
+    }
+    async fn get_versioned_raw(&self, key: &str) -> Result<(Vec<u8>, Version), AnyError> {
+        let entry = self.live_entry(&self.object(key)).await?;
+        Ok((entry.value()?, Version::new(entry.modify_index.to_string())))
+    }
+    async fn set_if_raw(
+        &self,
+        key: &str,
+        value: &[u8],
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError> {
+        let object = self.object(key);
+        let index = if version.is_absent() {
+            match self.entry(&object).await {
+                // an expired entry that was not cleaned up yet counts as missing
+                Ok(entry) if !entry.is_live() => entry.modify_index,
+                Ok(_) => return Err(Box::new(ConflictError {})),
+                Err(e) if e.is::<NotFoundError>() => 0,
+                Err(e) => return Err(e),
+            }
+        } else {
+            version
+                .as_str()
+                .parse::<u64>()
+                .map_err(|_| format!("invalid consul version: {}", version))?
+        };
+        if !self
+            .put(&object, value.to_vec(), expire_at(expire), Some(index))
+            .await?
+        {
+            return Err(Box::new(ConflictError {}));
+        }
+        Ok(())
+    }
+}
+
+// value is None when the key was deleted or expired
+#[derive(Debug, Clone)]
+pub struct KvChange<T> {
+    pub key: String,
+    pub value: Option<T>,
+}
+
+impl KVManager {
+    // the first items are the keys currently under the prefix, then every change
+    // as consul reports it. an expiry alone does not wake the watch, expired keys
+    // show up as deleted with the next change under the prefix
+    pub async fn watch<T>(&self, prefix: &str) -> Result<KvWatch<T>, AnyError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let consul = self
+            .consul()
+            .ok_or("kv watch is only supported on consul")?
+            .clone();
+        let object = consul.object(&normailze_key(prefix));
+        // the first listing happens here so the caller sees connection errors
+        let (entries, index) = consul.poll(&object, 0).await?;
+        let (tx, rx) = mpsc::channel(CAPACITY);
+        tokio::spawn(forward_consul(consul, object, entries, index, tx));
+        Ok(KvWatch {
+            rx,
+            value: PhantomData,
+        })
+    }
+}
+
+async fn forward_consul(
+    consul: KVConsul,
+    object: String,
+    mut entries: Vec<ConsulEntry>,
+    mut index: u64,
+    tx: mpsc::Sender<KvChange<Vec<u8>>>,
+) {
+    let base = consul.object(&key_prefix());
+    let mut known = HashMap::<String, u64>::new();
+    loop {
+        let mut seen = HashMap::with_capacity(entries.len());
+        for entry in entries {
+            seen.insert(entry.key.clone(), entry.modify_index);
+            if known.get(&entry.key) == Some(&entry.modify_index) {
+                continue;
+            }
+            let Some(key) = entry.key.strip_prefix(&consul.object("")) else {
+                continue;
+            };
+            let value = entry.value().and_then(|raw| decode_value(key, raw));
+            let value = match value {
+                Ok(value) => value,
+                Err(e) => {
+                    tracing::warn!("kv watch {}: skipping {}: {}", object, entry.key, e);
+                    continue;
+                }
+            };
+            let change = KvChange {
+                key: entry.key.strip_prefix(&base).unwrap_or(key).to_string(),
+                value: Some(value),
+            };
+            if tx.send(change).await.is_err() {
+                return;
+            }
+        }
+        for key in known.keys().filter(|key| !seen.contains_key(*key)) {
+            let change = KvChange {
+                key: key.strip_prefix(&base).unwrap_or(key).to_string(),
+                value: None,
+            };
+            if tx.send(change).await.is_err() {
+                return;
+            }
+        }
+        known = seen;
+        entries = loop {
+            let res = tokio::select! {
+                res = consul.poll(&object, index.max(1)) => res,
+                _ = tx.closed() => return,
+            };
+            match res {
+                Ok((entries, next)) => {
+                    // the index going backwards means the cluster state was reset
+                    index = if next < index { 0 } else { next };
+                    break entries;
+                }
+                Err(e) => {
+                    tracing::warn!("kv watch {}: {}", object, e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+                        _ = tx.closed() => return,
+                    }
+                }
+            }
+        };
+    }
+}
+
+pub struct KvWatch<T> {
+    rx: mpsc::Receiver<KvChange<Vec<u8>>>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Stream for KvWatch<T>
+where
+    T: DeserializeOwned,
+{
+    type Item = KvChange<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KvChange<T>>> {
+        loop {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(change)) => {
+                    let value = match change.value {
+                        Some(json) => match serde_json::from_slice(&json) {
+                            Ok(value) => Some(value),
+                            // a writer with another value shape must not end the stream
+                            Err(e) => {
+                                tracing::warn!("kv watch: skipping {}: {}", change.key, e);
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    return Poll::Ready(Some(KvChange {
+                        key: change.key,
+                        value,
+                    }));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
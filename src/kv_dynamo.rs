@@ -0,0 +1,448 @@
+use std::{env, fmt, time::SystemTime};
+
+use axum::async_trait;
+use base64::prelude::*;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::aws::{amz_date, hex, hmac, signing_key};
+use crate::kv::{expire_at, now, AnyError, ConflictError, KVStore, NotFoundError, Version};
+
+// items that are still there but past their expiry, dynamodb only deletes them eventually
+const LIVE: &str = "(#e = :zero OR #e >= :now)";
+
+#[derive(Clone)]
+pub struct KVDynamo {
+    client: reqwest::Client,
+    table: String,
+    region: String,
+    endpoint: String,
+    partition_key: String,
+    ttl_attribute: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+impl fmt::Debug for KVDynamo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KVDynamo")
+            .field("table", &self.table)
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .finish()
+    }
+}
+
+// a failed ConditionExpression, everything else is an error
+enum Outcome {
+    Done(Value),
+    ConditionFailed,
+}
+
+impl KVDynamo {
+    // items are `{<partition_key>: S, value: B, <ttl_attribute>: N, version: N}`, the
+    // expiry is a unix timestamp with 0 for persistent entries, which dynamodb ttl
+    // ignores as it is more than five years in the past
+    pub fn new(table: &str, region: &str) -> KVDynamo {
+        KVDynamo {
+            client: reqwest::Client::new(),
+            table: table.to_string(),
+            region: region.to_string(),
+            endpoint: format!("https://dynamodb.{}.amazonaws.com", region),
+            partition_key: "key".to_string(),
+            ttl_attribute: "expire".to_string(),
+            access_key: env::var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+            secret_key: env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        }
+    }
+    pub fn endpoint(mut self, endpoint: &str) -> KVDynamo {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+    pub fn partition_key(mut self, partition_key: &str) -> KVDynamo {
+        self.partition_key = partition_key.to_string();
+        self
+    }
+    pub fn ttl_attribute(mut self, ttl_attribute: &str) -> KVDynamo {
+        self.ttl_attribute = ttl_attribute.to_string();
+        self
+    }
+    pub fn credentials(mut self, access_key: &str, secret_key: &str) -> KVDynamo {
+        self.access_key = access_key.to_string();
+        self.secret_key = secret_key.to_string();
+        self.session_token = None;
+        self
+    }
+    pub fn open(conn: &str) -> Result<KVDynamo, AnyError> {
+        let rest = conn
+            .strip_prefix("dynamodb://")
+            .ok_or("invalid dynamodb connection")?;
+        let (table, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let table = table.trim_matches('/');
+        if table.is_empty() {
+            return Err("dynamodb connection is missing a table".into());
+        }
+        let mut region = env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".into());
+        let mut endpoint = env::var("AWS_ENDPOINT_URL").ok();
+        let mut partition_key = None;
+        let mut ttl_attribute = None;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_encoding::percent_decode_str(value)
+                .decode_utf8()?
+                .into_owned();
+            match name {
+                "region" => region = value,
+                "endpoint" => endpoint = Some(value),
+                "partition_key" => partition_key = Some(value),
+                "ttl_attribute" => ttl_attribute = Some(value),
+                _ => return Err(format!("unknown dynamodb option: {}", name).into()),
+            }
+        }
+        let mut kv = KVDynamo::new(table, &region);
+        if let Some(endpoint) = endpoint {
+            kv = kv.endpoint(&endpoint);
+        }
+        if let Some(partition_key) = partition_key {
+            kv = kv.partition_key(&partition_key);
+        }
+        if let Some(ttl_attribute) = ttl_attribute {
+            kv = kv.ttl_attribute(&ttl_attribute);
+        }
+        Ok(kv)
+    }
+    async fn request(&self, operation: &str, mut body: Value) -> Result<Outcome, AnyError> {
+        body["TableName"] = json!(self.table);
+        let body = serde_json::to_vec(&body)?;
+        let url = reqwest::Url::parse(&format!("{}/", self.endpoint))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+            None => url.host_str().unwrap_or("").to_string(),
+        };
+        let (date, timestamp) = amz_date(SystemTime::now());
+        let mut signed = vec![
+            (
+                "content-type".to_string(),
+                "application/x-amz-json-1.0".to_string(),
+            ),
+            ("host".to_string(), host),
+            ("x-amz-date".to_string(), timestamp.clone()),
+            (
+                "x-amz-target".to_string(),
+                format!("DynamoDB_20120810.{}", operation),
+            ),
+        ];
+        if let Some(token) = &self.session_token {
+            signed.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        signed.sort();
+        let signed_names = signed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            signed
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_names,
+            hex(&Sha256::digest(&body))
+        );
+        let scope = format!("{}/{}/dynamodb/aws4_request", date, self.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let key = signing_key(&self.secret_key, &date, &self.region, "dynamodb");
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            scope,
+            signed_names,
+            hex(&hmac(&key, &to_sign))
+        );
+        let mut request = self.client.post(url);
+        for (name, value) in signed.into_iter().filter(|(name, _)| name != "host") {
+            request = request.header(name, value);
+        }
+        let res = request
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?;
+        let status = res.status();
+        let body = res.bytes().await?;
+        let value = serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null);
+        if status.is_success() {
+            return Ok(Outcome::Done(value));
+        }
+        let kind = value["__type"].as_str().unwrap_or_default();
+        if status == StatusCode::BAD_REQUEST && kind.ends_with("#ConditionalCheckFailedException") {
+            return Ok(Outcome::ConditionFailed);
+        }
+        Err(format!(
+            "dynamodb {} on {} failed with {}: {}",
+            operation,
+            self.table,
+            status,
+            String::from_utf8_lossy(&body)
+        )
+        .into())
+    }
+    async fn call(&self, operation: &str, body: Value) -> Result<Value, AnyError> {
+        match self.request(operation, body).await? {
+            Outcome::Done(value) => Ok(value),
+            Outcome::ConditionFailed => {
+                Err(format!("dynamodb {} failed its condition", operation).into())
+            }
+        }
+    }
+    fn key(&self, key: &str) -> Value {
+        json!({ &self.partition_key: { "S": key } })
+    }
+    fn names(&self) -> Value {
+        json!({
+            "#k": self.partition_key,
+            "#v": "value",
+            "#e": self.ttl_attribute,
+            "#n": "version",
+        })
+    }
+    // the live item, None when it is missing or expired
+    async fn item(&self, key: &str) -> Result<Option<Value>, AnyError> {
+        let res = self
+            .call(
+                "GetItem",
+                json!({ "Key": self.key(key), "ConsistentRead": true }),
+            )
+            .await?;
+        let item = &res["Item"];
+        if item.is_null() {
+            return Ok(None);
+        }
+        let expire = number(&item[&self.ttl_attribute]);
+        if expire > 0 && expire < now() {
+            return Ok(None);
+        }
+        Ok(Some(item.clone()))
+    }
+    async fn update(
+        &self,
+        key: &str,
+        value: &[u8],
+        expire: u64,
+        condition: Option<(String, Value)>,
+    ) -> Result<bool, AnyError> {
+        let mut values = json!({
+            ":v": { "B": BASE64_STANDARD.encode(value) },
+            ":e": { "N": expire.to_string() },
+            ":one": { "N": "1" },
+        });
+        let mut body = json!({
+            "Key": self.key(key),
+            "UpdateExpression": "SET #v = :v, #e = :e ADD #n :one",
+            "ExpressionAttributeNames": self.names(),
+        });
+        if let Some((condition, extra)) = condition {
+            body["ConditionExpression"] = json!(condition);
+            if let (Some(values), Value::Object(extra)) = (values.as_object_mut(), extra) {
+                values.extend(extra);
+            }
+        }
+        body["ExpressionAttributeValues"] = values;
+        // dynamodb rejects names the expressions do not use
+        if !body["ConditionExpression"]
+            .as_str()
+            .is_some_and(|condition| condition.contains("#k"))
+        {
+            if let Some(names) = body["ExpressionAttributeNames"].as_object_mut() {
+                names.remove("#k");
+            }
+        }
+        match self.request("UpdateItem", body).await? {
+            Outcome::Done(_) => Ok(true),
+            Outcome::ConditionFailed => Ok(false),
+        }
+    }
+    async fn set_expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        let body = json!({
+            "Key": self.key(key),
+            "UpdateExpression": "SET #e = :e",
+            "ConditionExpression": format!("attribute_exists(#k) AND {}", LIVE),
+            "ExpressionAttributeNames": { "#k": self.partition_key, "#e": self.ttl_attribute },
+            "ExpressionAttributeValues": {
+                ":e": { "N": expire.to_string() },
+                ":zero": { "N": "0" },
+                ":now": { "N": now().to_string() },
+            },
+        });
+        match self.request("UpdateItem", body).await? {
+            Outcome::Done(_) => Ok(()),
+            Outcome::ConditionFailed => Err(Box::new(NotFoundError {})),
+        }
+    }
+    async fn put_lock(&self, key: &str, expire: u64) -> Result<bool, AnyError> {
+        let body = json!({
+            "Item": {
+                &self.partition_key: { "S": key },
+                &self.ttl_attribute: { "N": expire.to_string() },
+            },
+            "ConditionExpression": "attribute_not_exists(#k) OR #e < :now",
+            "ExpressionAttributeNames": { "#k": self.partition_key, "#e": self.ttl_attribute },
+            "ExpressionAttributeValues": { ":now": { "N": now().to_string() } },
+        });
+        match self.request("PutItem", body).await? {
+            Outcome::Done(_) => Ok(true),
+            Outcome::ConditionFailed => Ok(false),
+        }
+    }
+}
+
+fn number(attribute: &Value) -> u64 {
+    attribute["N"]
+        .as_str()
+        .and_then(|n| n.parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+fn binary(item: &Value) -> Result<Vec<u8>, AnyError> {
+    match item["value"]["B"].as_str() {
+        Some(value) => Ok(BASE64_STANDARD.decode(value)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[async_trait]
+impl KVStore for KVDynamo {
+    async fn get_raw(&self, key: &str) -> Result<Vec<u8>, AnyError> {
+        match self.item(key).await? {
+            Some(item) => binary(&item),
+            None => Err(Box::new(NotFoundError {})),
+        }
+    }
+    async fn set_raw(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        self.update(key, value, expire + now(), None).await?;
+        Ok(())
+    }
+    async fn del(&self, key: &str) -> Result<(), AnyError> {
+        self.call("DeleteItem", json!({ "Key": self.key(key) }))
+            .await?;
+        Ok(())
+    }
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        let Some(item) = self.item(key).await? else {
+            return Err(Box::new(NotFoundError {}));
+        };
+        match number(&item[&self.ttl_attribute]) {
+            0 => Ok(None),
+            expire => Ok(Some(expire.saturating_sub(now()))),
+        }
+    }
+    async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        self.set_expire(key, now() + expire).await
+    }
+    async fn persist(&self, key: &str) -> Result<(), AnyError> {
+        self.set_expire(key, 0).await
+    }
+    // a filtered scan, fine for the occasional maintenance call but it reads the whole table
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError> {
+        let mut keys = Vec::new();
+        let mut start = Value::Null;
+        loop {
+            let mut body = json!({
+                "ProjectionExpression": "#k",
+                "FilterExpression": format!("begins_with(#k, :p) AND {}", LIVE),
+                "ExpressionAttributeNames": { "#k": self.partition_key, "#e": self.ttl_attribute },
+                "ExpressionAttributeValues": {
+                    ":p": { "S": prefix },
+                    ":zero": { "N": "0" },
+                    ":now": { "N": now().to_string() },
+                },
+                "ConsistentRead": true,
+            });
+            if !start.is_null() {
+                body["ExclusiveStartKey"] = start;
+            }
+            let res = self.call("Scan", body).await?;
+            keys.extend(
+                res["Items"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|item| item[&self.partition_key]["S"].as_str())
+                    .filter(|key| !key.ends_with(".lock"))
+                    .map(|key| key.to_string()),
+            );
+            start = res["LastEvaluatedKey"].clone();
+            if start.is_null() {
+                break;
+            }
+        }
+        Ok(keys)
+    }
+    async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError> {
+        let keys = self.keys(prefix).await?;
+        if dry_run {
+            return Ok(keys.len() as u64);
+        }
+        let mut count = 0;
+        for key in keys {
+            self.del(&key).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+    async fn lock(&self, key: &str, ttl: u64) -> Result<bool, AnyError> {
+        self.put_lock(&format!("{}.lock", key), now() + ttl).await
+    }
+    async fn unlock(&self, key: &str) -> Result<(), AnyError> {
+        self.del(&format!("{}.lock", key)).await
+    }
+    async fn get_versioned_raw(&self, key: &str) -> Result<(Vec<u8>, Version), AnyError> {
+        match self.item(key).await? {
+            Some(item) => Ok((
+                binary(&item)?,
+                Version::new(number(&item["version"]).to_string()),
+            )),
+            None => Err(Box::new(NotFoundError {})),
+        }
+    }
+    async fn set_if_raw(
+        &self,
+        key: &str,
+        value: &[u8],
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError> {
+        let now = now();
+        let condition = if version.is_absent() {
+            // an expired item that was not removed yet counts as missing
+            (
+                "attribute_not_exists(#k) OR (#e > :zero AND #e < :now)".to_string(),
+                json!({ ":zero": { "N": "0" }, ":now": { "N": now.to_string() } }),
+            )
+        } else {
+            (
+                format!("#n = :n AND {}", LIVE),
+                json!({
+                    ":n": { "N": version.as_str() },
+                    ":zero": { "N": "0" },
+                    ":now": { "N": now.to_string() },
+                }),
+            )
+        };
+        match self
+            .update(key, value, expire_at(expire), Some(condition))
+            .await?
+        {
+            true => Ok(()),
+            false => Err(Box::new(ConflictError {})),
+        }
+    }
+}
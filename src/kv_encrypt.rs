@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use base64::prelude::*;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::AnyError;
+
+pub(crate) const ENCRYPT_AES_GCM: u8 = 0x03;
+
+// values are sealed with the active key, every key in the ring stays usable
+// for reading so keys can be rotated without rewriting the store first
+pub struct KvKeyring {
+    active: String,
+    keys: HashMap<String, Arc<LessSafeKey>>,
+}
+
+impl std::fmt::Debug for KvKeyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut ids = self.keys.keys().collect::<Vec<_>>();
+        ids.sort();
+        f.debug_struct("KvKeyring")
+            .field("active", &self.active)
+            .field("keys", &ids)
+            .finish()
+    }
+}
+
+impl KvKeyring {
+    // the first key added becomes the active one
+    pub fn new() -> KvKeyring {
+        KvKeyring {
+            active: String::new(),
+            keys: HashMap::new(),
+        }
+    }
+    pub fn key(mut self, id: &str, key: &[u8]) -> Result<KvKeyring, AnyError> {
+        if id.is_empty() || id.len() > u8::MAX as usize {
+            return Err(format!("invalid kv encryption key id: {:?}", id).into());
+        }
+        let key = UnboundKey::new(&AES_256_GCM, key)
+            .map_err(|_| format!("kv encryption key {} must be 32 bytes", id))?;
+        if self.active.is_empty() {
+            self.active = id.to_string();
+        }
+        self.keys
+            .insert(id.to_string(), Arc::new(LessSafeKey::new(key)));
+        Ok(self)
+    }
+    pub fn activate(mut self, id: &str) -> Result<KvKeyring, AnyError> {
+        if !self.keys.contains_key(id) {
+            return Err(format!("unknown kv encryption key: {}", id).into());
+        }
+        self.active = id.to_string();
+        Ok(self)
+    }
+    // "id:base64key,..." listed newest first, separated by commas or newlines
+    pub fn parse(spec: &str) -> Result<KvKeyring, AnyError> {
+        let mut keyring = KvKeyring::new();
+        for entry in spec
+            .split([',', '\n'])
+            .map(|e| e.trim())
+            .filter(|e| !e.is_empty() && !e.starts_with('#'))
+        {
+            let (id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("kv encryption key without id: {}", entry))?;
+            let key = BASE64_STANDARD
+                .decode(key.trim())
+                .map_err(|e| format!("kv encryption key {}: {}", id, e))?;
+            keyring = keyring.key(id.trim(), &key)?;
+        }
+        Ok(keyring)
+    }
+    // TOKI_KV_ENCRYPTION_KEYS holds the keys inline, TOKI_KV_ENCRYPTION_KEYS_FILE points to them
+    pub fn from_env() -> Result<Option<KvKeyring>, AnyError> {
+        let spec = match (
+            env::var("TOKI_KV_ENCRYPTION_KEYS"),
+            env::var("TOKI_KV_ENCRYPTION_KEYS_FILE"),
+        ) {
+            (Ok(spec), _) => spec,
+            (_, Ok(path)) => std::fs::read_to_string(&path)
+                .map_err(|e| format!("unable to read {}: {}", path, e))?,
+            _ => return Ok(None),
+        };
+        let keyring = KvKeyring::parse(&spec)?;
+        if keyring.keys.is_empty() {
+            return Err("no kv encryption keys configured".into());
+        }
+        Ok(Some(keyring))
+    }
+    // replaces the keys from the environment for every KVManager in the process
+    pub fn install(self) -> Result<(), AnyError> {
+        if self.keys.is_empty() {
+            return Err("no kv encryption keys configured".into());
+        }
+        *keyring_slot().write().unwrap() = Some(Arc::new(self));
+        Ok(())
+    }
+    fn seal(&self, key: &str, mut data: Vec<u8>) -> Result<Vec<u8>, AnyError> {
+        let sealing = self
+            .keys
+            .get(&self.active)
+            .ok_or("no kv encryption keys configured")?;
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| "unable to generate a nonce")?;
+        // binding the storage key stops a ciphertext from being replayed under another key
+        sealing
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| "kv encryption failed")?;
+        let mut sealed = Vec::with_capacity(2 + self.active.len() + NONCE_LEN + data.len());
+        sealed.push(ENCRYPT_AES_GCM);
+        sealed.push(self.active.len() as u8);
+        sealed.extend_from_slice(self.active.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&data);
+        Ok(sealed)
+    }
+    fn open(&self, key: &str, raw: &[u8]) -> Result<Vec<u8>, AnyError> {
+        let malformed = || -> AnyError { format!("malformed encrypted kv value: {}", key).into() };
+        let id_len = *raw.get(1).ok_or_else(malformed)? as usize;
+        let id = raw.get(2..2 + id_len).ok_or_else(malformed)?;
+        let id = std::str::from_utf8(id).map_err(|_| malformed())?;
+        let nonce = raw
+            .get(2 + id_len..2 + id_len + NONCE_LEN)
+            .ok_or_else(malformed)?;
+        let opening = self
+            .keys
+            .get(id)
+            .ok_or_else(|| format!("kv value {} is sealed with unknown key {}", key, id))?;
+        let mut data = raw[2 + id_len + NONCE_LEN..].to_vec();
+        let len = opening
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).map_err(|_| malformed())?,
+                Aad::from(key.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| format!("unable to decrypt kv value {}", key))?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+impl Default for KvKeyring {
+    fn default() -> KvKeyring {
+        KvKeyring::new()
+    }
+}
+
+fn keyring_slot() -> &'static RwLock<Option<Arc<KvKeyring>>> {
+    static KEYRING: OnceLock<RwLock<Option<Arc<KvKeyring>>>> = OnceLock::new();
+    KEYRING.get_or_init(|| {
+        // a broken key setup must not silently fall back to writing plaintext
+        let keyring = KvKeyring::from_env().unwrap_or_else(|e| {
+            tracing::error!("invalid kv encryption keys: {}", e);
+            std::process::exit(2601);
+        });
+        RwLock::new(keyring.map(Arc::new))
+    })
+}
+
+fn keyring() -> Option<Arc<KvKeyring>> {
+    keyring_slot().read().unwrap().clone()
+}
+
+pub(crate) fn encrypt_value(key: &str, data: Vec<u8>) -> Result<Vec<u8>, AnyError> {
+    match keyring() {
+        Some(keyring) => keyring.seal(key, data),
+        None => Ok(data),
+    }
+}
+
+pub(crate) fn decrypt_value(key: &str, raw: &[u8]) -> Result<Vec<u8>, AnyError> {
+    match keyring() {
+        Some(keyring) => keyring.open(key, raw),
+        None => Err(format!("kv value {} is encrypted but no keys are configured", key).into()),
+    }
+}
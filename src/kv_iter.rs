@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+use futures_util::{stream, Stream};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    kv::{key_prefix, RedisConnection},
+    kv_tombstone::is_tombstone,
+    AnyError, KVManager, NotFoundError,
+};
+
+// keys asked from redis per SCAN round trip, only a hint to the server
+const SCAN_COUNT: usize = 200;
+
+enum Pages {
+    Start,
+    Redis(u64),
+    Dir(tokio::fs::ReadDir),
+    Done,
+}
+
+struct Iter {
+    kv: KVManager,
+    prefix: String,
+    pages: Pages,
+    keys: VecDeque<String>,
+}
+
+impl KVManager {
+    // keys are listed a page at a time and values read as the stream is polled,
+    // keys that expire in between are skipped. redis clusters and custom stores
+    // have no cursor, their keys are listed up front
+    pub fn iter<B>(&self, prefix: &str) -> impl Stream<Item = Result<(String, B), AnyError>>
+    where
+        B: serde::Serialize + DeserializeOwned,
+    {
+        let iter = Iter {
+            kv: self.clone(),
+            prefix: self.normalize(prefix),
+            pages: Pages::Start,
+            keys: VecDeque::new(),
+        };
+        stream::unfold(iter, |mut iter| async move {
+            loop {
+                let key = match iter.keys.pop_front() {
+                    Some(key) if is_tombstone(&key) => continue,
+                    Some(key) => key,
+                    None => match iter.next_page().await {
+                        Ok(true) => continue,
+                        Ok(false) => return None,
+                        Err(e) => {
+                            iter.pages = Pages::Done;
+                            return Some((Err(e), iter));
+                        }
+                    },
+                };
+                let key = key.strip_prefix(&key_prefix()).unwrap_or(&key).to_string();
+                match iter.kv.get::<B>(&key).await {
+                    Ok(value) => return Some((Ok((key, value)), iter)),
+                    Err(e) if e.is::<NotFoundError>() => continue,
+                    Err(e) => return Some((Err(e), iter)),
+                }
+            }
+        })
+    }
+}
+
+impl Iter {
+    // false once every page has been read
+    async fn next_page(&mut self) -> Result<bool, AnyError> {
+        match &mut self.pages {
+            Pages::Start => {
+                // nested stores are spread over directories, they are walked up front
+                self.pages = if let Some(fs) = self.kv.filesystem().filter(|fs| !fs.is_nested()) {
+                    Pages::Dir(tokio::fs::read_dir(fs.path()).await?)
+                } else if self.single_redis().await? {
+                    Pages::Redis(0)
+                } else {
+                    self.keys = self.kv.store().keys(&self.prefix).await?.into();
+                    Pages::Done
+                };
+                Ok(true)
+            }
+            Pages::Redis(cursor) => {
+                let cursor = *cursor;
+                let redis = self.kv.redis().ok_or("kv iteration lost its redis store")?;
+                let pattern = format!("{}*", self.prefix.replace('[', "\\[").replace(']', "\\]"));
+                let mut con = redis.connection().await?;
+                let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_COUNT)
+                    .query_async(&mut con)
+                    .await?;
+                // lock keys hold timestamps, not values
+                self.keys
+                    .extend(keys.into_iter().filter(|key| !key.ends_with(".lock")));
+                self.pages = match next {
+                    0 => Pages::Done,
+                    next => Pages::Redis(next),
+                };
+                Ok(true)
+            }
+            Pages::Dir(dir) => {
+                // one directory entry per call, the file name is the key
+                match dir.next_entry().await? {
+                    Some(entry) => {
+                        let name = entry.file_name();
+                        let key = name.to_str().and_then(|name| name.strip_suffix(".json"));
+                        if let Some(key) = key.filter(|key| key.starts_with(&self.prefix)) {
+                            self.keys.push_back(key.to_string());
+                        }
+                    }
+                    None => self.pages = Pages::Done,
+                }
+                Ok(true)
+            }
+            Pages::Done => Ok(false),
+        }
+    }
+    async fn single_redis(&self) -> Result<bool, AnyError> {
+        match self.kv.redis() {
+            // SCAN only walks a single node, clusters fall back to KEYS
+            Some(redis) => Ok(matches!(
+                redis.connection().await?,
+                RedisConnection::Single(_)
+            )),
+            None => Ok(false),
+        }
+    }
+}
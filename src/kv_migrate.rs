@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use crate::{
+    kv::{KVStore, NotFoundError},
+    AnyError, KVManager,
+};
+
+// target stores have no way to write a key without expiry, persistent keys are
+// written with this ttl and persisted right after
+const PERSIST_TTL: u64 = 3600;
+
+#[derive(Debug, Clone, Default)]
+pub struct MigrationProgress {
+    pub total: u64,
+    pub copied: u64,
+    // expired between listing and reading, or already present with skip_existing
+    pub skipped: u64,
+    pub failed: u64,
+}
+
+impl MigrationProgress {
+    pub fn done(&self) -> u64 {
+        self.copied + self.skipped + self.failed
+    }
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(&MigrationProgress) + Send + 'a>;
+
+pub struct KvMigration<'a> {
+    from: &'a KVManager,
+    to: &'a KVManager,
+    prefix: String,
+    preserve_ttl: bool,
+    rate: Option<u32>,
+    skip_existing: bool,
+    progress: Option<ProgressFn<'a>>,
+}
+
+impl KVManager {
+    // values are copied as stored, compressed and encrypted entries stay that way
+    // and the target needs the same keys to read them
+    pub fn migrate_to<'a>(
+        &'a self,
+        other: &'a KVManager,
+        prefix: &str,
+        preserve_ttl: bool,
+    ) -> KvMigration<'a> {
+        KvMigration {
+            from: self,
+            to: other,
+            prefix: prefix.to_string(),
+            preserve_ttl,
+            rate: None,
+            skip_existing: false,
+            progress: None,
+        }
+    }
+}
+
+impl<'a> KvMigration<'a> {
+    // keys per second, keeps a live source or target from being flooded
+    pub fn rate(mut self, keys_per_second: u32) -> KvMigration<'a> {
+        self.rate = Some(keys_per_second).filter(|rate| *rate > 0);
+        self
+    }
+    // leaves keys the target already has untouched instead of overwriting them
+    pub fn skip_existing(mut self) -> KvMigration<'a> {
+        self.skip_existing = true;
+        self
+    }
+    // called after every key
+    pub fn on_progress<F>(mut self, progress: F) -> KvMigration<'a>
+    where
+        F: FnMut(&MigrationProgress) + Send + 'a,
+    {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+    // a key that fails is logged and counted, the rest of the migration goes on
+    pub async fn run(mut self) -> Result<MigrationProgress, AnyError> {
+        let from = self.from.store();
+        let to = self.to.store();
+        // lock keys belong to the running instances of the source
+        let keys = from
+            .keys(&self.from.normalize(&self.prefix))
+            .await?
+            .into_iter()
+            .filter(|key| !key.ends_with(".lock"))
+            .collect::<Vec<_>>();
+        let mut progress = MigrationProgress {
+            total: keys.len() as u64,
+            ..Default::default()
+        };
+        tracing::info!(
+            "migrating {} kv keys from {} to {}",
+            progress.total,
+            self.from.backend(),
+            self.to.backend()
+        );
+        let mut ticker = self.rate.map(|rate| {
+            let mut ticker = tokio::time::interval(Duration::from_secs(1) / rate);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker
+        });
+        for key in keys {
+            if let Some(ticker) = ticker.as_mut() {
+                ticker.tick().await;
+            }
+            match self.copy(from, to, &key).await {
+                Ok(true) => progress.copied += 1,
+                Ok(false) => progress.skipped += 1,
+                Err(e) => {
+                    tracing::warn!("kv migration of {} failed: {}", key, e);
+                    progress.failed += 1;
+                }
+            }
+            if let Some(callback) = self.progress.as_mut() {
+                callback(&progress);
+            }
+        }
+        tracing::info!(
+            "kv migration done: {} copied, {} skipped, {} failed",
+            progress.copied,
+            progress.skipped,
+            progress.failed
+        );
+        Ok(progress)
+    }
+    async fn copy(
+        &self,
+        from: &dyn KVStore,
+        to: &dyn KVStore,
+        key: &str,
+    ) -> Result<bool, AnyError> {
+        if self.skip_existing && exists(to, key).await? {
+            return Ok(false);
+        }
+        let (value, ttl) = match self.preserve_ttl {
+            true => {
+                let ttl = match from.ttl(key).await {
+                    Err(e) if e.is::<NotFoundError>() => return Ok(false),
+                    ttl => ttl?,
+                };
+                (from.get_raw(key).await, ttl)
+            }
+            false => (from.get_raw(key).await, None),
+        };
+        let value = match value {
+            Err(e) if e.is::<NotFoundError>() => return Ok(false),
+            value => value?,
+        };
+        match ttl {
+            // expired while we were reading it
+            Some(0) => return Ok(false),
+            Some(ttl) => to.set_raw(key, &value, ttl).await?,
+            None => {
+                to.set_raw(key, &value, PERSIST_TTL).await?;
+                to.persist(key).await?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+async fn exists(store: &dyn KVStore, key: &str) -> Result<bool, AnyError> {
+    match store.ttl(key).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.is::<NotFoundError>() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
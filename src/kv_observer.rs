@@ -0,0 +1,186 @@
+use std::{
+    env,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::Instrument;
+
+use crate::{kv::NotFoundError, AnyError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KvOperation {
+    Get,
+    Set,
+    Del,
+    Ttl,
+    Expire,
+    Persist,
+    Keys,
+    DelPrefix,
+    Lock,
+    Unlock,
+    ListPush,
+    ListPop,
+    ListRange,
+    SetAdd,
+    SetRemove,
+    SetMembers,
+}
+
+impl KvOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KvOperation::Get => "get",
+            KvOperation::Set => "set",
+            KvOperation::Del => "del",
+            KvOperation::Ttl => "ttl",
+            KvOperation::Expire => "expire",
+            KvOperation::Persist => "persist",
+            KvOperation::Keys => "keys",
+            KvOperation::DelPrefix => "del_prefix",
+            KvOperation::Lock => "lock",
+            KvOperation::Unlock => "unlock",
+            KvOperation::ListPush => "list_push",
+            KvOperation::ListPop => "list_pop",
+            KvOperation::ListRange => "list_range",
+            KvOperation::SetAdd => "set_add",
+            KvOperation::SetRemove => "set_remove",
+            KvOperation::SetMembers => "set_members",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KvOutcome {
+    Hit,
+    Miss,
+    Ok,
+    Error,
+}
+
+impl KvOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KvOutcome::Hit => "hit",
+            KvOutcome::Miss => "miss",
+            KvOutcome::Ok => "ok",
+            KvOutcome::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KvEvent<'a> {
+    pub operation: KvOperation,
+    pub backend: &'static str,
+    pub prefix: &'a str,
+    pub outcome: KvOutcome,
+    pub latency: Duration,
+    pub size: Option<usize>,
+}
+
+pub trait KvObserver: Send + Sync {
+    fn observe(&self, event: &KvEvent);
+}
+
+type Observers = RwLock<Vec<Arc<dyn KvObserver>>>;
+
+fn observers() -> &'static Observers {
+    static OBSERVERS: OnceLock<Observers> = OnceLock::new();
+    OBSERVERS.get_or_init(Default::default)
+}
+
+pub(crate) fn add_observer(observer: Arc<dyn KvObserver>) {
+    observers().write().unwrap().push(observer);
+}
+
+// milliseconds, u64::MAX until TOKI_KV_SLOW_MS has been read, 0 turns warnings off
+static SLOW_THRESHOLD: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn slow_threshold() -> Option<Duration> {
+    let mut ms = SLOW_THRESHOLD.load(Ordering::Relaxed);
+    if ms == u64::MAX {
+        ms = env::var("TOKI_KV_SLOW_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        SLOW_THRESHOLD.store(ms, Ordering::Relaxed);
+    }
+    Some(Duration::from_millis(ms)).filter(|_| ms > 0)
+}
+
+pub(crate) fn set_slow_threshold(threshold: Option<Duration>) {
+    let ms = threshold.map(|t| t.as_millis().clamp(1, u64::MAX as u128 - 1) as u64);
+    SLOW_THRESHOLD.store(ms.unwrap_or(0), Ordering::Relaxed);
+}
+
+// metrics are grouped by the part of the key before the first `:`, so ids in
+// keys do not blow up the label cardinality
+fn key_prefix(key: &str) -> &str {
+    key.split_once(':').map(|(prefix, _)| prefix).unwrap_or("")
+}
+
+pub(crate) async fn observe<T, F>(
+    operation: KvOperation,
+    backend: &'static str,
+    key: &str,
+    size: impl FnOnce(&T) -> Option<usize>,
+    fut: F,
+) -> Result<T, AnyError>
+where
+    F: Future<Output = Result<T, AnyError>>,
+{
+    let prefix = key_prefix(key);
+    // created here so it hangs under whatever span the caller is in, usually the request
+    let span = tracing::info_span!(
+        "kv",
+        kv.operation = operation.as_str(),
+        kv.backend = backend,
+        kv.prefix = prefix,
+        kv.outcome = tracing::field::Empty,
+        kv.latency_ms = tracing::field::Empty,
+    );
+    let start = Instant::now();
+    let res = fut.instrument(span.clone()).await;
+    let latency = start.elapsed();
+    let (outcome, size) = match &res {
+        Ok(value) if operation == KvOperation::Get => (KvOutcome::Hit, size(value)),
+        Ok(value) => (KvOutcome::Ok, size(value)),
+        Err(e) if e.is::<NotFoundError>() => (KvOutcome::Miss, None),
+        Err(_) => (KvOutcome::Error, None),
+    };
+    span.record("kv.outcome", outcome.as_str());
+    span.record("kv.latency_ms", latency.as_secs_f64() * 1000.0);
+    if slow_threshold().is_some_and(|threshold| latency >= threshold) {
+        span.in_scope(|| {
+            tracing::warn!(
+                "slow kv {} on {} ({}): {}ms",
+                operation.as_str(),
+                backend,
+                prefix,
+                latency.as_millis()
+            )
+        });
+    }
+    let observers = observers().read().unwrap();
+    if observers.is_empty() {
+        return res;
+    }
+    let event = KvEvent {
+        operation,
+        backend,
+        prefix,
+        outcome,
+        latency,
+        size,
+    };
+    for observer in observers.iter() {
+        observer.observe(&event);
+    }
+    res
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Mutex, OnceLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{Stream, StreamExt};
+use redis::AsyncCommands;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{kv::normailze_key, AnyError, KVManager};
+
+// buffered messages per subscriber, a slow consumer loses the oldest ones
+const CAPACITY: usize = 256;
+
+// stores without a broker share a process wide bus, good enough for a single
+// instance and for development
+fn local_bus(channel: &str) -> broadcast::Sender<String> {
+    static BUS: OnceLock<Mutex<HashMap<String, broadcast::Sender<String>>>> = OnceLock::new();
+    let mut bus = BUS.get_or_init(Default::default).lock().unwrap();
+    bus.retain(|_, tx| tx.receiver_count() > 0);
+    bus.entry(channel.to_string())
+        .or_insert_with(|| broadcast::channel(CAPACITY).0)
+        .clone()
+}
+
+impl KVManager {
+    // messages are json encoded, channels follow the same naming rules as keys
+    pub async fn publish<T: Serialize>(&self, channel: &str, msg: &T) -> Result<(), AnyError> {
+        let channel = normailze_key(channel);
+        let payload = serde_json::to_string(msg)?;
+        match self.redis() {
+            Some(redis) => {
+                let mut con = redis.connection().await?;
+                con.publish::<_, _, ()>(channel, payload).await?;
+            }
+            None => {
+                // no subscribers is not an error, same as redis
+                let _ = local_bus(&channel).send(payload);
+            }
+        }
+        Ok(())
+    }
+    // the redis subscription reconnects on its own, messages published while
+    // it was down are lost
+    pub async fn subscribe<T>(&self, channel: &str) -> Result<KvSubscription<T>, AnyError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let channel = normailze_key(channel);
+        let (tx, rx) = mpsc::channel(CAPACITY);
+        match self.redis() {
+            Some(redis) => {
+                let client = redis
+                    .client()
+                    .ok_or("kv subscribe is only supported on single redis nodes")?
+                    .clone();
+                // subscribe once up front so the caller sees connection errors
+                let mut pubsub = client.get_async_pubsub().await?;
+                pubsub.subscribe(&channel).await?;
+                tokio::spawn(forward_redis(client, channel, pubsub, tx));
+            }
+            None => {
+                tokio::spawn(forward_local(local_bus(&channel).subscribe(), channel, tx));
+            }
+        }
+        Ok(KvSubscription {
+            rx,
+            value: PhantomData,
+        })
+    }
+}
+
+async fn forward_redis(
+    client: redis::Client,
+    channel: String,
+    mut pubsub: redis::aio::PubSub,
+    tx: mpsc::Sender<String>,
+) {
+    loop {
+        {
+            let mut messages = pubsub.on_message();
+            loop {
+                tokio::select! {
+                    msg = messages.next() => match msg {
+                        Some(msg) => match msg.get_payload::<String>() {
+                            Ok(payload) => {
+                                if tx.send(payload).await.is_err() {
+                                    return;
+                                }
+                            }
+                            Err(e) => tracing::warn!("kv subscribe {}: {}", channel, e),
+                        },
+                        None => break,
+                    },
+                    _ = tx.closed() => return,
+                }
+            }
+        }
+        tracing::warn!("kv subscribe {}: connection lost, reconnecting", channel);
+        pubsub = loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+                _ = tx.closed() => return,
+            }
+            match client.get_async_pubsub().await {
+                Ok(mut pubsub) => match pubsub.subscribe(&channel).await {
+                    Ok(()) => break pubsub,
+                    Err(e) => tracing::warn!("kv subscribe {}: {}", channel, e),
+                },
+                Err(e) => tracing::warn!("kv subscribe {}: {}", channel, e),
+            }
+        };
+    }
+}
+
+async fn forward_local(
+    mut rx: broadcast::Receiver<String>,
+    channel: String,
+    tx: mpsc::Sender<String>,
+) {
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(payload) => {
+                    if tx.send(payload).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("kv subscribe {}: dropped {} messages", channel, n)
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = tx.closed() => return,
+        }
+    }
+}
+
+pub struct KvSubscription<T> {
+    rx: mpsc::Receiver<String>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Stream for KvSubscription<T>
+where
+    T: DeserializeOwned,
+{
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        loop {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(payload)) => match serde_json::from_str(&payload) {
+                    Ok(msg) => return Poll::Ready(Some(msg)),
+                    // a publisher with another message shape must not end the stream
+                    Err(e) => tracing::warn!("kv subscribe: skipping message: {}", e),
+                },
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
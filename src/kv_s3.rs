@@ -0,0 +1,493 @@
+use std::{env, fmt, time::SystemTime};
+
+use axum::async_trait;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header::HeaderMap, Method, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::aws::{amz_date, hex, hmac, signing_key};
+use crate::kv::{
+    expire_at, not_found_error, now, AnyError, ConflictError, KVStore, NotFoundError, Version,
+};
+
+const EXPIRE_HEADER: &str = "x-amz-meta-toki-expire";
+
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+const UNRESERVED_PATH: &AsciiSet = &UNRESERVED.remove(b'/');
+
+#[derive(Clone)]
+pub struct KVS3 {
+    client: reqwest::Client,
+    bucket: String,
+    prefix: String,
+    region: String,
+    endpoint: String,
+    path_style: bool,
+    lifecycle: bool,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+impl fmt::Debug for KVS3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KVS3")
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .finish()
+    }
+}
+
+struct S3Response {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl KVS3 {
+    pub fn new(bucket: &str, prefix: &str, region: &str) -> KVS3 {
+        KVS3 {
+            client: reqwest::Client::new(),
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            region: region.to_string(),
+            endpoint: format!("https://s3.{}.amazonaws.com", region),
+            path_style: false,
+            lifecycle: false,
+            access_key: env::var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+            secret_key: env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        }
+    }
+    pub fn endpoint(mut self, endpoint: &str) -> KVS3 {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+    pub fn path_style(mut self, path_style: bool) -> KVS3 {
+        self.path_style = path_style;
+        self
+    }
+    pub fn lifecycle(mut self, lifecycle: bool) -> KVS3 {
+        self.lifecycle = lifecycle;
+        self
+    }
+    pub fn credentials(mut self, access_key: &str, secret_key: &str) -> KVS3 {
+        self.access_key = access_key.to_string();
+        self.secret_key = secret_key.to_string();
+        self.session_token = None;
+        self
+    }
+    pub fn open(conn: &str) -> Result<KVS3, AnyError> {
+        let rest = conn.strip_prefix("s3://").ok_or("invalid s3 connection")?;
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            return Err("s3 connection is missing a bucket".into());
+        }
+        let mut region = env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".into());
+        let mut endpoint = env::var("AWS_ENDPOINT_URL").ok();
+        let mut path_style = None;
+        let mut lifecycle = false;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, "1"));
+            let value = percent_encoding::percent_decode_str(value).decode_utf8()?;
+            let flag = matches!(value.as_ref(), "1" | "true" | "yes" | "on");
+            match name {
+                "region" => region = value.into_owned(),
+                "endpoint" => endpoint = Some(value.into_owned()),
+                "path_style" => path_style = Some(flag),
+                "lifecycle" => lifecycle = flag,
+                _ => return Err(format!("unknown s3 option: {}", name).into()),
+            }
+        }
+        let mut kv = KVS3::new(bucket, prefix, &region).lifecycle(lifecycle);
+        if let Some(endpoint) = endpoint {
+            // custom endpoints are mostly self-hosted stores without bucket subdomains
+            kv = kv
+                .endpoint(&endpoint)
+                .path_style(path_style.unwrap_or(true));
+        } else if let Some(path_style) = path_style {
+            kv = kv.path_style(path_style);
+        }
+        Ok(kv)
+    }
+    fn object(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            return key.to_string();
+        }
+        format!("{}/{}", self.prefix, key)
+    }
+    fn url(&self, object: &str) -> Result<(reqwest::Url, String), AnyError> {
+        let object = utf8_percent_encode(object, UNRESERVED_PATH).to_string();
+        let url = match self.path_style {
+            true => format!("{}/{}/{}", self.endpoint, self.bucket, object),
+            false => {
+                let (scheme, host) = self
+                    .endpoint
+                    .split_once("://")
+                    .unwrap_or(("https", &self.endpoint));
+                format!("{}://{}.{}/{}", scheme, self.bucket, host, object)
+            }
+        };
+        let url = reqwest::Url::parse(&url)?;
+        let path = url.path().to_string();
+        Ok((url, path))
+    }
+    async fn request(
+        &self,
+        method: Method,
+        object: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<S3Response, AnyError> {
+        let (mut url, path) = self.url(object)?;
+        let mut query = query
+            .iter()
+            .map(|(k, v)| {
+                (
+                    utf8_percent_encode(k, UNRESERVED).to_string(),
+                    utf8_percent_encode(v, UNRESERVED).to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query(Some(&query).filter(|q| !q.is_empty()).map(|q| q.as_str()));
+
+        let (date, timestamp) = amz_date(SystemTime::now());
+        let payload = hex(&Sha256::digest(&body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+            None => url.host_str().unwrap_or("").to_string(),
+        };
+        let mut signed = vec![
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), payload.clone()),
+            ("x-amz-date".to_string(), timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            signed.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        let mut request = self.client.request(method.clone(), url);
+        for (name, value) in headers {
+            if name.starts_with("x-amz-") {
+                signed.push((name.to_string(), value.clone()));
+            } else {
+                request = request.header(*name, value);
+            }
+        }
+        signed.sort();
+        let signed_names = signed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            path,
+            query,
+            signed
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+                .collect::<String>(),
+            signed_names,
+            payload
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let key = signing_key(&self.secret_key, &date, &self.region, "s3");
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            scope,
+            signed_names,
+            hex(&hmac(&key, &to_sign))
+        );
+        for (name, value) in signed.into_iter().filter(|(name, _)| name != "host") {
+            request = request.header(name, value);
+        }
+        let res = request
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?;
+        Ok(S3Response {
+            status: res.status(),
+            headers: res.headers().clone(),
+            body: res.bytes().await?.to_vec(),
+        })
+    }
+    fn expire_headers(&self, expire: u64) -> Vec<(&'static str, String)> {
+        let mut headers = vec![(EXPIRE_HEADER, expire.to_string())];
+        if self.lifecycle && expire > 0 {
+            // bucket lifecycle rules match on tags and work in whole days
+            let days = expire.saturating_sub(now()).div_ceil(86400).max(1);
+            headers.push(("x-amz-tagging", format!("toki-expire-days={}", days)));
+        }
+        headers
+    }
+    fn check(&self, object: &str, res: &S3Response) -> Result<(), AnyError> {
+        if res.status.is_success() {
+            return Ok(());
+        }
+        if res.status == StatusCode::NOT_FOUND {
+            not_found_error()?;
+        }
+        Err(format!(
+            "s3 request for {} failed with {}: {}",
+            object,
+            res.status,
+            String::from_utf8_lossy(&res.body)
+        )
+        .into())
+    }
+    async fn head(&self, key: &str) -> Result<u64, AnyError> {
+        let object = self.object(key);
+        let res = self
+            .request(Method::HEAD, &object, &[], &[], Vec::new())
+            .await?;
+        self.check(&object, &res)?;
+        live_expire(&res.headers)
+    }
+    async fn set_expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        self.head(key).await?;
+        let object = self.object(key);
+        let source = format!(
+            "/{}/{}",
+            self.bucket,
+            utf8_percent_encode(&object, UNRESERVED_PATH)
+        );
+        let mut headers = self.expire_headers(expire);
+        headers.push(("x-amz-copy-source", source));
+        headers.push(("x-amz-metadata-directive", "REPLACE".to_string()));
+        if self.lifecycle {
+            headers.push(("x-amz-tagging-directive", "REPLACE".to_string()));
+        }
+        let res = self
+            .request(Method::PUT, &object, &[], &headers, Vec::new())
+            .await?;
+        self.check(&object, &res)
+    }
+    async fn put_lock(&self, object: &str, expire: u64) -> Result<bool, AnyError> {
+        let headers = [("if-none-match", "*".to_string())];
+        let res = self
+            .request(
+                Method::PUT,
+                object,
+                &[],
+                &headers,
+                expire.to_string().into_bytes(),
+            )
+            .await?;
+        if res.status == StatusCode::PRECONDITION_FAILED || res.status == StatusCode::CONFLICT {
+            return Ok(false);
+        }
+        self.check(object, &res)?;
+        Ok(true)
+    }
+}
+
+fn live_expire(headers: &HeaderMap) -> Result<u64, AnyError> {
+    let expire = headers
+        .get(EXPIRE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    // expired objects linger until the lifecycle rule or a delete removes them
+    if expire > 0 && expire < now() {
+        not_found_error()?;
+    }
+    Ok(expire)
+}
+
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    xml.split(&open)
+        .skip(1)
+        .filter_map(|part| part.split_once(&close).map(|(value, _)| value))
+        .map(|value| {
+            value
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+#[async_trait]
+impl KVStore for KVS3 {
+    async fn get_raw(&self, key: &str) -> Result<Vec<u8>, AnyError> {
+        let object = self.object(key);
+        let res = self
+            .request(Method::GET, &object, &[], &[], Vec::new())
+            .await?;
+        self.check(&object, &res)?;
+        live_expire(&res.headers)?;
+        Ok(res.body)
+    }
+    async fn set_raw(&self, key: &str, value: &[u8], expire: u64) -> Result<(), AnyError> {
+        let object = self.object(key);
+        let headers = self.expire_headers(expire + now());
+        let res = self
+            .request(Method::PUT, &object, &[], &headers, value.to_vec())
+            .await?;
+        self.check(&object, &res)
+    }
+    async fn del(&self, key: &str) -> Result<(), AnyError> {
+        let object = self.object(key);
+        let res = self
+            .request(Method::DELETE, &object, &[], &[], Vec::new())
+            .await?;
+        self.check(&object, &res)
+    }
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        match self.head(key).await? {
+            0 => Ok(None),
+            expire => Ok(Some(expire.saturating_sub(now()))),
+        }
+    }
+    async fn expire(&self, key: &str, expire: u64) -> Result<(), AnyError> {
+        self.set_expire(key, now() + expire).await
+    }
+    async fn persist(&self, key: &str) -> Result<(), AnyError> {
+        self.set_expire(key, 0).await
+    }
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, AnyError> {
+        let base = self.object("");
+        let search = self.object(prefix);
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", search.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let res = self
+                .request(Method::GET, "", &query, &[], Vec::new())
+                .await?;
+            self.check(&search, &res)?;
+            let xml = String::from_utf8_lossy(&res.body);
+            keys.extend(
+                xml_values(&xml, "Key")
+                    .into_iter()
+                    .filter(|key| !key.ends_with(".lock"))
+                    .filter_map(|key| key.strip_prefix(&base).map(|key| key.to_string())),
+            );
+            token = xml_values(&xml, "NextContinuationToken").into_iter().next();
+            if token.is_none() {
+                break;
+            }
+        }
+        Ok(keys)
+    }
+    async fn del_prefix(&self, prefix: &str, dry_run: bool) -> Result<u64, AnyError> {
+        let keys = self.keys(prefix).await?;
+        if dry_run {
+            return Ok(keys.len() as u64);
+        }
+        let mut count = 0;
+        for key in keys {
+            match self.del(&key).await {
+                Ok(()) => count += 1,
+                Err(e) if e.is::<NotFoundError>() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(count)
+    }
+    async fn lock(&self, key: &str, ttl: u64) -> Result<bool, AnyError> {
+        let object = self.object(&format!("{}.lock", key));
+        if self.put_lock(&object, now() + ttl).await? {
+            return Ok(true);
+        }
+        let res = self
+            .request(Method::GET, &object, &[], &[], Vec::new())
+            .await?;
+        let expire = String::from_utf8_lossy(&res.body)
+            .trim()
+            .parse::<u64>()
+            .unwrap_or(0);
+        if res.status.is_success() && expire >= now() {
+            return Ok(false);
+        }
+        // the previous holder timed out, remove its lock and race for a new one
+        self.request(Method::DELETE, &object, &[], &[], Vec::new())
+            .await?;
+        self.put_lock(&object, now() + ttl).await
+    }
+    async fn unlock(&self, key: &str) -> Result<(), AnyError> {
+        let object = self.object(&format!("{}.lock", key));
+        let res = self
+            .request(Method::DELETE, &object, &[], &[], Vec::new())
+            .await?;
+        self.check(&object, &res)
+    }
+    async fn get_versioned_raw(&self, key: &str) -> Result<(Vec<u8>, Version), AnyError> {
+        let object = self.object(key);
+        let res = self
+            .request(Method::GET, &object, &[], &[], Vec::new())
+            .await?;
+        self.check(&object, &res)?;
+        live_expire(&res.headers)?;
+        Ok((res.body, Version::new(etag(&res.headers))))
+    }
+    async fn set_if_raw(
+        &self,
+        key: &str,
+        value: &[u8],
+        version: &Version,
+        expire: u64,
+    ) -> Result<(), AnyError> {
+        let object = self.object(key);
+        let mut headers = self.expire_headers(expire_at(expire));
+        if version.is_absent() {
+            let res = self
+                .request(Method::HEAD, &object, &[], &[], Vec::new())
+                .await?;
+            match res.status {
+                // an expired object that was not cleaned up yet counts as missing
+                s if s.is_success() && live_expire(&res.headers).is_err() => {
+                    headers.push(("if-match", etag(&res.headers)))
+                }
+                s if s.is_success() => return Err(Box::new(ConflictError {})),
+                _ => headers.push(("if-none-match", "*".to_string())),
+            }
+        } else {
+            headers.push(("if-match", version.to_string()));
+        }
+        let res = self
+            .request(Method::PUT, &object, &[], &headers, value.to_vec())
+            .await?;
+        if res.status == StatusCode::PRECONDITION_FAILED || res.status == StatusCode::CONFLICT {
+            return Err(Box::new(ConflictError {}));
+        }
+        self.check(&object, &res)
+    }
+}
+
+fn etag(headers: &HeaderMap) -> String {
+    headers
+        .get("etag")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
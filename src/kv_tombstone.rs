@@ -0,0 +1,117 @@
+use std::{
+    env,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    kv::{key_prefix, now},
+    AnyError, KVManager, NotFoundError,
+};
+
+// normalized keys never start with an underscore, so tombstones cannot collide with values
+pub(crate) const TOMBSTONE_PREFIX: &str = "_tombstone-";
+
+// seconds, u64::MAX until TOKI_KV_TOMBSTONE_RETENTION has been read, 0 is off
+static RETENTION: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn retention() -> u64 {
+    let retention = RETENTION.load(Ordering::Relaxed);
+    if retention != u64::MAX {
+        return retention;
+    }
+    let retention = env::var("TOKI_KV_TOMBSTONE_RETENTION")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    RETENTION.store(retention, Ordering::Relaxed);
+    retention
+}
+
+// storage key of the tombstone for an already normalized key
+fn tombstone_key(key_normalized: &str) -> String {
+    let prefix = key_prefix();
+    let key = key_normalized
+        .strip_prefix(&prefix)
+        .unwrap_or(key_normalized);
+    format!("{}{}{}", prefix, TOMBSTONE_PREFIX, key)
+}
+
+pub(crate) fn is_tombstone(key: &str) -> bool {
+    key.strip_prefix(&key_prefix())
+        .unwrap_or(key)
+        .starts_with(TOMBSTONE_PREFIX)
+}
+
+// the value is kept exactly as stored, so encrypted values stay encrypted
+#[derive(Debug, Serialize, Deserialize)]
+struct Tombstone {
+    deleted_at: u64,
+    ttl: Option<u64>,
+    value: String,
+}
+
+impl KVManager {
+    // del() keeps deleted values as tombstones for this many seconds so they can
+    // be restored, overrides TOKI_KV_TOMBSTONE_RETENTION, 0 turns it off
+    pub fn set_tombstone_retention(seconds: u64) {
+        RETENTION.store(seconds, Ordering::Relaxed);
+    }
+    // writes the value back with the ttl it had left when it was deleted
+    pub async fn restore(&self, key: &str) -> Result<(), AnyError> {
+        let key_normalized = self.normalize(key);
+        let tombstone = tombstone_key(&key_normalized);
+        let store = self.store();
+        let raw: Tombstone = serde_json::from_slice(&store.get_raw(&tombstone).await?)?;
+        let value = BASE64_STANDARD.decode(raw.value)?;
+        match raw.ttl {
+            Some(ttl) => store.set_raw(&key_normalized, &value, ttl.max(1)).await?,
+            None => {
+                store
+                    .set_raw(&key_normalized, &value, retention().max(60))
+                    .await?;
+                store.persist(&key_normalized).await?;
+            }
+        }
+        store.del(&tombstone).await
+    }
+    // when the key was deleted, None if there is no tombstone for it
+    pub async fn deleted_at(&self, key: &str) -> Result<Option<u64>, AnyError> {
+        let tombstone = tombstone_key(&self.normalize(key));
+        match self.store().get_raw(&tombstone).await {
+            Ok(raw) => Ok(Some(serde_json::from_slice::<Tombstone>(&raw)?.deleted_at)),
+            Err(e) if e.is::<NotFoundError>() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    pub async fn purge_tombstones(&self) -> Result<u64, AnyError> {
+        let prefix = format!("{}{}", key_prefix(), TOMBSTONE_PREFIX);
+        self.store().del_prefix(&prefix, false).await
+    }
+    pub(crate) async fn bury(&self, key_normalized: &str) -> Result<(), AnyError> {
+        let retention = retention();
+        if retention == 0 {
+            return Ok(());
+        }
+        let store = self.store();
+        let value = match store.get_raw(key_normalized).await {
+            Ok(value) => value,
+            Err(e) if e.is::<NotFoundError>() => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let tombstone = Tombstone {
+            deleted_at: now(),
+            ttl: store.ttl(key_normalized).await?,
+            value: BASE64_STANDARD.encode(value),
+        };
+        store
+            .set_raw(
+                &tombstone_key(key_normalized),
+                &serde_json::to_vec(&tombstone)?,
+                retention,
+            )
+            .await
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    kv::{
+        encode_value, jittered, normailze_key, normalize_nested_key, now, ttl_jitter, KVFilesystem,
+        KVRedis,
+    },
+    AnyError, KVManager, KVStore, NotFoundError,
+};
+
+// journals older than this belong to a process that died halfway through
+const STALE_JOURNAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum TxOp {
+    // expiry is absolute so a replayed journal does not extend it
+    Set {
+        key: String,
+        value: String,
+        expire_at: u64,
+    },
+    Del {
+        key: String,
+    },
+    Expire {
+        key: String,
+        expire_at: u64,
+    },
+    Persist {
+        key: String,
+    },
+}
+
+impl TxOp {
+    fn key(&self) -> &str {
+        match self {
+            TxOp::Set { key, .. }
+            | TxOp::Del { key }
+            | TxOp::Expire { key, .. }
+            | TxOp::Persist { key } => key,
+        }
+    }
+}
+
+// operations are only recorded here, nothing is written until the closure returns
+#[derive(Default)]
+pub struct KvTransaction {
+    ops: Vec<TxOp>,
+    error: Option<AnyError>,
+    nested: bool,
+}
+
+impl KvTransaction {
+    fn normalize(&self, key: &str) -> String {
+        match self.nested {
+            true => normalize_nested_key(key),
+            false => normailze_key(key),
+        }
+    }
+    pub fn set<B>(&mut self, key: &str, value: &B, expire: u64) -> &mut KvTransaction
+    where
+        B: Serialize,
+    {
+        let key = self.normalize(key);
+        let value = serde_json::to_vec(value)
+            .map_err(AnyError::from)
+            .and_then(|json| encode_value(&key, json));
+        match value {
+            Ok(value) => self.ops.push(TxOp::Set {
+                key,
+                value: BASE64_STANDARD.encode(value),
+                expire_at: now() + jittered(expire, ttl_jitter()),
+            }),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+    pub fn del(&mut self, key: &str) -> &mut KvTransaction {
+        self.ops.push(TxOp::Del {
+            key: self.normalize(key),
+        });
+        self
+    }
+    pub fn expire(&mut self, key: &str, expire: u64) -> &mut KvTransaction {
+        self.ops.push(TxOp::Expire {
+            key: self.normalize(key),
+            expire_at: now() + expire,
+        });
+        self
+    }
+    pub fn persist(&mut self, key: &str) -> &mut KvTransaction {
+        self.ops.push(TxOp::Persist {
+            key: self.normalize(key),
+        });
+        self
+    }
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl KVManager {
+    // atomic on redis through MULTI/EXEC, on the filesystem the operations are
+    // journaled first and replayed if the process dies halfway, other stores
+    // apply them one by one
+    pub async fn transaction<F>(&self, build: F) -> Result<(), AnyError>
+    where
+        F: FnOnce(&mut KvTransaction),
+    {
+        let mut tx = KvTransaction {
+            nested: self.filesystem().is_some_and(|fs| fs.is_nested()),
+            ..Default::default()
+        };
+        build(&mut tx);
+        if let Some(e) = tx.error {
+            return Err(e);
+        }
+        if tx.ops.is_empty() {
+            return Ok(());
+        }
+        if let Some(redis) = self.redis() {
+            exec_redis(redis, &tx.ops).await?;
+        } else if let Some(fs) = self.filesystem() {
+            exec_filesystem(fs, &tx.ops).await?;
+        } else {
+            apply(self.store(), &tx.ops).await?;
+        }
+        if let KVManager::KVTiered(tiered) = self {
+            for op in &tx.ops {
+                tiered.forget(op.key());
+                if let Err(e) = tiered.publish(op.key()).await {
+                    tracing::warn!("kv invalidation of {} failed: {}", op.key(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn exec_redis(redis: &KVRedis, ops: &[TxOp]) -> Result<(), AnyError> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    let now = now();
+    for op in ops {
+        match op {
+            TxOp::Set {
+                key,
+                value,
+                expire_at,
+            } => {
+                let value = BASE64_STANDARD.decode(value)?;
+                pipe.set_ex(key, value, expire_at.saturating_sub(now).max(1))
+                    .ignore();
+            }
+            TxOp::Del { key } => {
+                pipe.del(key).ignore();
+            }
+            TxOp::Expire { key, expire_at } => {
+                pipe.expire(key, expire_at.saturating_sub(now).max(1) as i64)
+                    .ignore();
+            }
+            TxOp::Persist { key } => {
+                pipe.persist(key).ignore();
+            }
+        }
+    }
+    let mut con = redis.connection().await?;
+    // a nil reply means the server aborted the transaction
+    let res: Option<()> = pipe.query_async(&mut con).await?;
+    res.ok_or_else(|| "kv transaction aborted".into())
+}
+
+async fn exec_filesystem(fs: &KVFilesystem, ops: &[TxOp]) -> Result<(), AnyError> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    replay_journals(fs).await;
+    let journal = format!(
+        "{}/{}-{}-{}.txn",
+        fs.path(),
+        now(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let tmp = format!("{}.tmp", journal);
+    tokio::fs::write(&tmp, serde_json::to_vec(ops)?).await?;
+    tokio::fs::rename(&tmp, &journal).await?;
+    let res = apply(fs, ops).await;
+    // a failed transaction keeps its journal so a later one finishes the job
+    if res.is_ok() {
+        tokio::fs::remove_file(&journal).await?;
+    }
+    res
+}
+
+async fn replay_journals(fs: &KVFilesystem) {
+    let Ok(mut dir) = tokio::fs::read_dir(fs.path()).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "txn") {
+            continue;
+        }
+        let stale = entry
+            .metadata()
+            .await
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age >= STALE_JOURNAL);
+        if !stale {
+            continue;
+        }
+        let ops = match tokio::fs::read(&path).await {
+            Ok(journal) => serde_json::from_slice::<Vec<TxOp>>(&journal),
+            Err(_) => continue,
+        };
+        match ops {
+            Ok(ops) => match apply(fs, &ops).await {
+                Ok(()) => tracing::warn!("kv transaction {} replayed", path.display()),
+                Err(e) => {
+                    tracing::error!("kv transaction {} replay failed: {}", path.display(), e);
+                    continue;
+                }
+            },
+            Err(e) => tracing::error!("kv transaction {} is corrupt: {}", path.display(), e),
+        }
+        tokio::fs::remove_file(&path).await.unwrap_or(());
+    }
+}
+
+async fn apply(store: &dyn KVStore, ops: &[TxOp]) -> Result<(), AnyError> {
+    let now = now();
+    for op in ops {
+        let res = match op {
+            TxOp::Set {
+                key,
+                value,
+                expire_at,
+            } => {
+                // already expired by the time a journal is replayed
+                if *expire_at <= now {
+                    store.del(key).await
+                } else {
+                    let value = BASE64_STANDARD.decode(value)?;
+                    store.set_raw(key, &value, expire_at - now).await
+                }
+            }
+            TxOp::Del { key } => store.del(key).await,
+            TxOp::Expire { key, expire_at } => {
+                store
+                    .expire(key, expire_at.saturating_sub(now).max(1))
+                    .await
+            }
+            TxOp::Persist { key } => store.persist(key).await,
+        };
+        // same as redis, touching a missing key is not an error inside a transaction
+        match res {
+            Err(e) if !is_not_found(&e) => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn is_not_found(e: &AnyError) -> bool {
+    e.is::<NotFoundError>()
+        || e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
@@ -0,0 +1,108 @@
+use std::{fmt::Display, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{AnyError, KVManager};
+
+// fn() -> T keeps the key Send + Sync and usable in a const whatever T is
+pub struct TypedKey<T> {
+    format: &'static str,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedKey<T> {
+    pub const fn new(format: &'static str) -> TypedKey<T> {
+        TypedKey {
+            format,
+            value: PhantomData,
+        }
+    }
+    pub fn format(&self) -> &'static str {
+        self.format
+    }
+    // fills the first {} placeholder, chain `with` again for keys with several
+    pub fn with<A: Display>(&self, arg: A) -> BoundKey<T> {
+        self.key().with(arg)
+    }
+    pub fn key(&self) -> BoundKey<T> {
+        BoundKey {
+            key: self.format.to_string(),
+            value: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for TypedKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for TypedKey<T> {}
+
+pub struct BoundKey<T> {
+    key: String,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> BoundKey<T> {
+    pub fn with<A: Display>(mut self, arg: A) -> BoundKey<T> {
+        if let Some(idx) = self.key.find("{}") {
+            self.key.replace_range(idx..idx + 2, &arg.to_string());
+        }
+        self
+    }
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+    fn checked(&self) -> Result<&str, AnyError> {
+        if self.key.contains("{}") {
+            return Err(format!("kv key {} has unfilled placeholders", self.key).into());
+        }
+        Ok(&self.key)
+    }
+}
+
+impl<T> Clone for BoundKey<T> {
+    fn clone(&self) -> Self {
+        BoundKey {
+            key: self.key.clone(),
+            value: PhantomData,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for BoundKey<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("BoundKey").field(&self.key).finish()
+    }
+}
+
+impl KVManager {
+    pub async fn get_typed<T>(&self, key: &BoundKey<T>) -> Result<T, AnyError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.get::<T>(key.checked()?).await
+    }
+    pub async fn get_some_typed<T>(&self, key: &BoundKey<T>) -> Result<Option<T>, AnyError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.get_some::<T>(key.checked()?).await
+    }
+    pub async fn set_typed<T>(
+        &self,
+        key: &BoundKey<T>,
+        value: &T,
+        expire: u64,
+    ) -> Result<(), AnyError>
+    where
+        T: Serialize + DeserializeOwned + Sync,
+    {
+        self.set(key.checked()?, value, expire).await
+    }
+    pub async fn del_typed<T>(&self, key: &BoundKey<T>) -> Result<(), AnyError> {
+        self.del(key.checked()?).await
+    }
+}
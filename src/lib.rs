@@ -1,17 +1,258 @@
+pub mod admin;
+pub mod build_info;
 pub mod listener;
+#[cfg(unix)]
+mod sd_notify;
+pub mod startup;
+#[cfg(unix)]
+pub use sd_notify::sd_notify;
+#[cfg(unix)]
+mod upgrade;
+
+#[cfg(feature = "acme")]
+mod acme;
+#[cfg(feature = "mtls")]
+mod mtls;
+#[cfg(feature = "mtls")]
+pub use mtls::ClientCert;
 
 #[macro_use]
 mod error;
-pub use error::{AnyError, SimpleError};
+pub use error::{AnyError, SimpleContext, SimpleError};
 
 #[macro_use]
 mod response;
-pub use response::{HeaderJson, HeaderResponse, SimpleJson, SimpleResponse, SimpleStatus};
+pub use response::{
+    accepted_json, created_json, no_content, ok_json, CacheLookup, HeaderJson, HeaderResponse,
+    LastEventId, NdJsonStream, Redirect301, Redirect302, RedirectSeeOther, SimpleJson,
+    SimpleResponse, SimpleStatus, SimpleStream, SseEvent, SseStream,
+};
+#[cfg(feature = "csv")]
+pub use response::{CsvStream, SimpleCsv};
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+mod codec;
+#[cfg(feature = "cbor")]
+pub use codec::Cbor;
+#[cfg(feature = "msgpack")]
+pub use codec::MsgPack;
+#[cfg(feature = "cbor")]
+pub use response::SimpleCbor;
+#[cfg(feature = "msgpack")]
+pub use response::SimpleMsgPack;
+
+#[cfg(feature = "cors")]
+mod cors;
+#[cfg(feature = "cors")]
+pub use cors::Cors;
+
+#[cfg(feature = "compression")]
+mod compression;
+#[cfg(feature = "compression")]
+pub use compression::{CompressPredicate, Compression};
+
+mod limits;
+pub use limits::{Limits, LimitsLayer};
+
+mod negotiate;
+pub use negotiate::{AcceptFormat, Negotiate};
+
+mod locale;
+pub use locale::{AcceptLanguage, Locale, Locales};
+
+mod maintenance;
+pub use maintenance::{Maintenance, MaintenanceLayer, MaintenanceService, MaintenanceState};
+
+mod panic;
+pub use panic::{CatchPanicLayer, CatchPanicService};
+
+mod timeout;
+pub use timeout::{TimeoutLayer, TimeoutService};
+
+mod problem;
+pub use problem::ProblemDetails;
+
+mod static_files;
+pub use static_files::{static_files, StaticFiles};
+
+mod vhost;
+pub use vhost::VirtualHosts;
+
+mod trailing_slash;
+pub use trailing_slash::{TrailingSlashRedirect, TrailingSlashService};
+
+mod breaker;
+pub use breaker::{
+    BreakerEvent, BreakerObserver, BreakerOutcome, BreakerState, CallTimeoutError, CircuitBreaker,
+    CircuitOpenError,
+};
+
+#[cfg(feature = "ws")]
+mod ws;
+#[cfg(feature = "ws")]
+pub use ws::{WsClosedError, WsConnection, WsConnectionInfo, WsHub, WsMessage};
+
+#[cfg(feature = "csrf")]
+mod csrf;
+#[cfg(feature = "csrf")]
+pub use csrf::{Csrf, CsrfLayer, CsrfService, CsrfToken};
+
+mod download;
+pub use download::FileDownload;
+
+mod validate;
+pub use validate::{
+    FieldError, Validate, ValidatedJson, ValidatedQuery, ValidationErrors, ValidationRejection,
+};
+
+mod pagination;
+pub use pagination::{Enveloped, Paginated, Pagination};
 
 mod realip;
-pub use realip::RealIP;
+pub use realip::{
+    parse_forwarded, parse_ip_port, ClientAddr, ForwardedInfo, RealIP, RealIpHeaders,
+};
+
+mod trace_http;
+pub use trace_http::{trace_http, TraceHttp, TraceHttpLayer};
+
+mod request_id;
+pub use request_id::{current_request_id, RequestIdLayer, RequestIdService};
+
+mod access_log;
+pub use access_log::{AccessLog, AccessLogFormat, AccessLogLayer, AccessLogService};
 
 #[cfg(feature = "kv")]
 mod kv;
 #[cfg(feature = "kv")]
-pub use kv::{KVFilesystem, KVManager, KVRedis, KVTrait, KvGetOrInitResult};
+pub use kv::{
+    ConflictError, KVFilesystem, KVManager, KVRedis, KVStore, KVTiered, KVTrait, KvGetOrInitResult,
+    KvUnavailable, NotFoundError, RedisRetry, Version,
+};
+
+#[cfg(feature = "kv")]
+mod kv_migrate;
+#[cfg(feature = "kv")]
+pub use kv_migrate::{KvMigration, MigrationProgress};
+#[cfg(feature = "kv")]
+mod kv_iter;
+#[cfg(feature = "kv")]
+mod kv_tombstone;
+
+#[cfg(feature = "kv")]
+mod kv_codec;
+#[cfg(feature = "kv")]
+pub use kv_codec::{Codec, KvCodec};
+
+#[cfg(feature = "kv")]
+mod kv_transaction;
+#[cfg(feature = "kv")]
+pub use kv_transaction::KvTransaction;
+
+#[cfg(feature = "kv")]
+mod kv_pubsub;
+#[cfg(feature = "kv")]
+pub use kv_pubsub::KvSubscription;
+
+#[cfg(feature = "kv-encrypt")]
+mod kv_encrypt;
+#[cfg(feature = "kv-encrypt")]
+pub use kv_encrypt::KvKeyring;
+
+#[cfg(feature = "kv")]
+mod kv_typed;
+#[cfg(feature = "kv")]
+pub use kv_typed::{BoundKey, TypedKey};
+
+#[cfg(any(feature = "kv-s3", feature = "kv-dynamodb"))]
+mod aws;
+
+#[cfg(feature = "kv-s3")]
+mod kv_s3;
+#[cfg(feature = "kv-s3")]
+pub use kv_s3::KVS3;
+
+#[cfg(feature = "kv-consul")]
+mod kv_consul;
+#[cfg(feature = "kv-consul")]
+pub use kv_consul::{KVConsul, KvChange, KvWatch};
+
+#[cfg(feature = "kv-dynamodb")]
+mod kv_dynamo;
+#[cfg(feature = "kv-dynamodb")]
+pub use kv_dynamo::KVDynamo;
+
+#[cfg(feature = "kv")]
+mod kv_observer;
+#[cfg(feature = "kv")]
+pub use kv_observer::{KvEvent, KvObserver, KvOperation, KvOutcome};
+
+#[cfg(feature = "kv")]
+mod flags;
+#[cfg(feature = "kv")]
+pub use flags::{Flag, FlagContext, Flags};
+
+#[cfg(feature = "cache")]
+mod cache;
+#[cfg(feature = "cache")]
+pub use cache::{CacheLayer, CacheService};
+
+#[cfg(feature = "kv")]
+mod idempotency;
+#[cfg(feature = "kv")]
+pub use idempotency::{IdempotencyLayer, IdempotencyService};
+
+#[cfg(feature = "kv")]
+mod ratelimit;
+#[cfg(feature = "kv")]
+pub use ratelimit::{RateLimit, RateLimitAlgorithm, RateLimitLayer};
+
+#[cfg(feature = "queue")]
+mod queue;
+#[cfg(feature = "queue")]
+pub use queue::{Queue, QueuedJob};
+
+#[cfg(feature = "webhooks")]
+mod webhooks;
+#[cfg(feature = "webhooks")]
+pub use webhooks::{WebhookTarget, Webhooks};
+
+#[cfg(feature = "config")]
+mod config;
+#[cfg(feature = "config")]
+pub use config::{duration, Config, Configured, EnvConfig, Settings, SettingsHandle};
+
+#[cfg(feature = "auth")]
+mod auth;
+#[cfg(feature = "auth")]
+pub use auth::{BasicAuth, BasicCredentials, BearerToken, BearerTokens, JwtAuth, JwtClaims};
+
+#[cfg(feature = "apikey")]
+mod apikey;
+#[cfg(feature = "apikey")]
+pub use apikey::{ApiKey, ApiKeyInfo, ApiKeys};
+
+#[cfg(feature = "sentry")]
+mod sentry_http;
+#[cfg(feature = "sentry")]
+pub use sentry_http::{sentry_tracing_layer, SentryLayer, SentryService};
+
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "otel")]
+pub use otel::{inject_trace_context, otel_shutdown, otel_tracing_layer, OtelLayer, OtelService};
+
+#[cfg(feature = "upload")]
+mod upload;
+#[cfg(feature = "upload")]
+pub use upload::{Upload, UploadOptions, UploadedFile};
+
+#[cfg(feature = "log-reload")]
+mod log_level;
+#[cfg(feature = "log-reload")]
+pub use log_level::{log_level_layer, LogLevel};
+
+#[cfg(feature = "jobs")]
+mod jobs;
+#[cfg(feature = "jobs")]
+pub use jobs::{JobStatus, Jobs};
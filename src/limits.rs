@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    io,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::future::BoxFuture;
+use http_body_util::Limited;
+use tower::{Layer, Service};
+
+use crate::SimpleStatus;
+
+#[derive(Debug, Clone, Default)]
+pub struct LimitsLayer {
+    max_body: Option<usize>,
+}
+
+impl LimitsLayer {
+    pub fn new() -> LimitsLayer {
+        LimitsLayer::default()
+    }
+    pub fn max_body(mut self, max_body: usize) -> LimitsLayer {
+        self.max_body = Some(max_body);
+        self
+    }
+    pub(crate) fn from_options(
+        options: &HashMap<String, String>,
+    ) -> io::Result<Option<LimitsLayer>> {
+        let max_body = options.get("max_body").map(|v| parse_size(v)).transpose()?;
+        Ok(max_body.map(|max_body| LimitsLayer {
+            max_body: Some(max_body),
+        }))
+    }
+}
+
+pub(crate) fn parse_size(value: &str) -> io::Result<usize> {
+    let lower = value.trim().to_ascii_lowercase();
+    let (number, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => lower.split_at(idx),
+        None => (lower.as_str(), ""),
+    };
+    let unit = match unit.trim_end_matches('b') {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => 0,
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .filter(|_| unit > 0)
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid size: {}", value),
+            )
+        })
+}
+
+impl<S> Layer<S> for LimitsLayer {
+    type Service = Limits<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Limits {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Limits<S> {
+    inner: S,
+    layer: LimitsLayer,
+}
+
+impl<S> Service<Request<Body>> for Limits<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let req = match layer.max_body {
+                Some(max_body) => {
+                    let length = req
+                        .headers()
+                        .get(header::CONTENT_LENGTH)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse::<u64>().ok());
+                    if length.is_some_and(|length| length > max_body as u64) {
+                        return Ok(SimpleStatus::new(StatusCode::PAYLOAD_TOO_LARGE).into_response());
+                    }
+                    // chunked bodies are cut off once they grow past the limit
+                    req.map(|body| Body::new(Limited::new(body, max_body)))
+                }
+                None => req,
+            };
+            inner.call(req).await
+        })
+    }
+}
@@ -1,50 +1,141 @@
-use axum::{extract::connect_info, Router};
-use hyper::server::conn::AddrStream;
+use axum::{
+    body::Bytes,
+    extract::{connect_info, ConnectInfo},
+    http::Request,
+    serve::IncomingStream,
+    Router,
+};
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
 use listenfd::ListenFd;
-use std::{net::SocketAddr, str::FromStr};
-use tokio::signal;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    signal,
+    sync::{watch, Notify},
+};
+use tower::ServiceExt;
 
-#[cfg(unix)]
-use hyperlocal::UnixServerExt;
+use crate::{
+    limits::parse_size,
+    response::{accepts_trailers, scope_trailers},
+    startup, CatchPanicLayer, LimitsLayer, RequestIdLayer, SimpleError, TimeoutLayer,
+};
+
+pub(crate) type Shutdown = Shared<BoxFuture<'static, ()>>;
 
 pub async fn listen<F>(addr: &str, app: F) -> anyhow::Result<()>
 where
     F: FnOnce(&str) -> Router,
 {
-    if addr.starts_with("fd:") {
-        let mut listenfd = ListenFd::from_env();
-        let listener = listenfd.take_tcp_listener(0);
-        if listener.is_err() {
-            tracing::error!("listenfd faild: {}", listener.unwrap_err().to_string());
-            std::process::exit(2101);
+    listen_until(addr, app, async {
+        shutdown_signal().await;
+        trigger_shutdown();
+    })
+    .await
+}
+
+// serves a second router on an internal address, e.g. metrics and health checks
+// on a unix socket or loopback port that is never exposed publicly
+pub async fn listen_with_admin<F>(
+    addr: &str,
+    app: F,
+    admin_addr: &str,
+    admin: Router,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(&str) -> Router,
+{
+    listen_until_with_admin(addr, app, admin_addr, admin, async {
+        shutdown_signal().await;
+        trigger_shutdown();
+    })
+    .await
+}
+
+pub async fn listen_until_with_admin<F, S>(
+    addr: &str,
+    app: F,
+    admin_addr: &str,
+    admin: Router,
+    shutdown: S,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(&str) -> Router,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let shutdown: Shutdown = shutdown.boxed().shared();
+    let (main, admin) = tokio::join!(
+        listen_until(addr, app, shutdown.clone()),
+        listen_until(admin_addr, |_| admin, shutdown),
+    );
+    main.and(admin)
+}
+
+pub async fn listen_until<F, S>(addr: &str, app: F, shutdown: S) -> anyhow::Result<()>
+where
+    F: FnOnce(&str) -> Router,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let shutdown: Shutdown = shutdown.boxed().shared();
+    let options = match ServeOptions::parse(addr) {
+        Ok(options) => options,
+        Err(e) => {
+            tracing::error!("invalid options for {}: {}", addr, e);
+            std::process::exit(2001);
         }
-        let listener = listener.unwrap();
-        if listener.is_none() {
-            tracing::error!("listenfd faild: no listener");
-            std::process::exit(2102);
-        }
-        let s = axum::Server::from_tcp(listener.unwrap());
-        let app = app("fd:tcp");
-        let server = s
-            .unwrap()
-            .serve(app.into_make_service_with_connect_info::<IpConnectInfo>())
-            .with_graceful_shutdown(shutdown_signal());
-        if let Err(e) = server.await {
-            tracing::error!("server faild to start: {}", e);
-            std::process::exit(3);
-        }
-    } else if addr.starts_with("fd+unix:") {
-        #[cfg(not(unix))]
-        {
-            tracing::error!("unix socket is not supported on this platform");
-            std::process::exit(9);
+    };
+    let app = |addr: &str| options.apply(app(addr));
+    if addr.starts_with("fd:") || addr.starts_with("fd+unix:") {
+        let fd = split_options(addr).0;
+        let (unix, spec) = match fd.strip_prefix("fd+unix:") {
+            Some(spec) => (true, spec),
+            None => (false, fd.strip_prefix("fd:").unwrap()),
+        };
+        let mut listenfd = ListenFd::from_env();
+        if spec == "all" || spec == "*" {
+            let app = app("fd:all");
+            serve_all_fds(&mut listenfd, app, &options, shutdown).await;
+            return Ok(());
         }
-        #[cfg(unix)]
-        {
-            let mut listenfd = ListenFd::from_env();
-            let listener = listenfd.take_unix_listener(0);
-            if listener.is_err() {
-                tracing::error!("listenfd faild: {}", listener.unwrap_err().to_string());
+        let idx = if spec.is_empty() || spec == "tcp" || spec == "unix" {
+            0
+        } else {
+            match spec.parse::<usize>() {
+                Ok(idx) => idx,
+                Err(_) => {
+                    tracing::error!("invalid listenfd index: {}", spec);
+                    std::process::exit(2103);
+                }
+            }
+        };
+        if !unix {
+            let listener = listenfd.take_tcp_listener(idx);
+            if let Err(e) = listener {
+                tracing::error!("listenfd faild: {}", e.to_string());
                 std::process::exit(2101);
             }
             let listener = listener.unwrap();
@@ -52,20 +143,67 @@ where
                 tracing::error!("listenfd faild: no listener");
                 std::process::exit(2102);
             }
-            let listener = listener.unwrap();
-            listener.set_nonblocking(true).expect("Couldn't set non blocking");
-            let s = axum::Server::builder(hyperlocal::SocketIncoming::from_listener(
-                tokio::net::UnixListener::from_std(listener).unwrap(),
-            ));
-            let app = app("fd:unix");
-            let server = s
-                .serve(app.into_make_service_with_connect_info::<IpConnectInfo>())
-                .with_graceful_shutdown(shutdown_signal());
-            if let Err(e) = server.await {
-                tracing::error!("server faild to start: {}", e);
-                std::process::exit(3);
+            let listener = tcp_from_std(listener.unwrap());
+            startup::listening(describe_tcp(&format!("fd:{}", idx), &listener));
+            let app = app("fd:tcp");
+            serve(
+                || accept_tcp(&listener, &options.tcp),
+                app,
+                &options,
+                shutdown,
+            )
+            .await;
+        } else {
+            #[cfg(not(unix))]
+            {
+                tracing::error!("unix socket is not supported on this platform");
+                std::process::exit(9);
+            }
+            #[cfg(unix)]
+            {
+                let listener = listenfd.take_unix_listener(idx);
+                if let Err(e) = listener {
+                    tracing::error!("listenfd faild: {}", e.to_string());
+                    std::process::exit(2101);
+                }
+                let listener = listener.unwrap();
+                if listener.is_none() {
+                    tracing::error!("listenfd faild: no listener");
+                    std::process::exit(2102);
+                }
+                let listener = unix_from_std(listener.unwrap());
+                startup::listening(format!("fd+unix:{}", idx));
+                let app = app("fd:unix");
+                serve(|| accept_unix(&listener), app, &options, shutdown).await;
             }
         }
+    } else if addr.starts_with("acme:") {
+        #[cfg(not(feature = "acme"))]
+        {
+            tracing::error!("acme support is not enabled");
+            std::process::exit(9);
+        }
+        #[cfg(feature = "acme")]
+        crate::acme::listen_acme(addr, app, &options, shutdown).await;
+    } else if addr.starts_with("pipe:") {
+        #[cfg(not(windows))]
+        {
+            tracing::error!("named pipes are not supported on this platform");
+            std::process::exit(9);
+        }
+        #[cfg(windows)]
+        {
+            let listener = match PipeListener::bind(split_options(addr).0) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::error!("unable to bind to {}: {}", addr, e);
+                    std::process::exit(2501);
+                }
+            };
+            startup::listening(listener.name.clone());
+            let app = app(addr);
+            serve(|| listener.accept(), app, &options, shutdown).await;
+        }
     } else if addr.starts_with("unix:") {
         #[cfg(not(unix))]
         {
@@ -74,74 +212,1122 @@ where
         }
         #[cfg(unix)]
         {
-            let path = std::path::Path::new(addr.strip_prefix("unix:").unwrap());
-            if path.exists() {
-                std::fs::remove_file(path).unwrap_or(());
-            }
-            let s = axum::Server::bind_unix(path);
-            if s.is_err() {
-                tracing::error!("unable to bind to {}", addr);
+            use std::os::fd::AsRawFd;
+
+            let listener = match crate::upgrade::inherit(addr) {
+                Some(fd) => Ok(unix_from_std(fd.into())),
+                None => bind_unix(addr.strip_prefix("unix:").unwrap()),
+            };
+            if let Err(e) = listener {
+                tracing::error!("unable to bind to {}: {}", addr, e);
                 std::process::exit(2201);
             }
+            let listener = listener.unwrap();
+            let shutdown = crate::upgrade::register(addr, listener.as_raw_fd(), shutdown);
+            startup::listening(split_options(addr).0.to_string());
             let app = app(addr);
-            let server = s
-                .unwrap()
-                .serve(app.into_make_service_with_connect_info::<IpConnectInfo>())
-                .with_graceful_shutdown(shutdown_signal());
-            if let Err(e) = server.await {
-                tracing::error!("server faild to start: {}", e);
-                std::process::exit(3);
-            }
+            serve(|| accept_unix(&listener), app, &options, shutdown).await;
         }
     } else {
-        let s = SocketAddr::from_str(addr).unwrap();
-        let s = axum::Server::try_bind(&s);
-        if s.is_err() {
-            tracing::error!("unable to bind to {}", addr);
+        #[cfg(unix)]
+        let listener = match crate::upgrade::inherit(addr) {
+            Some(fd) => Ok(tcp_from_std(fd.into())),
+            None => bind_tcp(addr).and_then(TcpListener::from_std),
+        };
+        #[cfg(not(unix))]
+        let listener = bind_tcp(addr).and_then(TcpListener::from_std);
+        if let Err(e) = listener {
+            tracing::error!("unable to bind to {}: {}", addr, e);
             std::process::exit(2301);
         }
-        let app = app(addr);
-        let server = s
-            .unwrap()
-            .serve(app.into_make_service_with_connect_info::<IpConnectInfo>())
-            .with_graceful_shutdown(shutdown_signal());
-        if let Err(e) = server.await {
-            tracing::error!("server faild to start: {}", e);
-            std::process::exit(3);
-        }
+        let listener = listener.unwrap();
+        #[cfg(unix)]
+        let shutdown = {
+            use std::os::fd::AsRawFd;
+            crate::upgrade::register(addr, listener.as_raw_fd(), shutdown)
+        };
+        startup::listening(describe_tcp(split_options(addr).0, &listener));
+        let app = app(&bound(addr, &listener));
+        serve(
+            || accept_tcp(&listener, &options.tcp),
+            app,
+            &options,
+            shutdown,
+        )
+        .await;
     }
     Ok(())
 }
 
+pub(crate) fn describe_tcp(addr: &str, listener: &TcpListener) -> String {
+    match listener.local_addr() {
+        Ok(local) if local.to_string() != addr => format!("{} ({})", addr, local),
+        _ => addr.to_string(),
+    }
+}
+
+fn tcp_from_std(listener: std::net::TcpListener) -> TcpListener {
+    listener
+        .set_nonblocking(true)
+        .expect("Couldn't set non blocking");
+    TcpListener::from_std(listener).unwrap()
+}
+
+#[cfg(unix)]
+fn unix_from_std(listener: std::os::unix::net::UnixListener) -> tokio::net::UnixListener {
+    listener
+        .set_nonblocking(true)
+        .expect("Couldn't set non blocking");
+    tokio::net::UnixListener::from_std(listener).unwrap()
+}
+
+async fn serve_all_fds(
+    listenfd: &mut ListenFd,
+    app: Router,
+    options: &ServeOptions,
+    shutdown: Shutdown,
+) {
+    let mut servers: Vec<Pin<Box<dyn Future<Output = ()> + Send>>> = Vec::new();
+    for idx in 0..listenfd.len() {
+        if let Ok(Some(listener)) = listenfd.take_tcp_listener(idx) {
+            let listener = tcp_from_std(listener);
+            startup::listening(describe_tcp(&format!("fd:{}", idx), &listener));
+            let app = app.clone();
+            let options = options.clone();
+            let shutdown = shutdown.clone();
+            servers.push(Box::pin(async move {
+                serve(
+                    || accept_tcp(&listener, &options.tcp),
+                    app,
+                    &options,
+                    shutdown,
+                )
+                .await
+            }));
+            continue;
+        }
+        #[cfg(unix)]
+        if let Ok(Some(listener)) = listenfd.take_unix_listener(idx) {
+            let listener = unix_from_std(listener);
+            startup::listening(format!("fd+unix:{}", idx));
+            let app = app.clone();
+            let options = options.clone();
+            let shutdown = shutdown.clone();
+            servers.push(Box::pin(async move {
+                serve(|| accept_unix(&listener), app, &options, shutdown).await
+            }));
+            continue;
+        }
+        tracing::warn!("listenfd: skipping unsupported socket at index {}", idx);
+    }
+    if servers.is_empty() {
+        tracing::error!("listenfd faild: no listener");
+        std::process::exit(2102);
+    }
+    futures_util::future::join_all(servers).await;
+}
+
+pub(crate) async fn accept_tcp(
+    listener: &TcpListener,
+    tcp: &TcpOptions,
+) -> io::Result<(tokio::net::TcpStream, IpConnectInfo)> {
+    let (stream, addr) = listener.accept().await?;
+    if let Err(e) = tcp.apply(&stream) {
+        tracing::debug!("unable to tune connection from {}: {}", addr, e);
+    }
+    let info = IpConnectInfo::new(addr.ip().to_string(), addr.port());
+    Ok((stream, info))
+}
+
+#[cfg(unix)]
+async fn accept_unix(
+    listener: &tokio::net::UnixListener,
+) -> io::Result<(tokio::net::UnixStream, IpConnectInfo)> {
+    let (stream, _) = listener.accept().await?;
+    let info =
+        <IpConnectInfo as connect_info::Connected<&tokio::net::UnixStream>>::connect_info(&stream);
+    Ok((stream, info))
+}
+
+// a named pipe instance serves a single client, so a fresh instance is
+// created for the next client as soon as one connects
+#[cfg(windows)]
+struct PipeListener {
+    name: String,
+    next: tokio::sync::Mutex<tokio::net::windows::named_pipe::NamedPipeServer>,
+}
+
+#[cfg(windows)]
+impl PipeListener {
+    fn bind(addr: &str) -> io::Result<PipeListener> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let name = addr.strip_prefix("pipe:").unwrap_or(addr);
+        let name = match name.starts_with(r"\\") {
+            true => name.to_string(),
+            false => format!(r"\\.\pipe\{}", name),
+        };
+        let first = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&name)?;
+        Ok(PipeListener {
+            name,
+            next: tokio::sync::Mutex::new(first),
+        })
+    }
+    async fn accept(
+        &self,
+    ) -> io::Result<(
+        tokio::net::windows::named_pipe::NamedPipeServer,
+        IpConnectInfo,
+    )> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let mut next = self.next.lock().await;
+        next.connect().await?;
+        let stream = std::mem::replace(&mut *next, ServerOptions::new().create(&self.name)?);
+        let info = <IpConnectInfo as connect_info::Connected<
+            &tokio::net::windows::named_pipe::NamedPipeServer,
+        >>::connect_info(&stream);
+        Ok((stream, info))
+    }
+}
+
+async fn serve<A, Fut, I>(accept: A, app: Router, options: &ServeOptions, shutdown: Shutdown)
+where
+    A: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<(I, IpConnectInfo)>>,
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    serve_with(
+        accept,
+        |stream, info| std::future::ready(Ok(Some((stream, info)))),
+        app,
+        options,
+        shutdown,
+    )
+    .await
+}
+
+// `handshake` runs on the connection task before hyper takes over the stream,
+// returning `None` when the connection was fully handled there, it may also
+// fill in what it learned about the peer, like a tls client certificate
+pub(crate) async fn serve_with<A, Fut, I, H, HFut, T>(
+    mut accept: A,
+    handshake: H,
+    app: Router,
+    options: &ServeOptions,
+    shutdown: Shutdown,
+) where
+    A: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<(I, IpConnectInfo)>>,
+    I: Send + 'static,
+    H: Fn(I, IpConnectInfo) -> HFut,
+    HFut: Future<Output = io::Result<Option<(T, IpConnectInfo)>>> + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let builder = options.builder();
+    let upgrades = options.protocol == HttpProtocol::Auto;
+    let limits = options.connection;
+    // every connection holds a receiver, the sender sees all of them gone once drained
+    let (closing, _) = watch::channel(false);
+    let active = Active::default();
+    startup::report_once();
+    #[cfg(unix)]
+    crate::sd_notify::ready();
+    let shutdown = async {
+        tokio::select! {
+            _ = shutdown => {},
+            _ = shutdown_requested() => {},
+        }
+    };
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, info) = tokio::select! {
+            conn = accept() => match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    tracing::error!("accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let app = app.clone();
+        let builder = builder.clone();
+        let closing = closing.subscribe();
+        let handshake = handshake(stream, info);
+        let active = active.clone();
+        tokio::spawn(async move {
+            let _connection = ActiveGuard::new(&active.connections);
+            let (stream, info) = match handshake.await {
+                Ok(Some(conn)) => conn,
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::debug!("handshake failed: {}", e);
+                    return;
+                }
+            };
+            let state = Arc::new(ConnectionState::default());
+            let service = {
+                let state = state.clone();
+                hyper::service::service_fn(move |req: Request<Incoming>| {
+                    let mut req = req.map(|body| ReadTimeout::new(body, limits.read_timeout));
+                    req.extensions_mut().insert(ConnectInfo(info.clone()));
+                    // counted until the response head is ready, a streaming body is
+                    // still covered by its connection
+                    let request = ActiveGuard::new(&active.requests);
+                    let in_flight = state.started();
+                    let trailers = accepts_trailers(req.headers());
+                    scope_trailers(trailers, app.clone().oneshot(req)).inspect(move |_| {
+                        drop(request);
+                        drop(in_flight);
+                    })
+                })
+            };
+            let io = TokioIo::new(stream);
+            // hyper-util ignores a forced protocol on upgradable connections, so
+            // restricting the protocol gives up HTTP/1 upgrades
+            let res = match upgrades {
+                true => {
+                    let conn = builder.serve_connection_with_upgrades(io, service);
+                    drive(
+                        conn,
+                        |conn| conn.graceful_shutdown(),
+                        closing,
+                        state,
+                        limits,
+                    )
+                    .await
+                }
+                false => {
+                    let conn = builder.serve_connection(io, service);
+                    drive(
+                        conn,
+                        |conn| conn.graceful_shutdown(),
+                        closing,
+                        state,
+                        limits,
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = res {
+                tracing::debug!("connection error: {}", e);
+            }
+        });
+    }
+    #[cfg(unix)]
+    crate::sd_notify::stopping();
+    // the accept loop is gone, hyper now closes idle keep-alive connections, answers
+    // with connection: close on HTTP/1 and sends GOAWAY on HTTP/2
+    let (connections, requests) = active.counts();
+    if connections > 0 {
+        tracing::info!(
+            "draining {} connections with {} requests in flight",
+            connections,
+            requests
+        );
+    }
+    closing.send_replace(true);
+    match options.drain.or_else(drain_timeout) {
+        Some(timeout) => {
+            if tokio::time::timeout(timeout, closing.closed())
+                .await
+                .is_err()
+            {
+                let (connections, requests) = active.counts();
+                tracing::warn!(
+                    "drain timeout reached, closing {} connections and cutting off {} requests",
+                    connections,
+                    requests
+                );
+            }
+        }
+        None => closing.closed().await,
+    }
+}
+
+// runs a connection to the end, closing it gracefully on shutdown, once it has
+// been idle for too long or once it has served its share of requests. hyper
+// finishes the requests in flight, answers with connection: close on HTTP/1
+// and sends GOAWAY on HTTP/2
+async fn drive<C, F, E>(
+    conn: C,
+    mut close: F,
+    mut closing: watch::Receiver<bool>,
+    state: Arc<ConnectionState>,
+    limits: ConnectionLimits,
+) -> Result<(), E>
+where
+    C: Future<Output = Result<(), E>>,
+    F: FnMut(Pin<&mut C>),
+{
+    tokio::pin!(conn);
+    tokio::select! {
+        res = conn.as_mut() => return res,
+        _ = closing.wait_for(|closing| *closing) => {},
+        _ = state.exhausted(limits), if limits.is_set() => {},
+    }
+    close(conn.as_mut());
+    conn.await
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnectionLimits {
+    idle_timeout: Option<Duration>,
+    max_requests: Option<usize>,
+    read_timeout: Option<Duration>,
+}
+
+impl ConnectionLimits {
+    fn parse(options: &HashMap<String, String>) -> io::Result<ConnectionLimits> {
+        let max_requests = options
+            .get("max_requests")
+            .map(|v| {
+                v.parse::<usize>()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid value for max_requests: {}", v),
+                        )
+                    })
+            })
+            .transpose()?;
+        Ok(ConnectionLimits {
+            idle_timeout: option_duration(options, "idle_timeout")?,
+            max_requests,
+            read_timeout: option_duration(options, "read_timeout")?,
+        })
+    }
+    fn is_set(&self) -> bool {
+        self.idle_timeout.is_some() || self.max_requests.is_some()
+    }
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    in_flight: AtomicUsize,
+    served: AtomicUsize,
+    activity: Notify,
+}
+
+impl ConnectionState {
+    fn started(self: &Arc<Self>) -> InFlight {
+        self.served.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.activity.notify_waiters();
+        InFlight(self.clone())
+    }
+    // resolves once the connection went idle for too long or used up its requests
+    async fn exhausted(&self, limits: ConnectionLimits) {
+        loop {
+            let activity = self.activity.notified();
+            if limits
+                .max_requests
+                .is_some_and(|max| self.served.load(Ordering::Relaxed) >= max)
+            {
+                return;
+            }
+            match limits.idle_timeout {
+                Some(idle) if self.in_flight.load(Ordering::Relaxed) == 0 => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(idle) => return,
+                        _ = activity => {},
+                    }
+                }
+                _ => activity.await,
+            }
+        }
+    }
+}
+
+struct InFlight(Arc<ConnectionState>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.activity.notify_waiters();
+    }
+}
+
+// fails the request body when the client sends nothing for the timeout, only
+// runs while the handler is actually waiting for the body
+struct ReadTimeout {
+    inner: Incoming,
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl ReadTimeout {
+    fn new(inner: Incoming, timeout: Option<Duration>) -> ReadTimeout {
+        ReadTimeout {
+            inner,
+            timeout,
+            sleep: None,
+        }
+    }
+}
+
+impl http_body::Body for ReadTimeout {
+    type Data = Bytes;
+    type Error = axum::BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Bytes>, Self::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                this.sleep = None;
+                Poll::Ready(frame.map(|frame| frame.map_err(Into::into)))
+            }
+            Poll::Pending => {
+                let Some(timeout) = this.timeout else {
+                    return Poll::Pending;
+                };
+                let sleep = this
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "request body read timed out",
+                    )
+                    .into()))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[derive(Default, Clone)]
+struct Active {
+    connections: Arc<AtomicUsize>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl Active {
+    fn counts(&self) -> (usize, usize) {
+        (
+            self.connections.load(Ordering::Relaxed),
+            self.requests.load(Ordering::Relaxed),
+        )
+    }
+}
+
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(counter: &Arc<AtomicUsize>) -> ActiveGuard {
+        counter.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard(counter.clone())
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ServeOptions {
+    pub(crate) tcp: TcpOptions,
+    protocol: HttpProtocol,
+    http2: Http2Options,
+    header_timeout: Option<Duration>,
+    connection: ConnectionLimits,
+    drain: Option<Duration>,
+    timeout: Option<Duration>,
+    limits: Option<LimitsLayer>,
+    catch_panic: bool,
+    #[cfg(feature = "cors")]
+    cors: Option<tower_http::cors::CorsLayer>,
+    #[cfg(feature = "compression")]
+    compression: Option<crate::Compression>,
+    info_token: Option<String>,
+}
+
+impl ServeOptions {
+    fn parse(addr: &str) -> io::Result<ServeOptions> {
+        let (_, options) = split_options(addr);
+        Ok(ServeOptions {
+            tcp: TcpOptions::parse(&options)?,
+            protocol: HttpProtocol::parse(&options)?,
+            http2: Http2Options::parse(&options)?,
+            header_timeout: option_duration(&options, "header_timeout")?,
+            connection: ConnectionLimits::parse(&options)?,
+            drain: option_duration(&options, "drain")?,
+            timeout: option_duration(&options, "timeout")?.or_else(request_timeout),
+            limits: LimitsLayer::from_options(&options)?,
+            catch_panic: option_flag(&options, "catch_panic")?.unwrap_or(true),
+            #[cfg(feature = "cors")]
+            cors: option_flag(&options, "cors")?
+                .unwrap_or(false)
+                .then(|| crate::Cors::from_env().layer()),
+            #[cfg(feature = "compression")]
+            compression: option_flag(&options, "compress")?
+                .unwrap_or(false)
+                .then(crate::Compression::from_env),
+            info_token: startup::info_token(),
+        })
+    }
+    fn apply(&self, mut app: Router) -> Router {
+        if let Some(token) = self.info_token.clone() {
+            app = app.route(
+                "/__info",
+                axum::routing::get(move |headers| startup::info_handler(token.clone(), headers)),
+            );
+        }
+        // a TimeoutLayer rather than part of LimitsLayer, so routes can override it
+        if let Some(timeout) = self.timeout {
+            app = app.layer(TimeoutLayer::new(timeout));
+        }
+        if self.catch_panic {
+            app = app.layer(CatchPanicLayer::new());
+        }
+        if let Some(limits) = &self.limits {
+            app = app.layer(limits.clone());
+        }
+        #[cfg(feature = "compression")]
+        if let Some(compression) = &self.compression {
+            app = app.layer(compression.layer());
+        }
+        #[cfg(feature = "cors")]
+        if let Some(cors) = &self.cors {
+            app = app.layer(cors.clone());
+        }
+        // redacted errors point at the request id instead
+        if SimpleError::redacts() {
+            app = app.layer(RequestIdLayer::new());
+        }
+        app
+    }
+    // protocols offered through ALPN on TLS listeners
+    #[cfg(feature = "acme")]
+    pub(crate) fn alpn(&self) -> Vec<Vec<u8>> {
+        match self.protocol {
+            HttpProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            HttpProtocol::Http1 => vec![b"http/1.1".to_vec()],
+            HttpProtocol::Http2 => vec![b"h2".to_vec()],
+        }
+    }
+    fn builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        // auto detects the HTTP/2 preface, so h2c with prior knowledge works without configuration
+        builder = match self.protocol {
+            HttpProtocol::Auto => builder,
+            HttpProtocol::Http1 => builder.http1_only(),
+            HttpProtocol::Http2 => builder.http2_only(),
+        };
+        self.http2.apply(&mut builder);
+        if let Some(timeout) = self.header_timeout {
+            // drops clients that never finish sending their request headers
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .header_read_timeout(timeout);
+        }
+        builder
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum HttpProtocol {
+    #[default]
+    Auto,
+    Http1,
+    Http2,
+}
+
+impl HttpProtocol {
+    fn parse(options: &HashMap<String, String>) -> io::Result<HttpProtocol> {
+        match options.get("protocol").map(|v| v.as_str()) {
+            None | Some("auto") => Ok(HttpProtocol::Auto),
+            Some("http1" | "http1.1" | "http/1.1") => Ok(HttpProtocol::Http1),
+            Some("http2" | "h2" | "h2c") => Ok(HttpProtocol::Http2),
+            Some(v) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid value for protocol: {}", v),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Http2Options {
+    stream_window: Option<u32>,
+    connection_window: Option<u32>,
+    adaptive_window: Option<bool>,
+    max_streams: Option<u32>,
+    max_frame: Option<u32>,
+}
+
+impl Http2Options {
+    fn parse(options: &HashMap<String, String>) -> io::Result<Http2Options> {
+        let size = |key: &str| {
+            options
+                .get(key)
+                .map(|v| {
+                    parse_size(v)
+                        .ok()
+                        .and_then(|size| u32::try_from(size).ok())
+                        .ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("invalid value for {}: {}", key, v),
+                            )
+                        })
+                })
+                .transpose()
+        };
+        Ok(Http2Options {
+            stream_window: size("h2_stream_window")?,
+            connection_window: size("h2_connection_window")?,
+            adaptive_window: option_flag(options, "h2_adaptive_window")?,
+            max_streams: size("h2_max_streams")?,
+            max_frame: size("h2_max_frame")?,
+        })
+    }
+    fn apply(&self, builder: &mut auto::Builder<TokioExecutor>) {
+        let mut http2 = builder.http2();
+        if let Some(size) = self.stream_window {
+            http2.initial_stream_window_size(size);
+        }
+        if let Some(size) = self.connection_window {
+            http2.initial_connection_window_size(size);
+        }
+        if let Some(adaptive) = self.adaptive_window {
+            http2.adaptive_window(adaptive);
+        }
+        if let Some(max) = self.max_streams {
+            http2.max_concurrent_streams(max);
+        }
+        if let Some(size) = self.max_frame {
+            http2.max_frame_size(size);
+        }
+    }
+}
+
+fn request_timeout() -> Option<Duration> {
+    env::var("TOKI_REQUEST_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|secs| *secs > 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+fn drain_timeout() -> Option<Duration> {
+    env::var("TOKI_DRAIN_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .map(Duration::from_secs_f64)
+}
+
+pub(crate) fn split_options(addr: &str) -> (&str, HashMap<String, String>) {
+    match addr.split_once('?') {
+        Some((addr, query)) => (
+            addr,
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| match pair.split_once('=') {
+                    Some((k, v)) => (k.to_string(), v.to_string()),
+                    None => (pair.to_string(), "true".to_string()),
+                })
+                .collect(),
+        ),
+        None => (addr, HashMap::new()),
+    }
+}
+
+pub(crate) fn option_flag(
+    options: &HashMap<String, String>,
+    key: &str,
+) -> io::Result<Option<bool>> {
+    match options.get(key).map(|v| v.as_str()) {
+        None => Ok(None),
+        Some("1" | "true" | "yes" | "on") => Ok(Some(true)),
+        Some("0" | "false" | "no" | "off") => Ok(Some(false)),
+        Some(v) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid value for {}: {}", key, v),
+        )),
+    }
+}
+
+pub(crate) fn option_duration(
+    options: &HashMap<String, String>,
+    key: &str,
+) -> io::Result<Option<Duration>> {
+    options
+        .get(key)
+        .map(|v| {
+            v.parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid value for {}: {}", key, v),
+                    )
+                })
+        })
+        .transpose()
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TcpOptions {
+    v6only: Option<bool>,
+    reuseaddr: Option<bool>,
+    reuseport: bool,
+    backlog: Option<i32>,
+    recv_buffer: Option<usize>,
+    send_buffer: Option<usize>,
+    nodelay: Option<bool>,
+    keepalive: Option<TcpKeepalive>,
+}
+
+impl TcpOptions {
+    fn parse(options: &HashMap<String, String>) -> io::Result<TcpOptions> {
+        let invalid = |key: &str, v: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid value for {}: {}", key, v),
+            )
+        };
+        let size = |key: &str| {
+            options
+                .get(key)
+                .map(|v| parse_size(v).map_err(|_| invalid(key, v)))
+                .transpose()
+        };
+        let backlog = options
+            .get("backlog")
+            .map(|v| v.parse::<i32>().map_err(|_| invalid("backlog", v)))
+            .transpose()?;
+        let idle = option_duration(options, "keepalive_idle")?;
+        let interval = option_duration(options, "keepalive_interval")?;
+        let count = options
+            .get("keepalive_count")
+            .map(|v| v.parse::<u32>().map_err(|_| invalid("keepalive_count", v)))
+            .transpose()?;
+        // tuning any keepalive knob turns keepalive on unless it was disabled explicitly
+        let tuned = idle.is_some() || interval.is_some() || count.is_some();
+        let keepalive = match option_flag(options, "keepalive")?.unwrap_or(tuned) {
+            true => Some(keepalive(idle, interval, count)?),
+            false => None,
+        };
+        Ok(TcpOptions {
+            v6only: option_flag(options, "v6only")?,
+            reuseaddr: option_flag(options, "reuseaddr")?,
+            reuseport: option_flag(options, "reuseport")?.unwrap_or(false),
+            backlog,
+            recv_buffer: size("recv_buffer")?,
+            send_buffer: size("send_buffer")?,
+            nodelay: option_flag(options, "nodelay")?,
+            keepalive,
+        })
+    }
+    fn apply(&self, stream: &tokio::net::TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            socket2::SockRef::from(stream).set_tcp_keepalive(keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+fn keepalive(
+    idle: Option<Duration>,
+    interval: Option<Duration>,
+    count: Option<u32>,
+) -> io::Result<TcpKeepalive> {
+    let mut keepalive = TcpKeepalive::new();
+    if let Some(idle) = idle {
+        keepalive = keepalive.with_time(idle);
+    }
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd"
+    ))]
+    {
+        if let Some(interval) = interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        if let Some(count) = count {
+            keepalive = keepalive.with_retries(count);
+        }
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd"
+    )))]
+    if interval.is_some() || count.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "keepalive_interval and keepalive_count are not supported on this platform",
+        ));
+    }
+    Ok(keepalive)
+}
+
+pub(crate) fn bind_tcp(addr: &str) -> io::Result<std::net::TcpListener> {
+    let (addr, options) = split_options(addr);
+    let tcp = TcpOptions::parse(&options)?;
+    if let Some(port) = addr.strip_prefix(':') {
+        let port = u16::from_str(port)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let v6 = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+        let dual = TcpOptions {
+            v6only: Some(tcp.v6only.unwrap_or(false)),
+            ..tcp.clone()
+        };
+        return match bind_socket(v6, &dual) {
+            Ok(listener) => Ok(listener),
+            Err(e) => {
+                tracing::warn!("ipv6 unavailable ({}), listening on ipv4 only", e);
+                bind_socket(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), &tcp)
+            }
+        };
+    }
+    let addr = SocketAddr::from_str(addr)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    bind_socket(addr, &tcp)
+}
+
+fn bind_socket(addr: SocketAddr, tcp: &TcpOptions) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        if let Some(v6only) = tcp.v6only {
+            socket.set_only_v6(v6only)?;
+        }
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(tcp.reuseaddr.unwrap_or(true))?;
+    #[cfg(not(unix))]
+    if let Some(reuseaddr) = tcp.reuseaddr {
+        socket.set_reuse_address(reuseaddr)?;
+    }
+    // lets a new process bind the same port while the old one drains
+    #[cfg(unix)]
+    if tcp.reuseport {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    if tcp.reuseport {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reuseport is not supported on this platform",
+        ));
+    }
+    // buffer sizes have to be set before listen so the window scale is negotiated
+    if let Some(size) = tcp.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = tcp.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(tcp.backlog.unwrap_or(1024))?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix(addr: &str) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (path, options) = split_options(addr);
+    let path = std::path::Path::new(path);
+    let mode = options
+        .get("mode")
+        .map(|mode| u32::from_str_radix(mode, 8))
+        .transpose()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let owner = options.get("owner").map(|v| resolve_user(v)).transpose()?;
+    let group = options.get("group").map(|v| resolve_group(v)).transpose()?;
+    if path.exists() {
+        std::fs::remove_file(path).unwrap_or(());
+    }
+    if mode.is_none() && owner.is_none() && group.is_none() {
+        return tokio::net::UnixListener::bind(path);
+    }
+    // bind to a temporary name and rename once the permissions are in place,
+    // so no client can connect before they apply
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    if tmp.exists() {
+        std::fs::remove_file(&tmp).unwrap_or(());
+    }
+    let listener = tokio::net::UnixListener::bind(&tmp)?;
+    let applied = (|| {
+        if owner.is_some() || group.is_some() {
+            std::os::unix::fs::chown(&tmp, owner, group)?;
+        }
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode))?;
+        }
+        std::fs::rename(&tmp, path)
+    })();
+    if let Err(e) = applied {
+        std::fs::remove_file(&tmp).unwrap_or(());
+        return Err(e);
+    }
+    Ok(listener)
+}
+
+#[cfg(unix)]
+fn resolve_user(user: &str) -> io::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = std::ffi::CString::new(user)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user: {}", user),
+        ));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+#[cfg(unix)]
+fn resolve_group(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group: {}", group),
+        ));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
 #[derive(Clone, Debug)]
 pub struct IpConnectInfo {
     pub ip: String,
     pub port: u16,
+    #[cfg(feature = "mtls")]
+    pub client_cert: Option<std::sync::Arc<crate::ClientCert>>,
+}
+impl IpConnectInfo {
+    pub(crate) fn new(ip: String, port: u16) -> IpConnectInfo {
+        IpConnectInfo {
+            ip,
+            port,
+            #[cfg(feature = "mtls")]
+            client_cert: None,
+        }
+    }
 }
 impl std::fmt::Display for IpConnectInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}:{}", self.ip, self.port)
     }
 }
-impl connect_info::Connected<&AddrStream> for IpConnectInfo {
-    fn connect_info(target: &AddrStream) -> Self {
+impl connect_info::Connected<IncomingStream<'_>> for IpConnectInfo {
+    fn connect_info(target: IncomingStream<'_>) -> Self {
         let ip = target.remote_addr().ip().to_string();
         let port = target.remote_addr().port();
-        Self { ip, port }
+        Self::new(ip, port)
     }
 }
 
 #[cfg(unix)]
 impl connect_info::Connected<&tokio::net::UnixStream> for IpConnectInfo {
     fn connect_info(_target: &tokio::net::UnixStream) -> Self {
-        Self {
-            ip: "127.0.0.0".to_string(),
-            port: 0,
-        }
+        Self::new("127.0.0.0".to_string(), 0)
+    }
+}
+
+#[cfg(windows)]
+impl connect_info::Connected<&tokio::net::windows::named_pipe::NamedPipeServer> for IpConnectInfo {
+    fn connect_info(_target: &tokio::net::windows::named_pipe::NamedPipeServer) -> Self {
+        Self::new("127.0.0.0".to_string(), 0)
     }
 }
 
-async fn shutdown_signal() {
+fn bound_sender() -> &'static watch::Sender<HashMap<String, SocketAddr>> {
+    static BOUND: OnceLock<watch::Sender<HashMap<String, SocketAddr>>> = OnceLock::new();
+    BOUND.get_or_init(|| watch::channel(HashMap::new()).0)
+}
+
+// records the address the listener actually got and returns the address the
+// app closure sees, `127.0.0.1:0` becomes e.g. `127.0.0.1:40123`, options kept
+fn bound(addr: &str, listener: &TcpListener) -> String {
+    let Ok(local) = listener.local_addr() else {
+        return addr.to_string();
+    };
+    let (host, _) = split_options(addr);
+    bound_sender().send_modify(|bound| {
+        bound.insert(host.to_string(), local);
+    });
+    if !host.ends_with(":0") {
+        return addr.to_string();
+    }
+    match addr.split_once('?') {
+        Some((_, query)) => format!("{}?{}", local, query),
+        None => local.to_string(),
+    }
+}
+
+// the address a tcp listen() call bound to, looked up by the address it was
+// given, so `:0` listeners can be found by tests and service registration
+pub fn bound_addr(addr: &str) -> Option<SocketAddr> {
+    bound_sender().borrow().get(split_options(addr).0).copied()
+}
+
+// like bound_addr, waiting for a listen() running elsewhere to bind
+pub async fn wait_bound(addr: &str) -> SocketAddr {
+    let addr = split_options(addr).0;
+    let mut rx = bound_sender().subscribe();
+    let bound = rx
+        .wait_for(|bound| bound.contains_key(addr))
+        .await
+        .expect("bound addresses are never dropped");
+    bound[addr]
+}
+
+fn shutdown_sender() -> &'static watch::Sender<bool> {
+    static SHUTDOWN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+    SHUTDOWN.get_or_init(|| watch::channel(false).0)
+}
+
+pub fn trigger_shutdown() {
+    shutdown_sender().send_replace(true);
+}
+
+pub async fn shutdown_requested() {
+    let mut rx = shutdown_sender().subscribe();
+    let _ = rx.wait_for(|shutdown| *shutdown).await;
+}
+
+pub async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -161,8 +1347,43 @@ async fn shutdown_signal() {
     #[cfg(not(unix))]
     let terminate = std::future::pending::<()>();
 
+    #[cfg(unix)]
+    let extra = async {
+        let mut signals = Vec::new();
+        for name in env::var("TOKI_SHUTDOWN_SIGNALS")
+            .unwrap_or_default()
+            .split(',')
+        {
+            let (kind, name) = match name.trim().to_ascii_lowercase().trim_start_matches("sig") {
+                "" => continue,
+                "quit" => (signal::unix::SignalKind::quit(), "SIGQUIT"),
+                "usr1" => (signal::unix::SignalKind::user_defined1(), "SIGUSR1"),
+                "usr2" => (signal::unix::SignalKind::user_defined2(), "SIGUSR2"),
+                "hup" => (signal::unix::SignalKind::hangup(), "SIGHUP"),
+                "int" => (signal::unix::SignalKind::interrupt(), "SIGINT"),
+                other => {
+                    tracing::warn!("unknown shutdown signal: {}", other);
+                    continue;
+                }
+            };
+            let mut stream = signal::unix::signal(kind).expect("failed to install signal handler");
+            signals.push(Box::pin(async move {
+                stream.recv().await;
+                tracing::info!("{} received, exiting...", name);
+            }));
+        }
+        if signals.is_empty() {
+            return std::future::pending().await;
+        }
+        futures_util::future::select_all(signals).await;
+    };
+
+    #[cfg(not(unix))]
+    let extra = std::future::pending::<()>();
+
     tokio::select! {
         _ = ctrl_c => {},
         _ = terminate => {},
+        _ = extra => {},
     }
 }
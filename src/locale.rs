@@ -0,0 +1,200 @@
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap},
+    Extension,
+};
+
+use crate::{AnyError, SimpleError};
+
+// requested language tags, highest q first, ties keep the order they were sent in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AcceptLanguage(pub Vec<(String, f32)>);
+
+impl AcceptLanguage {
+    pub fn from_headers(headers: &HeaderMap) -> AcceptLanguage {
+        let mut tags = headers
+            .get_all(header::ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let tag = parts.next().unwrap_or_default().trim();
+                let q = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && q > 0.0).then(|| (tag.to_string(), q))
+            })
+            .collect::<Vec<_>>();
+        // stable, so equal weights stay in header order
+        tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+        AcceptLanguage(tags)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AcceptLanguage
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(AcceptLanguage::from_headers(&parts.headers))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Locales {
+    default: String,
+    supported: Vec<String>,
+    messages: HashMap<String, HashMap<String, String>>,
+}
+
+impl Locales {
+    // the default is always supported and is what every lookup falls back to
+    pub fn new(default: &str) -> Locales {
+        Locales {
+            default: default.to_string(),
+            supported: vec![default.to_string()],
+            messages: HashMap::new(),
+        }
+    }
+    pub fn supported(mut self, locale: &str) -> Locales {
+        if !self
+            .supported
+            .iter()
+            .any(|l| l.eq_ignore_ascii_case(locale))
+        {
+            self.supported.push(locale.to_string());
+        }
+        self
+    }
+    pub fn messages(mut self, locale: &str, messages: HashMap<String, String>) -> Locales {
+        self = self.supported(locale);
+        self.messages
+            .entry(locale.to_ascii_lowercase())
+            .or_default()
+            .extend(messages);
+        self
+    }
+    // one `<locale>.json` per locale, nested objects become dotted keys
+    pub fn load_dir(mut self, dir: &str) -> Result<Locales, AnyError> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(locale) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let json = serde_json::from_slice::<serde_json::Value>(&std::fs::read(&path)?)
+                .map_err(|e| format!("invalid messages in {}: {}", path.display(), e))?;
+            let mut messages = HashMap::new();
+            flatten("", &json, &mut messages);
+            self = self.messages(locale, messages);
+        }
+        Ok(self)
+    }
+    pub fn extension(self) -> Extension<Arc<Locales>> {
+        Extension(Arc::new(self))
+    }
+    // exact tags first, then the same language in another region, then the default
+    pub fn negotiate(&self, accept: &AcceptLanguage) -> &str {
+        for (tag, _) in &accept.0 {
+            if tag == "*" {
+                return &self.default;
+            }
+            if let Some(locale) = self.supported.iter().find(|l| l.eq_ignore_ascii_case(tag)) {
+                return locale;
+            }
+            let wanted = language(tag);
+            if let Some(locale) = self
+                .supported
+                .iter()
+                .find(|l| language(l).eq_ignore_ascii_case(wanted))
+            {
+                return locale;
+            }
+        }
+        &self.default
+    }
+    fn lookup(&self, locale: &str, key: &str) -> Option<&str> {
+        [locale, language(locale), &self.default]
+            .into_iter()
+            .filter_map(|locale| self.messages.get(&locale.to_ascii_lowercase()))
+            .find_map(|messages| messages.get(key))
+            .map(|message| message.as_str())
+    }
+}
+
+fn language(tag: &str) -> &str {
+    tag.split(['-', '_']).next().unwrap_or(tag)
+}
+
+fn flatten(prefix: &str, value: &serde_json::Value, out: &mut HashMap<String, String>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = match prefix {
+                    "" => key.clone(),
+                    prefix => format!("{}.{}", prefix, key),
+                };
+                flatten(&key, value, out);
+            }
+        }
+        serde_json::Value::String(message) => {
+            out.insert(prefix.to_string(), message.clone());
+        }
+        value => {
+            out.insert(prefix.to_string(), value.to_string());
+        }
+    }
+}
+
+// the locale chosen for this request, needs `Locales::extension()` on the router
+#[derive(Debug, Clone)]
+pub struct Locale {
+    pub tag: String,
+    locales: Arc<Locales>,
+}
+
+impl Locale {
+    // missing messages fall back to the language, then the default locale, then the key
+    pub fn t(&self, key: &str) -> String {
+        self.locales
+            .lookup(&self.tag, key)
+            .unwrap_or(key)
+            .to_string()
+    }
+    // fills `{name}` placeholders
+    pub fn format(&self, key: &str, args: &[(&str, &str)]) -> String {
+        args.iter().fold(self.t(key), |message, (name, value)| {
+            message.replace(&format!("{{{}}}", name), value)
+        })
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Locale
+where
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let locales = parts
+            .extensions
+            .get::<Arc<Locales>>()
+            .cloned()
+            .ok_or_else(|| SimpleError::internal("locales are not installed"))?;
+        let tag = locales
+            .negotiate(&AcceptLanguage::from_headers(&parts.headers))
+            .to_string();
+        Ok(Locale { tag, locales })
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+};
+
+use axum::{http::StatusCode, routing::get, Router};
+use tracing::Subscriber;
+use tracing_subscriber::{reload, EnvFilter};
+
+use crate::{AnyError, SimpleError};
+
+type Apply = Arc<dyn Fn(EnvFilter) -> Result<(), AnyError> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct LogLevel {
+    apply: Apply,
+    initial: String,
+    current: Arc<Mutex<String>>,
+}
+
+// add the returned layer to the subscriber, the handle changes its filter at runtime
+pub fn log_level_layer<S>() -> (reload::Layer<EnvFilter, S>, LogLevel)
+where
+    S: Subscriber + 'static,
+{
+    let initial = env::var("RUST_LOG")
+        .ok()
+        .filter(|v| EnvFilter::try_new(v).is_ok())
+        .unwrap_or_else(|| "info".to_string());
+    let (layer, handle) = reload::Layer::new(EnvFilter::new(&initial));
+    let level = LogLevel {
+        apply: Arc::new(move |filter| Ok(handle.reload(filter)?)),
+        current: Arc::new(Mutex::new(initial.clone())),
+        initial,
+    };
+    (layer, level)
+}
+
+impl LogLevel {
+    pub fn current(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+    pub fn set(&self, directives: &str) -> Result<(), AnyError> {
+        let directives = directives.trim();
+        (self.apply)(EnvFilter::try_new(directives)?)?;
+        *self.current.lock().unwrap() = directives.to_string();
+        tracing::info!("log level set to {}", directives);
+        Ok(())
+    }
+    pub fn reset(&self) -> Result<(), AnyError> {
+        self.set(&self.initial.clone())
+    }
+    // SIGUSR1 switches to debug, the next one goes back to the startup level
+    pub fn toggle_on_sigusr1(&self) {
+        #[cfg(unix)]
+        {
+            let level = self.clone();
+            tokio::spawn(async move {
+                let mut usr1 =
+                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+                        .expect("failed to install SIGUSR1 handler");
+                while usr1.recv().await.is_some() {
+                    let res = match level.current() == level.initial {
+                        true => level.set("debug"),
+                        false => level.reset(),
+                    };
+                    if let Err(e) = res {
+                        tracing::error!("log level switch failed: {}", e);
+                    }
+                }
+            });
+        }
+    }
+    // mount behind the app's own authentication, the route does not check access
+    pub fn router(&self) -> Router {
+        let show = self.clone();
+        let set = self.clone();
+        Router::new().route(
+            "/__log_level",
+            get(move || async move { show.current() }).put(move |body: String| async move {
+                set.set(&body).map_err(|e| {
+                    SimpleError::wrap(e, StatusCode::BAD_REQUEST).context("invalid log level")
+                })?;
+                Ok::<_, SimpleError>(set.current())
+            }),
+        )
+    }
+}
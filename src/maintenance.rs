@@ -0,0 +1,278 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+
+use crate::{AcceptFormat, AnyError};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl MaintenanceState {
+    pub fn new() -> MaintenanceState {
+        MaintenanceState::default()
+    }
+    pub fn message(mut self, message: &str) -> MaintenanceState {
+        self.message = Some(message.to_string());
+        self
+    }
+    pub fn retry_after(mut self, seconds: u64) -> MaintenanceState {
+        self.retry_after = Some(seconds);
+        self
+    }
+}
+
+#[derive(Clone)]
+enum Source {
+    #[cfg(feature = "kv")]
+    Kv(Box<crate::KVManager>, String),
+    File(PathBuf),
+}
+
+type Cached = Option<(Instant, Option<MaintenanceState>)>;
+
+#[derive(Clone)]
+pub struct Maintenance {
+    source: Source,
+    allow: Vec<String>,
+    message: String,
+    retry_after: u64,
+    page: Option<String>,
+    ttl: Duration,
+    cache: Arc<Mutex<Cached>>,
+}
+
+impl Maintenance {
+    #[cfg(feature = "kv")]
+    pub fn kv(kv: crate::KVManager) -> Maintenance {
+        Maintenance::new(Source::Kv(Box::new(kv), "maintenance".to_string()))
+    }
+    // the file existing turns maintenance on, it may hold a json MaintenanceState or a plain message
+    pub fn file<P: Into<PathBuf>>(path: P) -> Maintenance {
+        Maintenance::new(Source::File(path.into()))
+    }
+    fn new(source: Source) -> Maintenance {
+        Maintenance {
+            source,
+            allow: Vec::new(),
+            message: "Service is under maintenance".to_string(),
+            retry_after: 300,
+            page: None,
+            ttl: Duration::from_secs(1),
+            cache: Default::default(),
+        }
+    }
+    #[cfg(feature = "kv")]
+    pub fn key(mut self, key: &str) -> Maintenance {
+        if let Source::Kv(_, current) = &mut self.source {
+            *current = key.to_string();
+        }
+        self
+    }
+    // path prefixes that keep working, e.g. health checks
+    pub fn allow(mut self, prefix: &str) -> Maintenance {
+        self.allow.push(prefix.to_string());
+        self
+    }
+    pub fn message(mut self, message: &str) -> Maintenance {
+        self.message = message.to_string();
+        self
+    }
+    pub fn retry_after(mut self, seconds: u64) -> Maintenance {
+        self.retry_after = seconds;
+        self
+    }
+    // served to browsers instead of the default page, {message} is replaced
+    pub fn page(mut self, html: &str) -> Maintenance {
+        self.page = Some(html.to_string());
+        self
+    }
+    pub fn cache_ttl(mut self, ttl: Duration) -> Maintenance {
+        self.ttl = ttl;
+        self
+    }
+    pub fn layer(&self) -> MaintenanceLayer {
+        MaintenanceLayer {
+            maintenance: self.clone(),
+        }
+    }
+    async fn read(&self) -> Result<Option<MaintenanceState>, AnyError> {
+        match &self.source {
+            #[cfg(feature = "kv")]
+            Source::Kv(kv, key) => kv.get_some::<MaintenanceState>(key).await,
+            Source::File(path) => match tokio::fs::read_to_string(path).await {
+                Ok(contents) => Ok(Some(
+                    serde_json::from_str::<MaintenanceState>(&contents).unwrap_or_else(|_| {
+                        MaintenanceState {
+                            message: Some(contents.trim().to_string())
+                                .filter(|message| !message.is_empty()),
+                            retry_after: None,
+                        }
+                    }),
+                )),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+    pub async fn status(&self) -> Result<Option<MaintenanceState>, AnyError> {
+        if let Some((loaded, state)) = self.cache.lock().unwrap().as_ref() {
+            if loaded.elapsed() < self.ttl {
+                return Ok(state.clone());
+            }
+        }
+        let state = self.read().await?;
+        *self.cache.lock().unwrap() = Some((Instant::now(), state.clone()));
+        Ok(state)
+    }
+    pub async fn enable(&self, state: &MaintenanceState) -> Result<(), AnyError> {
+        match &self.source {
+            #[cfg(feature = "kv")]
+            Source::Kv(kv, key) => {
+                // set always takes an expiry, the switch stays on until disabled
+                kv.set(key, state, 86400).await?;
+                kv.persist(key).await?;
+            }
+            Source::File(path) => tokio::fs::write(path, serde_json::to_vec(state)?).await?,
+        }
+        *self.cache.lock().unwrap() = Some((Instant::now(), Some(state.clone())));
+        tracing::warn!("maintenance mode enabled");
+        Ok(())
+    }
+    pub async fn disable(&self) -> Result<(), AnyError> {
+        match &self.source {
+            #[cfg(feature = "kv")]
+            Source::Kv(kv, key) => kv.del(key).await?,
+            Source::File(path) => match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+        *self.cache.lock().unwrap() = Some((Instant::now(), None));
+        tracing::info!("maintenance mode disabled");
+        Ok(())
+    }
+    fn respond(&self, state: MaintenanceState, format: AcceptFormat) -> Response {
+        let message = state.message.unwrap_or_else(|| self.message.clone());
+        let retry_after = state.retry_after.unwrap_or(self.retry_after);
+        let body = match format {
+            AcceptFormat::PrettyJson => {
+                let message = escape_html(&message);
+                let page = match &self.page {
+                    Some(page) => page.replace("{message}", &message),
+                    None => format!(
+                        "<!doctype html><html><head><meta charset=\"utf-8\"><title>Maintenance</title></head><body><h1>{}</h1></body></html>",
+                        message
+                    ),
+                };
+                Html(page).into_response()
+            }
+            _ => Json(MaintenanceState {
+                message: Some(message),
+                retry_after: Some(retry_after),
+            })
+            .into_response(),
+        };
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [
+                (header::RETRY_AFTER, retry_after.to_string()),
+                (header::CACHE_CONTROL, "no-store".to_string()),
+                (header::VARY, "accept".to_string()),
+            ],
+            body,
+        )
+            .into_response()
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[derive(Clone)]
+pub struct MaintenanceLayer {
+    maintenance: Maintenance,
+}
+
+impl<S> Layer<S> for MaintenanceLayer {
+    type Service = MaintenanceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MaintenanceService {
+            inner,
+            maintenance: self.maintenance.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MaintenanceService<S> {
+    inner: S,
+    maintenance: Maintenance,
+}
+
+impl<S> Service<Request<Body>> for MaintenanceService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let maintenance = self.maintenance.clone();
+        Box::pin(async move {
+            let path = req.uri().path();
+            if maintenance
+                .allow
+                .iter()
+                .any(|prefix| path.starts_with(prefix))
+            {
+                return inner.call(req).await;
+            }
+            let state = match maintenance.status().await {
+                Ok(state) => state,
+                Err(e) => {
+                    // an unreachable switch should not take the whole service down
+                    tracing::warn!("unable to check maintenance mode: {}", e);
+                    None
+                }
+            };
+            match state {
+                Some(state) => {
+                    let format = AcceptFormat::from_headers(req.headers());
+                    Ok(maintenance.respond(state, format))
+                }
+                None => inner.call(req).await,
+            }
+        })
+    }
+}
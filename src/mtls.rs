@@ -0,0 +1,142 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, StatusCode},
+};
+use tokio_rustls::rustls::{
+    crypto::ring::default_provider,
+    pki_types::{pem::PemObject, CertificateDer},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore,
+};
+use x509_parser::extensions::GeneralName;
+
+use crate::{listener::IpConnectInfo, SimpleError};
+
+// the identity a client presented during the tls handshake, already verified
+// against the listener's client_ca bundle
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub issuer: String,
+    pub serial: String,
+    pub dns_names: Vec<String>,
+    pub emails: Vec<String>,
+    pub uris: Vec<String>,
+    pub not_after: i64,
+    pub der: Vec<u8>,
+}
+
+impl ClientCert {
+    pub(crate) fn parse(der: &[u8]) -> Option<ClientCert> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let mut client = ClientCert {
+            subject: cert.subject().to_string(),
+            common_name: cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(|cn| cn.to_string()),
+            issuer: cert.issuer().to_string(),
+            serial: cert.raw_serial_as_string(),
+            dns_names: Vec::new(),
+            emails: Vec::new(),
+            uris: Vec::new(),
+            not_after: cert.validity().not_after.timestamp(),
+            der: der.to_vec(),
+        };
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) => client.dns_names.push(name.to_string()),
+                    GeneralName::RFC822Name(email) => client.emails.push(email.to_string()),
+                    GeneralName::URI(uri) => client.uris.push(uri.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Some(client)
+    }
+    // spiffe ids and similar workload identities live in the uri san
+    pub fn identity(&self) -> Option<&str> {
+        self.uris
+            .first()
+            .or(self.dns_names.first())
+            .or(self.common_name.as_ref())
+            .map(|id| id.as_str())
+    }
+}
+
+// client_ca=/path/ca.pem turns on client certificates, client_auth=optional lets
+// clients without one through while still rejecting untrusted ones
+pub(crate) fn client_verifier(
+    options: &HashMap<String, String>,
+) -> io::Result<Option<Arc<dyn ClientCertVerifier>>> {
+    let Some(ca) = options.get("client_ca") else {
+        if options.contains_key("client_auth") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "client_auth needs a client_ca bundle",
+            ));
+        }
+        return Ok(None);
+    };
+    let optional = match options.get("client_auth").map(|v| v.as_str()) {
+        None | Some("required") => false,
+        Some("optional") => true,
+        Some(v) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid value for client_auth: {}", v),
+            ))
+        }
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", ca, e)))?
+    {
+        let cert =
+            cert.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", ca, e)))?;
+        roots
+            .add(cert)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", ca, e)))?;
+    }
+    if roots.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", ca),
+        ));
+    }
+    let mut builder =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(default_provider()));
+    if optional {
+        builder = builder.allow_unauthenticated();
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientCert
+where
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ConnectInfo<IpConnectInfo>>()
+            .and_then(|info| info.0.client_cert.as_deref())
+            .cloned()
+            .ok_or_else(|| {
+                SimpleError::new("client certificate required", StatusCode::UNAUTHORIZED)
+            })
+    }
+}
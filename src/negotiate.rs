@@ -0,0 +1,134 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::SimpleError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AcceptFormat {
+    #[default]
+    Json,
+    PrettyJson,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl AcceptFormat {
+    fn from_media(media: &str) -> Option<AcceptFormat> {
+        match media {
+            "application/json" | "*/*" | "application/*" => Some(AcceptFormat::Json),
+            // browsers ask for html first, give them something readable
+            "text/html" | "application/xhtml+xml" => Some(AcceptFormat::PrettyJson),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" => Some(AcceptFormat::MsgPack),
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Some(AcceptFormat::Cbor),
+            _ => None,
+        }
+    }
+    // highest q wins, ties go to the first listed type, anything unknown falls back to json
+    pub fn from_headers(headers: &HeaderMap) -> AcceptFormat {
+        let mut best: Option<(f32, AcceptFormat)> = None;
+        let accept = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for item in accept {
+            let mut parts = item.split(';');
+            let media = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q <= 0.0 {
+                continue;
+            }
+            let Some(format) = AcceptFormat::from_media(&media) else {
+                continue;
+            };
+            if best.is_none_or(|(best, _)| q > best) {
+                best = Some((q, format));
+            }
+        }
+        best.map(|(_, format)| format).unwrap_or_default()
+    }
+    pub fn respond<T>(self, value: T) -> Negotiate<T> {
+        Negotiate::new(self, value)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AcceptFormat
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(AcceptFormat::from_headers(&parts.headers))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Negotiate<T> {
+    format: AcceptFormat,
+    status: StatusCode,
+    value: T,
+}
+
+impl<T> Negotiate<T> {
+    pub fn new(format: AcceptFormat, value: T) -> Negotiate<T> {
+        Negotiate {
+            format,
+            status: StatusCode::OK,
+            value,
+        }
+    }
+    pub fn status(mut self, status: StatusCode) -> Negotiate<T> {
+        self.status = status;
+        self
+    }
+}
+
+impl<T> IntoResponse for Negotiate<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let mut res = match self.format {
+            AcceptFormat::Json => Json(self.value).into_response(),
+            AcceptFormat::PrettyJson => match serde_json::to_vec_pretty(&self.value) {
+                Ok(body) => (
+                    [(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/json"),
+                    )],
+                    body,
+                )
+                    .into_response(),
+                Err(e) => SimpleError::new(&e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                    .into_response(),
+            },
+            #[cfg(feature = "msgpack")]
+            AcceptFormat::MsgPack => crate::MsgPack(self.value).into_response(),
+            #[cfg(feature = "cbor")]
+            AcceptFormat::Cbor => crate::Cbor(self.value).into_response(),
+        };
+        if res.status().is_success() {
+            *res.status_mut() = self.status;
+        }
+        res.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept"));
+        res
+    }
+}
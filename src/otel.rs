@@ -0,0 +1,155 @@
+use std::task::{Context, Poll};
+
+use axum::{
+    extract::MatchedPath,
+    http::{HeaderMap, HeaderName, HeaderValue, Request},
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tower::{Layer, Service};
+use tracing::{field, Instrument, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::AnyError;
+
+pub fn otel_tracing_layer<S>(
+    service_name: &str,
+) -> Result<OpenTelemetryLayer<S, trace::Tracer>, AnyError>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let provider = trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            service_name.to_string(),
+        )]))
+        .build();
+    let tracer = provider.tracer(service_name.to_string());
+    global::set_tracer_provider(provider);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+pub fn otel_shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+pub fn inject_trace_context(headers: &mut HeaderMap) {
+    let cx = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderInjector(headers))
+    });
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OtelLayer {}
+
+impl OtelLayer {
+    pub fn new() -> OtelLayer {
+        OtelLayer {}
+    }
+}
+
+impl<S> Layer<S> for OtelLayer {
+    type Service = OtelService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        OtelService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OtelService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for OtelService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+        let method = req.method().to_string();
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| req.uri().path().to_string());
+        let span = tracing::info_span!(
+            "http.server",
+            otel.name = %format!("{} {}", method, route),
+            otel.kind = "server",
+            otel.status_code = field::Empty,
+            http.request.method = %method,
+            http.route = %route,
+            http.response.status_code = field::Empty,
+        );
+        span.set_parent(parent);
+
+        let fut = inner.call(req).instrument(span.clone());
+        Box::pin(async move {
+            let mut res = fut.await;
+            if let Ok(res) = &mut res {
+                let status = res.status().as_u16();
+                span.record("http.response.status_code", status);
+                if status >= 500 {
+                    span.record("otel.status_code", "ERROR");
+                }
+                let _guard = span.enter();
+                inject_trace_context(res.headers_mut());
+            }
+            res
+        })
+    }
+}
@@ -0,0 +1,167 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::SimpleError;
+
+const DEFAULT_PER_PAGE: u64 = 20;
+const MAX_PER_PAGE: u64 = 100;
+
+#[derive(Debug, Deserialize)]
+struct PaginationQuery {
+    page: Option<u64>,
+    per_page: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pagination {
+    pub page: u64,
+    pub per_page: u64,
+    path: String,
+    query: Vec<String>,
+}
+
+impl Pagination {
+    pub fn new(page: u64, per_page: u64) -> Pagination {
+        Pagination {
+            page,
+            per_page,
+            path: String::new(),
+            query: Vec::new(),
+        }
+    }
+    pub fn offset(&self) -> u64 {
+        (self.page - 1) * self.per_page
+    }
+    pub fn limit(&self) -> u64 {
+        self.per_page
+    }
+    fn link(&self, page: u64, rel: &str) -> String {
+        let mut query = self.query.clone();
+        query.push(format!("page={}", page));
+        query.push(format!("per_page={}", self.per_page));
+        format!("<{}?{}>; rel=\"{}\"", self.path, query.join("&"), rel)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Pagination
+where
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<PaginationQuery>::try_from_uri(&parts.uri)
+            .map_err(|e| SimpleError::new(&e.body_text(), StatusCode::BAD_REQUEST))?;
+        let page = query.page.unwrap_or(1);
+        let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE);
+        if page < 1 {
+            return Err(SimpleError::new(
+                "page must be at least 1",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        if !(1..=MAX_PER_PAGE).contains(&per_page) {
+            return Err(SimpleError::new(
+                &format!("per_page must be between 1 and {}", MAX_PER_PAGE),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        let query = parts
+            .uri
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or("");
+                key != "page" && key != "per_page"
+            })
+            .map(|pair| pair.to_string())
+            .collect();
+        Ok(Pagination {
+            page,
+            per_page,
+            path: parts.uri.path().to_string(),
+            query,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Enveloped<T> {
+    pub data: T,
+}
+
+impl<T> Enveloped<T> {
+    pub fn new(data: T) -> Enveloped<T> {
+        Enveloped { data }
+    }
+}
+
+impl<T> IntoResponse for Enveloped<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        Json(self).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Paginated<T> {
+    pub data: Vec<T>,
+    pub page: u64,
+    pub per_page: u64,
+    pub total: u64,
+    #[serde(skip)]
+    pagination: Pagination,
+}
+
+impl<T> Paginated<T> {
+    pub fn new(pagination: &Pagination, data: Vec<T>, total: u64) -> Paginated<T> {
+        Paginated {
+            data,
+            page: pagination.page,
+            per_page: pagination.per_page,
+            total,
+            pagination: pagination.clone(),
+        }
+    }
+    fn links(&self) -> Vec<String> {
+        let last = self.total.div_ceil(self.per_page).max(1);
+        let mut links = Vec::new();
+        if self.page > 1 {
+            links.push(self.pagination.link(1, "first"));
+            links.push(self.pagination.link((self.page - 1).min(last), "prev"));
+        }
+        if self.page < last {
+            links.push(self.pagination.link(self.page + 1, "next"));
+            links.push(self.pagination.link(last, "last"));
+        }
+        links
+    }
+}
+
+impl<T> IntoResponse for Paginated<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-total-count", HeaderValue::from(self.total));
+        let links = self.links();
+        if !links.is_empty() {
+            if let Ok(link) = HeaderValue::from_str(&links.join(", ")) {
+                headers.insert(header::LINK, link);
+            }
+        }
+        (headers, Json(self)).into_response()
+    }
+}
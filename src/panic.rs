@@ -0,0 +1,157 @@
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    pin::Pin,
+    sync::Once,
+    task::{Context, Poll},
+};
+
+use axum::{
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::future::BoxFuture;
+use tower::{Layer, Service};
+
+use crate::SimpleError;
+
+thread_local! {
+    // set while a handler is polled, the hook leaves those panics to the layer
+    static CATCHING: Cell<usize> = const { Cell::new(0) };
+    static BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+fn install_hook() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if CATCHING.get() > 0 {
+                BACKTRACE.set(Some(Backtrace::force_capture()));
+            } else {
+                previous(info);
+            }
+        }));
+    });
+}
+
+// turns handler panics into a plain 500, the panic itself is logged and sent
+// to sentry. listen() installs it unless the address has `catch_panic=false`
+#[derive(Debug, Clone, Default)]
+pub struct CatchPanicLayer {}
+
+impl CatchPanicLayer {
+    pub fn new() -> CatchPanicLayer {
+        install_hook();
+        CatchPanicLayer {}
+    }
+}
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanicService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        install_hook();
+        CatchPanicService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CatchPanicService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for CatchPanicService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+        // handlers can panic before they hand out a future as well
+        let future = catching(|| catch_unwind(AssertUnwindSafe(|| inner.call(req))));
+        Box::pin(async move {
+            let res = match future {
+                Ok(future) => {
+                    Catching {
+                        future: Box::pin(future),
+                    }
+                    .await
+                }
+                Err(payload) => Err(payload),
+            };
+            res.unwrap_or_else(|payload| {
+                let message = panic_message(payload.as_ref());
+                let backtrace = BACKTRACE.take();
+                tracing::error!(
+                    "handler panicked on {} {}: {}\n{}",
+                    method,
+                    uri,
+                    message,
+                    backtrace.map(|b| b.to_string()).unwrap_or_default()
+                );
+                #[cfg(feature = "sentry")]
+                sentry::capture_message(
+                    &format!("handler panicked on {} {}: {}", method, uri, message),
+                    sentry::Level::Fatal,
+                );
+                Ok(
+                    SimpleError::new("internal server error", StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_response(),
+                )
+            })
+        })
+    }
+}
+
+fn catching<T>(f: impl FnOnce() -> T) -> T {
+    CATCHING.set(CATCHING.get() + 1);
+    let res = f();
+    CATCHING.set(CATCHING.get() - 1);
+    res
+}
+
+struct Catching<F> {
+    future: F,
+}
+
+impl<F> Future for Catching<F>
+where
+    F: Future + Unpin,
+{
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = &mut self.future;
+        match catching(|| catch_unwind(AssertUnwindSafe(|| Pin::new(future).poll(cx)))) {
+            Ok(Poll::Ready(res)) => Poll::Ready(Ok(res)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic payload"
+    }
+}
@@ -0,0 +1,130 @@
+use axum::{
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{response::insert_header, SimpleError};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type", default = "about_blank")]
+    pub r#type: String,
+    #[serde(default)]
+    pub title: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(flatten)]
+    pub extensions: Map<String, Value>,
+    #[serde(skip)]
+    headers: HeaderMap,
+}
+
+fn about_blank() -> String {
+    "about:blank".to_string()
+}
+
+impl ProblemDetails {
+    pub fn new(status: StatusCode) -> ProblemDetails {
+        ProblemDetails {
+            r#type: about_blank(),
+            title: status.canonical_reason().unwrap_or("").to_string(),
+            status: status.as_u16(),
+            detail: None,
+            instance: None,
+            extensions: Map::new(),
+            headers: HeaderMap::new(),
+        }
+    }
+    pub fn with_type(mut self, r#type: &str) -> ProblemDetails {
+        self.r#type = r#type.to_string();
+        self
+    }
+    pub fn with_title(mut self, title: &str) -> ProblemDetails {
+        self.title = title.to_string();
+        self
+    }
+    pub fn with_detail(mut self, detail: &str) -> ProblemDetails {
+        self.detail = Some(detail.to_string());
+        self
+    }
+    pub fn with_instance(mut self, instance: &str) -> ProblemDetails {
+        self.instance = Some(instance.to_string());
+        self
+    }
+    pub fn with_extension<V>(mut self, key: &str, value: V) -> ProblemDetails
+    where
+        V: Serialize,
+    {
+        // members defined by the rfc take precedence over extensions
+        if matches!(key, "type" | "title" | "status" | "detail" | "instance") {
+            tracing::warn!("ignoring problem extension with reserved name {}", key);
+            return self;
+        }
+        match serde_json::to_value(value) {
+            Ok(value) => {
+                self.extensions.insert(key.to_string(), value);
+            }
+            Err(e) => tracing::warn!("ignoring problem extension {}: {}", key, e),
+        }
+        self
+    }
+    pub fn with_header<K, V>(mut self, name: K, value: V) -> ProblemDetails
+    where
+        K: TryInto<HeaderName>,
+        V: TryInto<HeaderValue>,
+    {
+        insert_header(&mut self.headers, name, value);
+        self
+    }
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl From<StatusCode> for ProblemDetails {
+    fn from(status: StatusCode) -> ProblemDetails {
+        ProblemDetails::new(status)
+    }
+}
+
+impl From<SimpleError> for ProblemDetails {
+    fn from(mut err: SimpleError) -> ProblemDetails {
+        err.report();
+        let mut problem = ProblemDetails::new(err.status()).with_detail(&err.client_message());
+        problem.headers = err.take_headers();
+        problem
+    }
+}
+
+impl std::fmt::Display for ProblemDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{}: {}", self.title, detail),
+            None => write!(f, "{}", self.title),
+        }
+    }
+}
+impl std::error::Error for ProblemDetails {}
+
+impl IntoResponse for ProblemDetails {
+    fn into_response(self) -> Response {
+        let body = match serde_json::to_vec(&self) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("unable to serialize problem details: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let status = self.status();
+        let mut headers = self.headers;
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        (status, headers, body).into_response()
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{kv::normailze_key, listener::shutdown_requested, AnyError, KVManager, KVRedis};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedJob {
+    pub id: String,
+    pub payload: serde_json::Value,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Redis(Box<KVRedis>),
+    Filesystem(PathBuf),
+}
+
+#[derive(Clone)]
+pub struct Queue {
+    backend: Backend,
+    name: String,
+    max_attempts: u32,
+    backoff: Duration,
+    visibility_timeout: Duration,
+    poll_interval: Duration,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn job_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let random = std::hash::BuildHasher::hash_one(
+        &std::collections::hash_map::RandomState::new(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+    );
+    format!("{:013x}{:016x}", now_ms(), random)
+}
+
+impl Queue {
+    // redis keeps jobs in lists and sorted sets, the filesystem store in directories next to its entries
+    pub fn new(kv: &KVManager, name: &str) -> Result<Queue, AnyError> {
+        let name = normailze_key(&format!("queue:{}", name));
+        let backend = match (kv.redis(), kv.filesystem()) {
+            (Some(redis), _) => Backend::Redis(Box::new(redis.clone())),
+            (_, Some(fs)) => Backend::Filesystem(Path::new(fs.path()).join(&name)),
+            _ => return Err("queues need a redis or filesystem kv store".into()),
+        };
+        Ok(Queue {
+            backend,
+            name,
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+            visibility_timeout: Duration::from_secs(300),
+            poll_interval: Duration::from_millis(500),
+        })
+    }
+    pub fn max_attempts(mut self, attempts: u32) -> Queue {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+    pub fn backoff(mut self, base: Duration) -> Queue {
+        self.backoff = base;
+        self
+    }
+    // a job not acknowledged in time, e.g. because its worker died, is handed out again
+    pub fn visibility_timeout(mut self, timeout: Duration) -> Queue {
+        self.visibility_timeout = timeout;
+        self
+    }
+    pub fn poll_interval(mut self, interval: Duration) -> Queue {
+        self.poll_interval = interval;
+        self
+    }
+    fn key(&self, kind: &str) -> String {
+        // the hash tag keeps every key of a queue in one cluster slot
+        format!("{{{}}}:{}", self.name, kind)
+    }
+    pub async fn push<T: Serialize>(&self, job: &T) -> Result<String, AnyError> {
+        self.push_delayed(job, Duration::ZERO).await
+    }
+    pub async fn push_delayed<T: Serialize>(
+        &self,
+        job: &T,
+        delay: Duration,
+    ) -> Result<String, AnyError> {
+        let job = QueuedJob {
+            id: job_id(),
+            payload: serde_json::to_value(job)?,
+            attempts: 0,
+            error: None,
+        };
+        self.schedule(&job, now_ms() + delay.as_millis() as u64)
+            .await?;
+        Ok(job.id)
+    }
+    async fn schedule(&self, job: &QueuedJob, run_at: u64) -> Result<(), AnyError> {
+        let raw = serde_json::to_string(job)?;
+        match &self.backend {
+            Backend::Redis(redis) => {
+                let mut con = redis.connection().await?;
+                if run_at <= now_ms() {
+                    redis::cmd("RPUSH")
+                        .arg(self.key("pending"))
+                        .arg(raw)
+                        .query_async::<()>(&mut con)
+                        .await?;
+                } else {
+                    redis::cmd("ZADD")
+                        .arg(self.key("delayed"))
+                        .arg(run_at)
+                        .arg(raw)
+                        .query_async::<()>(&mut con)
+                        .await?;
+                }
+            }
+            Backend::Filesystem(dir) => {
+                let pending = dir.join("pending");
+                tokio::fs::create_dir_all(&pending).await?;
+                // written aside first so a worker never claims a half written job
+                let tmp = dir.join(format!(".{}.tmp", job.id));
+                tokio::fs::write(&tmp, raw).await?;
+                tokio::fs::rename(
+                    &tmp,
+                    pending.join(format!("{:013x}-{}.json", run_at, job.id)),
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+    // moves due delayed jobs and expired claims back to pending
+    async fn reap(&self) -> Result<(), AnyError> {
+        match &self.backend {
+            Backend::Redis(redis) => {
+                let mut con = redis.connection().await?;
+                redis::Script::new(
+                    r"for _, key in ipairs({KEYS[2], KEYS[3]}) do
+                        local due = redis.call('ZRANGEBYSCORE', key, '-inf', ARGV[1], 'LIMIT', 0, 100)
+                        for _, job in ipairs(due) do
+                            redis.call('ZREM', key, job)
+                            redis.call('RPUSH', KEYS[1], job)
+                        end
+                    end
+                    return 0",
+                )
+                .key(self.key("pending"))
+                .key(self.key("processing"))
+                .key(self.key("delayed"))
+                .arg(now_ms())
+                .invoke_async::<()>(&mut con)
+                .await?;
+            }
+            Backend::Filesystem(dir) => {
+                let mut entries = match tokio::fs::read_dir(dir.join("processing")).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let expired = entry
+                        .metadata()
+                        .await
+                        .and_then(|meta| meta.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok())
+                        .is_some_and(|elapsed| elapsed > self.visibility_timeout);
+                    if expired {
+                        let name = entry.file_name().to_string_lossy().to_string();
+                        let target =
+                            dir.join("pending")
+                                .join(format!("{:013x}-{}", now_ms(), name));
+                        let _ = tokio::fs::rename(entry.path(), target).await;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    // raw is the exact stored form, needed to acknowledge the claim on redis
+    async fn claim(&self) -> Result<Option<(QueuedJob, String)>, AnyError> {
+        match &self.backend {
+            Backend::Redis(redis) => {
+                let mut con = redis.connection().await?;
+                let raw: Option<String> = redis::Script::new(
+                    r"local job = redis.call('LPOP', KEYS[1])
+                    if job then redis.call('ZADD', KEYS[2], ARGV[1], job) end
+                    return job",
+                )
+                .key(self.key("pending"))
+                .key(self.key("processing"))
+                .arg(now_ms() + self.visibility_timeout.as_millis() as u64)
+                .invoke_async(&mut con)
+                .await?;
+                match raw {
+                    Some(raw) => Ok(Some((serde_json::from_str(&raw)?, raw))),
+                    None => Ok(None),
+                }
+            }
+            Backend::Filesystem(dir) => {
+                let mut entries = match tokio::fs::read_dir(dir.join("pending")).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+                let mut names = Vec::new();
+                while let Some(entry) = entries.next_entry().await? {
+                    names.push(entry.file_name().to_string_lossy().to_string());
+                }
+                // names start with the run time, so sorting gives the oldest due job first
+                names.sort();
+                let now = format!("{:013x}", now_ms());
+                let processing = dir.join("processing");
+                tokio::fs::create_dir_all(&processing).await?;
+                for name in names {
+                    let Some((run_at, rest)) = name.split_once('-') else {
+                        continue;
+                    };
+                    if run_at > now.as_str() {
+                        break;
+                    }
+                    let claimed = processing.join(rest);
+                    // losing the rename race to another worker just means trying the next one
+                    if tokio::fs::rename(dir.join("pending").join(&name), &claimed)
+                        .await
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    let raw = tokio::fs::read_to_string(&claimed).await?;
+                    // rename keeps the old mtime, rewrite so the reaper counts from the claim
+                    tokio::fs::write(&claimed, &raw).await?;
+                    return Ok(Some((serde_json::from_str(&raw)?, raw)));
+                }
+                Ok(None)
+            }
+        }
+    }
+    async fn ack(&self, job: &QueuedJob, raw: &str) -> Result<(), AnyError> {
+        match &self.backend {
+            Backend::Redis(redis) => {
+                let mut con = redis.connection().await?;
+                redis::cmd("ZREM")
+                    .arg(self.key("processing"))
+                    .arg(raw)
+                    .query_async::<()>(&mut con)
+                    .await?;
+            }
+            Backend::Filesystem(dir) => {
+                let path = dir.join("processing").join(format!("{}.json", job.id));
+                match tokio::fs::remove_file(path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+    // replaces the claimed job with its updated copy, either delayed for a retry or dead
+    async fn requeue(
+        &self,
+        job: &QueuedJob,
+        raw: &str,
+        run_at: Option<u64>,
+    ) -> Result<(), AnyError> {
+        let updated = serde_json::to_string(job)?;
+        match &self.backend {
+            Backend::Redis(redis) => {
+                let mut con = redis.connection().await?;
+                let script = match run_at {
+                    Some(_) => redis::Script::new(
+                        r"redis.call('ZREM', KEYS[1], ARGV[1])
+                        redis.call('ZADD', KEYS[2], ARGV[3], ARGV[2])
+                        return 0",
+                    ),
+                    None => redis::Script::new(
+                        r"redis.call('ZREM', KEYS[1], ARGV[1])
+                        redis.call('RPUSH', KEYS[2], ARGV[2])
+                        return 0",
+                    ),
+                };
+                let target = match run_at {
+                    Some(_) => self.key("delayed"),
+                    None => self.key("dead"),
+                };
+                script
+                    .key(self.key("processing"))
+                    .key(target)
+                    .arg(raw)
+                    .arg(updated)
+                    .arg(run_at.unwrap_or_default())
+                    .invoke_async::<()>(&mut con)
+                    .await?;
+            }
+            Backend::Filesystem(dir) => {
+                let target = match run_at {
+                    Some(run_at) => dir
+                        .join("pending")
+                        .join(format!("{:013x}-{}.json", run_at, job.id)),
+                    None => {
+                        tokio::fs::create_dir_all(dir.join("dead")).await?;
+                        dir.join("dead").join(format!("{}.json", job.id))
+                    }
+                };
+                let tmp = dir.join(format!(".{}.tmp", job.id));
+                tokio::fs::write(&tmp, updated).await?;
+                tokio::fs::rename(&tmp, target).await?;
+                self.ack(job, raw).await?;
+            }
+        }
+        Ok(())
+    }
+    pub async fn dead_letters(&self) -> Result<Vec<QueuedJob>, AnyError> {
+        match &self.backend {
+            Backend::Redis(redis) => {
+                let mut con = redis.connection().await?;
+                let raw: Vec<String> = redis::cmd("LRANGE")
+                    .arg(self.key("dead"))
+                    .arg(0)
+                    .arg(-1)
+                    .query_async(&mut con)
+                    .await?;
+                Ok(raw
+                    .iter()
+                    .filter_map(|raw| serde_json::from_str(raw).ok())
+                    .collect())
+            }
+            Backend::Filesystem(dir) => {
+                let mut entries = match tokio::fs::read_dir(dir.join("dead")).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                    Err(e) => return Err(e.into()),
+                };
+                let mut jobs = Vec::new();
+                while let Some(entry) = entries.next_entry().await? {
+                    if let Ok(raw) = tokio::fs::read_to_string(entry.path()).await {
+                        jobs.extend(serde_json::from_str::<QueuedJob>(&raw).ok());
+                    }
+                }
+                Ok(jobs)
+            }
+        }
+    }
+    async fn failed(&self, mut job: QueuedJob, raw: String, error: String) {
+        job.attempts += 1;
+        job.error = Some(error);
+        let run_at = (job.attempts < self.max_attempts).then(|| {
+            let delay = self
+                .backoff
+                .saturating_mul(1 << (job.attempts - 1).min(16))
+                .min(Duration::from_secs(3600));
+            now_ms() + delay.as_millis() as u64
+        });
+        match run_at {
+            Some(_) => tracing::warn!(
+                "queue {} job {} failed, attempt {} of {}: {}",
+                self.name,
+                job.id,
+                job.attempts,
+                self.max_attempts,
+                job.error.as_deref().unwrap_or_default()
+            ),
+            None => tracing::error!(
+                "queue {} job {} failed permanently: {}",
+                self.name,
+                job.id,
+                job.error.as_deref().unwrap_or_default()
+            ),
+        }
+        if let Err(e) = self.requeue(&job, &raw, run_at).await {
+            // the claim expires and the job is handed out again
+            tracing::error!(
+                "queue {} unable to requeue job {}: {}",
+                self.name,
+                job.id,
+                e
+            );
+        }
+    }
+    // runs until shutdown is requested, then waits for the jobs already running
+    pub async fn worker<T, F, Fut>(&self, concurrency: usize, handler: F)
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), AnyError>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut running = JoinSet::new();
+        let mut reaped = 0;
+        loop {
+            let permit = tokio::select! {
+                permit = permits.clone().acquire_owned() => permit.expect("queue semaphore closed"),
+                _ = shutdown_requested() => break,
+            };
+            while running.try_join_next().is_some() {}
+            if now_ms() - reaped > self.poll_interval.as_millis() as u64 {
+                reaped = now_ms();
+                if let Err(e) = self.reap().await {
+                    tracing::warn!("queue {} reap failed: {}", self.name, e);
+                }
+            }
+            let claimed = match self.claim().await {
+                Ok(claimed) => claimed,
+                Err(e) => {
+                    tracing::warn!("queue {} claim failed: {}", self.name, e);
+                    None
+                }
+            };
+            let Some((job, raw)) = claimed else {
+                drop(permit);
+                tokio::select! {
+                    _ = tokio::time::sleep(self.poll_interval) => continue,
+                    _ = shutdown_requested() => break,
+                }
+            };
+            let queue = self.clone();
+            let handler = handler.clone();
+            running.spawn(async move {
+                let _permit = permit;
+                let payload = match serde_json::from_value::<T>(job.payload.clone()) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        // retrying cannot fix a payload of the wrong shape
+                        let attempts = queue.max_attempts;
+                        let job = QueuedJob {
+                            attempts: attempts - 1,
+                            ..job
+                        };
+                        queue
+                            .failed(job, raw, format!("invalid payload: {}", e))
+                            .await;
+                        return;
+                    }
+                };
+                match handler(payload).await {
+                    Ok(()) => {
+                        if let Err(e) = queue.ack(&job, &raw).await {
+                            tracing::error!(
+                                "queue {} unable to ack job {}: {}",
+                                queue.name,
+                                job.id,
+                                e
+                            );
+                        }
+                    }
+                    Err(e) => queue.failed(job, raw, e.to_string()).await,
+                }
+            });
+        }
+        tracing::info!("queue {} worker draining {} jobs", self.name, running.len());
+        while running.join_next().await.is_some() {}
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    sync::{Arc, Once},
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header, request::Parts, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+
+use crate::{
+    listener::IpConnectInfo, realip::trusted_ip, AnyError, ConflictError, KVManager, NotFoundError,
+    SimpleError, Version,
+};
+
+type KeyFn = Arc<dyn Fn(&Parts) -> Option<String> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimitAlgorithm {
+    TokenBucket { capacity: u64, refill_per_sec: f64 },
+    SlidingWindow { limit: u64, window: u64 },
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    kv: KVManager,
+    algorithm: RateLimitAlgorithm,
+    prefix: String,
+    key: KeyFn,
+}
+
+impl RateLimitLayer {
+    pub fn new(kv: KVManager, algorithm: RateLimitAlgorithm) -> RateLimitLayer {
+        match algorithm {
+            RateLimitAlgorithm::TokenBucket { refill_per_sec, .. } => assert!(
+                refill_per_sec.is_finite() && refill_per_sec > 0.0,
+                "rate limit refill_per_sec must be positive"
+            ),
+            RateLimitAlgorithm::SlidingWindow { window, .. } => {
+                assert!(window > 0, "rate limit window must be positive")
+            }
+        }
+        RateLimitLayer {
+            kv,
+            algorithm,
+            prefix: "ratelimit:".to_string(),
+            key: Arc::new(|parts: &Parts| {
+                let Some(info) = parts.extensions.get::<ConnectInfo<IpConnectInfo>>() else {
+                    static MISSING: Once = Once::new();
+                    MISSING.call_once(|| {
+                        tracing::warn!("rate limit skipped, requests carry no client address; serve through rstartup::listener or set a key_fn")
+                    });
+                    return None;
+                };
+                Some(trusted_ip(&parts.headers, &info.0))
+            }),
+        }
+    }
+    pub fn prefix(mut self, prefix: &str) -> RateLimitLayer {
+        self.prefix = prefix.to_string();
+        self
+    }
+    pub fn key_fn<F>(mut self, key: F) -> RateLimitLayer
+    where
+        F: Fn(&Parts) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Arc::new(key);
+        self
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S, B> Service<Request<B>> for RateLimit<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let key = (layer.key)(&parts);
+            let req = Request::from_parts(parts, body);
+            let key = match key {
+                Some(key) => format!("{}{}", layer.prefix, key),
+                None => return inner.call(req).await,
+            };
+            match check(&layer.kv, &key, layer.algorithm).await {
+                Ok(None) => inner.call(req).await,
+                Ok(Some(retry_after)) => Ok(SimpleError::new(
+                    "Too Many Requests",
+                    StatusCode::TOO_MANY_REQUESTS,
+                )
+                .with_header(header::RETRY_AFTER, retry_after)
+                .into_response()),
+                Err(e) => {
+                    tracing::warn!("rate limit check failed for {}: {}", key, e);
+                    inner.call(req).await
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenBucketState {
+    tokens: f64,
+    updated: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SlidingWindowState {
+    start: u64,
+    current: u64,
+    previous: u64,
+}
+
+fn now_f64() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+// read and written with set_if, so concurrent requests cannot all spend the same token
+async fn check(
+    kv: &KVManager,
+    key: &str,
+    algorithm: RateLimitAlgorithm,
+) -> Result<Option<u64>, AnyError> {
+    loop {
+        let now = now_f64();
+        let res = match algorithm {
+            RateLimitAlgorithm::TokenBucket {
+                capacity,
+                refill_per_sec,
+            } => {
+                let capacity = capacity as f64;
+                let (mut state, version) = match kv.get_versioned::<TokenBucketState>(key).await {
+                    Ok(res) => res,
+                    Err(e) if e.is::<NotFoundError>() => (
+                        TokenBucketState {
+                            tokens: capacity,
+                            updated: now,
+                        },
+                        Version::absent(),
+                    ),
+                    Err(e) => return Err(e),
+                };
+                state.tokens =
+                    (state.tokens + (now - state.updated).max(0.0) * refill_per_sec).min(capacity);
+                state.updated = now;
+                if state.tokens < 1.0 {
+                    return Ok(Some(((1.0 - state.tokens) / refill_per_sec).ceil() as u64));
+                }
+                state.tokens -= 1.0;
+                let expire = ((capacity / refill_per_sec).ceil() as u64).saturating_add(1);
+                kv.set_if(key, &state, &version, expire).await
+            }
+            RateLimitAlgorithm::SlidingWindow { limit, window } => {
+                let now_secs = now as u64;
+                let start = now_secs / window * window;
+                let (mut state, version) = match kv.get_versioned::<SlidingWindowState>(key).await {
+                    Ok(res) => res,
+                    Err(e) if e.is::<NotFoundError>() => (
+                        SlidingWindowState {
+                            start,
+                            current: 0,
+                            previous: 0,
+                        },
+                        Version::absent(),
+                    ),
+                    Err(e) => return Err(e),
+                };
+                if state.start != start {
+                    state.previous = if state.start + window == start {
+                        state.current
+                    } else {
+                        0
+                    };
+                    state.current = 0;
+                    state.start = start;
+                }
+                let weight = 1.0 - (now - start as f64) / window as f64;
+                let estimated = state.previous as f64 * weight + state.current as f64;
+                if estimated >= limit as f64 {
+                    return Ok(Some((start + window - now_secs).max(1)));
+                }
+                state.current += 1;
+                kv.set_if(key, &state, &version, window * 2).await
+            }
+        };
+        match res {
+            Ok(()) => return Ok(None),
+            Err(e) if e.is::<ConflictError>() => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
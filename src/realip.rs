@@ -1,31 +1,434 @@
 use axum::{
     async_trait,
-    extract::{ConnectInfo, FromRequest, RequestParts},
-    headers::HeaderName,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
     Extension,
 };
-use std::{str::FromStr};
+use std::{
+    convert::Infallible,
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, OnceLock, RwLock},
+};
 
-use crate::listener::IpConnectInfo;
+use crate::{listener::IpConnectInfo, AnyError};
 
 #[derive(Clone, Debug)]
 pub struct RealIP(pub String);
 #[async_trait]
-impl<B> FromRequest<B> for RealIP
+impl<S> FromRequestParts<S> for RealIP
+where
+    S: Send + Sync,
+{
+    type Rejection = <Extension<ConnectInfo<IpConnectInfo>> as FromRequestParts<S>>::Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(connect_info) =
+            Extension::<ConnectInfo<IpConnectInfo>>::from_request_parts(parts, state).await?;
+        Ok(Self(real_ip(&parts.headers, &connect_info.0)))
+    }
+}
+
+pub(crate) fn real_ip(headers: &HeaderMap, connect_info: &IpConnectInfo) -> String {
+    if let Some(chain) = header_chain() {
+        return match chain.resolve(headers, connect_info) {
+            Some((ip, _)) => ip.to_string(),
+            None => connect_info.ip.clone(),
+        };
+    }
+    if let Some((ip, _)) = preferred_forwarded(headers) {
+        return ip.to_string();
+    }
+    headers
+        .get("x-real-ip")
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or(&connect_info.ip)
+        .to_string()
+}
+
+// like real_ip, but only an installed header chain can override the peer address,
+// for keys a client must not be able to pick itself
+#[cfg(feature = "kv")]
+pub(crate) fn trusted_ip(headers: &HeaderMap, connect_info: &IpConnectInfo) -> String {
+    header_chain()
+        .and_then(|chain| chain.resolve(headers, connect_info))
+        .map(|(ip, _)| ip.to_string())
+        .unwrap_or_else(|| connect_info.ip.clone())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddr {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientAddr
 where
-    B: Send,
+    S: Send + Sync,
 {
-    type Rejection = <Extension<Self> as FromRequest<B>>::Rejection;
+    type Rejection = <Extension<ConnectInfo<IpConnectInfo>> as FromRequestParts<S>>::Rejection;
 
-    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let Extension(connect_info) =
-            Extension::<ConnectInfo<IpConnectInfo>>::from_request(req).await?;
-        let ip = req
-            .headers()
-            .get(HeaderName::from_str("x-real-ip").unwrap())
-            .and_then(|header| header.to_str().ok())
-            .unwrap_or(&connect_info.0.ip)
-            .to_string();
-        Ok(Self(ip))
+            Extension::<ConnectInfo<IpConnectInfo>>::from_request_parts(parts, state).await?;
+        Ok(client_addr(&parts.headers, &connect_info.0))
+    }
+}
+
+pub(crate) fn client_addr(headers: &HeaderMap, connect_info: &IpConnectInfo) -> ClientAddr {
+    let chain = header_chain();
+    let resolved = match &chain {
+        Some(chain) => chain.resolve(headers, connect_info),
+        None => preferred_forwarded(headers),
+    };
+    if let Some((ip, port)) = resolved {
+        return ClientAddr { ip, port };
+    }
+    let header = headers
+        .get("x-real-ip")
+        .and_then(|header| header.to_str().ok())
+        .filter(|_| chain.is_none());
+    if let Some(header) = header {
+        match parse_ip_port(header) {
+            Some((ip, port)) => return ClientAddr { ip, port },
+            None => tracing::warn!("ignoring malformed x-real-ip header: {}", header),
+        }
+    }
+    let ip = parse_ip_port(&connect_info.ip)
+        .map(|(ip, _)| ip)
+        .unwrap_or(IpAddr::from([127, 0, 0, 0]));
+    ClientAddr {
+        ip,
+        port: Some(connect_info.port).filter(|port| *port != 0),
+    }
+}
+
+pub fn parse_ip_port(value: &str) -> Option<(IpAddr, Option<u16>)> {
+    let value = value.trim();
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some((ip.to_canonical(), None));
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some((addr.ip().to_canonical(), Some(addr.port())));
+    }
+    let ip = value.strip_prefix('[')?.strip_suffix(']')?;
+    ip.parse::<IpAddr>()
+        .ok()
+        .map(|ip| (ip.to_canonical(), None))
+}
+
+fn prefer_forwarded() -> bool {
+    matches!(
+        env::var("TOKI_REALIP_FORWARDED").as_deref(),
+        Ok("1" | "true" | "yes" | "on")
+    )
+}
+
+fn preferred_forwarded(headers: &HeaderMap) -> Option<(IpAddr, Option<u16>)> {
+    if !prefer_forwarded() {
+        return None;
+    }
+    // obfuscated and unknown nodes fall back to the other sources
+    parse_ip_port(ForwardedInfo::from_headers(headers)?.r#for.as_deref()?)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Cidr {
+    net: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn parse(value: &str) -> Option<Cidr> {
+        let (ip, prefix) = match value.trim().split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix.parse::<u8>().ok()?)),
+            None => (value.trim(), None),
+        };
+        let net = ip.parse::<IpAddr>().ok()?.to_canonical();
+        let max = if net.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Cidr { net, prefix })
+    }
+    fn contains(&self, ip: IpAddr) -> bool {
+        let bits = |ip: IpAddr| match ip {
+            IpAddr::V4(ip) => (u32::from(ip) as u128) << 96,
+            IpAddr::V6(ip) => u128::from(ip),
+        };
+        let ip = ip.to_canonical();
+        if ip.is_ipv4() != self.net.is_ipv4() {
+            return false;
+        }
+        if self.prefix == 0 {
+            return true;
+        }
+        // ipv4 addresses sit in the top 32 bits, so the same mask works for both
+        let mask = u128::MAX << (128 - self.prefix as u32);
+        bits(ip) & mask == bits(self.net) & mask
+    }
+}
+
+#[derive(Clone, Debug)]
+struct HeaderRule {
+    name: String,
+    trusted: Vec<Cidr>,
+}
+
+impl HeaderRule {
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.is_empty() || self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RealIpHeaders {
+    rules: Vec<HeaderRule>,
+}
+
+impl RealIpHeaders {
+    pub fn new() -> RealIpHeaders {
+        RealIpHeaders::default()
+    }
+    pub fn cloudflare() -> RealIpHeaders {
+        RealIpHeaders::new()
+            .header("cf-connecting-ip")
+            .header("x-forwarded-for")
+    }
+    pub fn fastly() -> RealIpHeaders {
+        RealIpHeaders::new()
+            .header("fastly-client-ip")
+            .header("x-forwarded-for")
+    }
+    // honored whatever the peer is, only use behind a proxy that always overwrites it
+    pub fn header(mut self, name: &str) -> RealIpHeaders {
+        self.rules.push(HeaderRule {
+            name: name.trim().to_ascii_lowercase(),
+            trusted: Vec::new(),
+        });
+        self
+    }
+    // honored only when the connection comes from one of the proxies, ips or cidr ranges
+    pub fn header_from(mut self, name: &str, proxies: &[&str]) -> Result<RealIpHeaders, AnyError> {
+        let trusted = proxies
+            .iter()
+            .map(|proxy| {
+                Cidr::parse(proxy).ok_or_else(|| format!("invalid proxy range: {}", proxy))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.rules.push(HeaderRule {
+            name: name.trim().to_ascii_lowercase(),
+            trusted,
+        });
+        Ok(self)
+    }
+    // TOKI_REALIP_HEADERS=cf-connecting-ip@173.245.48.0/20|103.21.244.0/22,x-real-ip
+    pub fn from_env() -> Result<Option<RealIpHeaders>, AnyError> {
+        let Ok(value) = env::var("TOKI_REALIP_HEADERS") else {
+            return Ok(None);
+        };
+        let mut headers = RealIpHeaders::new();
+        for entry in value.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            headers = match entry.split_once('@') {
+                Some((name, proxies)) => {
+                    headers.header_from(name, &proxies.split('|').collect::<Vec<_>>())?
+                }
+                None => headers.header(entry),
+            };
+        }
+        Ok(Some(headers))
+    }
+    // replaces the chain used by RealIP, ClientAddr and the logging layers
+    pub fn install(self) {
+        *chain_slot().write().unwrap() = Some(Arc::new(self));
+    }
+    fn resolve(
+        &self,
+        headers: &HeaderMap,
+        connect_info: &IpConnectInfo,
+    ) -> Option<(IpAddr, Option<u16>)> {
+        let peer = parse_ip_port(&connect_info.ip).map(|(ip, _)| ip)?;
+        for rule in &self.rules {
+            if !rule.trusts(peer) {
+                continue;
+            }
+            let found = match rule.name.as_str() {
+                "forwarded" => {
+                    let value = headers
+                        .get_all("forwarded")
+                        .iter()
+                        .filter_map(|v| v.to_str().ok())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let hops = parse_forwarded(&value)
+                        .into_iter()
+                        .filter_map(|info| info.r#for)
+                        .collect::<Vec<_>>();
+                    pick_hop(rule, hops.iter().map(|hop| hop.as_str()))
+                }
+                name => {
+                    let value = headers
+                        .get_all(name)
+                        .iter()
+                        .filter_map(|v| v.to_str().ok())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    pick_hop(rule, value.split(','))
+                }
+            };
+            match found {
+                Some(found) => return Some(found),
+                None if headers.contains_key(rule.name.as_str()) => {
+                    tracing::debug!("ignoring unusable {} header", rule.name)
+                }
+                None => {}
+            }
+        }
+        None
+    }
+}
+
+// a list header is read from the right, skipping the trusted proxies that appended to it,
+// without trusted proxies the client supplied first entry is used
+fn pick_hop<'a, I>(rule: &HeaderRule, hops: I) -> Option<(IpAddr, Option<u16>)>
+where
+    I: DoubleEndedIterator<Item = &'a str>,
+{
+    let mut hops = hops.map(|hop| hop.trim()).filter(|hop| !hop.is_empty());
+    if rule.trusted.is_empty() {
+        return parse_ip_port(hops.next()?);
+    }
+    let mut last = None;
+    for hop in hops.rev() {
+        let parsed = parse_ip_port(hop)?;
+        last = Some(parsed);
+        if !rule.trusts(parsed.0) {
+            break;
+        }
+    }
+    last
+}
+
+fn chain_slot() -> &'static RwLock<Option<Arc<RealIpHeaders>>> {
+    static CHAIN: OnceLock<RwLock<Option<Arc<RealIpHeaders>>>> = OnceLock::new();
+    CHAIN.get_or_init(|| {
+        let headers = RealIpHeaders::from_env().unwrap_or_else(|e| {
+            tracing::error!("ignoring TOKI_REALIP_HEADERS: {}", e);
+            None
+        });
+        RwLock::new(headers.map(Arc::new))
+    })
+}
+
+fn header_chain() -> Option<Arc<RealIpHeaders>> {
+    chain_slot().read().unwrap().clone()
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardedInfo {
+    pub r#for: Option<String>,
+    pub by: Option<String>,
+    pub host: Option<String>,
+    pub proto: Option<String>,
+}
+
+impl ForwardedInfo {
+    // the first element is the one added by the proxy closest to the client
+    fn from_headers(headers: &HeaderMap) -> Option<ForwardedInfo> {
+        let value = headers
+            .get_all("forwarded")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        parse_forwarded(&value).into_iter().next()
+    }
+    fn from_legacy_headers(headers: &HeaderMap) -> ForwardedInfo {
+        let first = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        ForwardedInfo {
+            r#for: first("x-forwarded-for"),
+            by: None,
+            host: first("x-forwarded-host"),
+            proto: first("x-forwarded-proto"),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ForwardedInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ForwardedInfo::from_headers(&parts.headers)
+            .unwrap_or_else(|| ForwardedInfo::from_legacy_headers(&parts.headers)))
+    }
+}
+
+pub fn parse_forwarded(value: &str) -> Vec<ForwardedInfo> {
+    split_quoted(value, ',')
+        .into_iter()
+        .filter(|element| !element.trim().is_empty())
+        .map(|element| {
+            let mut info = ForwardedInfo::default();
+            for pair in split_quoted(&element, ';') {
+                let Some((name, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                let value = unquote(value.trim());
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "for" => info.r#for = Some(value),
+                    "by" => info.by = Some(value),
+                    "host" => info.host = Some(value),
+                    "proto" => info.proto = Some(value.to_ascii_lowercase()),
+                    _ => {}
+                }
+            }
+            info
+        })
+        .collect()
+}
+
+fn split_quoted(value: &str, separator: char) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    parts.push(current);
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
     }
+    unquoted
 }
@@ -0,0 +1,90 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    task::{Context, Poll},
+};
+
+use axum::{
+    http::{HeaderName, HeaderValue, Request},
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use tower::{Layer, Service};
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+// the id of the request being handled on this task, set by RequestIdLayer
+pub fn current_request_id() -> Option<String> {
+    CURRENT.try_with(|id| id.clone()).ok()
+}
+
+// keeps the caller's x-request-id or makes one up, echoes it on the response
+// and makes it available to current_request_id() while the request is handled
+#[derive(Debug, Clone, Default)]
+pub struct RequestIdLayer {}
+
+impl RequestIdLayer {
+    pub fn new() -> RequestIdLayer {
+        RequestIdLayer {}
+    }
+}
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for RequestIdService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let id = match req.headers().get(&REQUEST_ID).and_then(|v| v.to_str().ok()) {
+            Some(id) if !id.is_empty() => id.to_string(),
+            _ => {
+                let id = generate();
+                req.headers_mut()
+                    .insert(REQUEST_ID, HeaderValue::from_str(&id).unwrap());
+                id
+            }
+        };
+        let value = HeaderValue::from_str(&id).ok();
+        let fut = CURRENT.scope(id, async move { inner.call(req).await });
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(value) = value {
+                res.headers_mut().entry(REQUEST_ID).or_insert(value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+fn generate() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", random(), random())
+}
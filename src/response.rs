@@ -1,33 +1,67 @@
 use axum::{
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    body::{Body, Bytes},
+    http::{header, HeaderName, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
     Json,
 };
+use futures_util::{stream::BoxStream, Stream, StreamExt};
+use http_body::Frame;
 use hyper::HeaderMap;
-use std::ops::Deref;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Serialize;
+use std::{
+    convert::Infallible,
+    future::Future,
+    ops::Deref,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::task::futures::TaskLocalFuture;
+
+use crate::{current_request_id, AnyError, SimpleError};
 
 pub type SimpleResponse<T> = (StatusCode, T);
 pub type SimpleJson<T> = SimpleResponse<Json<T>>;
 pub type HeaderResponse<T> = (StatusCode, HeaderMap, T);
 pub type HeaderJson<T> = HeaderResponse<Json<T>>;
+#[cfg(feature = "msgpack")]
+pub type SimpleMsgPack<T> = SimpleResponse<crate::codec::MsgPack<T>>;
+#[cfg(feature = "cbor")]
+pub type SimpleCbor<T> = SimpleResponse<crate::codec::Cbor<T>>;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct SimpleStatus(StatusCode);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimpleStatus {
+    status: StatusCode,
+    headers: HeaderMap,
+}
 
 impl SimpleStatus {
     pub fn new(status: StatusCode) -> SimpleStatus {
-        SimpleStatus(status)
+        SimpleStatus {
+            status,
+            headers: HeaderMap::new(),
+        }
+    }
+    pub fn with_header<K, V>(mut self, name: K, value: V) -> SimpleStatus
+    where
+        K: TryInto<HeaderName>,
+        V: TryInto<HeaderValue>,
+    {
+        insert_header(&mut self.headers, name, value);
+        self
     }
 }
 impl IntoResponse for SimpleStatus {
     fn into_response(self) -> Response {
-        (self.0, "").into_response()
+        (self.status, self.headers, "").into_response()
     }
 }
 impl Deref for SimpleStatus {
     type Target = StatusCode;
     fn deref(&self) -> &StatusCode {
-        &self.0
+        &self.status
     }
 }
 impl From<StatusCode> for SimpleStatus {
@@ -37,7 +71,629 @@ impl From<StatusCode> for SimpleStatus {
 }
 impl From<SimpleStatus> for StatusCode {
     fn from(status: SimpleStatus) -> StatusCode {
-        status.0
+        status.status
+    }
+}
+
+pub fn ok_json<T>(value: T) -> SimpleJson<T> {
+    (StatusCode::OK, Json(value))
+}
+
+// the location is skipped with a warning when it is not a valid header value
+pub fn created_json<T>(location: &str, value: T) -> HeaderJson<T> {
+    let mut headers = HeaderMap::new();
+    insert_header(&mut headers, header::LOCATION, location);
+    (StatusCode::CREATED, headers, Json(value))
+}
+
+pub fn accepted_json<T>(value: T) -> SimpleJson<T> {
+    (StatusCode::ACCEPTED, Json(value))
+}
+
+pub fn no_content() -> SimpleStatus {
+    SimpleStatus::new(StatusCode::NO_CONTENT)
+}
+
+// what a Location header may carry unescaped, existing %xx escapes are kept as they are
+const LOCATION: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'<')
+    .add(b'>')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+// relative targets are passed through, absolute ones have to be http or https
+pub(crate) fn redirect_location(target: &str) -> Result<HeaderValue, SimpleError> {
+    let scheme = target
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| !scheme.contains(['/', '?', '#']));
+    if let Some(scheme) = scheme {
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return Err(SimpleError::bad_request("invalid redirect target"));
+        }
+    }
+    HeaderValue::try_from(utf8_percent_encode(target, LOCATION).to_string())
+        .map_err(|_| SimpleError::bad_request("invalid redirect target"))
+}
+
+macro_rules! redirect {
+    ($name:ident, $status:expr) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $name {
+            location: String,
+        }
+        impl $name {
+            pub fn to(target: &str) -> Result<$name, SimpleError> {
+                redirect_location(target)?;
+                Ok($name {
+                    location: target.to_string(),
+                })
+            }
+            // appends the query of the current request, after any the target already has
+            pub fn preserve_query(mut self, uri: &Uri) -> $name {
+                if let Some(query) = uri.query().filter(|query| !query.is_empty()) {
+                    let (target, fragment) = match self.location.split_once('#') {
+                        Some((target, fragment)) => (target, Some(fragment)),
+                        None => (self.location.as_str(), None),
+                    };
+                    let separator = match target.contains('?') {
+                        true => "&",
+                        false => "?",
+                    };
+                    let mut location = format!("{}{}{}", target, separator, query);
+                    if let Some(fragment) = fragment {
+                        location = format!("{}#{}", location, fragment);
+                    }
+                    self.location = location;
+                }
+                self
+            }
+            pub fn location(&self) -> &str {
+                &self.location
+            }
+        }
+        impl IntoResponse for $name {
+            fn into_response(self) -> Response {
+                match redirect_location(&self.location) {
+                    Ok(location) => ($status, [(header::LOCATION, location)]).into_response(),
+                    Err(e) => e.into_response(),
+                }
+            }
+        }
+    };
+}
+
+redirect!(Redirect301, StatusCode::MOVED_PERMANENTLY);
+redirect!(Redirect302, StatusCode::FOUND);
+// after a form post, the client follows up with a GET
+redirect!(RedirectSeeOther, StatusCode::SEE_OTHER);
+
+pub(crate) fn insert_header<K, V>(headers: &mut HeaderMap, name: K, value: V)
+where
+    K: TryInto<HeaderName>,
+    V: TryInto<HeaderValue>,
+{
+    match (name.try_into(), value.try_into()) {
+        (Ok(name), Ok(value)) => {
+            headers.append(name, value);
+        }
+        _ => tracing::warn!("ignoring invalid response header"),
+    }
+}
+
+pub struct SimpleStream<S> {
+    status: StatusCode,
+    headers: HeaderMap,
+    stream: S,
+}
+
+impl<S> SimpleStream<S>
+where
+    S: Stream<Item = Result<Bytes, AnyError>> + Send + 'static,
+{
+    pub fn new(status: StatusCode, headers: HeaderMap, stream: S) -> SimpleStream<S> {
+        SimpleStream {
+            status,
+            headers,
+            stream,
+        }
+    }
+}
+impl<S> IntoResponse for SimpleStream<S>
+where
+    S: Stream<Item = Result<Bytes, AnyError>> + Send + 'static,
+{
+    fn into_response(self) -> Response {
+        let trailers = ACCEPTS_TRAILERS.try_with(|t| *t).unwrap_or(false);
+        let body = SimpleStreamBody {
+            stream: Box::pin(self.stream),
+            finished: false,
+            // the body is polled after the request id scope has ended
+            request_id: current_request_id(),
+            trailers,
+        };
+        let mut res = Response::new(Body::new(body));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers;
+        if trailers {
+            res.headers_mut().append(
+                header::TRAILER,
+                HeaderValue::from_static(STREAM_ERROR_TRAILER),
+            );
+        }
+        res
+    }
+}
+
+const STREAM_ERROR_TRAILER: &str = "x-stream-error";
+
+// logs a failed stream and returns what the client gets to see, the detail
+// stays in the log when server errors are redacted
+fn stream_error(kind: &str, err: &AnyError, request_id: &Option<String>) -> String {
+    match request_id {
+        Some(request_id) => tracing::error!(request_id, "{} stream failed: {}", kind, err),
+        None => tracing::error!("{} stream failed: {}", kind, err),
+    }
+    match (SimpleError::redacts(), request_id) {
+        (false, _) => err.to_string(),
+        (true, Some(request_id)) => format!("stream error (request id {})", request_id),
+        (true, None) => "stream error".to_string(),
+    }
+}
+
+tokio::task_local! {
+    static ACCEPTS_TRAILERS: bool;
+}
+
+// whether the client asked for trailers with `TE: trailers`, without it hyper
+// drops them silently
+pub(crate) fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("trailers"))
+}
+
+// set by the listener for the request being handled
+pub(crate) fn scope_trailers<F>(accepts: bool, fut: F) -> TaskLocalFuture<bool, F>
+where
+    F: Future,
+{
+    ACCEPTS_TRAILERS.scope(accepts, fut)
+}
+
+struct SimpleStreamBody {
+    stream: Pin<Box<dyn Stream<Item = Result<Bytes, AnyError>> + Send>>,
+    finished: bool,
+    request_id: Option<String>,
+    trailers: bool,
+}
+impl http_body::Body for SimpleStreamBody {
+    type Data = Bytes;
+    type Error = AnyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        match self.stream.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(data))) => Poll::Ready(Some(Ok(Frame::data(data)))),
+            Poll::Ready(Some(Err(err))) => {
+                self.finished = true;
+                let message = stream_error("response", &err, &self.request_id);
+                // without trailers the connection or stream is aborted, so the
+                // client never mistakes the cut off body for a complete one
+                if !self.trailers {
+                    return Poll::Ready(Some(Err(message.into())));
+                }
+                let mut trailers = HeaderMap::new();
+                let value = HeaderValue::from_str(&message)
+                    .unwrap_or_else(|_| HeaderValue::from_static("stream error"));
+                trailers.insert(STREAM_ERROR_TRAILER, value);
+                Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+            }
+            Poll::Ready(None) => {
+                self.finished = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.finished
+    }
+}
+
+pub struct NdJsonStream<S> {
+    headers: HeaderMap,
+    stream: S,
+}
+
+impl<S, T> NdJsonStream<S>
+where
+    S: Stream<Item = Result<T, AnyError>> + Send + 'static,
+    T: Serialize,
+{
+    pub fn new(stream: S) -> NdJsonStream<S> {
+        NdJsonStream {
+            headers: HeaderMap::new(),
+            stream,
+        }
+    }
+    pub fn with_header<K, V>(mut self, name: K, value: V) -> NdJsonStream<S>
+    where
+        K: TryInto<HeaderName>,
+        V: TryInto<HeaderValue>,
+    {
+        insert_header(&mut self.headers, name, value);
+        self
+    }
+}
+impl<T> NdJsonStream<BoxStream<'static, Result<T, AnyError>>>
+where
+    T: Serialize + Send + 'static,
+{
+    pub fn iter<I>(rows: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        NdJsonStream::new(futures_util::stream::iter(rows.into_iter().map(Ok)).boxed())
+    }
+}
+impl<S, T> IntoResponse for NdJsonStream<S>
+where
+    S: Stream<Item = Result<T, AnyError>> + Send + 'static,
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let mut headers = self.headers;
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        let stream = self.stream.map(|row| {
+            let mut line = serde_json::to_vec(&row?)?;
+            line.push(b'\n');
+            Ok(Bytes::from(line))
+        });
+        SimpleStream::new(StatusCode::OK, headers, stream).into_response()
+    }
+}
+
+#[cfg(feature = "csv")]
+pub struct SimpleCsv<T> {
+    headers: HeaderMap,
+    rows: T,
+}
+
+#[cfg(feature = "csv")]
+impl<T> SimpleCsv<T> {
+    pub fn new(rows: T) -> SimpleCsv<T> {
+        SimpleCsv {
+            headers: HeaderMap::new(),
+            rows,
+        }
+    }
+    pub fn filename(self, filename: &str) -> SimpleCsv<T> {
+        let value = format!("attachment; filename=\"{}\"", filename.replace('"', ""));
+        self.with_header(header::CONTENT_DISPOSITION, value)
+    }
+    pub fn with_header<K, V>(mut self, name: K, value: V) -> SimpleCsv<T>
+    where
+        K: TryInto<HeaderName>,
+        V: TryInto<HeaderValue>,
+    {
+        insert_header(&mut self.headers, name, value);
+        self
+    }
+    fn content_headers(mut headers: HeaderMap) -> HeaderMap {
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/csv; charset=utf-8"),
+        );
+        headers
+    }
+}
+
+// rows are serialized one by one as the stream yields them, the header line
+// is written with the first row
+#[cfg(feature = "csv")]
+pub struct CsvStream<S>(S);
+
+#[cfg(feature = "csv")]
+impl<S> SimpleCsv<CsvStream<S>> {
+    pub fn stream(stream: S) -> SimpleCsv<CsvStream<S>> {
+        SimpleCsv::new(CsvStream(stream))
+    }
+}
+
+#[cfg(feature = "csv")]
+fn csv_row<T>(row: &T, with_headers: bool) -> Result<Vec<u8>, AnyError>
+where
+    T: Serialize,
+{
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(with_headers)
+        .from_writer(Vec::new());
+    writer.serialize(row)?;
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+#[cfg(feature = "csv")]
+impl<I> IntoResponse for SimpleCsv<I>
+where
+    I: IntoIterator,
+    I::Item: Serialize,
+{
+    fn into_response(self) -> Response {
+        let mut body = Vec::new();
+        for (idx, row) in self.rows.into_iter().enumerate() {
+            match csv_row(&row, idx == 0) {
+                Ok(line) => body.extend(line),
+                Err(e) => {
+                    return SimpleError::new(
+                        &format!("unable to serialize csv: {}", e),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                    .into_response()
+                }
+            }
+        }
+        (SimpleCsv::<I>::content_headers(self.headers), body).into_response()
+    }
+}
+
+#[cfg(feature = "csv")]
+impl<S, T> IntoResponse for SimpleCsv<CsvStream<S>>
+where
+    S: Stream<Item = Result<T, AnyError>> + Send + 'static,
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let headers = SimpleCsv::<()>::content_headers(self.headers);
+        let mut first = true;
+        let stream = self.rows.0.map(move |row| {
+            let line = csv_row(&row?, first)?;
+            first = false;
+            Ok(Bytes::from(line))
+        });
+        SimpleStream::new(StatusCode::OK, headers, stream).into_response()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SseEvent<T> {
+    data: T,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl<T> SseEvent<T>
+where
+    T: Serialize,
+{
+    pub fn new(data: T) -> SseEvent<T> {
+        SseEvent {
+            data,
+            event: None,
+            id: None,
+            retry: None,
+        }
+    }
+    pub fn event(mut self, event: &str) -> SseEvent<T> {
+        self.event = Some(event.to_string());
+        self
+    }
+    pub fn id(mut self, id: &str) -> SseEvent<T> {
+        self.id = Some(id.to_string());
+        self
+    }
+    pub fn retry(mut self, retry: Duration) -> SseEvent<T> {
+        self.retry = Some(retry);
+        self
+    }
+    fn encode(&self) -> Result<Bytes, AnyError> {
+        let mut buf = String::new();
+        // newlines would end the field early, so they are stripped from names and ids
+        let field = |value: &str| value.replace(['\r', '\n'], "");
+        if let Some(event) = &self.event {
+            buf.push_str(&format!("event: {}\n", field(event)));
+        }
+        if let Some(id) = &self.id {
+            buf.push_str(&format!("id: {}\n", field(id)));
+        }
+        if let Some(retry) = self.retry {
+            buf.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        buf.push_str("data: ");
+        buf.push_str(&serde_json::to_string(&self.data)?);
+        buf.push_str("\n\n");
+        Ok(Bytes::from(buf))
+    }
+}
+
+pub struct SseStream<S> {
+    headers: HeaderMap,
+    keep_alive: Option<Duration>,
+    stream: S,
+}
+
+impl<S, T> SseStream<S>
+where
+    S: Stream<Item = Result<SseEvent<T>, AnyError>> + Send + 'static,
+    T: Serialize,
+{
+    pub fn new(stream: S) -> SseStream<S> {
+        SseStream {
+            headers: HeaderMap::new(),
+            keep_alive: Some(Duration::from_secs(15)),
+            stream,
+        }
+    }
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> SseStream<S> {
+        self.keep_alive = interval;
+        self
+    }
+    pub fn with_header<K, V>(mut self, name: K, value: V) -> SseStream<S>
+    where
+        K: TryInto<HeaderName>,
+        V: TryInto<HeaderValue>,
+    {
+        insert_header(&mut self.headers, name, value);
+        self
+    }
+}
+impl<S, T> IntoResponse for SseStream<S>
+where
+    S: Stream<Item = Result<SseEvent<T>, AnyError>> + Send + 'static,
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let mut headers = self.headers;
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream"),
+        );
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        // keeps nginx from buffering the events
+        headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+        let events = self.stream.map(|event| event?.encode()).boxed();
+        let body = SseBody {
+            events,
+            keep_alive: self.keep_alive.map(|interval| {
+                let mut timer =
+                    tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+                timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                timer
+            }),
+            finished: false,
+            request_id: current_request_id(),
+        };
+        SimpleStream::new(StatusCode::OK, headers, body).into_response()
+    }
+}
+
+struct SseBody {
+    events: BoxStream<'static, Result<Bytes, AnyError>>,
+    keep_alive: Option<tokio::time::Interval>,
+    finished: bool,
+    request_id: Option<String>,
+}
+impl Stream for SseBody {
+    type Item = Result<Bytes, AnyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        match self.events.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(event))) => {
+                if let Some(keep_alive) = self.keep_alive.as_mut() {
+                    keep_alive.reset();
+                }
+                return Poll::Ready(Some(Ok(event)));
+            }
+            Poll::Ready(Some(Err(err))) => {
+                // trailers are invisible to EventSource, report the failure as an event
+                self.finished = true;
+                let message = stream_error("sse", &err, &self.request_id);
+                let data = serde_json::to_string(&message).unwrap_or_default();
+                let event = format!("event: error\ndata: {}\n\n", data);
+                return Poll::Ready(Some(Ok(Bytes::from(event))));
+            }
+            Poll::Ready(None) => {
+                self.finished = true;
+                return Poll::Ready(None);
+            }
+            Poll::Pending => {}
+        }
+        let tick = self
+            .keep_alive
+            .as_mut()
+            .is_some_and(|keep_alive| keep_alive.poll_tick(cx).is_ready());
+        match tick {
+            true => Poll::Ready(Some(Ok(Bytes::from_static(b":\n\n")))),
+            false => Poll::Pending,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LastEventId(pub Option<String>);
+
+#[axum::async_trait]
+impl<S> axum::extract::FromRequestParts<S> for LastEventId
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(LastEventId(
+            parts
+                .headers
+                .get("last-event-id")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+        ))
+    }
+}
+
+// the `x-cache-lookup` header, set by CacheLayer and impl_hit_and_304!
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheLookup {
+    Hit,
+    Miss,
+}
+
+impl CacheLookup {
+    pub const HEADER: HeaderName = HeaderName::from_static("x-cache-lookup");
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheLookup::Hit => "HIT",
+            CacheLookup::Miss => "MISS",
+        }
+    }
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_static(self.as_str())
+    }
+}
+
+impl From<bool> for CacheLookup {
+    fn from(hit: bool) -> CacheLookup {
+        match hit {
+            true => CacheLookup::Hit,
+            false => CacheLookup::Miss,
+        }
+    }
+}
+
+impl IntoResponseParts for CacheLookup {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.headers_mut()
+            .insert(CacheLookup::HEADER, self.header_value());
+        Ok(res)
     }
 }
 
@@ -59,13 +715,8 @@ macro_rules! impl_hit_and_304 {
                     );
                 }
                 res.headers_mut().append(
-                    <axum::headers::HeaderName as std::str::FromStr>::from_str("x-cache-lookup")
-                        .unwrap(),
-                    if self._hit {
-                        "HIT".parse().unwrap()
-                    } else {
-                        "MISS".parse().unwrap()
-                    },
+                    $crate::CacheLookup::HEADER,
+                    $crate::CacheLookup::from(self._hit).header_value(),
                 );
                 res
             }
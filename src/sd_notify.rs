@@ -0,0 +1,81 @@
+use std::{env, io, os::unix::net::UnixDatagram, sync::Once, time::Duration};
+
+fn socket() -> Option<String> {
+    env::var("NOTIFY_SOCKET").ok().filter(|s| !s.is_empty())
+}
+
+// sends a state like `STATUS=warming up` to the systemd notify socket, returns
+// false when there is none, i.e. the unit is not `Type=notify`
+pub fn sd_notify(state: &str) -> bool {
+    let Some(path) = socket() else {
+        return false;
+    };
+    match send(&path, state) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!("sd_notify to {} failed: {}", path, e);
+            false
+        }
+    }
+}
+
+fn send(path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    // `@name` is an abstract socket
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract sockets are linux only",
+            ));
+        }
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+// first listener serving, also starts the watchdog pings systemd asks for
+pub(crate) fn ready() {
+    static READY: Once = Once::new();
+    READY.call_once(|| {
+        if !sd_notify(&format!("READY=1\nMAINPID={}", std::process::id())) {
+            return;
+        }
+        if let Some(interval) = watchdog() {
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    sd_notify("WATCHDOG=1");
+                }
+            });
+        }
+    });
+}
+
+pub(crate) fn stopping() {
+    static STOPPING: Once = Once::new();
+    STOPPING.call_once(|| {
+        sd_notify("STOPPING=1");
+    });
+}
+
+// pings at half the WATCHDOG_USEC timeout, as sd_watchdog_enabled(3) recommends
+fn watchdog() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
@@ -0,0 +1,150 @@
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath},
+    http::Request,
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use sentry::{
+    protocol::{self, SpanStatus},
+    Hub, SentryFutureExt, TransactionContext,
+};
+use tower::{Layer, Service};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::{listener::IpConnectInfo, realip::real_ip};
+
+pub fn sentry_tracing_layer<S>() -> sentry_tracing::SentryLayer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    sentry_tracing::layer().event_filter(|metadata| match *metadata.level() {
+        Level::ERROR | Level::WARN | Level::INFO => sentry_tracing::EventFilter::Breadcrumb,
+        _ => sentry_tracing::EventFilter::Ignore,
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SentryLayer {}
+
+impl SentryLayer {
+    pub fn new() -> SentryLayer {
+        SentryLayer {}
+    }
+}
+
+impl<S> Layer<S> for SentryLayer {
+    type Service = SentryService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SentryService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SentryService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for SentryService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let hub = Arc::new(Hub::new_from_top(Hub::current()));
+        let method = req.method().to_string();
+        let path = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| req.uri().path().to_string());
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<IpConnectInfo>>()
+            .map(|info| real_ip(req.headers(), &info.0));
+        let request_id = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        let transaction = hub.start_transaction(TransactionContext::new(
+            &format!("{} {}", method, path),
+            "http.server",
+        ));
+        transaction.set_request(protocol::Request {
+            method: Some(method.clone()),
+            url: req.uri().to_string().parse().ok(),
+            headers: req
+                .headers()
+                .iter()
+                .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+                .collect(),
+            ..Default::default()
+        });
+        hub.configure_scope(|scope| {
+            scope.set_span(Some(transaction.clone().into()));
+            scope.set_tag("http.method", &method);
+            scope.set_tag("http.path", &path);
+            if let Some(ip) = &ip {
+                scope.set_tag("real_ip", ip);
+                scope.set_user(Some(protocol::User {
+                    ip_address: ip.parse().ok().map(protocol::IpAddress::Exact),
+                    ..Default::default()
+                }));
+            }
+            if let Some(request_id) = &request_id {
+                scope.set_tag("request_id", request_id);
+            }
+        });
+
+        let fut = inner.call(req);
+        Box::pin(
+            async move {
+                let res = fut.await;
+                if let Ok(res) = &res {
+                    transaction.set_status(status_to_span(res.status().as_u16()));
+                }
+                transaction.finish();
+                res
+            }
+            .bind_hub(hub),
+        )
+    }
+}
+
+fn status_to_span(status: u16) -> SpanStatus {
+    match status {
+        100..=399 => SpanStatus::Ok,
+        400 => SpanStatus::InvalidArgument,
+        401 => SpanStatus::Unauthenticated,
+        403 => SpanStatus::PermissionDenied,
+        404 => SpanStatus::NotFound,
+        409 => SpanStatus::AlreadyExists,
+        429 => SpanStatus::ResourceExhausted,
+        499 => SpanStatus::Cancelled,
+        status if status < 500 => SpanStatus::FailedPrecondition,
+        501 => SpanStatus::Unimplemented,
+        503 => SpanStatus::Unavailable,
+        504 => SpanStatus::DeadlineExceeded,
+        _ => SpanStatus::InternalError,
+    }
+}
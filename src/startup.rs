@@ -0,0 +1,208 @@
+use std::{
+    env,
+    sync::{Mutex, Once, OnceLock},
+};
+
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StartupInfo {
+    pub version: Option<String>,
+    pub git_sha: Option<String>,
+    pub build: Option<crate::build_info::BuildInfo>,
+    pub listeners: Vec<String>,
+    pub features: Vec<&'static str>,
+    pub kv: Option<KvHealth>,
+    pub config_sources: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KvHealth {
+    pub backend: &'static str,
+    pub healthy: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    version: Option<String>,
+    git_sha: Option<String>,
+    listeners: Vec<String>,
+    config_sources: Vec<String>,
+    #[cfg(feature = "kv")]
+    kv: Option<crate::KVManager>,
+}
+
+fn state() -> &'static Mutex<State> {
+    static STATE: OnceLock<Mutex<State>> = OnceLock::new();
+    STATE.get_or_init(Default::default)
+}
+
+pub fn set_version(version: &str, git_sha: Option<&str>) {
+    let mut state = state().lock().unwrap();
+    state.version = Some(version.to_string());
+    state.git_sha = git_sha.map(|sha| sha.to_string());
+}
+
+#[cfg(feature = "kv")]
+pub fn register_kv(kv: &crate::KVManager) {
+    state().lock().unwrap().kv = Some(kv.clone());
+}
+
+#[cfg(feature = "kv")]
+pub(crate) fn registered_kv() -> Option<crate::KVManager> {
+    state().lock().unwrap().kv.clone()
+}
+
+pub fn config_source(source: &str) {
+    let mut state = state().lock().unwrap();
+    if !state.config_sources.iter().any(|s| s == source) {
+        state.config_sources.push(source.to_string());
+    }
+}
+
+pub(crate) fn listening(addr: String) {
+    state().lock().unwrap().listeners.push(addr);
+}
+
+const FEATURES: &[(&str, bool)] = &[
+    ("sentry", cfg!(feature = "sentry")),
+    ("kv", cfg!(feature = "kv")),
+    ("kv-compress", cfg!(feature = "kv-compress")),
+    ("kv-encrypt", cfg!(feature = "kv-encrypt")),
+    ("kv-s3", cfg!(feature = "kv-s3")),
+    ("kv-consul", cfg!(feature = "kv-consul")),
+    ("kv-dynamodb", cfg!(feature = "kv-dynamodb")),
+    ("config", cfg!(feature = "config")),
+    ("jobs", cfg!(feature = "jobs")),
+    ("queue", cfg!(feature = "queue")),
+    ("webhooks", cfg!(feature = "webhooks")),
+    ("msgpack", cfg!(feature = "msgpack")),
+    ("reqwest", cfg!(feature = "reqwest")),
+    ("sqlx", cfg!(feature = "sqlx")),
+    ("cbor", cfg!(feature = "cbor")),
+    ("csv", cfg!(feature = "csv")),
+    ("cors", cfg!(feature = "cors")),
+    ("compression", cfg!(feature = "compression")),
+    ("auth", cfg!(feature = "auth")),
+    ("auth-jwks", cfg!(feature = "auth-jwks")),
+    ("apikey", cfg!(feature = "apikey")),
+    ("acme", cfg!(feature = "acme")),
+    ("mtls", cfg!(feature = "mtls")),
+    ("otel", cfg!(feature = "otel")),
+    ("log-reload", cfg!(feature = "log-reload")),
+    ("upload", cfg!(feature = "upload")),
+    ("csrf", cfg!(feature = "csrf")),
+    ("ws", cfg!(feature = "ws")),
+    ("cache", cfg!(feature = "cache")),
+];
+
+#[cfg(feature = "kv")]
+async fn kv_health() -> Option<KvHealth> {
+    let kv = state().lock().unwrap().kv.clone()?;
+    // a missing probe key still proves the backend answers
+    let res = kv.get_some::<serde_json::Value>("__startup:probe").await;
+    Some(KvHealth {
+        backend: kv.backend(),
+        healthy: res.is_ok(),
+        error: res.err().map(|e| e.to_string()),
+    })
+}
+
+#[cfg(not(feature = "kv"))]
+async fn kv_health() -> Option<KvHealth> {
+    None
+}
+
+pub async fn collect() -> StartupInfo {
+    let kv = kv_health().await;
+    let state = state().lock().unwrap();
+    StartupInfo {
+        // fall back to variables commonly injected by build pipelines
+        version: state
+            .version
+            .clone()
+            .or_else(|| env::var("TOKI_BUILD_VERSION").ok()),
+        git_sha: state
+            .git_sha
+            .clone()
+            .or_else(|| env::var("TOKI_BUILD_SHA").ok()),
+        build: crate::build_info::registered(),
+        listeners: state.listeners.clone(),
+        features: FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect(),
+        kv,
+        config_sources: state.config_sources.clone(),
+    }
+}
+
+pub async fn report() -> StartupInfo {
+    let info = collect().await;
+    tracing::info!(
+        version = info.version.as_deref().unwrap_or("unknown"),
+        git_sha = info.git_sha.as_deref().unwrap_or("unknown"),
+        built_at = info.build.and_then(|b| b.built_at).unwrap_or("unknown"),
+        rustc = info.build.and_then(|b| b.rustc).unwrap_or("unknown"),
+        listeners = ?info.listeners,
+        features = ?info.features,
+        config_sources = ?info.config_sources,
+        "starting up"
+    );
+    match &info.kv {
+        Some(KvHealth {
+            backend,
+            healthy: true,
+            ..
+        }) => tracing::info!(backend, "kv is reachable"),
+        Some(KvHealth {
+            backend,
+            error: Some(error),
+            ..
+        }) => tracing::warn!(backend, error = error.as_str(), "kv health check failed"),
+        _ => {}
+    }
+    info
+}
+
+// the report waits for the first accept loop, so every listener bound by then is included
+pub(crate) fn report_once() {
+    static REPORTED: Once = Once::new();
+    REPORTED.call_once(|| {
+        tokio::spawn(report());
+    });
+}
+
+pub(crate) fn info_token() -> Option<String> {
+    env::var("TOKI_INFO_TOKEN").ok().filter(|t| !t.is_empty())
+}
+
+// checks every byte regardless of where the first mismatch is
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
+
+pub(crate) async fn info_handler(token: String, headers: HeaderMap) -> impl IntoResponse {
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !constant_time_eq(given.as_bytes(), token.as_bytes()) {
+        return crate::SimpleStatus::new(StatusCode::NOT_FOUND).into_response();
+    }
+    Json(collect().await).into_response()
+}
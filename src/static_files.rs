@@ -0,0 +1,369 @@
+use std::{
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::SimpleStatus;
+
+pub fn static_files<P>(dir: P) -> Router
+where
+    P: Into<PathBuf>,
+{
+    StaticFiles::new(dir).router()
+}
+
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    dir: PathBuf,
+    index: String,
+    spa: bool,
+    precompressed: bool,
+    cache_control: Option<HeaderValue>,
+}
+
+impl StaticFiles {
+    pub fn new<P>(dir: P) -> StaticFiles
+    where
+        P: Into<PathBuf>,
+    {
+        StaticFiles {
+            dir: dir.into(),
+            index: "index.html".to_string(),
+            spa: false,
+            precompressed: true,
+            cache_control: None,
+        }
+    }
+    pub fn index(mut self, index: &str) -> StaticFiles {
+        self.index = index.to_string();
+        self
+    }
+    pub fn spa(mut self, spa: bool) -> StaticFiles {
+        self.spa = spa;
+        self
+    }
+    pub fn precompressed(mut self, precompressed: bool) -> StaticFiles {
+        self.precompressed = precompressed;
+        self
+    }
+    pub fn cache_control(mut self, cache_control: &str) -> StaticFiles {
+        self.cache_control =
+            Some(HeaderValue::from_str(cache_control).expect("invalid cache-control header value"));
+        self
+    }
+    pub fn router(self) -> Router {
+        let files = Arc::new(self);
+        Router::new().fallback(move |req: Request| {
+            let files = files.clone();
+            async move { files.serve(req).await }
+        })
+    }
+
+    async fn serve(&self, req: Request) -> Response {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return SimpleStatus::new(StatusCode::METHOD_NOT_ALLOWED)
+                .with_header(header::ALLOW, "GET, HEAD")
+                .into_response();
+        }
+        let Some(path) = self.resolve(req.uri().path()).await else {
+            return SimpleStatus::new(StatusCode::NOT_FOUND).into_response();
+        };
+        match self.respond(&path, req.method(), req.headers()).await {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::warn!("unable to serve {}: {}", path.display(), e);
+                SimpleStatus::new(StatusCode::NOT_FOUND).into_response()
+            }
+        }
+    }
+
+    async fn resolve(&self, uri_path: &str) -> Option<PathBuf> {
+        let decoded = percent_encoding::percent_decode_str(uri_path)
+            .decode_utf8()
+            .ok()?;
+        let mut path = self.dir.clone();
+        for component in Path::new(decoded.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        match tokio::fs::metadata(&path).await {
+            Ok(meta) if meta.is_dir() => {
+                let index = path.join(&self.index);
+                if tokio::fs::metadata(&index).await.is_ok() {
+                    return Some(index);
+                }
+            }
+            Ok(_) => return Some(path),
+            Err(_) => {}
+        }
+        // unknown routes without a file extension belong to the client side router
+        if self.spa && path.extension().is_none() {
+            return Some(self.dir.join(&self.index));
+        }
+        None
+    }
+
+    async fn respond(
+        &self,
+        path: &Path,
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> std::io::Result<Response> {
+        let (file_path, encoding) = self.negotiate(path, headers).await;
+        let meta = tokio::fs::metadata(&file_path).await?;
+        let len = meta.len();
+        let modified = meta.modified().ok();
+        let etag = format!(
+            "\"{:x}-{:x}\"",
+            len,
+            modified
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        );
+
+        let mut res_headers = HeaderMap::new();
+        res_headers.insert(header::CONTENT_TYPE, content_type(path));
+        res_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if let Ok(etag) = HeaderValue::from_str(&etag) {
+            res_headers.insert(header::ETAG, etag);
+        }
+        if let Some(modified) = modified {
+            if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+                res_headers.insert(header::LAST_MODIFIED, value);
+            }
+        }
+        if self.precompressed {
+            res_headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        if let Some(encoding) = encoding {
+            res_headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            res_headers.insert(header::CACHE_CONTROL, cache_control.clone());
+        }
+
+        if not_modified(headers, &etag, modified) {
+            return Ok((StatusCode::NOT_MODIFIED, res_headers).into_response());
+        }
+
+        let mut status = StatusCode::OK;
+        let (mut start, mut end) = (0, len);
+        if let Some(range) = range_header(headers, &etag, modified) {
+            match parse_range(range, len) {
+                Some((from, to)) => {
+                    status = StatusCode::PARTIAL_CONTENT;
+                    (start, end) = (from, to + 1);
+                    if let Ok(value) =
+                        HeaderValue::from_str(&format!("bytes {}-{}/{}", from, to, len))
+                    {
+                        res_headers.insert(header::CONTENT_RANGE, value);
+                    }
+                }
+                None if range.starts_with("bytes=") && !range.contains(',') => {
+                    if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+                        res_headers.insert(header::CONTENT_RANGE, value);
+                    }
+                    return Ok((StatusCode::RANGE_NOT_SATISFIABLE, res_headers).into_response());
+                }
+                None => {}
+            }
+        }
+        res_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+
+        if method == Method::HEAD {
+            return Ok((status, res_headers).into_response());
+        }
+        let mut file = tokio::fs::File::open(&file_path).await?;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+        let body = Body::from_stream(read_chunks(file, end - start));
+        Ok((status, res_headers, body).into_response())
+    }
+
+    async fn negotiate(&self, path: &Path, headers: &HeaderMap) -> (PathBuf, Option<&'static str>) {
+        if self.precompressed {
+            let accept = headers
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            for (encoding, extension) in [("br", "br"), ("gzip", "gz")] {
+                if !accepts_encoding(accept, encoding) {
+                    continue;
+                }
+                let mut candidate = path.as_os_str().to_owned();
+                candidate.push(".");
+                candidate.push(extension);
+                let candidate = PathBuf::from(candidate);
+                if tokio::fs::metadata(&candidate).await.is_ok() {
+                    return (candidate, Some(encoding));
+                }
+            }
+        }
+        (path.to_path_buf(), None)
+    }
+}
+
+fn accepts_encoding(accept: &str, encoding: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let rejected = parts.any(|p| {
+            p.trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .map(|q| q == 0.0)
+                .unwrap_or(false)
+        });
+        name.eq_ignore_ascii_case(encoding) && !rejected
+    })
+}
+
+fn not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    {
+        return if_none_match.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        });
+    }
+    match (header_date(headers, header::IF_MODIFIED_SINCE), modified) {
+        (Some(since), Some(modified)) => truncate_secs(modified) <= since,
+        _ => false,
+    }
+}
+
+pub(crate) fn range_header<'a>(
+    headers: &'a HeaderMap,
+    etag: &str,
+    modified: Option<SystemTime>,
+) -> Option<&'a str> {
+    let range = headers.get(header::RANGE)?.to_str().ok()?;
+    if let Some(if_range) = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        let matches = if if_range.starts_with('"') {
+            if_range == etag
+        } else {
+            match (httpdate::parse_http_date(if_range).ok(), modified) {
+                (Some(date), Some(modified)) => truncate_secs(modified) == date,
+                _ => false,
+            }
+        };
+        if !matches {
+            return None;
+        }
+    }
+    Some(range)
+}
+
+pub(crate) fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = range.strip_prefix("bytes=")?;
+    if spec.contains(',') || len == 0 {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return None;
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (start, "") => (start.parse().ok()?, len - 1),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len - 1)),
+    };
+    if start > end || start >= len {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+}
+
+fn truncate_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + std::time::Duration::from_secs(secs)
+}
+
+pub(crate) fn read_chunks(
+    file: tokio::fs::File,
+    remaining: u64,
+) -> impl futures_util::Stream<Item = std::io::Result<Bytes>> {
+    futures_util::stream::unfold((file, remaining), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buf = vec![0; remaining.min(64 * 1024) as usize];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), (file, remaining - n as u64)))
+            }
+            Err(e) => Some((Err(e), (file, 0))),
+        }
+    })
+}
+
+pub(crate) fn content_type(path: &Path) -> HeaderValue {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    HeaderValue::from_static(match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    })
+}
@@ -0,0 +1,113 @@
+use std::{
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::future::BoxFuture;
+use tokio::sync::watch;
+use tower::{Layer, Service};
+use tracing::Span;
+
+use crate::SimpleError;
+
+// set by the outermost TimeoutLayer, inner ones move the deadline instead of
+// starting timers of their own, so a route can ask for more time than the
+// listener default as well as less
+#[derive(Clone)]
+struct Deadline(watch::Sender<Option<Duration>>);
+
+// cancels the handler and answers 504 once the request runs longer than the
+// timeout. applied globally through the listener's `timeout` option or
+// TOKI_REQUEST_TIMEOUT, a TimeoutLayer on a route overrides it for that route
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutLayer {
+    timeout: Option<Duration>,
+}
+
+impl TimeoutLayer {
+    pub fn new(timeout: Duration) -> TimeoutLayer {
+        TimeoutLayer {
+            timeout: Some(timeout),
+        }
+    }
+    // lifts the timeout, e.g. for streaming routes
+    pub fn none() -> TimeoutLayer {
+        TimeoutLayer { timeout: None }
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = TimeoutService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimeoutService {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeoutService<S> {
+    inner: S,
+    timeout: Option<Duration>,
+}
+
+impl<S, B> Service<Request<B>> for TimeoutService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        if let Some(deadline) = req.extensions().get::<Deadline>() {
+            deadline.0.send_replace(self.timeout);
+            return Box::pin(inner.call(req));
+        }
+        let (tx, mut rx) = watch::channel(self.timeout);
+        req.extensions_mut().insert(Deadline(tx));
+        let start = Instant::now();
+        let fut = inner.call(req);
+        Box::pin(async move {
+            tokio::pin!(fut);
+            loop {
+                let timeout = *rx.borrow_and_update();
+                let expired = async {
+                    match timeout {
+                        Some(timeout) => tokio::time::sleep_until((start + timeout).into()).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    res = &mut fut => return res,
+                    // a route moved the deadline
+                    Ok(()) = rx.changed() => continue,
+                    _ = expired => {
+                        let timeout = timeout.unwrap_or_default();
+                        Span::current().record("timeout_ms", timeout.as_millis() as u64);
+                        tracing::warn!("request timed out after {}ms", timeout.as_millis());
+                        return Ok(SimpleError::new(
+                            "request timed out",
+                            StatusCode::GATEWAY_TIMEOUT,
+                        )
+                        .into_response());
+                    }
+                }
+            }
+        })
+    }
+}
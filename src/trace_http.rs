@@ -0,0 +1,199 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath},
+    http::{header, HeaderMap, HeaderName, Request},
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use http_body::Body;
+use tower::{Layer, Service};
+use tracing::{field, Instrument, Span};
+
+use crate::{listener::IpConnectInfo, realip::real_ip};
+
+pub fn trace_http() -> TraceHttpLayer {
+    TraceHttpLayer::new()
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceHttpLayer {
+    sample_rate: f64,
+    headers: bool,
+    redact: Arc<Vec<HeaderName>>,
+}
+
+impl Default for TraceHttpLayer {
+    fn default() -> Self {
+        TraceHttpLayer::new()
+    }
+}
+
+impl TraceHttpLayer {
+    pub fn new() -> TraceHttpLayer {
+        TraceHttpLayer {
+            sample_rate: 1.0,
+            headers: false,
+            redact: Arc::new(vec![
+                header::AUTHORIZATION,
+                header::PROXY_AUTHORIZATION,
+                header::COOKIE,
+                header::SET_COOKIE,
+            ]),
+        }
+    }
+    pub fn sample(mut self, rate: f64) -> TraceHttpLayer {
+        self.sample_rate = rate;
+        self
+    }
+    pub fn headers(mut self, enabled: bool) -> TraceHttpLayer {
+        self.headers = enabled;
+        self
+    }
+    pub fn redact(mut self, name: &str) -> TraceHttpLayer {
+        let name = HeaderName::from_bytes(name.as_bytes()).expect("invalid header name");
+        Arc::make_mut(&mut self.redact).push(name);
+        self
+    }
+    fn sampled(&self) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        if self.sample_rate <= 0.0 {
+            return false;
+        }
+        let random = RandomState::new().build_hasher().finish();
+        (random as f64 / u64::MAX as f64) < self.sample_rate
+    }
+    fn format_headers(&self, headers: &HeaderMap) -> String {
+        headers
+            .iter()
+            .map(|(k, v)| {
+                if self.redact.contains(k) {
+                    format!("{}: [redacted]", k)
+                } else {
+                    format!("{}: {}", k, String::from_utf8_lossy(v.as_bytes()))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl<S> Layer<S> for TraceHttpLayer {
+    type Service = TraceHttp<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceHttp {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceHttp<S> {
+    inner: S,
+    layer: TraceHttpLayer,
+}
+
+impl<S, B> Service<Request<B>> for TraceHttp<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let sampled = self.layer.sampled();
+        let method = req.method().to_string();
+        let path = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| req.uri().path().to_string());
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<IpConnectInfo>>()
+            .map(|info| real_ip(req.headers(), &info.0));
+        let request_id = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let request_size = content_length(req.headers());
+        let headers = self
+            .layer
+            .headers
+            .then(|| self.layer.format_headers(req.headers()));
+
+        let span = tracing::info_span!(
+            "http",
+            method = %method,
+            path = %path,
+            real_ip = ip.as_deref(),
+            request_id = request_id.as_deref(),
+            request_size,
+            headers = headers.as_deref(),
+            status = field::Empty,
+            latency_ms = field::Empty,
+            response_size = field::Empty,
+            timeout_ms = field::Empty,
+        );
+        let span = if sampled { span } else { Span::none() };
+
+        let start = Instant::now();
+        let fut = inner.call(req);
+        Box::pin(
+            async move {
+                let res = fut.await;
+                let latency = start.elapsed().as_secs_f64() * 1000.0;
+                if let Ok(res) = &res {
+                    let status = res.status().as_u16();
+                    let response_size =
+                        content_length(res.headers()).or_else(|| res.body().size_hint().exact());
+                    let span = Span::current();
+                    span.record("status", status);
+                    span.record("latency_ms", latency);
+                    span.record("response_size", response_size);
+                    if status >= 500 {
+                        tracing::error!(
+                            method = %method,
+                            path = %path,
+                            status,
+                            latency_ms = latency,
+                            "request failed"
+                        );
+                    } else if sampled {
+                        tracing::info!(status, latency_ms = latency, "request finished");
+                    }
+                }
+                res
+            }
+            .instrument(span),
+        )
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
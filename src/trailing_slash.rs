@@ -0,0 +1,95 @@
+use std::task::{Context, Poll};
+
+use axum::{
+    http::{header, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::future::{BoxFuture, FutureExt};
+use tower::{Layer, Service};
+
+use crate::response::redirect_location;
+
+// redirects `/a/` to `/a`, or `/a` to `/a/` with append(), keeping the query.
+// GET and HEAD get a 301, everything else a 308 so the method and body survive
+#[derive(Debug, Clone, Default)]
+pub struct TrailingSlashRedirect {
+    append: bool,
+}
+
+impl TrailingSlashRedirect {
+    pub fn trim() -> TrailingSlashRedirect {
+        TrailingSlashRedirect { append: false }
+    }
+    pub fn append() -> TrailingSlashRedirect {
+        TrailingSlashRedirect { append: true }
+    }
+    fn target(&self, path: &str) -> Option<String> {
+        // `//host/` would turn into a protocol relative redirect, and browsers
+        // read `/\host/` the same way
+        if path.starts_with("//") || path.starts_with("/\\") {
+            return None;
+        }
+        if self.append {
+            match path.ends_with('/') {
+                true => None,
+                false => Some(format!("{}/", path)),
+            }
+        } else {
+            match path.trim_end_matches('/') {
+                trimmed if trimmed.len() == path.len() || trimmed.is_empty() => None,
+                trimmed => Some(trimmed.to_string()),
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for TrailingSlashRedirect {
+    type Service = TrailingSlashService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TrailingSlashService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrailingSlashService<S> {
+    inner: S,
+    layer: TrailingSlashRedirect,
+}
+
+impl<S, B> Service<Request<B>> for TrailingSlashService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        if let Some(mut location) = self.layer.target(req.uri().path()) {
+            if let Some(query) = req.uri().query() {
+                location = format!("{}?{}", location, query);
+            }
+            if let Ok(location) = redirect_location(&location) {
+                let status = match *req.method() {
+                    Method::GET | Method::HEAD => StatusCode::MOVED_PERMANENTLY,
+                    _ => StatusCode::PERMANENT_REDIRECT,
+                };
+                let res = (status, [(header::LOCATION, location)]).into_response();
+                return async move { Ok(res) }.boxed();
+            }
+        }
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move { inner.call(req).await })
+    }
+}
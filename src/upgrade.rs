@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, BufRead, BufReader, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use futures_util::FutureExt;
+use tokio::sync::Notify;
+
+use crate::listener::{trigger_shutdown, Shutdown};
+
+struct Handoff {
+    fd: RawFd,
+    done: Arc<Notify>,
+}
+
+fn handoffs() -> &'static Mutex<HashMap<String, Handoff>> {
+    static HANDOFFS: OnceLock<Mutex<HashMap<String, Handoff>>> = OnceLock::new();
+    HANDOFFS.get_or_init(Default::default)
+}
+
+fn control_path() -> Option<String> {
+    env::var("TOKI_UPGRADE_SOCKET")
+        .ok()
+        .filter(|p| !p.is_empty())
+}
+
+// the connection to the previous process is opened once, before this process
+// replaces the control socket with its own
+fn previous() -> &'static Mutex<Option<BufReader<UnixStream>>> {
+    static PREVIOUS: OnceLock<Mutex<Option<BufReader<UnixStream>>>> = OnceLock::new();
+    PREVIOUS.get_or_init(|| {
+        let conn = control_path().and_then(|path| UnixStream::connect(path).ok());
+        Mutex::new(conn.map(BufReader::new))
+    })
+}
+
+pub(crate) fn inherit(addr: &str) -> Option<OwnedFd> {
+    control_path()?;
+    let mut previous = previous().lock().unwrap();
+    let conn = previous.as_mut()?;
+    let res = (|| {
+        conn.get_mut().write_all(format!("{}\n", addr).as_bytes())?;
+        recv_fd(conn.get_ref().as_raw_fd())
+    })();
+    match res {
+        Ok(Some(fd)) => {
+            tracing::info!("took over {} from the previous process", addr);
+            Some(fd)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            tracing::warn!("upgrade handoff for {} failed: {}", addr, e);
+            *previous = None;
+            None
+        }
+    }
+}
+
+pub(crate) fn register(addr: &str, fd: RawFd, shutdown: Shutdown) -> Shutdown {
+    let Some(path) = control_path() else {
+        return shutdown;
+    };
+    let done = Arc::new(Notify::new());
+    handoffs().lock().unwrap().insert(
+        addr.to_string(),
+        Handoff {
+            fd,
+            done: done.clone(),
+        },
+    );
+    start_control(path);
+    async move {
+        tokio::select! {
+            _ = shutdown => {},
+            _ = done.notified() => {},
+        }
+    }
+    .boxed()
+    .shared()
+}
+
+fn start_control(path: String) {
+    static STARTED: std::sync::Once = std::sync::Once::new();
+    STARTED.call_once(|| {
+        // make sure the previous process is asked before its socket is replaced
+        drop(previous().lock().unwrap());
+        std::fs::remove_file(&path).unwrap_or(());
+        let listener = match tokio::net::UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("unable to bind upgrade socket {}: {}", path, e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("upgrade socket accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let stream = match stream.into_std() {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = serve_control(stream) {
+                        tracing::warn!("upgrade handoff failed: {}", e);
+                    }
+                });
+            }
+        });
+    });
+}
+
+fn serve_control(stream: UnixStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut addr = String::new();
+        if reader.read_line(&mut addr)? == 0 {
+            return Ok(());
+        }
+        let addr = addr.trim_end();
+        let handoff = handoffs().lock().unwrap().remove(addr);
+        let fd = reader.get_ref().as_raw_fd();
+        match handoff {
+            Some(handoff) => {
+                send_fd(fd, Some(handoff.fd))?;
+                tracing::info!("handed {} over to a new process, draining", addr);
+                handoff.done.notify_one();
+                if handoffs().lock().unwrap().is_empty() {
+                    trigger_shutdown();
+                    return Ok(());
+                }
+            }
+            None => send_fd(fd, None)?,
+        }
+    }
+}
+
+fn send_fd(sock: RawFd, fd: Option<RawFd>) -> io::Result<()> {
+    let mut byte = [fd.is_some() as u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+    }
+    if unsafe { libc::sendmsg(sock, &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recv_fd(sock: RawFd) -> io::Result<Option<OwnedFd>> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    let received = unsafe { libc::recvmsg(sock, &mut msg, 0) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if byte[0] == 0 {
+        return Ok(None);
+    }
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no file descriptor received",
+            ));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+        Ok(Some(OwnedFd::from_raw_fd(fd)))
+    }
+}
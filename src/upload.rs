@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request},
+    http::{header, StatusCode},
+    Extension,
+};
+use tokio::io::AsyncWriteExt;
+
+use crate::{limits::parse_size, AnyError, SimpleError};
+
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    dir: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    max_field_size: usize,
+    max_fields: usize,
+    allowed_types: Vec<String>,
+}
+
+impl UploadOptions {
+    pub fn new() -> UploadOptions {
+        UploadOptions {
+            dir: env::var("TOKI_UPLOAD_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| env::temp_dir()),
+            max_file_size: env::var("TOKI_UPLOAD_MAX_SIZE")
+                .ok()
+                .and_then(|v| parse_size(&v).ok())
+                .unwrap_or(10 << 20) as u64,
+            max_files: 10,
+            max_field_size: 64 << 10,
+            max_fields: 100,
+            allowed_types: Vec::new(),
+        }
+    }
+    pub fn dir<P: AsRef<Path>>(mut self, dir: P) -> UploadOptions {
+        self.dir = dir.as_ref().to_path_buf();
+        self
+    }
+    pub fn max_file_size(mut self, size: u64) -> UploadOptions {
+        self.max_file_size = size;
+        self
+    }
+    pub fn max_files(mut self, files: usize) -> UploadOptions {
+        self.max_files = files;
+        self
+    }
+    pub fn max_field_size(mut self, size: usize) -> UploadOptions {
+        self.max_field_size = size;
+        self
+    }
+    // text fields, counted with repeats of the same name
+    pub fn max_fields(mut self, fields: usize) -> UploadOptions {
+        self.max_fields = fields;
+        self
+    }
+    // exact types or a wildcard subtype like image/*, anything is accepted when none are set
+    pub fn allow(mut self, content_type: &str) -> UploadOptions {
+        self.allowed_types.push(content_type.to_ascii_lowercase());
+        self
+    }
+    pub fn extension(self) -> Extension<Arc<UploadOptions>> {
+        Extension(Arc::new(self))
+    }
+    fn is_allowed(&self, content_type: &str) -> bool {
+        self.allowed_types.is_empty()
+            || self
+                .allowed_types
+                .iter()
+                .any(|allowed| match allowed.strip_suffix("/*") {
+                    Some(kind) => content_type
+                        .split_once('/')
+                        .is_some_and(|(given, _)| given == kind),
+                    None => allowed == content_type,
+                })
+    }
+    fn temp_path(&self) -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let random = std::hash::BuildHasher::hash_one(
+            &std::collections::hash_map::RandomState::new(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+        );
+        self.dir
+            .join(format!("upload-{}-{:016x}", std::process::id(), random))
+    }
+}
+
+impl Default for UploadOptions {
+    fn default() -> UploadOptions {
+        UploadOptions::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct UploadedFile {
+    field: String,
+    file_name: Option<String>,
+    content_type: String,
+    size: u64,
+    path: PathBuf,
+    keep: bool,
+}
+
+impl UploadedFile {
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+    // only the last path component the client sent, never use it unchecked as a path
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub async fn bytes(&self) -> Result<Vec<u8>, AnyError> {
+        Ok(tokio::fs::read(&self.path).await?)
+    }
+    // moves the temp file out of the way of the cleanup on drop
+    pub async fn persist<P: AsRef<Path>>(mut self, to: P) -> Result<PathBuf, AnyError> {
+        let to = to.as_ref().to_path_buf();
+        if tokio::fs::rename(&self.path, &to).await.is_err() {
+            // rename fails across filesystems
+            tokio::fs::copy(&self.path, &to).await?;
+            let _ = tokio::fs::remove_file(&self.path).await;
+        }
+        self.keep = true;
+        Ok(to)
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Upload {
+    pub fields: HashMap<String, String>,
+    pub files: Vec<UploadedFile>,
+}
+
+impl Upload {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(|v| v.as_str())
+    }
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|f| f.field == name)
+    }
+    pub fn take(&mut self, name: &str) -> Option<UploadedFile> {
+        let idx = self.files.iter().position(|f| f.field == name)?;
+        Some(self.files.remove(idx))
+    }
+}
+
+fn bad_request<E>(err: E) -> SimpleError
+where
+    E: std::fmt::Display,
+{
+    SimpleError::new(&err.to_string(), StatusCode::BAD_REQUEST)
+}
+
+fn clean_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    (!name.is_empty() && name != "." && name != "..").then(|| name.to_string())
+}
+
+async fn spill(
+    options: &UploadOptions,
+    field: &mut multer::Field<'_>,
+    file: &mut UploadedFile,
+) -> Result<(), SimpleError> {
+    let io_error = |e: std::io::Error| {
+        SimpleError::wrap(Box::new(e), StatusCode::INTERNAL_SERVER_ERROR)
+            .context("unable to store upload")
+    };
+    // never opens a file that is already there, nor removes it on drop
+    let out = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&file.path)
+        .await;
+    let mut out = match out {
+        Ok(out) => out,
+        Err(e) => {
+            file.keep = true;
+            return Err(io_error(e));
+        }
+    };
+    while let Some(chunk) = field.chunk().await.map_err(bad_request)? {
+        file.size += chunk.len() as u64;
+        if file.size > options.max_file_size {
+            return Err(SimpleError::new(
+                &format!("file {} is too large", file.field),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ));
+        }
+        out.write_all(&chunk).await.map_err(io_error)?;
+    }
+    out.flush().await.map_err(io_error)?;
+    Ok(())
+}
+
+// the body is read directly, so axum's default body limit does not cap uploads, the options do
+#[async_trait]
+impl<S> FromRequest<S> for Upload
+where
+    S: Send + Sync,
+{
+    type Rejection = SimpleError;
+
+    async fn from_request(req: Request, _state: &S) -> Result<Self, Self::Rejection> {
+        let options = req
+            .extensions()
+            .get::<Arc<UploadOptions>>()
+            .cloned()
+            .unwrap_or_default();
+        let boundary = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| multer::parse_boundary(v).ok())
+            .ok_or_else(|| {
+                SimpleError::new(
+                    "expected content type multipart/form-data",
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                )
+            })?;
+        let mut multipart = multer::Multipart::new(req.into_body().into_data_stream(), boundary);
+        let mut upload = Upload::default();
+        let mut fields = 0;
+        while let Some(mut field) = multipart.next_field().await.map_err(bad_request)? {
+            let name = field.name().unwrap_or_default().to_string();
+            let Some(file_name) = field.file_name().map(|n| n.to_string()) else {
+                fields += 1;
+                if fields > options.max_fields {
+                    return Err(SimpleError::new(
+                        "too many fields",
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                    ));
+                }
+                let mut value = Vec::new();
+                while let Some(chunk) = field.chunk().await.map_err(bad_request)? {
+                    value.extend_from_slice(&chunk);
+                    if value.len() > options.max_field_size {
+                        return Err(SimpleError::new(
+                            &format!("field {} is too large", name),
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                        ));
+                    }
+                }
+                let value = String::from_utf8(value).map_err(bad_request)?;
+                upload.fields.insert(name, value);
+                continue;
+            };
+            if upload.files.len() >= options.max_files {
+                return Err(SimpleError::new(
+                    "too many files",
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                ));
+            }
+            let content_type = field
+                .content_type()
+                .map(|m| m.essence_str().to_ascii_lowercase())
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            if !options.is_allowed(&content_type) {
+                return Err(SimpleError::new(
+                    &format!("content type {} is not allowed", content_type),
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                ));
+            }
+            // created before writing, so a failed upload is removed on drop
+            let mut file = UploadedFile {
+                field: name,
+                file_name: clean_file_name(&file_name),
+                content_type,
+                size: 0,
+                path: options.temp_path(),
+                keep: false,
+            };
+            spill(&options, &mut field, &mut file).await?;
+            upload.files.push(file);
+        }
+        Ok(upload)
+    }
+}
@@ -0,0 +1,295 @@
+use std::fmt::Display;
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{ProblemDetails, SimpleError};
+
+// runs after deserialization, rules report into the collector instead of
+// returning early so a client sees every problem at once
+pub trait Validate {
+    fn validate(&self, errors: &mut ValidationErrors);
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationErrors {
+    errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> ValidationErrors {
+        ValidationErrors::default()
+    }
+    pub fn add(&mut self, field: &str, message: &str) -> &mut ValidationErrors {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        });
+        self
+    }
+    pub fn check(&mut self, field: &str, ok: bool, message: &str) -> &mut ValidationErrors {
+        if !ok {
+            self.add(field, message);
+        }
+        self
+    }
+    pub fn required<T>(&mut self, field: &str, value: &Option<T>) -> &mut ValidationErrors {
+        self.check(field, value.is_some(), "is required")
+    }
+    pub fn not_empty(&mut self, field: &str, value: &str) -> &mut ValidationErrors {
+        self.check(field, !value.trim().is_empty(), "must not be empty")
+    }
+    // counts characters, not bytes
+    pub fn length(
+        &mut self,
+        field: &str,
+        value: &str,
+        min: usize,
+        max: usize,
+    ) -> &mut ValidationErrors {
+        let len = value.chars().count();
+        self.check(
+            field,
+            (min..=max).contains(&len),
+            &format!("length must be between {} and {}", min, max),
+        )
+    }
+    pub fn range<T>(&mut self, field: &str, value: T, min: T, max: T) -> &mut ValidationErrors
+    where
+        T: PartialOrd + Display,
+    {
+        let message = format!("must be between {} and {}", min, max);
+        self.check(field, min <= value && value <= max, &message)
+    }
+    pub fn one_of<T>(&mut self, field: &str, value: &T, allowed: &[T]) -> &mut ValidationErrors
+    where
+        T: PartialEq + Display,
+    {
+        let ok = allowed.contains(value);
+        if !ok {
+            let allowed = allowed
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            self.add(field, &format!("must be one of {}", allowed));
+        }
+        self
+    }
+    // deliberately loose, only catches values that cannot be an address at all
+    pub fn email(&mut self, field: &str, value: &str) -> &mut ValidationErrors {
+        let ok = match value.rsplit_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+                    && !value.contains(char::is_whitespace)
+            }
+            None => false,
+        };
+        self.check(field, ok, "must be an email address")
+    }
+    // validates a nested value, its fields are reported as `field.inner`
+    pub fn nested<T: Validate>(&mut self, field: &str, value: &T) -> &mut ValidationErrors {
+        let mut inner = ValidationErrors::new();
+        value.validate(&mut inner);
+        for error in inner.errors {
+            self.errors.push(FieldError {
+                field: format!("{}.{}", field, error.field),
+                message: error.message,
+            });
+        }
+        self
+    }
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+    pub fn errors(&self) -> &[FieldError] {
+        &self.errors
+    }
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+    pub fn run<T: Validate>(value: &T) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        value.validate(&mut errors);
+        errors.into_result()
+    }
+    fn deserialize<E: Display>(err: serde_path_to_error::Error<E>) -> ValidationErrors {
+        let field = err.path().to_string();
+        let mut errors = ValidationErrors::new();
+        // a "." path means the document itself could not be read
+        let field = match field.as_str() {
+            "." => "",
+            field => field,
+        };
+        errors.add(field, &err.into_inner().to_string());
+        errors
+    }
+}
+
+impl std::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "validation failed")?;
+        for (i, error) in self.errors.iter().enumerate() {
+            let sep = if i == 0 { ": " } else { "; " };
+            match error.field.is_empty() {
+                true => write!(f, "{}{}", sep, error.message)?,
+                false => write!(f, "{}{} {}", sep, error.field, error.message)?,
+            }
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for ValidationErrors {}
+
+impl From<ValidationErrors> for SimpleError {
+    fn from(errors: ValidationErrors) -> Self {
+        SimpleError::new(&errors.to_string(), StatusCode::UNPROCESSABLE_ENTITY)
+    }
+}
+
+impl From<ValidationErrors> for ProblemDetails {
+    fn from(errors: ValidationErrors) -> Self {
+        ProblemDetails::new(StatusCode::UNPROCESSABLE_ENTITY)
+            .with_detail("validation failed")
+            .with_extension("errors", &errors.errors)
+    }
+}
+
+impl IntoResponse for ValidationErrors {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "error": "validation failed",
+            "errors": self.errors,
+        });
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValidatedJson<T>(pub T);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValidatedQuery<T>(pub T);
+
+// malformed documents are still a 400, only well formed but invalid input is a 422
+pub enum ValidationRejection {
+    Malformed(SimpleError),
+    Invalid(ValidationErrors),
+}
+
+impl IntoResponse for ValidationRejection {
+    fn into_response(self) -> Response {
+        match self {
+            ValidationRejection::Malformed(err) => err.into_response(),
+            ValidationRejection::Invalid(errors) => errors.into_response(),
+        }
+    }
+}
+
+impl From<ValidationRejection> for SimpleError {
+    fn from(rejection: ValidationRejection) -> Self {
+        match rejection {
+            ValidationRejection::Malformed(err) => err,
+            ValidationRejection::Invalid(errors) => errors.into(),
+        }
+    }
+}
+
+fn is_json(req: &Request) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .is_some_and(|v| v == "application/json" || v.ends_with("+json"))
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ValidationRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_json(&req) {
+            return Err(ValidationRejection::Malformed(SimpleError::new(
+                "expected content type application/json",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            )));
+        }
+        let body = Bytes::from_request(req, state).await.map_err(|e| {
+            ValidationRejection::Malformed(SimpleError::new(&e.body_text(), e.status()))
+        })?;
+        let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+        let value: T = match serde_path_to_error::deserialize(deserializer) {
+            Ok(value) => value,
+            // syntax errors mean the body is not json at all
+            Err(e) if !e.inner().is_data() => {
+                return Err(ValidationRejection::Malformed(SimpleError::json_body(
+                    e.into_inner(),
+                )))
+            }
+            Err(e) => {
+                return Err(ValidationRejection::Invalid(ValidationErrors::deserialize(
+                    e,
+                )))
+            }
+        };
+        ValidationErrors::run(&value).map_err(ValidationRejection::Invalid)?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ValidationRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+        let value: T = serde_path_to_error::deserialize(deserializer)
+            .map_err(|e| ValidationRejection::Invalid(ValidationErrors::deserialize(e)))?;
+        ValidationErrors::run(&value).map_err(ValidationRejection::Invalid)?;
+        Ok(ValidatedQuery(value))
+    }
+}
+
+impl<T> std::ops::Deref for ValidatedJson<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> std::ops::Deref for ValidatedQuery<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
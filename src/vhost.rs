@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use tower::ServiceExt;
+
+use crate::SimpleStatus;
+
+// picks a router by the Host header so several apps can share one listener.
+// exact hosts win over wildcards, longer wildcards over shorter ones, and
+// requests matching nothing go to the fallback or get a 404
+#[derive(Debug, Clone, Default)]
+pub struct VirtualHosts {
+    exact: Vec<(String, Router)>,
+    wildcard: Vec<(String, Router)>,
+    fallback: Option<Router>,
+}
+
+impl VirtualHosts {
+    pub fn new() -> VirtualHosts {
+        VirtualHosts::default()
+    }
+    // `api.example.com` or `*.example.com`, the wildcard matches any depth of
+    // subdomain but not `example.com` itself
+    pub fn host(mut self, pattern: &str, router: Router) -> VirtualHosts {
+        let pattern = pattern.trim().trim_end_matches('.').to_ascii_lowercase();
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => {
+                self.wildcard.push((suffix.to_string(), router));
+                self.wildcard
+                    .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+            }
+            Some(_) => panic!("invalid virtual host pattern: {}", pattern),
+            None => self.exact.push((pattern, router)),
+        }
+        self
+    }
+    pub fn fallback(mut self, router: Router) -> VirtualHosts {
+        self.fallback = Some(router);
+        self
+    }
+    pub fn router(self) -> Router {
+        let hosts = Arc::new(self);
+        Router::new().fallback(move |req: Request| {
+            let hosts = hosts.clone();
+            async move { hosts.serve(req).await }
+        })
+    }
+
+    fn find(&self, host: &str) -> Option<&Router> {
+        if let Some((_, router)) = self.exact.iter().find(|(h, _)| h == host) {
+            return Some(router);
+        }
+        self.wildcard
+            .iter()
+            .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+            .map(|(_, router)| router)
+            .or(self.fallback.as_ref())
+    }
+
+    async fn serve(&self, req: Request) -> Response {
+        let host = request_host(&req).unwrap_or_default();
+        match self.find(&host) {
+            Some(router) => match router.clone().oneshot(req).await {
+                Ok(res) => res,
+                Err(e) => match e {},
+            },
+            None => SimpleStatus::new(StatusCode::NOT_FOUND).into_response(),
+        }
+    }
+}
+
+// http/2 carries the host in the uri authority instead of a header
+fn request_host(req: &Request) -> Option<String> {
+    let host = match req.headers().get(header::HOST) {
+        Some(host) => host.to_str().ok()?,
+        None => req.uri().host()?,
+    };
+    let host = match host.strip_prefix('[') {
+        // ipv6 literal, keep the brackets and drop the port
+        Some(rest) => &host[..rest.find(']')? + 2],
+        None => host.rsplit_once(':').map_or(host, |(host, _)| host),
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
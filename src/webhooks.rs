@@ -0,0 +1,389 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    routing::{delete, get},
+    Json, Router,
+};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    kv::{now, NotFoundError},
+    AnyError, ConflictError, KVManager, Queue, QueuedJob, SimpleError, Version,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookTarget {
+    pub id: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    // empty means every event
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub created: u64,
+}
+
+impl WebhookTarget {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    id: String,
+    target: String,
+    event: String,
+    created: u64,
+    payload: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct Registration {
+    url: String,
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Webhooks {
+    kv: KVManager,
+    key: String,
+    queue: Queue,
+    client: reqwest::Client,
+    timeout: Duration,
+    allow_private: bool,
+}
+
+impl Webhooks {
+    // targets live in kv under `webhooks:<name>`, deliveries go through the queue of the same name
+    pub fn new(kv: &KVManager, name: &str) -> Result<Webhooks, AnyError> {
+        Ok(Webhooks {
+            kv: kv.clone(),
+            key: format!("webhooks:{}", name),
+            queue: Queue::new(kv, &format!("webhooks-{}", name))?
+                .max_attempts(8)
+                .backoff(Duration::from_secs(10)),
+            client: client(false),
+            timeout: Duration::from_secs(10),
+            allow_private: false,
+        })
+    }
+    // lets targets point at loopback and private networks, for local development
+    pub fn allow_private_targets(mut self, allow: bool) -> Webhooks {
+        self.client = client(allow);
+        self.allow_private = allow;
+        self
+    }
+    pub fn max_attempts(mut self, attempts: u32) -> Webhooks {
+        self.queue = self.queue.max_attempts(attempts);
+        self
+    }
+    pub fn backoff(mut self, base: Duration) -> Webhooks {
+        self.queue = self.queue.backoff(base);
+        self
+    }
+    pub fn timeout(mut self, timeout: Duration) -> Webhooks {
+        self.timeout = timeout;
+        self
+    }
+    pub async fn targets(&self) -> Result<Vec<WebhookTarget>, AnyError> {
+        Ok(self
+            .kv
+            .get_some::<Vec<WebhookTarget>>(&self.key)
+            .await?
+            .unwrap_or_default())
+    }
+    async fn update<F>(&self, change: F) -> Result<(), AnyError>
+    where
+        F: Fn(&mut Vec<WebhookTarget>),
+    {
+        loop {
+            let (mut targets, version) =
+                match self.kv.get_versioned::<Vec<WebhookTarget>>(&self.key).await {
+                    Ok(res) => res,
+                    Err(e) if e.is::<NotFoundError>() => (Vec::new(), Version::absent()),
+                    Err(e) => return Err(e),
+                };
+            change(&mut targets);
+            match self.kv.set_if(&self.key, &targets, &version, 0).await {
+                Ok(()) => {}
+                Err(e) if e.is::<ConflictError>() => continue,
+                Err(e) => return Err(e),
+            }
+            return Ok(());
+        }
+    }
+    // the returned secret is what receivers verify signatures with
+    pub async fn register(&self, url: &str, events: &[&str]) -> Result<WebhookTarget, AnyError> {
+        let parsed = reqwest::Url::parse(url)?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(format!("unsupported webhook url: {}", url).into());
+        }
+        if !self.allow_private {
+            match (literal_ip(&parsed), parsed.host_str()) {
+                (Some(ip), _) => public_ip(ip)?,
+                (None, Some(host)) => {
+                    public_addrs(host).await?;
+                }
+                (None, None) => return Err(format!("unsupported webhook url: {}", url).into()),
+            }
+        }
+        let target = WebhookTarget {
+            id: random_id(12)?,
+            url: url.to_string(),
+            secret: format!("whsec_{}", random_id(24)?),
+            events: events.iter().map(|e| e.to_string()).collect(),
+            created: now(),
+        };
+        self.update(|targets| targets.push(target.clone())).await?;
+        Ok(target)
+    }
+    pub async fn unregister(&self, id: &str) -> Result<(), AnyError> {
+        self.update(|targets| targets.retain(|target| target.id != id))
+            .await
+    }
+    // one delivery per interested target, returns how many were queued
+    pub async fn emit<T: Serialize>(&self, event: &str, payload: &T) -> Result<usize, AnyError> {
+        let payload = serde_json::to_value(payload)?;
+        let mut queued = 0;
+        for target in self.targets().await? {
+            if !target.wants(event) {
+                continue;
+            }
+            let delivery = Delivery {
+                id: random_id(12)?,
+                target: target.id,
+                event: event.to_string(),
+                created: now(),
+                payload: payload.clone(),
+            };
+            self.queue.push(&delivery).await?;
+            queued += 1;
+        }
+        Ok(queued)
+    }
+    pub async fn dead_letters(&self) -> Result<Vec<QueuedJob>, AnyError> {
+        self.queue.dead_letters().await
+    }
+    // delivers until shutdown is requested
+    pub async fn worker(&self, concurrency: usize) {
+        let webhooks = self.clone();
+        self.queue
+            .worker(concurrency, move |delivery: Delivery| {
+                let webhooks = webhooks.clone();
+                async move { webhooks.deliver(delivery).await }
+            })
+            .await
+    }
+    async fn deliver(&self, delivery: Delivery) -> Result<(), AnyError> {
+        let targets = self.targets().await?;
+        let Some(target) = targets.iter().find(|t| t.id == delivery.target) else {
+            // unregistered since the event was emitted
+            return Ok(());
+        };
+        let body = serde_json::to_vec(&serde_json::json!({
+            "id": delivery.id,
+            "event": delivery.event,
+            "created": delivery.created,
+            "data": delivery.payload,
+        }))?;
+        // hostnames are checked again by the client's resolver, literal addresses skip it
+        if !self.allow_private {
+            if let Some(ip) = literal_ip(&reqwest::Url::parse(&target.url)?) {
+                public_ip(ip)?;
+            }
+        }
+        let timestamp = now();
+        let res = self
+            .client
+            .post(&target.url)
+            .timeout(self.timeout)
+            .header("content-type", "application/json")
+            .header("webhook-id", &delivery.id)
+            .header("webhook-event", &delivery.event)
+            .header("webhook-timestamp", timestamp.to_string())
+            .header(
+                "webhook-signature",
+                signature(&target.secret, timestamp, &body),
+            )
+            .body(body)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(format!("{} answered {}", target.url, res.status()).into());
+        }
+        Ok(())
+    }
+    // mount behind the app's own authentication, the routes do not check access
+    pub fn router(&self) -> Router {
+        let list = self.clone();
+        let register = self.clone();
+        let remove = self.clone();
+        let dead = self.clone();
+        Router::new()
+            .route(
+                "/",
+                get(move || async move {
+                    let targets = list.targets().await.map_err(unavailable)?;
+                    // secrets are only shown once, when the target is registered
+                    let targets = targets
+                        .into_iter()
+                        .map(|target| WebhookTarget {
+                            secret: String::new(),
+                            ..target
+                        })
+                        .collect::<Vec<_>>();
+                    Ok::<_, SimpleError>(Json(targets))
+                })
+                .post(move |Json(req): Json<Registration>| async move {
+                    let events = req.events.iter().map(|e| e.as_str()).collect::<Vec<_>>();
+                    let target = register
+                        .register(&req.url, &events)
+                        .await
+                        .map_err(|e| SimpleError::wrap(e, StatusCode::BAD_REQUEST))?;
+                    Ok::<_, SimpleError>((StatusCode::CREATED, Json(target)))
+                }),
+            )
+            .route(
+                "/dead",
+                get(
+                    move || async move { dead.dead_letters().await.map(Json).map_err(unavailable) },
+                ),
+            )
+            .route(
+                "/:id",
+                delete(move |Path(id): Path<String>| async move {
+                    remove.unregister(&id).await.map_err(unavailable)?;
+                    Ok::<_, SimpleError>(StatusCode::NO_CONTENT)
+                }),
+            )
+    }
+}
+
+// redirects are not followed, they could lead anywhere after the checks
+fn client(allow_private: bool) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    if !allow_private {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    builder.build().expect("webhook client")
+}
+
+// resolves like the default resolver, but fails for hosts with non-public addresses,
+// so a name cannot be pointed at an internal address after it was registered
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = public_addrs(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+fn literal_ip(url: &reqwest::Url) -> Option<IpAddr> {
+    let host = url.host_str()?;
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+async fn public_addrs(host: &str) -> Result<Vec<SocketAddr>, AnyError> {
+    let addrs = tokio::net::lookup_host((host, 0))
+        .await?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(format!("{} does not resolve", host).into());
+    }
+    for addr in &addrs {
+        public_ip(addr.ip())?;
+    }
+    Ok(addrs)
+}
+
+fn public_ip(ip: IpAddr) -> Result<(), AnyError> {
+    match is_public(ip) {
+        true => Ok(()),
+        false => Err(format!("webhook address {} is not public", ip).into()),
+    }
+}
+
+// not loopback, private, link local (cloud metadata), shared, reserved or multicast
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_v4(ip);
+            }
+            let segments = ip.segments();
+            // nat64 embeds an ipv4 address
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public_v4(Ipv4Addr::new(a, b, c, d));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || segments[0] & 0xfe00 == 0xfc00
+                || segments[0] & 0xffc0 == 0xfe80
+                || segments[0] & 0xffc0 == 0xfec0
+                || segments[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        || a >= 240
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b)))
+}
+
+fn unavailable(err: AnyError) -> SimpleError {
+    SimpleError::wrap(err, StatusCode::SERVICE_UNAVAILABLE).context("unable to access webhooks")
+}
+
+// `t=<unix>,v1=<hex hmac-sha256 of "<unix>.<body>">`, receivers should reject old timestamps
+fn signature(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex = digest
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("t={},v1={}", timestamp, hex)
+}
+
+fn random_id(len: usize) -> Result<String, AnyError> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("unable to generate id: {}", e))?;
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(bytes))
+}
@@ -0,0 +1,438 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{
+        ws::{
+            rejection::WebSocketUpgradeRejection, CloseFrame, Message, WebSocket, WebSocketUpgrade,
+        },
+        ConnectInfo,
+    },
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    routing::{get, MethodRouter},
+};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde::Serialize;
+use tokio::sync::{mpsc, watch};
+
+use crate::{listener::IpConnectInfo, realip::real_ip, AnyError, SimpleError};
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL: u16 = 1002;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for WsMessage {
+    fn from(text: String) -> Self {
+        WsMessage::Text(text)
+    }
+}
+
+impl From<&str> for WsMessage {
+    fn from(text: &str) -> Self {
+        WsMessage::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for WsMessage {
+    fn from(data: Vec<u8>) -> Self {
+        WsMessage::Binary(data)
+    }
+}
+
+impl From<WsMessage> for Message {
+    fn from(message: WsMessage) -> Self {
+        match message {
+            WsMessage::Text(text) => Message::Text(text),
+            WsMessage::Binary(data) => Message::Binary(data),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WsClosedError {}
+impl Display for WsClosedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "websocket connection closed")
+    }
+}
+impl std::error::Error for WsClosedError {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WsConnectionInfo {
+    pub id: u64,
+    pub ip: String,
+    // the subprotocol both sides agreed on, if any
+    pub protocol: Option<String>,
+    // unix seconds
+    pub connected_at: u64,
+}
+
+enum Outgoing {
+    Message(WsMessage),
+    Close(u16, String),
+}
+
+struct Entry {
+    info: WsConnectionInfo,
+    tx: mpsc::Sender<Outgoing>,
+}
+
+// hands out websocket connections and keeps track of the live ones, so they can
+// be listed, messaged and closed from anywhere. clients that stop answering
+// pings are dropped, all connections are closed with 1001 once the listener
+// starts draining
+#[derive(Clone)]
+pub struct WsHub {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    max_message: usize,
+    buffer: usize,
+    protocols: Vec<String>,
+    next_id: Arc<AtomicU64>,
+    connections: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+impl Default for WsHub {
+    fn default() -> Self {
+        WsHub::new()
+    }
+}
+
+impl WsHub {
+    pub fn new() -> WsHub {
+        WsHub {
+            ping_interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+            max_message: 16 * 1024 * 1024,
+            buffer: 64,
+            protocols: Vec::new(),
+            next_id: Arc::new(AtomicU64::new(1)),
+            connections: Default::default(),
+        }
+    }
+    pub fn ping_interval(mut self, interval: Duration) -> WsHub {
+        self.ping_interval = interval;
+        self
+    }
+    // how long after a ping the pong may take before the connection is dropped
+    pub fn pong_timeout(mut self, timeout: Duration) -> WsHub {
+        self.pong_timeout = timeout;
+        self
+    }
+    // larger messages close the connection
+    pub fn max_message(mut self, bytes: usize) -> WsHub {
+        self.max_message = bytes;
+        self
+    }
+    // messages queued per connection, broadcasts skip connections that are full
+    pub fn buffer(mut self, messages: usize) -> WsHub {
+        self.buffer = messages.max(1);
+        self
+    }
+    // subprotocols in order of preference, the first one the client offers is picked
+    pub fn protocols<I, P>(mut self, protocols: I) -> WsHub
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // a GET route that upgrades and runs `on_connect` for every connection,
+    // the connection is closed once it returns
+    pub fn handler<F, Fut, S>(&self, on_connect: F) -> MethodRouter<S>
+    where
+        F: Fn(WsConnection) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+        S: Clone + Send + Sync + 'static,
+    {
+        let hub = self.clone();
+        get(
+            move |ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+                  info: Option<ConnectInfo<IpConnectInfo>>,
+                  headers: HeaderMap| {
+                let hub = hub.clone();
+                let on_connect = on_connect.clone();
+                async move {
+                    let ws = match ws {
+                        Ok(ws) => ws,
+                        Err(e) => {
+                            return SimpleError::new(&e.body_text(), e.status()).into_response()
+                        }
+                    };
+                    let ip = info
+                        .map(|info| real_ip(&headers, &info.0))
+                        .unwrap_or_default();
+                    hub.upgrade(ws, ip, on_connect)
+                }
+            },
+        )
+    }
+
+    fn upgrade<F, Fut>(&self, ws: WebSocketUpgrade, ip: String, on_connect: F) -> Response
+    where
+        F: FnOnce(WsConnection) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let hub = self.clone();
+        ws.max_message_size(self.max_message)
+            .protocols(self.protocols.clone())
+            .on_failed_upgrade(|e| tracing::debug!("websocket upgrade failed: {}", e))
+            .on_upgrade(move |socket| async move { hub.run(socket, ip, on_connect).await })
+    }
+
+    async fn run<F, Fut>(&self, socket: WebSocket, ip: String, on_connect: F)
+    where
+        F: FnOnce(WsConnection) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let info = WsConnectionInfo {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            ip,
+            protocol: socket
+                .protocol()
+                .and_then(|p| p.to_str().ok())
+                .map(str::to_string),
+            connected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let id = info.id;
+        let (write, read) = socket.split();
+        let (out_tx, out_rx) = mpsc::channel(self.buffer);
+        let (in_tx, in_rx) = mpsc::channel(self.buffer);
+        // written by the reader, the writer closes with it once it is set
+        let (closed_tx, closed_rx) = watch::channel(None);
+        let pong = Arc::new(Mutex::new(Instant::now()));
+        self.connections.lock().unwrap().insert(
+            id,
+            Entry {
+                info: info.clone(),
+                tx: out_tx.clone(),
+            },
+        );
+        let mut reader = tokio::spawn(read_loop(read, in_tx, closed_tx, pong.clone()));
+        let conn = WsConnection {
+            info,
+            tx: out_tx,
+            rx: in_rx,
+        };
+        tokio::spawn(on_connect(conn));
+        self.write_loop(write, out_rx, closed_rx, pong).await;
+        self.connections.lock().unwrap().remove(&id);
+        // the client gets a moment to answer the close frame
+        if tokio::time::timeout(Duration::from_secs(5), &mut reader)
+            .await
+            .is_err()
+        {
+            reader.abort();
+        }
+    }
+
+    async fn write_loop(
+        &self,
+        mut write: SplitSink<WebSocket, Message>,
+        mut out_rx: mpsc::Receiver<Outgoing>,
+        mut closed_rx: watch::Receiver<Option<Option<u16>>>,
+        pong: Arc<Mutex<Instant>>,
+    ) {
+        let mut ping = tokio::time::interval(self.ping_interval);
+        ping.tick().await;
+        let shutdown = crate::listener::shutdown_requested();
+        tokio::pin!(shutdown);
+        let (code, reason) = loop {
+            let message = tokio::select! {
+                // the reader's close code wins over the close sent when the callback ends
+                biased;
+                _ = closed_rx.changed() => {
+                    let closed = closed_rx.borrow().flatten();
+                    match closed {
+                        Some(code) => break (code, String::new()),
+                        // the client closed first, closing the sink flushes the queued reply
+                        None => {
+                            let _ = write.close().await;
+                            return;
+                        }
+                    }
+                }
+                outgoing = out_rx.recv() => match outgoing {
+                    Some(Outgoing::Message(message)) => message.into(),
+                    Some(Outgoing::Close(code, reason)) => break (code, reason),
+                    None => break (CLOSE_NORMAL, String::new()),
+                },
+                _ = ping.tick() => {
+                    let since = pong.lock().unwrap().elapsed();
+                    if since > self.ping_interval + self.pong_timeout {
+                        break (CLOSE_GOING_AWAY, "ping timeout".to_string());
+                    }
+                    Message::Ping(Vec::new())
+                }
+                _ = &mut shutdown => break (CLOSE_GOING_AWAY, "server shutting down".to_string()),
+            };
+            if let Err(e) = write.send(message).await {
+                tracing::debug!("websocket write failed: {}", e);
+                return;
+            }
+        };
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let close = CloseFrame {
+            code,
+            reason: reason[..end].to_string().into(),
+        };
+        let _ = write.send(Message::Close(Some(close))).await;
+        let _ = write.close().await;
+    }
+
+    pub fn connections(&self) -> Vec<WsConnectionInfo> {
+        let mut connections = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|info| info.id);
+        connections
+    }
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // waits while the connection's queue is full
+    pub async fn send<M: Into<WsMessage>>(&self, id: u64, message: M) -> Result<(), AnyError> {
+        let tx = match self.connections.lock().unwrap().get(&id) {
+            Some(entry) => entry.tx.clone(),
+            None => return Err(Box::new(WsClosedError {})),
+        };
+        tx.send(Outgoing::Message(message.into()))
+            .await
+            .map_err(|_| Box::new(WsClosedError {}) as AnyError)
+    }
+    // returns how many connections the message was queued for
+    pub fn broadcast<M: Into<WsMessage>>(&self, message: M) -> usize {
+        let message = message.into();
+        let connections = self.connections.lock().unwrap();
+        connections
+            .values()
+            .filter(|entry| {
+                entry
+                    .tx
+                    .try_send(Outgoing::Message(message.clone()))
+                    .is_ok()
+            })
+            .count()
+    }
+    pub fn close(&self, id: u64, code: u16, reason: &str) {
+        if let Some(entry) = self.connections.lock().unwrap().get(&id) {
+            let _ = entry.tx.try_send(Outgoing::Close(code, reason.to_string()));
+        }
+    }
+    pub fn close_all(&self, code: u16, reason: &str) {
+        for entry in self.connections.lock().unwrap().values() {
+            let _ = entry.tx.try_send(Outgoing::Close(code, reason.to_string()));
+        }
+    }
+}
+
+// one side of a connection, handed to the handler's callback
+pub struct WsConnection {
+    info: WsConnectionInfo,
+    tx: mpsc::Sender<Outgoing>,
+    rx: mpsc::Receiver<WsMessage>,
+}
+
+impl WsConnection {
+    pub fn id(&self) -> u64 {
+        self.info.id
+    }
+    pub fn ip(&self) -> &str {
+        &self.info.ip
+    }
+    pub fn protocol(&self) -> Option<&str> {
+        self.info.protocol.as_deref()
+    }
+    pub fn info(&self) -> &WsConnectionInfo {
+        &self.info
+    }
+    // None once the client is gone
+    pub async fn recv(&mut self) -> Option<WsMessage> {
+        self.rx.recv().await
+    }
+    pub async fn send<M: Into<WsMessage>>(&self, message: M) -> Result<(), AnyError> {
+        self.tx
+            .send(Outgoing::Message(message.into()))
+            .await
+            .map_err(|_| Box::new(WsClosedError {}) as AnyError)
+    }
+    pub async fn close(self, code: u16, reason: &str) {
+        let _ = self
+            .tx
+            .send(Outgoing::Close(code, reason.to_string()))
+            .await;
+    }
+}
+
+impl Drop for WsConnection {
+    fn drop(&mut self) {
+        let _ = self
+            .tx
+            .try_send(Outgoing::Close(CLOSE_NORMAL, String::new()));
+    }
+}
+
+// forwards messages until the client is gone. pings and the client's close
+// frame are answered by the socket itself, so `closed` only carries a code
+// when the writer still has to send one
+async fn read_loop(
+    mut read: SplitStream<WebSocket>,
+    in_tx: mpsc::Sender<WsMessage>,
+    closed: watch::Sender<Option<Option<u16>>>,
+    pong: Arc<Mutex<Instant>>,
+) {
+    let code = loop {
+        let message = match read.next().await {
+            Some(Ok(Message::Text(text))) => WsMessage::Text(text),
+            Some(Ok(Message::Binary(data))) => WsMessage::Binary(data),
+            Some(Ok(Message::Pong(_))) => {
+                *pong.lock().unwrap() = Instant::now();
+                continue;
+            }
+            Some(Ok(Message::Ping(_))) => continue,
+            Some(Ok(Message::Close(_))) | None => break None,
+            Some(Err(e)) => {
+                tracing::debug!("websocket read failed: {}", e);
+                break Some(CLOSE_PROTOCOL);
+            }
+        };
+        if in_tx.send(message).await.is_err() {
+            // nobody listens anymore, the writer is closing already
+            break Some(CLOSE_NORMAL);
+        }
+    };
+    closed.send_replace(Some(code));
+}